@@ -0,0 +1,266 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    AddressEntry, AddressList, BTag, DatabaseError, DatabaseErrorKind, DatabaseReader, Query,
+    QueryEntry, ReferenceCountTable, SearchResult, TagIndex, TagIndexTable, TagType,
+};
+
+// Full paths of the tags index, used to skip start candidates without reading tag records.
+// Children are found under full_path of their first parent, other parents link them,
+// i.e. `admins.#87`. Names of tags that link children are taken from REFERENCE_COUNT_TABLE,
+// search can't be narrowed down past them.
+struct PathFilter<'a> {
+    prefixes: HashSet<&'a [u64]>,
+    linking_names: HashSet<u64>,
+}
+
+impl<'a> PathFilter<'a> {
+    fn new(tags: &'a TagIndexTable, references: &ReferenceCountTable) -> Self {
+        let mut prefixes: HashSet<&[u64]> = HashSet::new();
+        for tag_index in tags.tags.iter() {
+            for i in 1..=tag_index.full_path.len() {
+                prefixes.insert(&tag_index.full_path[..i]);
+            }
+        }
+
+        // Name of the first parent accounts for a single reference to the child, every other
+        // referencing name may be a parent linking it. Value references are taken as well.
+        let first_parents: HashMap<u64, u64> = tags
+            .tags
+            .iter()
+            .filter_map(|x| Some((x.offset, *x.full_path.iter().rev().nth(1)?)))
+            .collect();
+        let mut linking_names: HashSet<u64> = HashSet::new();
+        for reference in references.references() {
+            let mut first_parent = first_parents.get(&reference.address()).copied();
+            for name in reference.referencing_tags() {
+                if first_parent == Some(*name) {
+                    first_parent = None;
+                } else {
+                    linking_names.insert(*name);
+                }
+            }
+        }
+
+        PathFilter {
+            prefixes,
+            linking_names,
+        }
+    }
+
+    // Whether names following the first entry of the query can be children of the tag.
+    fn may_match(&self, tag_index: &TagIndex, query: &[QueryEntry]) -> bool {
+        let mut path = tag_index.full_path.clone();
+        for entry in query.iter().skip(1) {
+            let name = match entry {
+                QueryEntry::Name(v) => *v,
+                _ => break,
+            };
+            if path.last().is_some_and(|x| self.linking_names.contains(x)) {
+                return true;
+            }
+            path.push(name);
+            if !self.prefixes.contains(path.as_slice()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl DatabaseReader {
+    // Search from parents to children, i.e. `Joey.wallet.euro`.
+    // First entry selects tags anywhere in the cluster, every next entry is matched against
+    // children of the previous match. Children are entries of AddressList or AddressEntry value.
+    // Returns every full path from the first match to the last one.
+    // Start candidates are narrowed down by their full_path when references of the cluster
+    // are given. Version 1 clusters don't store them, so every candidate is searched.
+    pub fn find_downstream(
+        &mut self,
+        query: &[QueryEntry],
+        tags: &TagIndexTable,
+        references: Option<&ReferenceCountTable>,
+    ) -> Result<SearchResult, DatabaseError> {
+        let first = match query.first() {
+            Some(v) => v,
+            None => return Ok(SearchResult::None),
+        };
+
+        // Query can't match when one of it's names isn't present in any full_path.
+        let names: HashSet<u64> = tags
+            .tags
+            .iter()
+            .flat_map(|x| x.full_path.iter().copied())
+            .collect();
+        for entry in query {
+            if let QueryEntry::Name(name) = entry {
+                if !names.contains(name) {
+                    return Ok(SearchResult::None);
+                }
+            }
+        }
+
+        let filter = references.map(|x| PathFilter::new(tags, x));
+        let mut matches: Vec<AddressList> = Vec::new();
+        for tag_index in tags.tags.iter() {
+            // Start candidates are picked from tags index without reading tag records.
+            if filter
+                .as_ref()
+                .is_some_and(|x| !x.may_match(tag_index, query))
+            {
+                continue;
+            }
+            let matched = match first {
+                QueryEntry::Name(name) => tag_index.name == *name,
+                QueryEntry::Id(id) => tag_index.tag_id == *id,
+                QueryEntry::ArrayIndex(_) => false,
+                QueryEntry::Conditional(predicate) => match self.read_tag_data(tag_index.offset) {
+                    Ok(data) => predicate(data),
+                    Err(_) => false,
+                },
+                QueryEntry::QueryConditional(queries, predicate) => matches!(
+                    self.test_query_conditional(queries, predicate, tag_index.offset),
+                    Ok(true)
+                ),
+                _ => return Err(DatabaseErrorKind::UnsupportedQuery.into()),
+            };
+            if !matched {
+                continue;
+            }
+
+            let entry = AddressEntry {
+                name: tag_index.name,
+                address: tag_index.offset,
+            };
+            self.recursive_downstream_search(query, 1, vec![entry], &mut matches)?;
+        }
+
+        if matches.is_empty() {
+            return Ok(SearchResult::None);
+        }
+        Ok(SearchResult::Found(matches))
+    }
+
+    // Search children of already found tag, every entry is matched against children.
+    // Returned paths start with the tag itself.
+    pub fn find_downstream_from(
+        &mut self,
+        query: &[QueryEntry],
+        tag: AddressEntry,
+    ) -> Result<SearchResult, DatabaseError> {
+        let mut matches: Vec<AddressList> = Vec::new();
+        self.recursive_downstream_search(query, 0, vec![tag], &mut matches)?;
+
+        if matches.is_empty() {
+            return Ok(SearchResult::None);
+        }
+        Ok(SearchResult::Found(matches))
+    }
+
+    fn recursive_downstream_search(
+        &mut self,
+        query: &[QueryEntry],
+        query_index: usize,
+        path: Vec<AddressEntry>,
+        matches: &mut Vec<AddressList>,
+    ) -> Result<(), DatabaseError> {
+        if query_index == query.len() {
+            // Query has ended, path is a full match.
+            matches.push(AddressList::new(path));
+            return Ok(());
+        }
+
+        let current = match path.last() {
+            Some(v) => v.address,
+            None => return Ok(()),
+        };
+        let children = match self.read_tag_data(current) {
+            Ok(tag) => match tag.tag_data {
+                TagType::AddressList(list) => list.array,
+                TagType::AddressEntry(entry) => vec![entry],
+                _ => return Ok(()),
+            },
+            Err(_) => return Ok(()),
+        };
+
+        for (i, child) in children.into_iter().enumerate() {
+            let matched = match &query[query_index] {
+                QueryEntry::Name(name) => child.name == *name,
+                QueryEntry::ArrayIndex(index) => i as u64 == *index,
+                QueryEntry::Id(id) => match self.read_tag_data(child.address) {
+                    Ok(data) => data.tag_id == *id,
+                    Err(_) => false,
+                },
+                QueryEntry::Conditional(predicate) => match self.read_tag_data(child.address) {
+                    Ok(data) => predicate(data),
+                    Err(_) => false,
+                },
+                QueryEntry::QueryConditional(queries, predicate) => matches!(
+                    self.test_query_conditional(queries, predicate, child.address),
+                    Ok(true)
+                ),
+                _ => return Err(DatabaseErrorKind::UnsupportedQuery.into()),
+            };
+
+            // Tag can't be it's own descendant.
+            if !matched || path.contains(&child) {
+                continue;
+            }
+            let mut next_path = path.clone();
+            next_path.push(child);
+            self.recursive_downstream_search(query, query_index + 1, next_path, matches)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BTag {
+    // Run downstream query in every cluster. Names are resolved through names index of each
+    // cluster, clusters missing any of the names are skipped.
+    // Returns cluster index and full path of every match, `:<num>` of the query is respected.
+    pub fn find_downstream(
+        &mut self,
+        query: &Query,
+    ) -> Result<Vec<(u64, AddressList)>, DatabaseError> {
+        let mut results: Vec<(u64, AddressList)> = Vec::new();
+
+        for cluster in self.clusters.iter() {
+            let cluster_index = cluster.cluster_index;
+            let names = match self.name_registries.get(&cluster_index) {
+                Some(v) => v,
+                None => continue,
+            };
+            if !query.names().iter().all(|x| names.id(x).is_some()) {
+                continue;
+            }
+
+            let entries = match query.to_downstream_entries(|x| names.id(x)) {
+                Ok(v) => v,
+                Err(_) => return Err(DatabaseErrorKind::UnsupportedQuery.into()),
+            };
+            let tags = match self.tag_index_tables.get(&cluster_index) {
+                Some(v) => v,
+                None => continue,
+            };
+            let references = if cluster.version < 2 {
+                None
+            } else {
+                self.reference_count_tables.get(&cluster_index)
+            };
+            let reader = &mut self.readers[self.cluster_readers[&cluster_index]];
+            reader.select_cluster(cluster);
+
+            if let SearchResult::Found(paths) =
+                reader.find_downstream(&entries, tags, references)?
+            {
+                results.extend(paths.into_iter().map(|x| (cluster_index, x)));
+            }
+        }
+
+        if let Some(nth) = query.nth {
+            return Ok(results.into_iter().nth(nth as usize).into_iter().collect());
+        }
+        Ok(results)
+    }
+}
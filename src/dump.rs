@@ -0,0 +1,670 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    io::Write,
+};
+
+use crate::{
+    BTag, ClusterMetadata, DatabaseError, DatabaseErrorKind, DatabaseReader, IndexTablePage,
+    TagData, TagType, DATA_TYPE_ADDRESS_ENTRY, DATA_TYPE_ADDRESS_LIST, DATA_TYPE_CHAR,
+    DATA_TYPE_DOUBLE, DATA_TYPE_FLOAT, DATA_TYPE_FREE, DATA_TYPE_INTEGER, DATA_TYPE_TEXT,
+    DATA_TYPE_VALUE_REFERENCE,
+};
+
+// Name of tag_data_type as used by docs/specification.md.
+pub fn data_type_name(tag_data_type: u8) -> &'static str {
+    match tag_data_type {
+        DATA_TYPE_INTEGER => "Integer",
+        DATA_TYPE_FLOAT => "Float",
+        DATA_TYPE_DOUBLE => "Double",
+        DATA_TYPE_ADDRESS_ENTRY => "Address",
+        DATA_TYPE_ADDRESS_LIST => "AddressList",
+        DATA_TYPE_TEXT => "Text",
+        DATA_TYPE_CHAR => "Char[]",
+        DATA_TYPE_VALUE_REFERENCE => "ValueReference",
+        DATA_TYPE_FREE => "free",
+        _ => "unknown",
+    }
+}
+
+impl BTag {
+    // Name string of the name, or `?<name>` when the cluster doesn't have it.
+    pub fn display_name(&self, cluster_index: u64, name: u64) -> String {
+        match self.name_string(cluster_index, name) {
+            Some(v) => v.to_string(),
+            None => format!("?{}", name),
+        }
+    }
+
+    // Names of full_path joined with dots, i.e. `users.joey.wallet`.
+    pub fn display_path(&self, cluster_index: u64, full_path: &[u64]) -> String {
+        let names: Vec<String> = full_path
+            .iter()
+            .map(|x| self.display_name(cluster_index, *x))
+            .collect();
+        names.join(".")
+    }
+
+    // `#<tag_id>` of the tag at the address, or `@<address>` when no tag is there.
+    pub fn display_address(&self, cluster_index: u64, address: u64) -> String {
+        match self.tag_at(cluster_index, address) {
+            Some(v) => format!("#{}", v.tag_id),
+            None => format!("@{}", address),
+        }
+    }
+
+    // Value in the syntax of Set statement, addresses are shown as tag ids.
+    pub fn display_value(&self, cluster_index: u64, value: &TagType) -> String {
+        match value {
+            TagType::Integer(_)
+            | TagType::Float(_)
+            | TagType::Double(_)
+            | TagType::Text(_)
+            | TagType::Char(_) => value.to_string(),
+            TagType::AddressEntry(entry) => format!(
+                "{} {}",
+                self.display_name(cluster_index, entry.name),
+                self.display_address(cluster_index, entry.address)
+            ),
+            TagType::AddressList(list) => {
+                let entries: Vec<String> = list
+                    .array
+                    .iter()
+                    .map(|x| {
+                        format!(
+                            "{} {}",
+                            self.display_name(cluster_index, x.name),
+                            self.display_address(cluster_index, x.address)
+                        )
+                    })
+                    .collect();
+                format!("[{}]", entries.join(", "))
+            }
+            TagType::ValueReference(reference) => {
+                format!(
+                    "&{}",
+                    self.display_address(cluster_index, reference.address)
+                )
+            }
+        }
+    }
+
+    // Write every cluster as a tree of tags. Tags are placed under their first parent,
+    // which depth is counted through, other parents are listed with every tag.
+    pub fn dump<W: Write>(&mut self, out: &mut W) -> Result<(), DatabaseError> {
+        let cluster_indexes: Vec<u64> = self.clusters.iter().map(|x| x.cluster_index).collect();
+        for cluster_index in cluster_indexes {
+            if let Some(cluster) = self.cluster(cluster_index) {
+                writeln!(
+                    out,
+                    "cluster {} (version {}, {:?}, offset {}, {} bytes)",
+                    cluster.cluster_index,
+                    cluster.version,
+                    cluster.encoding,
+                    cluster.cluster_offset,
+                    cluster.database_size
+                )?;
+            }
+            self.dump_cluster(cluster_index, out)?;
+        }
+
+        Ok(())
+    }
+
+    fn dump_cluster<W: Write>(
+        &mut self,
+        cluster_index: u64,
+        out: &mut W,
+    ) -> Result<(), DatabaseError> {
+        let tags: Vec<(u64, u64)> = match self.tags_index(cluster_index) {
+            Some(v) => v.tags.iter().map(|x| (x.tag_id, x.offset)).collect(),
+            None => Vec::new(),
+        };
+        let tag_ids: Vec<u64> = tags.iter().map(|(tag_id, _)| *tag_id).collect();
+
+        // Unreadable tags are still shown, as roots.
+        let mut records: HashMap<u64, Result<TagData<TagType>, DatabaseError>> = HashMap::new();
+        let mut roots: Vec<u64> = Vec::new();
+        let mut children: HashMap<u64, Vec<u64>> = HashMap::new();
+        for (tag_id, offset) in tags.iter() {
+            let record = match self.reader(cluster_index) {
+                Some(reader) => reader.read_tag_data(*offset).and_then(|mut x| {
+                    reader.read_parents(*offset, &mut x)?;
+                    Ok(x)
+                }),
+                None => Err(DatabaseErrorKind::TagMissing.into()),
+            };
+            let first_parent = match &record {
+                Ok(v) => v
+                    .tag_parents
+                    .array
+                    .first()
+                    .and_then(|x| self.tag_at(cluster_index, x.address))
+                    .map(|x| x.tag_id),
+                Err(_) => None,
+            };
+            match first_parent {
+                Some(v) if v != *tag_id => children.entry(v).or_default().push(*tag_id),
+                _ => roots.push(*tag_id),
+            }
+            records.insert(*tag_id, record);
+        }
+
+        // Tags of a first parent cycle can't be reached from roots, they follow them.
+        let mut visited: HashSet<u64> = HashSet::new();
+        let mut stack: Vec<(u64, usize)> = roots.iter().rev().map(|x| (*x, 1)).collect();
+        let mut unvisited = tag_ids.iter();
+        loop {
+            let (tag_id, level) = match stack.pop() {
+                Some(v) => v,
+                None => match unvisited.find(|x| !visited.contains(x)) {
+                    Some(v) => (*v, 1),
+                    None => break,
+                },
+            };
+            if !visited.insert(tag_id) {
+                continue;
+            }
+
+            self.dump_tag(cluster_index, tag_id, &records[&tag_id], level, out)?;
+            if let Some(v) = children.get(&tag_id) {
+                stack.extend(v.iter().rev().map(|x| (*x, level + 1)));
+            }
+        }
+
+        Ok(())
+    }
+
+    // `  euro #2 Double = 1.5 (depth 2, parents [wallet #1], address 173, offset 235, 57 bytes)`
+    fn dump_tag<W: Write>(
+        &self,
+        cluster_index: u64,
+        tag_id: u64,
+        record: &Result<TagData<TagType>, DatabaseError>,
+        level: usize,
+        out: &mut W,
+    ) -> Result<(), DatabaseError> {
+        let indent = "  ".repeat(level);
+        let (name, address) = match self.tag_at_id(cluster_index, tag_id) {
+            Some((name, address)) => (self.display_name(cluster_index, name), address),
+            None => (String::from("?"), 0),
+        };
+        let offset = self
+            .cluster(cluster_index)
+            .map(|x| x.index_table_offset.saturating_add(address))
+            .unwrap_or_default();
+
+        let record = match record {
+            Ok(v) => v,
+            Err(e) => {
+                writeln!(
+                    out,
+                    "{}{} #{} unreadable: {} (address {}, offset {})",
+                    indent, name, tag_id, e, address, offset
+                )?;
+                return Ok(());
+            }
+        };
+        let parents: Vec<String> = record
+            .tag_parents
+            .array
+            .iter()
+            .map(|x| {
+                format!(
+                    "{} {}",
+                    self.display_name(cluster_index, x.name),
+                    self.display_address(cluster_index, x.address)
+                )
+            })
+            .collect();
+        writeln!(
+            out,
+            "{}{} #{} {} = {} (depth {}, parents [{}], address {}, offset {}, {} bytes)",
+            indent,
+            name,
+            tag_id,
+            data_type_name(record.tag_data_type),
+            self.display_value(cluster_index, &record.tag_data),
+            record.tag_depth,
+            parents.join(", "),
+            address,
+            offset,
+            record.tag_total_size
+        )?;
+
+        Ok(())
+    }
+
+    // Name and address of the tag from the data index table of the cluster.
+    fn tag_at_id(&self, cluster_index: u64, tag_id: u64) -> Option<(u64, u64)> {
+        self.tags_index(cluster_index)?
+            .tags
+            .iter()
+            .find(|x| x.tag_id == tag_id)
+            .map(|x| (x.name, x.offset))
+    }
+
+    // Write field by field layout of every file, reference DatabaseReader::dump_layout.
+    pub fn dump_layout<W: Write>(&mut self, out: &mut W) -> Result<(), DatabaseError> {
+        for reader in self.readers.iter_mut() {
+            writeln!(out, "file {}", reader.path.display())?;
+            reader.dump_layout(out)?;
+        }
+
+        Ok(())
+    }
+}
+
+// `     62  index_table_size          230`, offset is absolute in the file.
+fn field<W: Write>(
+    out: &mut W,
+    offset: u64,
+    name: &str,
+    value: impl Display,
+) -> Result<(), DatabaseError> {
+    writeln!(out, "{:>10}  {:<30} {}", offset, name, value)?;
+    Ok(())
+}
+
+impl DatabaseReader {
+    // Write every field of the file in the order of docs/specification.md, with it's absolute
+    // offset. Tag data records are found by following tag_total_size from the end of every
+    // index table page and from every index entry, free records included.
+    // Parts that can't be read are reported and skipped.
+    pub fn dump_layout<W: Write>(&mut self, out: &mut W) -> Result<(), DatabaseError> {
+        let mut cluster_offset: u64 = 0;
+        loop {
+            let cluster = match self.read_cluster(cluster_offset) {
+                Ok(v) => v,
+                Err(e) => {
+                    writeln!(out, "{:>10}  unreadable cluster: {}", cluster_offset, e)?;
+                    break;
+                }
+            };
+            self.dump_cluster_layout(&cluster, out)?;
+
+            if cluster.next_cluster <= cluster_offset {
+                break;
+            }
+            cluster_offset = cluster.next_cluster;
+        }
+
+        Ok(())
+    }
+
+    fn dump_cluster_layout<W: Write>(
+        &mut self,
+        cluster: &ClusterMetadata,
+        out: &mut W,
+    ) -> Result<(), DatabaseError> {
+        let offset = cluster.cluster_offset;
+        writeln!(
+            out,
+            "cluster {} at offset {}",
+            cluster.cluster_index, offset
+        )?;
+        field(out, offset, "BTAG", "\"BTAG\"")?;
+        field(out, offset + 4, "version", cluster.version)?;
+        field(out, offset + 8, "cluster_index", cluster.cluster_index)?;
+        field(
+            out,
+            offset + 16,
+            "index_table_offset",
+            cluster.index_table_offset,
+        )?;
+        field(
+            out,
+            offset + 24,
+            "text_encoding",
+            format!("{} ({:?})", cluster.text_encoding, cluster.encoding),
+        )?;
+        field(out, offset + 26, "database_size", cluster.database_size)?;
+        field(out, offset + 34, "last_name_index", cluster.last_name_index)?;
+        field(
+            out,
+            offset + 42,
+            "names_index_padding",
+            cluster.names_index_padding,
+        )?;
+        field(
+            out,
+            offset + 46,
+            "data_index_padding",
+            cluster.data_index_padding,
+        )?;
+        field(
+            out,
+            offset + 50,
+            "minum_tag_data_padding",
+            cluster.tag_data_padding,
+        )?;
+        field(out, offset + 54, "next_cluster", cluster.next_cluster)?;
+
+        let mut pages: Vec<IndexTablePage> = Vec::new();
+        let mut page_error: Option<DatabaseError> = None;
+        for page in self.index_table_pages(cluster) {
+            match page {
+                Ok(v) => pages.push(v),
+                Err(e) => {
+                    page_error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        // Ranges taken by pages and their reference count tables, records start after them.
+        let mut taken: Vec<(u64, u64)> = Vec::new();
+        let mut starts: BTreeSet<u64> = BTreeSet::new();
+        let mut names: HashMap<u64, String> = HashMap::new();
+        for page in pages.iter() {
+            self.dump_page_layout(cluster, page, out)?;
+
+            let table = &page.index_table;
+            let page_end = table.page_address.saturating_add(table.index_table_size);
+            taken.push((table.page_address, page_end));
+            starts.insert(page_end);
+            if table.index_table_references_size != 0 {
+                let references_start = table
+                    .page_address
+                    .saturating_add(table.index_table_references_offset);
+                let references_end =
+                    references_start.saturating_add(table.index_table_references_size.into());
+                if references_start >= page_end {
+                    taken.push((references_start, references_end));
+                    starts.insert(references_end);
+                }
+            }
+            for name in page.names.names.iter() {
+                names.insert(name.name, name.name_string.clone());
+            }
+            for tag in page.tags.tags.iter() {
+                starts.insert(tag.offset);
+            }
+        }
+        if let Some(e) = page_error {
+            writeln!(out, "{:>10}  unreadable index table page: {}", "", e)?;
+        }
+
+        let file_end = self.file_size()?;
+        let mut cluster_end = cluster
+            .cluster_offset
+            .saturating_add(cluster.database_size)
+            .min(file_end);
+        if cluster.next_cluster > cluster.cluster_offset {
+            cluster_end = cluster_end.min(cluster.next_cluster);
+        }
+        let end = cluster_end.saturating_sub(cluster.index_table_offset);
+
+        let mut visited: HashSet<u64> = HashSet::new();
+        while let Some(address) = starts.pop_first() {
+            if address >= end
+                || !visited.insert(address)
+                || taken
+                    .iter()
+                    .any(|(start, end)| address >= *start && address < *end)
+            {
+                continue;
+            }
+
+            let record_end = self.dump_record_layout(cluster, address, &names, out)?;
+            if let Some(v) = record_end {
+                taken.push((address, v));
+                starts.insert(v);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dump_page_layout<W: Write>(
+        &mut self,
+        cluster: &ClusterMetadata,
+        page: &IndexTablePage,
+        out: &mut W,
+    ) -> Result<(), DatabaseError> {
+        let table = &page.index_table;
+        let base = cluster
+            .index_table_offset
+            .saturating_add(table.page_address);
+        writeln!(out, "index table page at address {}", table.page_address)?;
+        field(out, base, "index_table_size", table.index_table_size)?;
+        field(
+            out,
+            base + 8,
+            "index_table_names_size",
+            table.index_table_names_size,
+        )?;
+        field(
+            out,
+            base + 12,
+            "index_table_names_offset",
+            table.index_table_names_offset,
+        )?;
+        field(
+            out,
+            base + 20,
+            "index_table_tags_size",
+            table.index_table_tags_size,
+        )?;
+        field(
+            out,
+            base + 24,
+            "index_table_tags_offset",
+            table.index_table_tags_offset,
+        )?;
+        field(
+            out,
+            base + 32,
+            "index_table_next_page_offset",
+            table.index_table_next_page_offset,
+        )?;
+        if cluster.version >= 2 {
+            field(
+                out,
+                base + 40,
+                "index_table_references_size",
+                table.index_table_references_size,
+            )?;
+            field(
+                out,
+                base + 44,
+                "index_table_references_offset",
+                table.index_table_references_offset,
+            )?;
+        }
+
+        writeln!(out, "names")?;
+        let mut offset = base.saturating_add(table.index_table_names_offset);
+        for name in page.names.names.iter() {
+            let size = u64::from(name.name_string_size);
+            field(out, offset, "name", name.name)?;
+            field(out, offset + 8, "name_string_size", name.name_string_size)?;
+            field(
+                out,
+                offset + 10,
+                "name_string",
+                format!("{:?}", name.name_string),
+            )?;
+            field(
+                out,
+                offset + 10 + size,
+                "padding",
+                format!("{} bytes", cluster.names_index_padding),
+            )?;
+            offset += 10 + size + u64::from(cluster.names_index_padding);
+        }
+
+        writeln!(out, "tags")?;
+        for tag in page.tags.tags.iter() {
+            let offset = cluster.index_table_offset.saturating_add(tag.entry_address);
+            let path_size = 8 * tag.full_path.len() as u64;
+            field(out, offset, "tag_id", tag.tag_id)?;
+            field(out, offset + 8, "name", tag.name)?;
+            field(out, offset + 16, "depth", tag.depth)?;
+            field(
+                out,
+                offset + 24,
+                "full_path",
+                format!("{:?}", tag.full_path),
+            )?;
+            field(out, offset + 24 + path_size, "offset", tag.offset)?;
+            field(
+                out,
+                offset + 32 + path_size,
+                "padding",
+                format!("{} bytes", cluster.data_index_padding),
+            )?;
+        }
+
+        if table.index_table_references_size != 0 {
+            writeln!(out, "references")?;
+            let mut offset = base.saturating_add(table.index_table_references_offset);
+            for reference in page.references.references() {
+                field(out, offset, "address", reference.address())?;
+                field(out, offset + 8, "count", reference.count())?;
+                field(
+                    out,
+                    offset + 16,
+                    "referencing_tags",
+                    format!("{:?}", reference.referencing_tags()),
+                )?;
+                offset += 16 + 8 * reference.count();
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write tag data record at the address, returning it's end when it could be read.
+    fn dump_record_layout<W: Write>(
+        &mut self,
+        cluster: &ClusterMetadata,
+        address: u64,
+        names: &HashMap<u64, String>,
+        out: &mut W,
+    ) -> Result<Option<u64>, DatabaseError> {
+        let base = cluster.index_table_offset.saturating_add(address);
+        let name = |x: u64| match names.get(&x) {
+            Some(v) => format!("{} ({})", x, v),
+            None => x.to_string(),
+        };
+
+        let (tag_total_size, tag_data_type) = match self.read_record_header(address) {
+            Some(v) => v,
+            None => {
+                writeln!(
+                    out,
+                    "{:>10}  unreadable tag data at address {}",
+                    base, address
+                )?;
+                return Ok(None);
+            }
+        };
+        let record_end = match address.checked_add(tag_total_size) {
+            Some(v) if tag_total_size > 0 => v,
+            _ => {
+                writeln!(
+                    out,
+                    "{:>10}  unreadable tag data at address {}",
+                    base, address
+                )?;
+                return Ok(None);
+            }
+        };
+
+        if tag_data_type == DATA_TYPE_FREE {
+            writeln!(out, "free record at address {}", address)?;
+            field(out, base + 8, "tag_total_size", tag_total_size)?;
+            return Ok(Some(record_end));
+        }
+
+        let mut record = match self.read_tag_data(address) {
+            Ok(v) => v,
+            Err(e) => {
+                writeln!(
+                    out,
+                    "{:>10}  unreadable tag data at address {}: {}",
+                    base, address, e
+                )?;
+                return Ok(None);
+            }
+        };
+        self.read_parents(address, &mut record)?;
+
+        writeln!(out, "tag data at address {}", address)?;
+        field(out, base, "tag_id", record.tag_id)?;
+        field(out, base + 8, "tag_total_size", record.tag_total_size)?;
+        field(out, base + 16, "tag_name", name(record.tag_name))?;
+        field(out, base + 24, "tag_depth_level", record.tag_depth)?;
+        field(out, base + 32, "tag_parents_size", record.tag_parents_size)?;
+        let mut offset = base + 40;
+        for parent in record.tag_parents.array.iter() {
+            field(out, offset, "parent name", name(parent.name))?;
+            field(out, offset + 8, "parent address", parent.address)?;
+            offset += 16;
+        }
+        field(
+            out,
+            offset,
+            "tag_data_type",
+            format!(
+                "{} ({})",
+                record.tag_data_type,
+                data_type_name(record.tag_data_type)
+            ),
+        )?;
+        field(out, offset + 1, "tag_data_size", record.tag_data_size)?;
+
+        let data = offset + 9;
+        match &record.tag_data {
+            TagType::Integer(v) => field(out, data, "tag_data", v)?,
+            TagType::Float(v) => field(out, data, "tag_data", format!("{:?}", v))?,
+            TagType::Double(v) => field(out, data, "tag_data", format!("{:?}", v))?,
+            TagType::Text(v) => {
+                field(
+                    out,
+                    data,
+                    "text_size",
+                    self.current_encoding.encode(v)?.len(),
+                )?;
+                field(out, data + 2, "text", format!("{:?}", v))?;
+            }
+            TagType::Char(v) => {
+                field(
+                    out,
+                    data,
+                    "char_size",
+                    self.current_encoding.encode(v)?.len(),
+                )?;
+                field(out, data + 1, "text", format!("{:?}", v))?;
+            }
+            TagType::AddressEntry(entry) => {
+                field(out, data, "name", name(entry.name))?;
+                field(out, data + 8, "address", entry.address)?;
+            }
+            TagType::AddressList(list) => {
+                field(out, data, "address_count", list.address_count)?;
+                for (i, entry) in list.array.iter().enumerate() {
+                    let offset = data + 8 + 16 * i as u64;
+                    field(out, offset, "name", name(entry.name))?;
+                    field(out, offset + 8, "address", entry.address)?;
+                }
+            }
+            TagType::ValueReference(reference) => field(out, data, "address", reference.address)?,
+        }
+
+        let data_end = data.saturating_add(record.tag_data_size);
+        field(
+            out,
+            data_end,
+            "padding",
+            format!(
+                "{} bytes",
+                base.saturating_add(record.tag_total_size)
+                    .saturating_sub(data_end)
+            ),
+        )?;
+
+        Ok(Some(record_end))
+    }
+}
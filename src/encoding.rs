@@ -0,0 +1,135 @@
+use crate::{DatabaseError, DatabaseErrorKind};
+
+// Codes of text_encoding of cluster metadata, reference docs/specification.md.
+pub const TEXT_ENCODING_UTF8: u16 = 0;
+pub const TEXT_ENCODING_UTF16LE: u16 = 1;
+pub const TEXT_ENCODING_LATIN1: u16 = 2;
+
+// Encoding of names, Text and Char[] values of a cluster.
+// Sizes stored in the file are sizes of encoded bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Latin1,
+}
+
+impl TextEncoding {
+    // Encoding registered for the text_encoding code.
+    pub fn from_code(text_encoding: u16) -> Result<TextEncoding, DatabaseError> {
+        match text_encoding {
+            TEXT_ENCODING_UTF8 => Ok(TextEncoding::Utf8),
+            TEXT_ENCODING_UTF16LE => Ok(TextEncoding::Utf16Le),
+            TEXT_ENCODING_LATIN1 => Ok(TextEncoding::Latin1),
+            _ => Err(DatabaseErrorKind::UnsupportedTextEncoding.into()),
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            TextEncoding::Utf8 => TEXT_ENCODING_UTF8,
+            TextEncoding::Utf16Le => TEXT_ENCODING_UTF16LE,
+            TextEncoding::Latin1 => TEXT_ENCODING_LATIN1,
+        }
+    }
+
+    pub fn decode(&self, buf: &[u8]) -> Result<String, DatabaseError> {
+        match self {
+            TextEncoding::Utf8 => match String::from_utf8(buf.to_vec()) {
+                Ok(v) => Ok(v),
+                Err(_) => Err(DatabaseErrorKind::StringValidity.into()),
+            },
+            TextEncoding::Utf16Le => {
+                if !buf.len().is_multiple_of(2) {
+                    return Err(DatabaseErrorKind::StringValidity.into());
+                }
+                let units: Vec<u16> = buf
+                    .chunks_exact(2)
+                    .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                    .collect();
+                match String::from_utf16(&units) {
+                    Ok(v) => Ok(v),
+                    Err(_) => Err(DatabaseErrorKind::StringValidity.into()),
+                }
+            }
+            // Every byte is the code point of the same value.
+            TextEncoding::Latin1 => Ok(buf.iter().map(|x| char::from(*x)).collect()),
+        }
+    }
+
+    // Strings with characters the encoding can't represent are rejected.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, DatabaseError> {
+        match self {
+            TextEncoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            TextEncoding::Utf16Le => {
+                Ok(text.encode_utf16().flat_map(|x| x.to_le_bytes()).collect())
+            }
+            TextEncoding::Latin1 => {
+                let mut buf: Vec<u8> = Vec::with_capacity(text.len());
+                for c in text.chars() {
+                    match u8::try_from(c) {
+                        Ok(v) => buf.push(v),
+                        Err(_) => return Err(DatabaseErrorKind::StringValidity.into()),
+                    }
+                }
+                Ok(buf)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_round_trips_in_every_encoding() {
+        for encoding in [
+            TextEncoding::Utf8,
+            TextEncoding::Utf16Le,
+            TextEncoding::Latin1,
+        ] {
+            for text in ["", "joey", "déjà vu", "ÿ"] {
+                let buf = encoding.encode(text).unwrap();
+                assert_eq!(encoding.decode(&buf).unwrap(), text);
+            }
+        }
+        assert_eq!(
+            TextEncoding::Latin1.encode("crème").unwrap(),
+            b"cr\xe8me".to_vec()
+        );
+        assert_eq!(
+            TextEncoding::Utf16Le.encode("日本").unwrap(),
+            vec![0xe5, 0x65, 0x2c, 0x67]
+        );
+        // Latin-1 has no € sign.
+        assert_eq!(
+            TextEncoding::Latin1.encode("5 €").unwrap_err().kind(),
+            DatabaseErrorKind::StringValidity
+        );
+    }
+
+    #[test]
+    fn unknown_encoding_is_refused() {
+        for code in [0, 1, 2] {
+            assert_eq!(TextEncoding::from_code(code).unwrap().code(), code);
+        }
+        assert_eq!(
+            TextEncoding::from_code(3).unwrap_err().kind(),
+            DatabaseErrorKind::UnsupportedTextEncoding
+        );
+
+        // Invalid UTF-16LE is refused as well, odd size can't hold code units.
+        for buf in [&[b'a', 0, b'b'][..], &[0, 0xd8][..]] {
+            assert_eq!(
+                TextEncoding::Utf16Le.decode(buf).unwrap_err().kind(),
+                DatabaseErrorKind::StringValidity
+            );
+        }
+        assert_eq!(
+            TextEncoding::Utf8.decode(&[0xff]).unwrap_err().kind(),
+            DatabaseErrorKind::StringValidity
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{BTag, DatabaseErrorKind, DatabaseReader};
+
+// Error together with where it happened. Context is filled by the code closest to the file,
+// so path, offset and cluster index point at the record that failed.
+#[derive(Debug)]
+pub struct DatabaseError {
+    kind: DatabaseErrorKind,
+    path: Option<PathBuf>,
+    // Absolute offset in the file.
+    offset: Option<u64>,
+    cluster_index: Option<u64>,
+    source: Option<std::io::Error>,
+}
+
+impl DatabaseError {
+    pub fn new(kind: DatabaseErrorKind) -> Self {
+        DatabaseError {
+            kind,
+            path: None,
+            offset: None,
+            cluster_index: None,
+            source: None,
+        }
+    }
+
+    pub fn kind(&self) -> DatabaseErrorKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    pub fn cluster_index(&self) -> Option<u64> {
+        self.cluster_index
+    }
+
+    // Context already present is kept, it comes from the place closer to the failure.
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        if self.path.is_none() {
+            self.path = Some(path.as_ref().to_path_buf());
+        }
+        self
+    }
+
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        if self.offset.is_none() {
+            self.offset = Some(offset);
+        }
+        self
+    }
+
+    pub fn with_cluster_index(mut self, cluster_index: u64) -> Self {
+        if self.cluster_index.is_none() {
+            self.cluster_index = Some(cluster_index);
+        }
+        self
+    }
+}
+
+impl From<DatabaseErrorKind> for DatabaseError {
+    fn from(kind: DatabaseErrorKind) -> Self {
+        DatabaseError::new(kind)
+    }
+}
+
+impl From<std::io::Error> for DatabaseError {
+    fn from(source: std::io::Error) -> Self {
+        DatabaseError {
+            source: Some(source),
+            ..DatabaseError::new(DatabaseErrorKind::IOError)
+        }
+    }
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(path) = &self.path {
+            write!(f, " in {}", path.display())?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        if let Some(cluster_index) = self.cluster_index {
+            write!(f, " of cluster {}", cluster_index)?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, ": {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|x| x as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl fmt::Display for DatabaseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            DatabaseErrorKind::ClusterValidity => "invalid cluster metadata",
+            DatabaseErrorKind::ClusterIncompatibleVersion => "unsupported cluster version",
+            DatabaseErrorKind::ClusterDuplicateIndex => "duplicate cluster index",
+            DatabaseErrorKind::UnsupportedTextEncoding => "unsupported text encoding",
+            DatabaseErrorKind::IndexTableValidity => "invalid index table",
+            DatabaseErrorKind::StringValidity => "invalid string",
+            DatabaseErrorKind::TagValidity => "invalid tag",
+            DatabaseErrorKind::TagDuplicateId => "duplicate tag id",
+            DatabaseErrorKind::UnsupportedDataType => "unsupported data type",
+            DatabaseErrorKind::TagMissing => "tag not found",
+            DatabaseErrorKind::ClusterFull => "cluster can't grow",
+            DatabaseErrorKind::UnsupportedQuery => "unsupported query",
+            DatabaseErrorKind::NameDuplicate => "duplicate name",
+            DatabaseErrorKind::TagReferenced => "tag is referenced",
+            DatabaseErrorKind::TransactionState => "invalid transaction state",
+            DatabaseErrorKind::ReferenceCycle => "value references form a cycle",
+            DatabaseErrorKind::IOError => "I/O error",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl DatabaseReader {
+    // Attach path, absolute position in the file and cluster that is currently being read.
+    pub(crate) fn locate(&self, error: DatabaseError, position: u64) -> DatabaseError {
+        let error = error.with_path(&self.path).with_offset(position);
+        match self.current_cluster_index {
+            Some(v) => error.with_cluster_index(v),
+            None => error,
+        }
+    }
+
+    // Same as locate, for address of current cluster.
+    pub(crate) fn locate_address(&self, error: DatabaseError, address: u64) -> DatabaseError {
+        self.locate(
+            error,
+            self.current_index_table_offset.saturating_add(address),
+        )
+    }
+
+    // Error of the kind at the address of current cluster.
+    pub(crate) fn error_at(&self, kind: DatabaseErrorKind, address: u64) -> DatabaseError {
+        self.locate_address(kind.into(), address)
+    }
+
+    // I/O error while reading or writing at the address of current cluster.
+    pub(crate) fn io_error_at(&self, source: std::io::Error, address: u64) -> DatabaseError {
+        self.locate_address(source.into(), address)
+    }
+}
+
+impl BTag {
+    // Attach path of the file of the cluster and the cluster index.
+    pub(crate) fn cluster_error(
+        &self,
+        kind: DatabaseErrorKind,
+        cluster_index: u64,
+    ) -> DatabaseError {
+        let error = DatabaseError::from(kind).with_cluster_index(cluster_index);
+        match self
+            .cluster_readers
+            .get(&cluster_index)
+            .and_then(|x| self.readers.get(*x))
+        {
+            Some(reader) => error.with_path(reader.path()),
+            None => error,
+        }
+    }
+
+    // Same as DatabaseReader::error_at, for address of the cluster.
+    pub(crate) fn error_at(
+        &self,
+        kind: DatabaseErrorKind,
+        cluster_index: u64,
+        address: u64,
+    ) -> DatabaseError {
+        let error = self.cluster_error(kind, cluster_index);
+        match self.cluster(cluster_index) {
+            Some(v) => error.with_offset(v.index_table_offset.saturating_add(address)),
+            None => error,
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{BTag, DatabaseError, DatabaseErrorKind, DatabaseReader};
+
+pub const JOURNAL_FILE_EXTENSION: &str = "journal";
+
+const JOURNAL_MAGIC: &[u8; 4] = b"BTJL";
+const JOURNAL_VERSION: u32 = 1;
+
+const JOURNAL_RECORD_QUERY: u8 = 0;
+const JOURNAL_RECORD_WRITE: u8 = 1;
+const JOURNAL_RECORD_COMMIT: u8 = 2;
+
+// Single logged write, database bytes at offset before and after it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournalWrite {
+    offset: u64,
+    before: Vec<u8>,
+    after: Vec<u8>,
+}
+
+impl JournalWrite {
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn before(&self) -> &[u8] {
+        &self.before
+    }
+
+    pub fn after(&self) -> &[u8] {
+        &self.after
+    }
+}
+
+// Everything that made it into the journal file.
+// Incomplete record at the end of the file, left by a crash, is ignored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JournalContents {
+    // None when the header didn't make it to the disk.
+    original_size: Option<u64>,
+    // Journal of the first database file of the transaction, commit of it commits this one.
+    // None when this is the first file.
+    primary: Option<PathBuf>,
+    queries: Vec<String>,
+    writes: Vec<JournalWrite>,
+    committed: bool,
+}
+
+impl JournalContents {
+    // Database is only written after the header is synced, so journal without it is empty.
+    pub fn original_size(&self) -> Option<u64> {
+        self.original_size
+    }
+
+    pub fn primary(&self) -> Option<&Path> {
+        self.primary.as_deref()
+    }
+
+    pub fn queries(&self) -> &[String] {
+        &self.queries
+    }
+
+    pub fn writes(&self) -> &[JournalWrite] {
+        &self.writes
+    }
+
+    pub fn committed(&self) -> bool {
+        self.committed
+    }
+}
+
+// Write-ahead journal of a database file, kept next to it as `<file>.journal`.
+// Journal is synced before every change of the database file, so interrupted transactions
+// can be either rolled back with before-images or, once committed, replayed with after-images.
+// Reference "Journaling" of docs/specification.md.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    // `database.btag` is journaled into `database.btag.journal`.
+    pub fn path_for(database_path: &Path) -> PathBuf {
+        let mut path = OsString::from(database_path.as_os_str());
+        path.push(".");
+        path.push(JOURNAL_FILE_EXTENSION);
+        PathBuf::from(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn create(
+        database_path: &Path,
+        original_size: u64,
+        primary: Option<&Path>,
+    ) -> Result<Journal, DatabaseError> {
+        let path = Journal::path_for(database_path);
+        let file = match OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+        {
+            Ok(v) => v,
+            Err(e) => return Err(DatabaseError::from(e).with_path(&path)),
+        };
+
+        let primary = match primary.map(|x| x.to_str()) {
+            Some(Some(v)) => v.as_bytes(),
+            Some(None) => {
+                return Err(DatabaseError::from(DatabaseErrorKind::StringValidity).with_path(&path))
+            }
+            None => &[],
+        };
+        let primary_size: u16 = match primary.len().try_into() {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(DatabaseError::from(DatabaseErrorKind::StringValidity).with_path(&path))
+            }
+        };
+
+        let mut buf: Vec<u8> = Vec::new();
+        buf.extend_from_slice(JOURNAL_MAGIC);
+        buf.extend_from_slice(&JOURNAL_VERSION.to_le_bytes());
+        buf.extend_from_slice(&original_size.to_le_bytes());
+        buf.extend_from_slice(&primary_size.to_le_bytes());
+        buf.extend_from_slice(primary);
+
+        let mut journal = Journal { path, file };
+        journal.append(&buf)?;
+        Ok(journal)
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<(), DatabaseError> {
+        if let Err(e) = self.file.write_all(buf).and_then(|_| self.file.sync_data()) {
+            return Err(DatabaseError::from(e).with_path(&self.path));
+        }
+        Ok(())
+    }
+
+    pub fn log_query(&mut self, query: &str) -> Result<(), DatabaseError> {
+        let size: u32 = match query.len().try_into() {
+            Ok(v) => v,
+            Err(_) => return Err(DatabaseErrorKind::StringValidity.into()),
+        };
+        let mut buf: Vec<u8> = Vec::with_capacity(query.len() + 5);
+        buf.push(JOURNAL_RECORD_QUERY);
+        buf.extend_from_slice(&size.to_le_bytes());
+        buf.extend_from_slice(query.as_bytes());
+        self.append(&buf)
+    }
+
+    pub fn log_write(
+        &mut self,
+        offset: u64,
+        before: &[u8],
+        after: &[u8],
+    ) -> Result<(), DatabaseError> {
+        let mut buf: Vec<u8> = Vec::with_capacity(before.len() + after.len() + 25);
+        buf.push(JOURNAL_RECORD_WRITE);
+        buf.extend_from_slice(&offset.to_le_bytes());
+        buf.extend_from_slice(&(before.len() as u64).to_le_bytes());
+        buf.extend_from_slice(before);
+        buf.extend_from_slice(&(after.len() as u64).to_le_bytes());
+        buf.extend_from_slice(after);
+        self.append(&buf)
+    }
+
+    // Commit point of the transaction, from now on recovery replays it.
+    pub fn log_commit(&mut self) -> Result<(), DatabaseError> {
+        self.append(&[JOURNAL_RECORD_COMMIT])
+    }
+
+    // Transaction is finished, journal is no longer needed.
+    pub fn remove(self) -> Result<(), DatabaseError> {
+        drop(self.file);
+        if let Err(e) = fs::remove_file(&self.path) {
+            return Err(DatabaseError::from(e).with_path(&self.path));
+        }
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<JournalContents, DatabaseError> {
+        let buf = match fs::read(path) {
+            Ok(v) => v,
+            Err(e) => return Err(DatabaseError::from(e).with_path(path)),
+        };
+        // Partial or invalid header is left by a crash while the journal was created.
+        if buf.len() < 18 || &buf[0..4] != JOURNAL_MAGIC {
+            return Ok(JournalContents::default());
+        }
+        if DatabaseReader::read_u32_from_slice(&buf[4..8]) != JOURNAL_VERSION {
+            return Err(
+                DatabaseError::from(DatabaseErrorKind::ClusterIncompatibleVersion).with_path(path),
+            );
+        }
+
+        let primary_size = DatabaseReader::read_u16_from_slice(&buf[16..18]) as usize;
+        let primary = match buf.get(18..18 + primary_size) {
+            Some([]) => None,
+            Some(v) => match String::from_utf8(v.to_vec()) {
+                Ok(v) => Some(PathBuf::from(v)),
+                Err(_) => return Ok(JournalContents::default()),
+            },
+            None => return Ok(JournalContents::default()),
+        };
+        let mut contents = JournalContents {
+            original_size: Some(DatabaseReader::read_u64_from_slice(&buf[8..16])),
+            primary,
+            ..Default::default()
+        };
+
+        let mut position = 18 + primary_size;
+        let take = |position: &mut usize, size: usize| -> Option<&[u8]> {
+            let slice = buf.get(*position..position.checked_add(size)?)?;
+            *position += size;
+            Some(slice)
+        };
+        // Records are read until the first incomplete one.
+        while let Some(record) = take(&mut position, 1) {
+            match record[0] {
+                JOURNAL_RECORD_QUERY => {
+                    let size = match take(&mut position, 4) {
+                        Some(v) => DatabaseReader::read_u32_from_slice(v) as usize,
+                        None => break,
+                    };
+                    match take(&mut position, size) {
+                        Some(v) => contents
+                            .queries
+                            .push(String::from_utf8_lossy(v).into_owned()),
+                        None => break,
+                    }
+                }
+                JOURNAL_RECORD_WRITE => {
+                    let offset = match take(&mut position, 8) {
+                        Some(v) => DatabaseReader::read_u64_from_slice(v),
+                        None => break,
+                    };
+                    let mut images: Vec<Vec<u8>> = Vec::with_capacity(2);
+                    for _ in 0..2 {
+                        let size = match take(&mut position, 8) {
+                            Some(v) => DatabaseReader::read_u64_from_slice(v),
+                            None => break,
+                        };
+                        match usize::try_from(size)
+                            .ok()
+                            .and_then(|x| take(&mut position, x))
+                        {
+                            Some(v) => images.push(v.to_vec()),
+                            None => break,
+                        }
+                    }
+                    if images.len() != 2 {
+                        break;
+                    }
+                    let after = images.pop().unwrap_or_default();
+                    let before = images.pop().unwrap_or_default();
+                    contents.writes.push(JournalWrite {
+                        offset,
+                        before,
+                        after,
+                    });
+                }
+                JOURNAL_RECORD_COMMIT => {
+                    contents.committed = true;
+                    break;
+                }
+                _ => break,
+            }
+        }
+
+        Ok(contents)
+    }
+
+    // Finish interrupted transaction of the database file, if there is one.
+    // Committed transactions are replayed, others are rolled back.
+    // Returns whether there was anything to recover.
+    pub fn recover(database_path: &Path) -> Result<bool, DatabaseError> {
+        let path = Journal::path_for(database_path);
+        if !path.exists() {
+            return Ok(false);
+        }
+        let contents = Journal::read(&path)?;
+
+        // Transaction spanning several files is committed by journal of the first one.
+        // Primary journal is removed last, so missing one means it never got committed.
+        let committed = match contents.primary() {
+            Some(primary) if !contents.committed => match primary.exists() {
+                true => Journal::read(primary)?.committed,
+                false => false,
+            },
+            _ => contents.committed,
+        };
+
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(database_path)
+        {
+            Ok(v) => v,
+            Err(e) => return Err(DatabaseError::from(e).with_path(database_path)),
+        };
+        let result = match committed {
+            true => Journal::redo(&mut file, &contents),
+            false => Journal::undo(&mut file, &contents),
+        };
+        if let Err(e) = result {
+            return Err(e.with_path(database_path));
+        }
+
+        if let Err(e) = fs::remove_file(&path) {
+            return Err(DatabaseError::from(e).with_path(&path));
+        }
+        Ok(true)
+    }
+
+    fn write_file(file: &mut File, offset: u64, buf: &[u8]) -> Result<(), DatabaseError> {
+        if let Err(e) = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(buf))
+        {
+            return Err(DatabaseError::from(e).with_offset(offset));
+        }
+        Ok(())
+    }
+
+    // Apply after-images in order.
+    fn redo(file: &mut File, contents: &JournalContents) -> Result<(), DatabaseError> {
+        for write in contents.writes.iter() {
+            Journal::write_file(file, write.offset, &write.after)?;
+        }
+        if let Err(e) = file.sync_all() {
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    // Restore before-images in reverse order and drop everything appended to the file.
+    fn undo(file: &mut File, contents: &JournalContents) -> Result<(), DatabaseError> {
+        let original_size = match contents.original_size {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        for write in contents.writes.iter().rev() {
+            Journal::write_file(file, write.offset, &write.before)?;
+        }
+        if let Err(e) = file.set_len(original_size).and_then(|_| file.sync_all()) {
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+impl DatabaseReader {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn in_journal(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    // Start journaling every write of the file. Query is only logged when already started.
+    pub fn begin_journal(
+        &mut self,
+        query: &str,
+        primary: Option<&Path>,
+    ) -> Result<(), DatabaseError> {
+        if self.journal.is_none() {
+            let file_size = self.file_size()?;
+            self.journal = Some(Journal::create(&self.path, file_size, primary)?);
+        }
+        match self.journal.as_mut() {
+            Some(journal) => journal.log_query(query),
+            None => Err(DatabaseErrorKind::IOError.into()),
+        }
+    }
+
+    // Every write has to reach the disk before the transaction is committed.
+    pub fn sync(&mut self) -> Result<(), DatabaseError> {
+        if let Err(e) = self.database_file.sync_all() {
+            return Err(DatabaseError::from(e).with_path(&self.path));
+        }
+        Ok(())
+    }
+
+    pub fn log_journal_commit(&mut self) -> Result<(), DatabaseError> {
+        match self.journal.as_mut() {
+            Some(journal) => journal.log_commit(),
+            None => Ok(()),
+        }
+    }
+
+    // Stop journaling, removing the journal file.
+    pub fn end_journal(&mut self) -> Result<(), DatabaseError> {
+        match self.journal.take() {
+            Some(journal) => journal.remove(),
+            None => Ok(()),
+        }
+    }
+
+    // Undo every journaled write and stop journaling.
+    pub fn rollback_journal(&mut self) -> Result<(), DatabaseError> {
+        let journal = match self.journal.take() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let contents = Journal::read(journal.path())?;
+        if let Err(e) = Journal::undo(&mut self.database_file, &contents) {
+            return Err(e.with_path(&self.path));
+        }
+        journal.remove()?;
+
+        // Reader shares file with database_file, drop whatever it has buffered.
+        if let Err(e) = self.file_reader.seek(SeekFrom::Start(0)) {
+            return Err(DatabaseError::from(e).with_path(&self.path));
+        }
+        Ok(())
+    }
+}
+
+impl BTag {
+    pub fn in_journal(&self) -> bool {
+        self.readers.iter().any(|x| x.in_journal())
+    }
+
+    // Start journaled transaction over every database file, logging the query.
+    // Journal of the first file decides whether the whole transaction is committed.
+    // Reference "Journaling" of docs/specification.md.
+    pub fn begin_journal(&mut self, query: &str) -> Result<(), DatabaseError> {
+        let primary = match fs::canonicalize(self.readers[0].path()) {
+            Ok(v) => Journal::path_for(&v),
+            Err(e) => return Err(DatabaseError::from(e).with_path(self.readers[0].path())),
+        };
+
+        for i in 0..self.readers.len() {
+            let result = if i == 0 {
+                self.readers[i].begin_journal(query, None)
+            } else {
+                self.readers[i].begin_journal(query, Some(&primary))
+            };
+            if let Err(e) = result {
+                self.rollback_journal()?;
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Commit journaled transaction. Once commit is logged into the first journal,
+    // transaction is replayed by recovery even if the rest is interrupted.
+    pub fn commit_journal(&mut self) -> Result<(), DatabaseError> {
+        for reader in self.readers.iter_mut() {
+            reader.sync()?;
+        }
+
+        self.readers[0].log_journal_commit()?;
+        for reader in self.readers.iter_mut().skip(1) {
+            reader.log_journal_commit()?;
+            reader.end_journal()?;
+        }
+        self.readers[0].end_journal()
+    }
+
+    // Run change as a journaled transaction of it's own, or as a part of the one in progress.
+    pub(crate) fn journaled<T>(
+        &mut self,
+        query: &str,
+        change: impl FnOnce(&mut BTag) -> Result<T, DatabaseError>,
+    ) -> Result<T, DatabaseError> {
+        if self.in_journal() {
+            self.begin_journal(query)?;
+            return change(self);
+        }
+
+        self.begin_journal(query)?;
+        match change(self) {
+            Ok(v) => {
+                self.commit_journal()?;
+                Ok(v)
+            }
+            Err(e) => {
+                self.rollback_journal()?;
+                Err(e)
+            }
+        }
+    }
+
+    // Undo journaled transaction and load clusters again, since everything kept in memory
+    // may have been changed by it.
+    pub fn rollback_journal(&mut self) -> Result<(), DatabaseError> {
+        for i in (0..self.readers.len()).rev() {
+            if !self.readers[i].in_journal() {
+                continue;
+            }
+            self.readers[i].rollback_journal()?;
+            self.reload_clusters(i)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1186 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+};
+
+use crate::{
+    AddressEntry, AddressList, BTag, DatabaseError, DatabaseErrorKind, DatabaseWriter, TagType,
+    ValueReference,
+};
+
+// Nesting deeper than this is refused, parser is recursive.
+const MAX_DEPTH: usize = 512;
+
+// JSON document, reference "JSON" of docs/specification.md for how tags are mapped onto it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    // Number as it is written, so that u64 values are kept exactly.
+    Number(String),
+    String(String),
+    Array(Vec<JsonValue>),
+    // Members in order of the document, keys may repeat.
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    // First member of the object with the key.
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, indent: Option<usize>) -> fmt::Result {
+        // Pretty form puts every member and item on it's own line.
+        let newline = |f: &mut fmt::Formatter<'_>, level: usize| match indent {
+            Some(_) => write!(f, "\n{}", "  ".repeat(level)),
+            None => Ok(()),
+        };
+        let level = indent.unwrap_or(0);
+        let inner = indent.map(|x| x + 1);
+
+        match self {
+            JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(v) => write!(f, "{}", v),
+            JsonValue::Number(v) => write!(f, "{}", v),
+            JsonValue::String(v) => write_string(f, v),
+            JsonValue::Array(items) if items.is_empty() => write!(f, "[]"),
+            JsonValue::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    newline(f, level + 1)?;
+                    item.write(f, inner)?;
+                }
+                newline(f, level)?;
+                write!(f, "]")
+            }
+            JsonValue::Object(members) if members.is_empty() => write!(f, "{{}}"),
+            JsonValue::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    newline(f, level + 1)?;
+                    write_string(f, key)?;
+                    write!(f, "{}", if indent.is_some() { ": " } else { ":" })?;
+                    value.write(f, inner)?;
+                }
+                newline(f, level)?;
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+// Compact JSON, `{:#}` writes it indented.
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, if f.alternate() { Some(0) } else { None })
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if u32::from(c) < 0x20 => write!(f, "\\u{:04x}", u32::from(c))?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+// Syntax error of the document, or a value that can't be imported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonError {
+    pub message: String,
+    // Byte offset in the document, for syntax errors.
+    pub offset: Option<usize>,
+}
+
+impl JsonError {
+    fn new(message: impl Into<String>) -> Self {
+        JsonError {
+            message: message.into(),
+            offset: None,
+        }
+    }
+
+    fn at(message: impl Into<String>, offset: usize) -> Self {
+        JsonError {
+            message: message.into(),
+            offset: Some(offset),
+        }
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for JsonError {}
+
+pub fn parse_json(source: &str) -> Result<JsonValue, JsonError> {
+    let mut parser = JsonParser {
+        source,
+        position: 0,
+    };
+    let value = parser.parse_value(0)?;
+    parser.skip_whitespace();
+    if parser.position < source.len() {
+        return Err(JsonError::at(
+            "unexpected data after value",
+            parser.position,
+        ));
+    }
+    Ok(value)
+}
+
+struct JsonParser<'a> {
+    source: &'a str,
+    position: usize,
+}
+
+impl JsonParser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(self.unexpected(&format!("`{}`", c as char)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn unexpected(&self, expected: &str) -> JsonError {
+        match self.source[self.position..].chars().next() {
+            Some(c) => JsonError::at(
+                format!("expected {}, found `{}`", expected, c),
+                self.position,
+            ),
+            None => JsonError::at(
+                format!("expected {}, found end of document", expected),
+                self.position,
+            ),
+        }
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<JsonValue, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(JsonError::at("nesting is too deep", self.position));
+        }
+
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.parse_object(depth),
+            Some(b'[') => self.parse_array(depth),
+            Some(b'"') => Ok(JsonValue::String(self.parse_string()?)),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            Some(b't') => self.parse_literal("true", JsonValue::Bool(true)),
+            Some(b'f') => self.parse_literal("false", JsonValue::Bool(false)),
+            Some(b'n') => self.parse_literal("null", JsonValue::Null),
+            _ => Err(self.unexpected("value")),
+        }
+    }
+
+    fn parse_literal(&mut self, word: &str, value: JsonValue) -> Result<JsonValue, JsonError> {
+        if !self.source[self.position..].starts_with(word) {
+            return Err(self.unexpected("value"));
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn parse_object(&mut self, depth: usize) -> Result<JsonValue, JsonError> {
+        self.position += 1;
+        let mut members: Vec<(String, JsonValue)> = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            return Ok(JsonValue::Object(members));
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.unexpected("string"));
+            }
+            let key = self.parse_string()?;
+            self.expect(b':')?;
+            let value = self.parse_value(depth + 1)?;
+            members.push((key, value));
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b'}') => {
+                    self.position += 1;
+                    return Ok(JsonValue::Object(members));
+                }
+                _ => return Err(self.unexpected("`,` or `}`")),
+            }
+        }
+    }
+
+    fn parse_array(&mut self, depth: usize) -> Result<JsonValue, JsonError> {
+        self.position += 1;
+        let mut items: Vec<JsonValue> = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            return Ok(JsonValue::Array(items));
+        }
+
+        loop {
+            items.push(self.parse_value(depth + 1)?);
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b']') => {
+                    self.position += 1;
+                    return Ok(JsonValue::Array(items));
+                }
+                _ => return Err(self.unexpected("`,` or `]`")),
+            }
+        }
+    }
+
+    // `-? (0 | [1-9][0-9]*) (. [0-9]+)? ([eE] [+-]? [0-9]+)?`
+    fn parse_number(&mut self) -> Result<JsonValue, JsonError> {
+        let start = self.position;
+        let bytes = self.source.as_bytes();
+        let digits = |position: &mut usize| -> usize {
+            let from = *position;
+            while bytes.get(*position).is_some_and(|x| x.is_ascii_digit()) {
+                *position += 1;
+            }
+            *position - from
+        };
+
+        let mut position = self.position;
+        if bytes.get(position) == Some(&b'-') {
+            position += 1;
+        }
+        let integer_start = position;
+        let integer = digits(&mut position);
+        if integer == 0 || (integer > 1 && bytes[integer_start] == b'0') {
+            return Err(JsonError::at("invalid number", start));
+        }
+        if bytes.get(position) == Some(&b'.') {
+            position += 1;
+            if digits(&mut position) == 0 {
+                return Err(JsonError::at("invalid number", start));
+            }
+        }
+        if let Some(b'e' | b'E') = bytes.get(position) {
+            position += 1;
+            if let Some(b'+' | b'-') = bytes.get(position) {
+                position += 1;
+            }
+            if digits(&mut position) == 0 {
+                return Err(JsonError::at("invalid number", start));
+            }
+        }
+
+        self.position = position;
+        Ok(JsonValue::Number(self.source[start..position].to_string()))
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        let start = self.position;
+        self.position += 1;
+        let mut value = String::new();
+        loop {
+            let c = match self.source[self.position..].chars().next() {
+                Some(v) => v,
+                None => return Err(JsonError::at("unterminated string", start)),
+            };
+            let position = self.position;
+            self.position += c.len_utf8();
+            match c {
+                '"' => return Ok(value),
+                '\\' => value.push(self.parse_escape(position)?),
+                c if u32::from(c) < 0x20 => {
+                    return Err(JsonError::at("control character in string", position))
+                }
+                c => value.push(c),
+            }
+        }
+    }
+
+    fn parse_escape(&mut self, start: usize) -> Result<char, JsonError> {
+        let c = match self.peek() {
+            Some(v) => v,
+            None => return Err(JsonError::at("unterminated string", start)),
+        };
+        self.position += 1;
+        Ok(match c {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let high = self.parse_hex(start)?;
+                let code = if (0xD800..0xDC00).contains(&high) {
+                    // Surrogate pair, low surrogate must follow.
+                    if !self.source[self.position..].starts_with("\\u") {
+                        return Err(JsonError::at("invalid escape", start));
+                    }
+                    self.position += 2;
+                    let low = self.parse_hex(start)?;
+                    if !(0xDC00..0xE000).contains(&low) {
+                        return Err(JsonError::at("invalid escape", start));
+                    }
+                    0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                } else {
+                    high
+                };
+                match char::from_u32(code) {
+                    Some(v) => v,
+                    None => return Err(JsonError::at("invalid escape", start)),
+                }
+            }
+            _ => return Err(JsonError::at("invalid escape", start)),
+        })
+    }
+
+    fn parse_hex(&mut self, start: usize) -> Result<u32, JsonError> {
+        let hex = match self.source.get(self.position..self.position + 4) {
+            Some(v) => v,
+            None => return Err(JsonError::at("invalid escape", start)),
+        };
+        match u32::from_str_radix(hex, 16) {
+            Ok(v) if hex.bytes().all(|x| x.is_ascii_hexdigit()) => {
+                self.position += 4;
+                Ok(v)
+            }
+            _ => Err(JsonError::at("invalid escape", start)),
+        }
+    }
+}
+
+// Names starting with `$` are escaped by another `$`, the rest of such keys are reserved.
+fn escape_key(name: &str) -> String {
+    if name.starts_with('$') {
+        format!("${}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn tag_label(tag_id: u64) -> JsonValue {
+    JsonValue::String(format!("#{}", tag_id))
+}
+
+// Tag being exported, addresses are resolved to tag ids.
+struct ExportTag {
+    name: String,
+    parents: Vec<u64>,
+    value: ExportValue,
+}
+
+enum ExportValue {
+    Scalar(TagType),
+    // Entry name and tag id.
+    List(Vec<(String, u64)>),
+    Entry(String, u64),
+    Reference(u64),
+}
+
+// Where every tag is written. Tag is written inside of the AddressList of it's first parent,
+// entries of any other list refer to it with `$link`.
+#[derive(Default)]
+struct ExportPlan {
+    // Tags written at the top level, in order.
+    top: Vec<u64>,
+    placed: HashSet<u64>,
+    // (list tag id, entry position) of entries holding the tag itself.
+    inline: HashSet<(u64, usize)>,
+    // List tags that link the tag, in order of the document.
+    links: HashMap<u64, Vec<u64>>,
+}
+
+impl BTag {
+    // Every cluster as a single JSON object, reference "JSON" of docs/specification.md.
+    // Tags without parents are members of the top level object. With preserve, every tag gets
+    // it's `$id` and whatever is needed to import the same tags, otherwise only tags that are
+    // referenced get their `$id`.
+    pub fn export_json(&mut self, preserve: bool) -> Result<JsonValue, DatabaseError> {
+        let mut tags: HashMap<u64, ExportTag> = HashMap::new();
+        let mut order: Vec<u64> = Vec::new();
+        let cluster_indexes: Vec<u64> = self.clusters.iter().map(|x| x.cluster_index).collect();
+        for cluster_index in cluster_indexes {
+            let entries: Vec<(u64, u64)> = match self.tags_index(cluster_index) {
+                Some(v) => v.tags.iter().map(|x| (x.tag_id, x.offset)).collect(),
+                None => Vec::new(),
+            };
+            for (tag_id, offset) in entries {
+                if tags.contains_key(&tag_id) {
+                    return Err(DatabaseError::from(DatabaseErrorKind::TagDuplicateId)
+                        .with_cluster_index(cluster_index));
+                }
+                let tag = self.export_tag(cluster_index, offset)?;
+                tags.insert(tag_id, tag);
+                order.push(tag_id);
+            }
+        }
+
+        let mut plan = ExportPlan::default();
+        for tag_id in order.iter() {
+            if tags[tag_id].parents.is_empty() {
+                plan.place(&tags, *tag_id);
+            }
+        }
+        // Tags that can't be reached from the top, i.e. first parent cycles, are written there.
+        for tag_id in order.iter() {
+            if !plan.placed.contains(tag_id) {
+                plan.place(&tags, *tag_id);
+            }
+        }
+
+        // Tags referred to by a value or a link need `$id`.
+        let mut labelled: HashSet<u64> = plan.links.keys().copied().collect();
+        for tag in tags.values() {
+            match &tag.value {
+                ExportValue::Entry(_, tag_id) | ExportValue::Reference(tag_id) => {
+                    labelled.insert(*tag_id);
+                }
+                _ => {}
+            }
+        }
+        if preserve {
+            labelled.extend(order.iter().copied());
+        }
+
+        let exporter = JsonExporter {
+            tags: &tags,
+            plan: &plan,
+            labelled: &labelled,
+            preserve,
+        };
+        let members: Vec<(String, JsonValue)> = plan
+            .top
+            .iter()
+            .map(|x| (escape_key(&tags[x].name), exporter.tag(*x, None)))
+            .collect();
+
+        Ok(JsonValue::Object(members))
+    }
+
+    fn export_tag(&mut self, cluster_index: u64, offset: u64) -> Result<ExportTag, DatabaseError> {
+        let reader = match self.reader(cluster_index) {
+            Some(v) => v,
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+        let mut record = reader.read_tag_data(offset)?;
+        reader.read_parents(offset, &mut record)?;
+
+        let tag_id = |address: u64| match self.tag_at(cluster_index, address) {
+            Some(v) => Ok(v.tag_id),
+            None => Err(DatabaseError::from(DatabaseErrorKind::TagMissing)
+                .with_cluster_index(cluster_index)),
+        };
+        let mut parents: Vec<u64> = Vec::with_capacity(record.tag_parents.array.len());
+        for parent in record.tag_parents.array.iter() {
+            parents.push(tag_id(parent.address)?);
+        }
+        let value = match record.tag_data {
+            TagType::AddressList(list) => {
+                let mut entries: Vec<(String, u64)> = Vec::with_capacity(list.array.len());
+                for entry in list.array.iter() {
+                    entries.push((
+                        self.display_name(cluster_index, entry.name),
+                        tag_id(entry.address)?,
+                    ));
+                }
+                ExportValue::List(entries)
+            }
+            TagType::AddressEntry(entry) => ExportValue::Entry(
+                self.display_name(cluster_index, entry.name),
+                tag_id(entry.address)?,
+            ),
+            TagType::ValueReference(reference) => {
+                ExportValue::Reference(tag_id(reference.address)?)
+            }
+            other => ExportValue::Scalar(other),
+        };
+
+        Ok(ExportTag {
+            name: self.display_name(cluster_index, record.tag_name),
+            parents,
+            value,
+        })
+    }
+}
+
+impl ExportPlan {
+    fn place(&mut self, tags: &HashMap<u64, ExportTag>, tag_id: u64) {
+        self.placed.insert(tag_id);
+        self.top.push(tag_id);
+        self.place_entries(tags, tag_id);
+    }
+
+    fn place_entries(&mut self, tags: &HashMap<u64, ExportTag>, tag_id: u64) {
+        let entries = match &tags[&tag_id].value {
+            ExportValue::List(v) => v,
+            _ => return,
+        };
+        for (i, (_, child)) in entries.iter().enumerate() {
+            if tags[child].parents.first() == Some(&tag_id) && self.placed.insert(*child) {
+                self.inline.insert((tag_id, i));
+                self.place_entries(tags, *child);
+            } else {
+                self.links.entry(*child).or_default().push(tag_id);
+            }
+        }
+    }
+
+    // Parents that import gives the tag without `$parents`.
+    fn imported_parents(&self, tags: &HashMap<u64, ExportTag>, tag_id: u64) -> Vec<u64> {
+        let mut parents: Vec<u64> = Vec::new();
+        if let Some(v) = tags[&tag_id].parents.first() {
+            if self.is_inline(tags, *v, tag_id) {
+                parents.push(*v);
+            }
+        }
+        for v in self.links.get(&tag_id).into_iter().flatten() {
+            if !parents.contains(v) {
+                parents.push(*v);
+            }
+        }
+        parents
+    }
+
+    fn is_inline(&self, tags: &HashMap<u64, ExportTag>, list: u64, tag_id: u64) -> bool {
+        match &tags[&list].value {
+            ExportValue::List(entries) => entries
+                .iter()
+                .enumerate()
+                .any(|(i, (_, x))| *x == tag_id && self.inline.contains(&(list, i))),
+            _ => false,
+        }
+    }
+}
+
+struct JsonExporter<'a> {
+    tags: &'a HashMap<u64, ExportTag>,
+    plan: &'a ExportPlan,
+    labelled: &'a HashSet<u64>,
+    preserve: bool,
+}
+
+impl JsonExporter<'_> {
+    // Tag written under entry_name, which is None for the top level.
+    fn tag(&self, tag_id: u64, entry_name: Option<&str>) -> JsonValue {
+        let tag = &self.tags[&tag_id];
+        let mut members: Vec<(String, JsonValue)> = Vec::new();
+        if self.labelled.contains(&tag_id) {
+            members.push((String::from("$id"), JsonValue::Number(tag_id.to_string())));
+        }
+        if self.preserve {
+            if self.plan.imported_parents(self.tags, tag_id) != tag.parents {
+                let parents = tag.parents.iter().map(|x| tag_label(*x)).collect();
+                members.push((String::from("$parents"), JsonValue::Array(parents)));
+            }
+            if entry_name.is_some_and(|x| x != tag.name) {
+                members.push((
+                    String::from("$tag_name"),
+                    JsonValue::String(tag.name.clone()),
+                ));
+            }
+        }
+
+        match &tag.value {
+            ExportValue::List(entries) => {
+                let values: Vec<(String, JsonValue)> = entries
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (name, child))| {
+                        let value = match self.plan.inline.contains(&(tag_id, i)) {
+                            true => self.tag(*child, Some(name)),
+                            false => {
+                                JsonValue::Object(vec![(String::from("$link"), tag_label(*child))])
+                            }
+                        };
+                        (escape_key(name), value)
+                    })
+                    .collect();
+                // List with entries named by their position is an array.
+                let is_array = !values.is_empty()
+                    && entries
+                        .iter()
+                        .enumerate()
+                        .all(|(i, (x, _))| *x == i.to_string());
+                if members.is_empty() && !self.preserve && is_array {
+                    return JsonValue::Array(values.into_iter().map(|(_, x)| x).collect());
+                }
+                members.extend(values);
+            }
+            ExportValue::Entry(name, child) => {
+                members.push((String::from("$address"), tag_label(*child)));
+                members.push((String::from("$name"), JsonValue::String(name.clone())));
+            }
+            ExportValue::Reference(child) => {
+                members.push((String::from("$ref"), tag_label(*child)));
+            }
+            ExportValue::Scalar(value) => {
+                let (data_type, value) = self.scalar(value);
+                if members.is_empty() && data_type.is_none() {
+                    return value;
+                }
+                if let Some(v) = data_type {
+                    members.push((String::from("$type"), JsonValue::String(v.to_string())));
+                }
+                members.push((String::from("$value"), value));
+            }
+        }
+
+        JsonValue::Object(members)
+    }
+
+    // Value and `$type` when it can't be told from the value itself.
+    fn scalar(&self, value: &TagType) -> (Option<&'static str>, JsonValue) {
+        match value {
+            TagType::Integer(v) => (None, JsonValue::Number(v.to_string())),
+            TagType::Double(v) if v.is_finite() => (None, JsonValue::Number(format!("{:?}", v))),
+            TagType::Double(v) => (Some("Double"), JsonValue::String(v.to_string())),
+            TagType::Float(v) if v.is_finite() => (
+                self.preserve.then_some("Float"),
+                JsonValue::Number(format!("{:?}", v)),
+            ),
+            TagType::Float(v) => (Some("Float"), JsonValue::String(v.to_string())),
+            TagType::Text(v) => (None, JsonValue::String(v.clone())),
+            TagType::Char(v) => (Some("Char"), JsonValue::String(v.clone())),
+            // Addresses are exported as ExportValue.
+            _ => (None, JsonValue::Null),
+        }
+    }
+}
+
+// Tag being imported. Labels are `$id` values, resolved to tag ids once every tag is known.
+struct ImportTag {
+    path: String,
+    label: Option<u64>,
+    name: String,
+    // Tag holding this one in it's AddressList.
+    owner: Option<usize>,
+    parents: Option<Vec<u64>>,
+    value: ImportValue,
+}
+
+enum ImportValue {
+    Scalar(TagType),
+    List(Vec<(String, ImportEntry)>),
+    Entry(String, u64),
+    Reference(u64),
+}
+
+enum ImportEntry {
+    Inline(usize),
+    Link(u64),
+}
+
+// `#12`
+fn parse_label(value: &JsonValue, path: &str) -> Result<u64, JsonError> {
+    match value {
+        JsonValue::String(v) => match v.strip_prefix('#').map(|x| x.parse::<u64>()) {
+            Some(Ok(v)) => Ok(v),
+            _ => Err(JsonError::new(format!(
+                "{}: invalid tag reference {:?}",
+                path, v
+            ))),
+        },
+        _ => Err(JsonError::new(format!(
+            "{}: tag reference must be a string",
+            path
+        ))),
+    }
+}
+
+// Integer when the number is a u64, Double otherwise.
+fn parse_number(value: &str) -> Result<TagType, JsonError> {
+    if value.bytes().all(|x| x.is_ascii_digit()) {
+        if let Ok(v) = value.parse::<u64>() {
+            return Ok(TagType::Integer(v));
+        }
+    }
+    match value.parse::<f64>() {
+        Ok(v) => Ok(TagType::Double(v)),
+        Err(_) => Err(JsonError::new(format!("invalid number {}", value))),
+    }
+}
+
+// Value of `$value` with given `$type`. Float and Double may be written as strings,
+// for NaN and infinities.
+fn parse_typed(data_type: &str, value: &JsonValue, path: &str) -> Result<TagType, JsonError> {
+    let invalid = || JsonError::new(format!("{}: invalid {} value", path, data_type));
+    Ok(match (data_type, value) {
+        ("Integer", JsonValue::Number(v)) => TagType::Integer(v.parse().map_err(|_| invalid())?),
+        ("Float", JsonValue::Number(v) | JsonValue::String(v)) => {
+            TagType::Float(v.parse().map_err(|_| invalid())?)
+        }
+        ("Double", JsonValue::Number(v) | JsonValue::String(v)) => {
+            TagType::Double(v.parse().map_err(|_| invalid())?)
+        }
+        ("Text", JsonValue::String(v)) => TagType::Text(v.clone()),
+        ("Char", JsonValue::String(v)) => TagType::Char(v.clone()),
+        ("Integer" | "Float" | "Double" | "Text" | "Char", _) => return Err(invalid()),
+        _ => {
+            return Err(JsonError::new(format!(
+                "{}: unsupported type {:?}",
+                path, data_type
+            )))
+        }
+    })
+}
+
+#[derive(Default)]
+struct JsonImporter {
+    tags: Vec<ImportTag>,
+}
+
+impl JsonImporter {
+    // Tag of the value and every tag inside of it, returning it's position in tags.
+    fn collect(
+        &mut self,
+        value: &JsonValue,
+        name: String,
+        owner: Option<usize>,
+        path: String,
+        depth: usize,
+    ) -> Result<usize, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(JsonError::new(format!("{}: nesting is too deep", path)));
+        }
+        let position = self.tags.len();
+        self.tags.push(ImportTag {
+            path: path.clone(),
+            label: None,
+            name,
+            owner,
+            parents: None,
+            value: ImportValue::Scalar(TagType::Integer(0)),
+        });
+
+        let value = match value {
+            JsonValue::Null => return Err(JsonError::new(format!("{}: null has no type", path))),
+            JsonValue::Bool(v) => ImportValue::Scalar(TagType::Integer(u64::from(*v))),
+            JsonValue::Number(v) => ImportValue::Scalar(parse_number(v)?),
+            JsonValue::String(v) => ImportValue::Scalar(TagType::Text(v.clone())),
+            JsonValue::Array(items) => {
+                let mut entries: Vec<(String, ImportEntry)> = Vec::with_capacity(items.len());
+                for (i, item) in items.iter().enumerate() {
+                    let name = i.to_string();
+                    let entry = self.entry(item, &name, position, &path, depth)?;
+                    entries.push((name, entry));
+                }
+                ImportValue::List(entries)
+            }
+            JsonValue::Object(members) => self.collect_object(members, position, &path, depth)?,
+        };
+        self.tags[position].value = value;
+
+        Ok(position)
+    }
+
+    fn collect_object(
+        &mut self,
+        members: &[(String, JsonValue)],
+        position: usize,
+        path: &str,
+        depth: usize,
+    ) -> Result<ImportValue, JsonError> {
+        let mut entries: Vec<(String, ImportEntry)> = Vec::new();
+        let mut reference: Option<u64> = None;
+        let mut address: Option<u64> = None;
+        let mut entry_name: Option<String> = None;
+        let mut data_type: Option<&str> = None;
+        let mut scalar: Option<&JsonValue> = None;
+        for (key, value) in members.iter() {
+            let string = |x: &JsonValue| match x {
+                JsonValue::String(v) => Ok(v.clone()),
+                _ => Err(JsonError::new(format!(
+                    "{}: {} must be a string",
+                    path, key
+                ))),
+            };
+            match key.as_str() {
+                "$id" => match value {
+                    JsonValue::Number(v) => match v.parse::<u64>() {
+                        Ok(v) => self.tags[position].label = Some(v),
+                        Err(_) => {
+                            return Err(JsonError::new(format!("{}: invalid $id {}", path, v)))
+                        }
+                    },
+                    _ => return Err(JsonError::new(format!("{}: $id must be a number", path))),
+                },
+                "$parents" => match value {
+                    JsonValue::Array(items) => {
+                        let mut parents: Vec<u64> = Vec::with_capacity(items.len());
+                        for item in items.iter() {
+                            parents.push(parse_label(item, path)?);
+                        }
+                        self.tags[position].parents = Some(parents);
+                    }
+                    _ => {
+                        return Err(JsonError::new(format!(
+                            "{}: $parents must be an array",
+                            path
+                        )))
+                    }
+                },
+                "$tag_name" => self.tags[position].name = string(value)?,
+                "$ref" => reference = Some(parse_label(value, path)?),
+                "$address" => address = Some(parse_label(value, path)?),
+                "$name" => entry_name = Some(string(value)?),
+                "$type" => match value {
+                    JsonValue::String(v) => data_type = Some(v),
+                    _ => return Err(JsonError::new(format!("{}: $type must be a string", path))),
+                },
+                "$value" => scalar = Some(value),
+                key if key.starts_with("$$") => {
+                    let name = key[1..].to_string();
+                    let entry = self.entry(value, &name, position, path, depth)?;
+                    entries.push((name, entry));
+                }
+                key if key.starts_with('$') => {
+                    return Err(JsonError::new(format!("{}: unknown member {}", path, key)))
+                }
+                key => {
+                    let entry = self.entry(value, key, position, path, depth)?;
+                    entries.push((key.to_string(), entry));
+                }
+            }
+        }
+
+        let kinds = [
+            !entries.is_empty(),
+            reference.is_some(),
+            address.is_some() || entry_name.is_some(),
+            scalar.is_some() || data_type.is_some(),
+        ];
+        if kinds.iter().filter(|x| **x).count() > 1 {
+            return Err(JsonError::new(format!("{}: mixed kinds of value", path)));
+        }
+
+        Ok(match (reference, address, entry_name, scalar) {
+            (Some(v), _, _, _) => ImportValue::Reference(v),
+            (_, Some(v), Some(name), _) => ImportValue::Entry(name, v),
+            (_, Some(_), None, _) | (_, None, Some(_), _) => {
+                return Err(JsonError::new(format!("{}: $address requires $name", path)))
+            }
+            (_, _, _, Some(value)) => match data_type {
+                Some(v) => ImportValue::Scalar(parse_typed(v, value, path)?),
+                None => match value {
+                    JsonValue::Number(v) => ImportValue::Scalar(parse_number(v)?),
+                    JsonValue::String(v) => ImportValue::Scalar(TagType::Text(v.clone())),
+                    _ => {
+                        return Err(JsonError::new(format!(
+                            "{}: $value must be a number or a string",
+                            path
+                        )))
+                    }
+                },
+            },
+            _ if data_type.is_some() => {
+                return Err(JsonError::new(format!("{}: $type requires $value", path)))
+            }
+            _ => ImportValue::List(entries),
+        })
+    }
+
+    // Entry of an AddressList, either a new tag or a `$link` to a tag written elsewhere.
+    fn entry(
+        &mut self,
+        value: &JsonValue,
+        name: &str,
+        owner: usize,
+        path: &str,
+        depth: usize,
+    ) -> Result<ImportEntry, JsonError> {
+        let path = format!("{}.{}", path, name);
+        if let JsonValue::Object(members) = value {
+            if let Some((_, v)) = members.iter().find(|(k, _)| k == "$link") {
+                if members.len() > 1 {
+                    return Err(JsonError::new(format!("{}: $link must be alone", path)));
+                }
+                return Ok(ImportEntry::Link(parse_label(v, &path)?));
+            }
+        }
+        let position = self.collect(value, name.to_string(), Some(owner), path, depth + 1)?;
+        Ok(ImportEntry::Inline(position))
+    }
+}
+
+impl DatabaseWriter {
+    // Add every tag of the document, reference "JSON" of docs/specification.md.
+    // With preserve_ids tags keep their `$id`, otherwise `$id` is only used to resolve
+    // references and every tag gets a new id.
+    pub fn import_json(
+        &mut self,
+        document: &JsonValue,
+        preserve_ids: bool,
+    ) -> Result<(), JsonError> {
+        let members = match document {
+            JsonValue::Object(v) => v,
+            _ => return Err(JsonError::new("document must be an object")),
+        };
+        let mut importer = JsonImporter::default();
+        for (key, value) in members.iter() {
+            let name = match key.strip_prefix('$') {
+                Some(v) if v.starts_with('$') => v.to_string(),
+                Some(_) => return Err(JsonError::new(format!("unknown member {}", key))),
+                None => key.clone(),
+            };
+            if value.get("$link").is_some() {
+                return Err(JsonError::new(format!("{}: $link outside of a list", name)));
+            }
+            importer.collect(value, name.clone(), None, name, 0)?;
+        }
+        let tags = importer.tags;
+
+        // Labels must be unique, new ids don't collide with them.
+        let mut labels: HashMap<u64, usize> = HashMap::new();
+        for (i, tag) in tags.iter().enumerate() {
+            if let Some(label) = tag.label {
+                if labels.insert(label, i).is_some() {
+                    return Err(JsonError::new(format!(
+                        "{}: duplicate $id {}",
+                        tag.path, label
+                    )));
+                }
+            }
+        }
+        let mut next_tag_id = self.next_tag_id();
+        if preserve_ids {
+            if let Some(v) = labels.keys().max() {
+                next_tag_id = next_tag_id.max(v.saturating_add(1));
+            }
+        }
+        let mut tag_ids: Vec<u64> = Vec::with_capacity(tags.len());
+        for tag in tags.iter() {
+            match tag.label {
+                Some(v) if preserve_ids => tag_ids.push(v),
+                _ => {
+                    tag_ids.push(next_tag_id);
+                    next_tag_id = next_tag_id.saturating_add(1);
+                }
+            }
+        }
+        let resolve = |label: u64, path: &str| match labels.get(&label) {
+            Some(v) => Ok(tag_ids[*v]),
+            None => Err(JsonError::new(format!(
+                "{}: no tag with $id {}",
+                path, label
+            ))),
+        };
+
+        // Parents are the tag holding the entry, then every tag that links to it.
+        let mut parents: Vec<Vec<u64>> = tags
+            .iter()
+            .map(|x| x.owner.map(|v| tag_ids[v]).into_iter().collect())
+            .collect();
+        for (i, tag) in tags.iter().enumerate() {
+            if let ImportValue::List(entries) = &tag.value {
+                for (_, entry) in entries.iter() {
+                    if let ImportEntry::Link(label) = entry {
+                        let target = match labels.get(label) {
+                            Some(v) => *v,
+                            None => {
+                                return Err(JsonError::new(format!(
+                                    "{}: no tag with $id {}",
+                                    tag.path, label
+                                )))
+                            }
+                        };
+                        if !parents[target].contains(&tag_ids[i]) {
+                            parents[target].push(tag_ids[i]);
+                        }
+                    }
+                }
+            }
+        }
+
+        let error = |e: DatabaseError, path: &str| JsonError::new(format!("{}: {}", path, e));
+        for (i, tag) in tags.iter().enumerate() {
+            let path = tag.path.as_str();
+            let tag_parents = match &tag.parents {
+                Some(v) => v
+                    .iter()
+                    .map(|x| resolve(*x, path))
+                    .collect::<Result<Vec<u64>, JsonError>>()?,
+                None => parents[i].clone(),
+            };
+            let value = match &tag.value {
+                ImportValue::Scalar(v) => v.clone(),
+                ImportValue::List(entries) => {
+                    let mut array: Vec<AddressEntry> = Vec::with_capacity(entries.len());
+                    for (name, entry) in entries.iter() {
+                        let tag_id = match entry {
+                            ImportEntry::Inline(v) => tag_ids[*v],
+                            ImportEntry::Link(v) => resolve(*v, path)?,
+                        };
+                        let name = self.add_name(name).map_err(|e| error(e, path))?;
+                        array.push(AddressEntry::new(name, tag_id));
+                    }
+                    TagType::AddressList(AddressList::new(array))
+                }
+                ImportValue::Entry(name, label) => {
+                    let name = self.add_name(name).map_err(|e| error(e, path))?;
+                    TagType::AddressEntry(AddressEntry::new(name, resolve(*label, path)?))
+                }
+                ImportValue::Reference(label) => {
+                    TagType::ValueReference(ValueReference::new(resolve(*label, path)?))
+                }
+            };
+            let name = self.add_name(&tag.name).map_err(|e| error(e, path))?;
+            self.add_tag(tag_ids[i], name, &tag_parents, value)
+                .map_err(|e| error(e, path))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(v: &str) -> JsonValue {
+        JsonValue::Number(v.to_string())
+    }
+
+    fn string(v: &str) -> JsonValue {
+        JsonValue::String(v.to_string())
+    }
+
+    // Message and offset of the syntax error.
+    fn error(source: &str) -> (String, Option<usize>) {
+        let error = parse_json(source).unwrap_err();
+        (error.message, error.offset)
+    }
+
+    #[test]
+    fn values_are_parsed() {
+        assert_eq!(
+            parse_json(" {\"a\": [1, -2.5e3, true, false, null], \"b\": {}, \"a\": []} ").unwrap(),
+            JsonValue::Object(vec![
+                (
+                    "a".to_string(),
+                    JsonValue::Array(vec![
+                        number("1"),
+                        number("-2.5e3"),
+                        JsonValue::Bool(true),
+                        JsonValue::Bool(false),
+                        JsonValue::Null,
+                    ])
+                ),
+                ("b".to_string(), JsonValue::Object(Vec::new())),
+                ("a".to_string(), JsonValue::Array(Vec::new())),
+            ])
+        );
+        // Numbers are kept as written, so u64 values don't lose precision.
+        assert_eq!(
+            parse_json("18446744073709551615").unwrap(),
+            number("18446744073709551615")
+        );
+        assert_eq!(
+            parse_json(r#""q\"b\\s\/n\nt\tué😀""#).unwrap(),
+            string("q\"b\\s/n\nt\tu\u{e9}\u{1f600}")
+        );
+        assert_eq!(parse_json("\"žluť\"").unwrap(), string("žluť"));
+    }
+
+    #[test]
+    fn first_member_is_found_by_key() {
+        let value = parse_json(r#"{"a": 1, "b": 2, "a": 3}"#).unwrap();
+        assert_eq!(value.get("a"), Some(&number("1")));
+        assert_eq!(value.get("c"), None);
+        assert_eq!(number("1").get("a"), None);
+    }
+
+    #[test]
+    fn errors_point_at_the_offset() {
+        let invalid = |x: usize| ("invalid number".to_string(), Some(x));
+        assert_eq!(error("01"), invalid(0));
+        assert_eq!(error("[1.]"), invalid(1));
+        assert_eq!(error("-"), invalid(0));
+        assert_eq!(error("1e+"), invalid(0));
+        assert_eq!(error("\"abc"), ("unterminated string".to_string(), Some(0)));
+        assert_eq!(
+            error("[\"a\nb\"]"),
+            ("control character in string".to_string(), Some(3))
+        );
+        assert_eq!(
+            error(r#"["\ud800"]"#),
+            ("invalid escape".to_string(), Some(2))
+        );
+        assert_eq!(error(r#""\x""#), ("invalid escape".to_string(), Some(1)));
+        assert_eq!(
+            error("[1] 2"),
+            ("unexpected data after value".to_string(), Some(4))
+        );
+        assert_eq!(
+            error("{1: 2}"),
+            ("expected string, found `1`".to_string(), Some(1))
+        );
+        assert_eq!(
+            error("tru"),
+            ("expected value, found `t`".to_string(), Some(0))
+        );
+        assert_eq!(
+            error(""),
+            ("expected value, found end of document".to_string(), Some(0))
+        );
+        assert_eq!(
+            error(&"[".repeat(MAX_DEPTH + 2)),
+            ("nesting is too deep".to_string(), Some(MAX_DEPTH + 1))
+        );
+    }
+
+    #[test]
+    fn written_values_are_parsed_back() {
+        let source = r#"{"a":[1,{"$$b":"x\"\n\u0001"}],"c":{},"d":[]}"#;
+        let value = parse_json(source).unwrap();
+        assert_eq!(value.to_string(), source);
+        let pretty = format!("{:#}", value);
+        assert_eq!(
+            pretty,
+            "{\n  \"a\": [\n    1,\n    {\n      \"$$b\": \"x\\\"\\n\\u0001\"\n    }\n  ],\n  \"c\": {},\n  \"d\": []\n}"
+        );
+        assert_eq!(parse_json(&pretty).unwrap(), value);
+    }
+}
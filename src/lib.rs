@@ -1,21 +1,141 @@
 use std::{
     collections::HashMap,
-    fs::File,
-    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread,
 };
 
+mod downstream;
+mod dump;
+mod encoding;
+mod error;
+mod journal;
+mod json;
+mod names;
+mod query;
+mod references;
+mod repair;
+mod search;
+mod source;
+mod transaction;
+mod update;
+mod upgrade;
+mod verify;
+mod writer;
+
+pub use dump::data_type_name;
+pub use encoding::{TextEncoding, TEXT_ENCODING_LATIN1, TEXT_ENCODING_UTF16LE, TEXT_ENCODING_UTF8};
+pub use error::DatabaseError;
+pub use journal::{Journal, JournalContents, JournalWrite, JOURNAL_FILE_EXTENSION};
+pub use json::{parse_json, JsonError, JsonValue};
+pub use names::NameRegistry;
+pub use query::{
+    parse_query, Combinator, Comparison, Condition, Group, Literal, Operator, Property, Query,
+    QueryParseError, SetStatement, Span, Statement, Step, StepKind, TagPredicate, Value,
+};
+pub use references::{ReferenceCount, ReferenceCountTable};
+pub use repair::RepairReport;
+pub use source::{compile_source, SourceError};
+pub use transaction::Transaction;
+pub use verify::{VerifyProblem, VerifyProblemKind, VerifyReport};
+pub use writer::DatabaseWriter;
+
+// Version of the format produced by DatabaseWriter.
+// Version 2 adds REFERENCE_COUNT_TABLE to index table pages.
+pub const FORMAT_VERSION: u32 = 2;
+// Versions that can be read. Older versions are read as they are and can be upgraded in place.
+pub const SUPPORTED_VERSIONS: [u32; 2] = [1, 2];
+
+// Extension of database files, used when opening directory as a database.
+pub const DATABASE_FILE_EXTENSION: &str = "btag";
+
+// Size of cluster metadata header in bytes, including BTAG magic.
+pub const CLUSTER_METADATA_SIZE: u64 = 62;
+// Size of index table page header in bytes.
+pub const INDEX_TABLE_HEADER_SIZE: u64 = 52;
+// Size of index table page header of version 1, which has no REFERENCE_COUNT_TABLE.
+pub const INDEX_TABLE_HEADER_SIZE_V1: u64 = 40;
+
 #[derive(Debug)]
 pub struct ClusterMetadata {
     version: u32,
     cluster_index: u64,
     index_table_offset: u64,
     text_encoding: u16,
+    // Encoding registered for text_encoding. Not stored in file.
+    encoding: TextEncoding,
     database_size: u64,
     last_name_index: u64,
     names_index_padding: u32,
     data_index_padding: u32,
     tag_data_padding: u32,
     next_cluster: u64,
+    // Offset of the cluster in it's file. Not stored in file.
+    cluster_offset: u64,
+}
+
+impl ClusterMetadata {
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn cluster_index(&self) -> u64 {
+        self.cluster_index
+    }
+
+    pub fn index_table_offset(&self) -> u64 {
+        self.index_table_offset
+    }
+
+    pub fn text_encoding(&self) -> u16 {
+        self.text_encoding
+    }
+
+    pub fn encoding(&self) -> TextEncoding {
+        self.encoding
+    }
+
+    pub fn database_size(&self) -> u64 {
+        self.database_size
+    }
+
+    pub fn last_name_index(&self) -> u64 {
+        self.last_name_index
+    }
+
+    pub fn names_index_padding(&self) -> u32 {
+        self.names_index_padding
+    }
+
+    pub fn data_index_padding(&self) -> u32 {
+        self.data_index_padding
+    }
+
+    pub fn tag_data_padding(&self) -> u32 {
+        self.tag_data_padding
+    }
+
+    pub fn next_cluster(&self) -> u64 {
+        self.next_cluster
+    }
+
+    pub fn cluster_offset(&self) -> u64 {
+        self.cluster_offset
+    }
+
+    pub fn index_table_header_size(&self) -> u64 {
+        index_table_header_size(self.version)
+    }
+}
+
+pub fn index_table_header_size(version: u32) -> u64 {
+    if version < 2 {
+        INDEX_TABLE_HEADER_SIZE_V1
+    } else {
+        INDEX_TABLE_HEADER_SIZE
+    }
 }
 
 #[derive(Debug)]
@@ -26,6 +146,139 @@ pub struct IndexTable {
     index_table_tags_size: u32,
     index_table_tags_offset: u64,
     index_table_next_page_offset: u64,
+    index_table_references_size: u32,
+    index_table_references_offset: u64,
+    // Address of the page itself, i.e. offset from the first page start. Not stored in file.
+    page_address: u64,
+}
+
+impl IndexTable {
+    pub fn index_table_size(&self) -> u64 {
+        self.index_table_size
+    }
+
+    pub fn index_table_names_size(&self) -> u32 {
+        self.index_table_names_size
+    }
+
+    pub fn index_table_names_offset(&self) -> u64 {
+        self.index_table_names_offset
+    }
+
+    pub fn index_table_tags_size(&self) -> u32 {
+        self.index_table_tags_size
+    }
+
+    pub fn index_table_tags_offset(&self) -> u64 {
+        self.index_table_tags_offset
+    }
+
+    pub fn index_table_next_page_offset(&self) -> u64 {
+        self.index_table_next_page_offset
+    }
+
+    pub fn index_table_references_size(&self) -> u32 {
+        self.index_table_references_size
+    }
+
+    pub fn index_table_references_offset(&self) -> u64 {
+        self.index_table_references_offset
+    }
+
+    pub fn page_address(&self) -> u64 {
+        self.page_address
+    }
+}
+
+// Single page of index table with it's names and tags.
+#[derive(Debug)]
+pub struct IndexTablePage {
+    index_table: IndexTable,
+    names: NamesIndexTable,
+    tags: DataIndexTable,
+    references: ReferenceCountTable,
+}
+
+impl IndexTablePage {
+    pub fn index_table(&self) -> &IndexTable {
+        &self.index_table
+    }
+
+    pub fn names(&self) -> &NamesIndexTable {
+        &self.names
+    }
+
+    pub fn tags(&self) -> &DataIndexTable {
+        &self.tags
+    }
+
+    pub fn references(&self) -> &ReferenceCountTable {
+        &self.references
+    }
+
+    pub fn into_parts(
+        self,
+    ) -> (
+        IndexTable,
+        NamesIndexTable,
+        DataIndexTable,
+        ReferenceCountTable,
+    ) {
+        (self.index_table, self.names, self.tags, self.references)
+    }
+}
+
+// Lazily reads index table pages of a cluster one by one, following index_table_next_page_offset.
+pub struct IndexTablePages<'a> {
+    reader: &'a mut DatabaseReader,
+    cluster_metadata: &'a ClusterMetadata,
+    next_page: Option<u64>,
+}
+
+impl Iterator for IndexTablePages<'_> {
+    type Item = Result<IndexTablePage, DatabaseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let page_address = self.next_page.take()?;
+
+        let index_table = match self.reader.read_index_table_page(page_address) {
+            Ok(v) => v,
+            Err(e) => return Some(Err(e)),
+        };
+        let names = match self
+            .reader
+            .read_names_index(&index_table, self.cluster_metadata)
+        {
+            Ok(v) => v,
+            Err(e) => return Some(Err(e)),
+        };
+        let tags = match self
+            .reader
+            .read_tags_index(&index_table, self.cluster_metadata)
+        {
+            Ok(v) => v,
+            Err(e) => return Some(Err(e)),
+        };
+        let references = match self.reader.read_references(&index_table) {
+            Ok(v) => v,
+            Err(e) => return Some(Err(e)),
+        };
+
+        // Next page offset is relative to current page, 0x00 means there are no more pages.
+        if index_table.index_table_next_page_offset != 0 {
+            match page_address.checked_add(index_table.index_table_next_page_offset) {
+                Some(v) => self.next_page = Some(v),
+                None => return Some(Err(DatabaseErrorKind::IndexTableValidity.into())),
+            }
+        }
+
+        Some(Ok(IndexTablePage {
+            index_table,
+            names,
+            tags,
+            references,
+        }))
+    }
 }
 
 #[derive(Debug)]
@@ -43,6 +296,18 @@ impl NameIndex {
             name_string,
         }
     }
+
+    pub fn name(&self) -> u64 {
+        self.name
+    }
+
+    pub fn name_string_size(&self) -> u16 {
+        self.name_string_size
+    }
+
+    pub fn name_string(&self) -> &str {
+        &self.name_string
+    }
 }
 
 #[derive(Debug)]
@@ -52,6 +317,8 @@ pub struct TagIndex {
     depth: u64,
     full_path: Vec<u64>,
     offset: u64,
+    // Address of this entry in the tags section. Not stored in file.
+    entry_address: u64,
 }
 
 impl TagIndex {
@@ -62,11 +329,36 @@ impl TagIndex {
             depth,
             full_path,
             offset,
+            entry_address: 0,
         }
     }
+
+    pub fn tag_id(&self) -> u64 {
+        self.tag_id
+    }
+
+    pub fn name(&self) -> u64 {
+        self.name
+    }
+
+    pub fn depth(&self) -> u64 {
+        self.depth
+    }
+
+    pub fn full_path(&self) -> &[u64] {
+        &self.full_path
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn entry_address(&self) -> u64 {
+        self.entry_address
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct NamesIndexTable {
     names: Vec<NameIndex>,
 }
@@ -75,6 +367,10 @@ impl NamesIndexTable {
     pub fn new(names: Vec<NameIndex>) -> Self {
         NamesIndexTable { names }
     }
+
+    pub fn names(&self) -> &[NameIndex] {
+        &self.names
+    }
 }
 
 #[derive(Debug)]
@@ -86,9 +382,31 @@ impl TagIndexTable {
     pub fn new(tags: Vec<TagIndex>) -> Self {
         TagIndexTable { tags }
     }
+
+    pub fn tags(&self) -> &[TagIndex] {
+        &self.tags
+    }
 }
 
-#[derive(Debug)]
+impl From<DataIndexTable> for TagIndexTable {
+    fn from(table: DataIndexTable) -> Self {
+        TagIndexTable { tags: table.tags }
+    }
+}
+
+// Codes of tag_data_type, reference docs/specification.md for data types.
+pub const DATA_TYPE_INTEGER: u8 = 0;
+pub const DATA_TYPE_FLOAT: u8 = 1;
+pub const DATA_TYPE_DOUBLE: u8 = 2;
+pub const DATA_TYPE_ADDRESS_ENTRY: u8 = 3;
+pub const DATA_TYPE_ADDRESS_LIST: u8 = 4;
+pub const DATA_TYPE_TEXT: u8 = 5;
+pub const DATA_TYPE_CHAR: u8 = 6;
+pub const DATA_TYPE_VALUE_REFERENCE: u8 = 7;
+// Record which value has been moved to another address. It's space is no longer used.
+pub const DATA_TYPE_FREE: u8 = u8::MAX;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum TagType {
     AddressEntry(AddressEntry),
     AddressList(AddressList),
@@ -100,6 +418,42 @@ pub enum TagType {
     Char(String),
 }
 
+impl TagType {
+    // Addresses this value references, these are counted in REFERENCE_COUNT_TABLE.
+    pub fn referenced_addresses(&self) -> Vec<u64> {
+        match self {
+            TagType::AddressEntry(entry) => vec![entry.address],
+            TagType::AddressList(list) => list.array.iter().map(|x| x.address).collect(),
+            TagType::ValueReference(reference) => vec![reference.address],
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Value in the syntax of Set statement. Names are shown as `?<name>` and addresses
+// as `@<address>`, see BTag::display_value for their strings and tag ids.
+impl fmt::Display for TagType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagType::Integer(v) => write!(f, "{}", v),
+            TagType::Float(v) => write!(f, "FLOAT {:?}", v),
+            TagType::Double(v) => write!(f, "{:?}", v),
+            TagType::Text(v) => write!(f, "{:?}", v),
+            TagType::Char(v) => write!(f, "CHAR {:?}", v),
+            TagType::AddressEntry(entry) => write!(f, "?{} @{}", entry.name, entry.address),
+            TagType::AddressList(list) => {
+                let entries: Vec<String> = list
+                    .array
+                    .iter()
+                    .map(|x| format!("?{} @{}", x.name, x.address))
+                    .collect();
+                write!(f, "[{}]", entries.join(", "))
+            }
+            TagType::ValueReference(reference) => write!(f, "&@{}", reference.address),
+        }
+    }
+}
+
 // Represetns tag address by which it can be accessed
 // address is equal to offset in byte stream from index table]
 // Reference docs/specification.md for further information.
@@ -109,9 +463,23 @@ pub struct AddressEntry {
     address: u64,
 }
 
+impl AddressEntry {
+    pub fn new(name: u64, address: u64) -> Self {
+        AddressEntry { name, address }
+    }
+
+    pub fn name(&self) -> u64 {
+        self.name
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+}
+
 impl PartialEq for AddressEntry {
     fn eq(&self, other: &Self) -> bool {
-        return self.name == other.name && self.address == other.address;
+        self.name == other.name && self.address == other.address
     }
 }
 
@@ -121,6 +489,23 @@ pub struct AddressList {
     array: Vec<AddressEntry>,
 }
 
+impl AddressList {
+    pub fn new(array: Vec<AddressEntry>) -> Self {
+        AddressList {
+            address_count: array.len().try_into().unwrap(),
+            array,
+        }
+    }
+
+    pub fn address_count(&self) -> u64 {
+        self.address_count
+    }
+
+    pub fn array(&self) -> &[AddressEntry] {
+        &self.array
+    }
+}
+
 impl PartialEq for AddressList {
     fn eq(&self, other: &Self) -> bool {
         for i in self.array.iter().zip(&other.array) {
@@ -128,18 +513,28 @@ impl PartialEq for AddressList {
                 return false;
             }
         }
-        return self.address_count == other.address_count;
+        self.address_count == other.address_count
     }
 }
 
 // Used to reference a specific point in byte stream, where value is stored.
 // May reference any type, not just tags.
 // Reference docs/specification.md for further information.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ValueReference {
     address: u64,
 }
 
+impl ValueReference {
+    pub fn new(address: u64) -> Self {
+        ValueReference { address }
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+}
+
 #[derive(Debug)]
 pub struct TagData<T> {
     tag_id: u64,
@@ -153,31 +548,103 @@ pub struct TagData<T> {
     tag_data: T,
 }
 
+impl<T> TagData<T> {
+    pub fn tag_id(&self) -> u64 {
+        self.tag_id
+    }
+
+    pub fn tag_total_size(&self) -> u64 {
+        self.tag_total_size
+    }
+
+    pub fn tag_name(&self) -> u64 {
+        self.tag_name
+    }
+
+    pub fn tag_depth(&self) -> u64 {
+        self.tag_depth
+    }
+
+    pub fn tag_parents_size(&self) -> u64 {
+        self.tag_parents_size
+    }
+
+    pub fn tag_parents(&self) -> &AddressList {
+        &self.tag_parents
+    }
+
+    pub fn tag_data_type(&self) -> u8 {
+        self.tag_data_type
+    }
+
+    pub fn tag_data_size(&self) -> u64 {
+        self.tag_data_size
+    }
+
+    pub fn tag_data(&self) -> &T {
+        &self.tag_data
+    }
+}
+
+#[derive(Debug)]
 pub struct DataIndexTable {
     tags: Vec<TagIndex>,
 }
 
+impl DataIndexTable {
+    pub fn tags(&self) -> &[TagIndex] {
+        &self.tags
+    }
+}
+
 pub struct BTag {
     readers: Vec<DatabaseReader>,
     clusters: Vec<ClusterMetadata>,
-    name_index_tables: HashMap<u64, NamesIndexTable>,
+    index_tables: HashMap<u64, Vec<IndexTable>>,
+    name_registries: HashMap<u64, NameRegistry>,
     tag_index_tables: HashMap<u64, TagIndexTable>,
+    reference_count_tables: HashMap<u64, ReferenceCountTable>,
+    // Index of the reader in readers for every cluster_index.
+    cluster_readers: HashMap<u64, usize>,
+    last_cluster_index: u64,
 }
 
 #[derive(Debug)]
 pub struct DatabaseReader {
+    path: PathBuf,
     database_file: File,
     file_reader: BufReader<File>,
-    current_index_table_offset: i64,
+    // Absolute offset of the index table of the cluster that is currently being read.
+    // Every address (tag offset, AddressEntry address) is relative to it.
+    current_index_table_offset: u64,
+    // Format version of the cluster that is currently being read.
+    current_version: u32,
+    // Text encoding of the cluster that is currently being read.
+    current_encoding: TextEncoding,
+    // Index of the cluster that is currently being read, used for error context.
+    current_cluster_index: Option<u64>,
+    // Journal of the transaction in progress, every write is logged into it.
+    journal: Option<Journal>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DatabaseErrorKind {
     ClusterValidity,
     ClusterIncompatibleVersion,
+    ClusterDuplicateIndex,
     UnsupportedTextEncoding,
     IndexTableValidity,
     StringValidity,
+    TagValidity,
+    TagDuplicateId,
+    UnsupportedDataType,
+    TagMissing,
+    ClusterFull,
+    UnsupportedQuery,
+    NameDuplicate,
+    TagReferenced,
+    TransactionState,
+    ReferenceCycle,
     IOError,
 }
 
@@ -187,9 +654,14 @@ pub enum QueryEntry {
     Id(u64),
     Conditional(Box<dyn Fn(TagData<TagType>) -> bool>),
     UpstreamConditional(Box<dyn Fn(TagData<TagType>) -> bool>),
-    QueryConditional(Box<dyn Fn(Vec<(SearchResult, Option<Vec<TagData<TagType>>>)>) -> bool>),
+    // Queries are run upstream from the parent, parent matches when predicate accepts results.
+    QueryConditional(Vec<Vec<QueryEntry>>, Box<QueryPredicate>),
+    // Same as QueryConditional, for every node upstream.
+    UpstreamQueryConditional(Vec<Vec<QueryEntry>>, Box<QueryPredicate>),
 }
 
+pub type QueryPredicate = dyn Fn(Vec<(SearchResult, Option<Vec<TagData<TagType>>>)>) -> bool;
+
 #[derive(Debug)]
 pub enum SearchResult {
     Found(Vec<AddressList>),
@@ -198,6 +670,33 @@ pub enum SearchResult {
 }
 
 impl DatabaseReader {
+    // Open database file for reading and, if permitted, for writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DatabaseReader, DatabaseError> {
+        let path = path.as_ref();
+        let database_file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(v) => v,
+            Err(_) => match File::open(path) {
+                Ok(v) => v,
+                Err(e) => return Err(DatabaseError::from(e).with_path(path)),
+            },
+        };
+        let file_reader = match database_file.try_clone() {
+            Ok(v) => BufReader::new(v),
+            Err(e) => return Err(DatabaseError::from(e).with_path(path)),
+        };
+
+        Ok(DatabaseReader {
+            path: path.to_path_buf(),
+            database_file,
+            file_reader,
+            current_index_table_offset: 0,
+            current_version: FORMAT_VERSION,
+            current_encoding: TextEncoding::default(),
+            current_cluster_index: None,
+            journal: None,
+        })
+    }
+
     pub fn read_u64_from_slice(slice: &[u8]) -> u64 {
         u64::from_le_bytes(slice.try_into().unwrap())
     }
@@ -223,48 +722,135 @@ impl DatabaseReader {
         u8::from_le_bytes(slice.try_into().unwrap())
     }*/
 
-    pub fn seek(&mut self, offset: i64) -> Result<(), DatabaseErrorKind> {
-        if let Err(_) = self.file_reader.seek_relative(offset) {
-            return Err(DatabaseErrorKind::IOError);
+    pub fn file_size(&self) -> Result<u64, DatabaseError> {
+        match self.database_file.metadata() {
+            Ok(v) => Ok(v.len()),
+            Err(e) => Err(DatabaseError::from(e).with_path(&self.path)),
+        }
+    }
+
+    // Seek relative to current position.
+    pub fn seek(&mut self, offset: i64) -> Result<(), DatabaseError> {
+        if let Err(e) = self.file_reader.seek_relative(offset) {
+            return Err(DatabaseError::from(e).with_path(&self.path));
+        }
+        Ok(())
+    }
+
+    // Seek to address, i.e. offset from the index table start of current cluster.
+    pub fn seek_address(&mut self, address: u64) -> Result<(), DatabaseError> {
+        let position = match self.current_index_table_offset.checked_add(address) {
+            Some(v) => v,
+            None => return Err(self.error_at(DatabaseErrorKind::IOError, address)),
+        };
+        if let Err(e) = self.file_reader.seek(SeekFrom::Start(position)) {
+            return Err(self.io_error_at(e, address));
         }
-        self.current_index_table_offset -= offset;
         Ok(())
     }
 
     pub fn read_to_buf(&mut self, buf: &mut [u8]) -> Result<(), std::io::Error> {
-        let r = self.file_reader.read_exact(buf);
-        self.advance_seeker(buf.len().try_into().unwrap());
-        r
+        self.file_reader.read_exact(buf)
     }
 
-    pub fn advance_seeker(&mut self, advance: i64) {
-        self.current_index_table_offset -= advance;
+    // Read exactly size bytes. Buffer only grows with data that is actually read,
+    // so corrupted size can't request a huge allocation.
+    pub fn read_to_vec(&mut self, size: u64) -> Result<Vec<u8>, std::io::Error> {
+        let mut buf: Vec<u8> = Vec::new();
+        (&mut self.file_reader).take(size).read_to_end(&mut buf)?;
+        if (buf.len() as u64) < size {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf)
     }
 
-    pub fn read_cluster(
-        &mut self,
-        cluster_offset: u64,
-    ) -> Result<ClusterMetadata, DatabaseErrorKind> {
-        if let Err(_) = self
+    // Use index table of the cluster as base for addresses.
+    pub fn select_cluster(&mut self, cluster_metadata: &ClusterMetadata) {
+        self.current_index_table_offset = cluster_metadata.index_table_offset;
+        self.current_version = cluster_metadata.version;
+        self.current_encoding = cluster_metadata.encoding;
+        self.current_cluster_index = Some(cluster_metadata.cluster_index);
+    }
+
+    // Read size bytes at absolute position of the file.
+    pub fn read_at(&mut self, position: u64, size: u64) -> Result<Vec<u8>, DatabaseError> {
+        if let Err(e) = self.file_reader.seek(SeekFrom::Start(position)) {
+            return Err(self.locate(e.into(), position));
+        }
+        match self.read_to_vec(size) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(self.locate(e.into(), position)),
+        }
+    }
+
+    // Write buffer at absolute position of the file.
+    pub fn write_at(&mut self, position: u64, buf: &[u8]) -> Result<(), DatabaseError> {
+        // Before-image must reach the journal before the database is changed.
+        if self.journal.is_some() {
+            let file_size = self.file_size()?;
+            let mut before =
+                vec![0; file_size.saturating_sub(position).min(buf.len() as u64) as usize];
+            if !before.is_empty() {
+                if let Err(e) = self
+                    .database_file
+                    .seek(SeekFrom::Start(position))
+                    .and_then(|_| self.database_file.read_exact(&mut before))
+                {
+                    return Err(self.locate(e.into(), position));
+                }
+            }
+            if let Some(journal) = self.journal.as_mut() {
+                journal.log_write(position, &before, buf)?;
+            }
+        }
+
+        if let Err(e) = self
+            .database_file
+            .seek(SeekFrom::Start(position))
+            .and_then(|_| self.database_file.write_all(buf))
+        {
+            return Err(self.locate(e.into(), position));
+        }
+        // Reader shares file with database_file, drop whatever it has buffered.
+        if let Err(e) = self.file_reader.seek(SeekFrom::Start(position)) {
+            return Err(self.locate(e.into(), position));
+        }
+        Ok(())
+    }
+
+    // Write buffer at address, i.e. offset from the index table start of current cluster.
+    pub fn write_at_address(&mut self, address: u64, buf: &[u8]) -> Result<(), DatabaseError> {
+        match self.current_index_table_offset.checked_add(address) {
+            Some(v) => self.write_at(v, buf),
+            None => Err(self.error_at(DatabaseErrorKind::IOError, address)),
+        }
+    }
+
+    pub fn read_cluster(&mut self, cluster_offset: u64) -> Result<ClusterMetadata, DatabaseError> {
+        self.current_cluster_index = None;
+        if self
             .file_reader
             .seek(std::io::SeekFrom::Start(cluster_offset))
+            .is_err()
         {
-            return Err(DatabaseErrorKind::ClusterValidity);
+            return Err(self.locate(DatabaseErrorKind::ClusterValidity.into(), cluster_offset));
         }
 
         let mut cluster_data: [u8; 62] = [0; 62];
-        if let Err(_) = self.read_to_buf(&mut cluster_data) {
-            return Err(DatabaseErrorKind::ClusterValidity);
+        if self.read_to_buf(&mut cluster_data).is_err() {
+            return Err(self.locate(DatabaseErrorKind::ClusterValidity.into(), cluster_offset));
         }
-        let validity = match String::from_utf8(cluster_data[0..4].to_vec()) {
-            // 0-3
-            Ok(v) => v,
-            Err(_) => return Err(DatabaseErrorKind::ClusterValidity),
-        };
-        if validity != "BTAG" {
-            return Err(DatabaseErrorKind::ClusterValidity);
+        // 0-3
+        if &cluster_data[0..4] != b"BTAG" {
+            return Err(self.locate(DatabaseErrorKind::ClusterValidity.into(), cluster_offset));
         }
         let version = DatabaseReader::read_u32_from_slice(&cluster_data[4..8]); // 4-8
+        if !SUPPORTED_VERSIONS.contains(&version) {
+            return Err(self.locate(
+                DatabaseErrorKind::ClusterIncompatibleVersion.into(),
+                cluster_offset,
+            ));
+        }
         let cluster_index = DatabaseReader::read_u64_from_slice(&cluster_data[8..16]);
         let index_table_offset = DatabaseReader::read_u64_from_slice(&cluster_data[16..24]);
         let text_encoding = DatabaseReader::read_u16_from_slice(&cluster_data[24..26]);
@@ -274,32 +860,69 @@ impl DatabaseReader {
         let data_index_padding = DatabaseReader::read_u32_from_slice(&cluster_data[46..50]);
         let tag_data_padding = DatabaseReader::read_u32_from_slice(&cluster_data[50..54]);
         let next_cluster = DatabaseReader::read_u64_from_slice(&cluster_data[54..62]);
+        let encoding = match TextEncoding::from_code(text_encoding) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(self
+                    .locate(e, cluster_offset)
+                    .with_cluster_index(cluster_index))
+            }
+        };
 
         Ok(ClusterMetadata {
             version,
             cluster_index,
             index_table_offset,
             text_encoding,
+            encoding,
             database_size,
             last_name_index,
             names_index_padding,
             data_index_padding,
             tag_data_padding,
             next_cluster,
+            cluster_offset,
         })
     }
 
+    // Read first page of the index table and use it as base for addresses.
     pub fn read_index_table(
         &mut self,
         index_table_offset: u64,
-    ) -> Result<IndexTable, DatabaseErrorKind> {
-        if let Err(_) = self.file_reader.seek(SeekFrom::Start(index_table_offset)) {
-            return Err(DatabaseErrorKind::IndexTableValidity);
+    ) -> Result<IndexTable, DatabaseError> {
+        self.current_index_table_offset = index_table_offset;
+        self.read_index_table_page(0)
+    }
+
+    // Iterate every index table page of the cluster, starting with the first one.
+    pub fn index_table_pages<'a>(
+        &'a mut self,
+        cluster_metadata: &'a ClusterMetadata,
+    ) -> IndexTablePages<'a> {
+        self.select_cluster(cluster_metadata);
+        IndexTablePages {
+            reader: self,
+            cluster_metadata,
+            next_page: Some(0),
         }
+    }
 
-        let mut table_data: [u8; 40] = [0; 40];
-        if let Err(_) = self.read_to_buf(&mut table_data) {
-            return Err(DatabaseErrorKind::IndexTableValidity);
+    // Read index table page by it's address, i.e. offset from the first page start.
+    pub fn read_index_table_page(
+        &mut self,
+        page_address: u64,
+    ) -> Result<IndexTable, DatabaseError> {
+        if self.seek_address(page_address).is_err() {
+            return Err(self.error_at(DatabaseErrorKind::IndexTableValidity, page_address));
+        }
+
+        let header_size = index_table_header_size(self.current_version);
+        let mut table_data: [u8; 52] = [0; 52];
+        if self
+            .read_to_buf(&mut table_data[0..header_size as usize])
+            .is_err()
+        {
+            return Err(self.error_at(DatabaseErrorKind::IndexTableValidity, page_address));
         }
         let index_table_size = DatabaseReader::read_u64_from_slice(&table_data[0..8]);
         let index_table_names_size = DatabaseReader::read_u32_from_slice(&table_data[8..12]);
@@ -307,8 +930,26 @@ impl DatabaseReader {
         let index_table_tags_size = DatabaseReader::read_u32_from_slice(&table_data[20..24]);
         let index_table_tags_offset = DatabaseReader::read_u64_from_slice(&table_data[24..32]);
         let index_table_next_page_offset = DatabaseReader::read_u64_from_slice(&table_data[32..40]);
-
-        self.current_index_table_offset = 0;
+        // Version 1 has no reference count table, these are left as 0.
+        let index_table_references_size = DatabaseReader::read_u32_from_slice(&table_data[40..44]);
+        let index_table_references_offset =
+            DatabaseReader::read_u64_from_slice(&table_data[44..52]);
+
+        // Names and tags must be inside of the page.
+        // Reference count table may be moved out of the page when it grows.
+        let names_end = index_table_names_offset.checked_add(index_table_names_size.into());
+        let tags_end = index_table_tags_offset.checked_add(index_table_tags_size.into());
+        let references_end =
+            index_table_references_offset.checked_add(index_table_references_size.into());
+        if index_table_names_offset < header_size
+            || index_table_tags_offset < header_size
+            || names_end.is_none_or(|x| x > index_table_size)
+            || tags_end.is_none_or(|x| x > index_table_size)
+            || (index_table_references_size != 0
+                && (index_table_references_offset < header_size || references_end.is_none()))
+        {
+            return Err(self.error_at(DatabaseErrorKind::IndexTableValidity, page_address));
+        }
 
         Ok(IndexTable {
             index_table_size,
@@ -317,39 +958,49 @@ impl DatabaseReader {
             index_table_tags_size,
             index_table_tags_offset,
             index_table_next_page_offset,
+            index_table_references_size,
+            index_table_references_offset,
+            page_address,
         })
     }
 
     pub fn read_names_index(
         &mut self,
-        index_table: IndexTable,
-        cluster_metadata: ClusterMetadata,
-    ) -> Result<NamesIndexTable, DatabaseErrorKind> {
-        self.seek(
-            self.current_index_table_offset
-                + <u64 as TryInto<i64>>::try_into(index_table.index_table_names_offset).unwrap(),
-        )?;
-        let size = index_table.index_table_names_size;
+        index_table: &IndexTable,
+        cluster_metadata: &ClusterMetadata,
+    ) -> Result<NamesIndexTable, DatabaseError> {
+        let section_address = index_table
+            .page_address
+            .saturating_add(index_table.index_table_names_offset);
+        self.seek_address(section_address)?;
+        let size = u64::from(index_table.index_table_names_size);
 
-        let mut i: u32 = 0;
+        let mut i: u64 = 0;
 
         let mut names: Vec<NameIndex> = Vec::new();
 
         while i < size {
-            let mut name_data = [0; 8];
-            if let Err(_) = self.read_to_buf(&mut name_data) {
-                return Err(DatabaseErrorKind::IOError);
+            let entry_address = section_address.saturating_add(i);
+            if i + 10 > size {
+                return Err(self.error_at(DatabaseErrorKind::IndexTableValidity, entry_address));
+            }
+            let mut name_data = [0; 10];
+            if let Err(e) = self.read_to_buf(&mut name_data) {
+                return Err(self.io_error_at(e, entry_address));
             }
             let name = DatabaseReader::read_u64_from_slice(&name_data[0..8]);
             let name_string_size = DatabaseReader::read_u16_from_slice(&name_data[8..10]);
-            let mut name_string = Vec::with_capacity(name_string_size.into());
-            if let Err(_) = self.read_to_buf(&mut name_string) {
-                return Err(DatabaseErrorKind::IOError);
+            if i + 10 + u64::from(name_string_size) > size {
+                return Err(self.error_at(DatabaseErrorKind::IndexTableValidity, entry_address));
+            }
+            let mut name_string = vec![0; name_string_size.into()];
+            if let Err(e) = self.read_to_buf(&mut name_string) {
+                return Err(self.io_error_at(e, entry_address));
             }
 
-            let s = match String::from_utf8(name_string) {
+            let s = match cluster_metadata.encoding.decode(&name_string) {
                 Ok(v) => v,
-                Err(_) => return Err(DatabaseErrorKind::StringValidity),
+                Err(e) => return Err(self.locate_address(e, entry_address)),
             };
 
             names.push(NameIndex {
@@ -360,7 +1011,7 @@ impl DatabaseReader {
 
             self.seek(cluster_metadata.names_index_padding.into())?;
 
-            i += 8 + u32::from(name_string_size) + cluster_metadata.names_index_padding;
+            i += 10 + u64::from(name_string_size) + u64::from(cluster_metadata.names_index_padding);
         }
 
         Ok(NamesIndexTable { names })
@@ -368,44 +1019,55 @@ impl DatabaseReader {
 
     pub fn read_tags_index(
         &mut self,
-        index_table: IndexTable,
-        cluster_metadata: ClusterMetadata,
-    ) -> Result<DataIndexTable, DatabaseErrorKind> {
-        self.seek(
-            self.current_index_table_offset
-                + <u64 as TryInto<i64>>::try_into(index_table.index_table_tags_offset).unwrap(),
-        )?;
-        let size = index_table.index_table_tags_size;
+        index_table: &IndexTable,
+        cluster_metadata: &ClusterMetadata,
+    ) -> Result<DataIndexTable, DatabaseError> {
+        let section_address = index_table
+            .page_address
+            .saturating_add(index_table.index_table_tags_offset);
+        self.seek_address(section_address)?;
+        let size = u64::from(index_table.index_table_tags_size);
 
-        let mut tags: Vec<TagIndex> = Vec::new();
+        let mut i: u64 = 0;
 
-        let tags_count = size / 32;
+        let mut tags: Vec<TagIndex> = Vec::new();
 
-        for _ in 0..tags_count {
+        while i < size {
+            let entry_address = section_address.saturating_add(i);
+            if i + 24 > size {
+                return Err(self.error_at(DatabaseErrorKind::IndexTableValidity, entry_address));
+            }
             let mut buf = [0; 24];
-            if let Err(_) = self.read_to_buf(&mut buf) {
-                return Err(DatabaseErrorKind::IOError);
+            if let Err(e) = self.read_to_buf(&mut buf) {
+                return Err(self.io_error_at(e, entry_address));
             }
 
             let tag_id = DatabaseReader::read_u64_from_slice(&buf[0..8]);
             let name = DatabaseReader::read_u64_from_slice(&buf[8..16]);
             let depth = DatabaseReader::read_u64_from_slice(&buf[16..24]);
 
-            let full_path_size = depth * 8;
-            let mut full_path: Vec<u64> = Vec::with_capacity(depth.try_into().unwrap());
-            let mut buf = Vec::with_capacity(full_path_size.try_into().unwrap());
+            // full_path contains every name from root to the tag itself, i.e. depth + 1 entries.
+            let full_path_size = match depth.checked_add(1).and_then(|x| x.checked_mul(8)) {
+                Some(v) if i + 24 + 8 <= size && v <= size - i - 24 - 8 => v,
+                _ => {
+                    return Err(self.error_at(DatabaseErrorKind::IndexTableValidity, entry_address))
+                }
+            };
+            let buf = match self.read_to_vec(full_path_size) {
+                Ok(v) => v,
+                Err(e) => return Err(self.io_error_at(e, entry_address)),
+            };
 
-            if let Err(_) = self.read_to_buf(&mut buf) {
-                return Err(DatabaseErrorKind::IOError);
-            }
+            let full_path: Vec<u64> = buf
+                .chunks_exact(8)
+                .map(DatabaseReader::read_u64_from_slice)
+                .collect();
 
-            for j in 0..depth {
-                let start = (j * 8).try_into().unwrap();
-                let end = (j * 8 + 8).try_into().unwrap();
-                full_path[<u64 as TryInto<usize>>::try_into(j).unwrap()] =
-                    DatabaseReader::read_u64_from_slice(&buf[start..end])
+            let mut buf = [0; 8];
+            if let Err(e) = self.read_to_buf(&mut buf) {
+                return Err(self.io_error_at(e, entry_address));
             }
-            let offset = DatabaseReader::read_u64_from_slice(&buf[24..32]);
+            let offset = DatabaseReader::read_u64_from_slice(&buf);
 
             tags.push(TagIndex {
                 tag_id,
@@ -413,23 +1075,73 @@ impl DatabaseReader {
                 depth,
                 full_path,
                 offset,
+                entry_address,
             });
 
             self.seek(cluster_metadata.data_index_padding.into())?;
+
+            i += 24 + full_path_size + 8 + u64::from(cluster_metadata.data_index_padding);
         }
 
         Ok(DataIndexTable { tags })
     }
 
-    pub fn read_tag_data(&mut self, offset: u64) -> Result<TagData<TagType>, DatabaseErrorKind> {
-        self.seek(
-            self.current_index_table_offset + <u64 as TryInto<i64>>::try_into(offset).unwrap(),
-        )?;
+    pub fn read_references(
+        &mut self,
+        index_table: &IndexTable,
+    ) -> Result<ReferenceCountTable, DatabaseError> {
+        let size = u64::from(index_table.index_table_references_size);
+        if size == 0 {
+            return Ok(ReferenceCountTable::new(Vec::new()));
+        }
+        let section_address = index_table
+            .page_address
+            .saturating_add(index_table.index_table_references_offset);
+        self.seek_address(section_address)?;
+
+        let mut i: u64 = 0;
+
+        let mut references: Vec<ReferenceCount> = Vec::new();
+
+        while i < size {
+            let entry_address = section_address.saturating_add(i);
+            if i + 16 > size {
+                return Err(self.error_at(DatabaseErrorKind::IndexTableValidity, entry_address));
+            }
+            let mut buf = [0; 16];
+            if let Err(e) = self.read_to_buf(&mut buf) {
+                return Err(self.io_error_at(e, entry_address));
+            }
+            let address = DatabaseReader::read_u64_from_slice(&buf[0..8]);
+            let count = DatabaseReader::read_u64_from_slice(&buf[8..16]);
+
+            if count > (size - i - 16) / 8 {
+                return Err(self.error_at(DatabaseErrorKind::IndexTableValidity, entry_address));
+            }
+            let mut buf = vec![0; (count * 8) as usize];
+            if let Err(e) = self.read_to_buf(&mut buf) {
+                return Err(self.io_error_at(e, entry_address));
+            }
+            let referencing_tags: Vec<u64> = buf
+                .chunks_exact(8)
+                .map(DatabaseReader::read_u64_from_slice)
+                .collect();
+
+            references.push(ReferenceCount::new(address, referencing_tags));
+
+            i += 16 + count * 8;
+        }
+
+        Ok(ReferenceCountTable::new(references))
+    }
+
+    pub fn read_tag_data(&mut self, offset: u64) -> Result<TagData<TagType>, DatabaseError> {
+        self.seek_address(offset)?;
 
         // read basic data
         let mut buf = [0; 40];
-        if let Err(_) = self.read_to_buf(&mut buf) {
-            return Err(DatabaseErrorKind::IOError);
+        if let Err(e) = self.read_to_buf(&mut buf) {
+            return Err(self.io_error_at(e, offset));
         }
 
         let tag_id = DatabaseReader::read_u64_from_slice(&buf[0..8]);
@@ -438,91 +1150,171 @@ impl DatabaseReader {
         let tag_depth = DatabaseReader::read_u64_from_slice(&buf[24..32]);
         let tag_parents_size = DatabaseReader::read_u64_from_slice(&buf[32..40]);
 
+        // Parents and value must fit into the record.
+        if !tag_parents_size.is_multiple_of(16) || tag_parents_size > tag_total_size {
+            return Err(self.error_at(DatabaseErrorKind::TagValidity, offset));
+        }
+        let tag_parents_skip: i64 = match tag_parents_size.try_into() {
+            Ok(v) => v,
+            Err(_) => return Err(self.error_at(DatabaseErrorKind::TagValidity, offset)),
+        };
+
         // skip parents
-        self.seek(tag_parents_size.try_into().unwrap())?;
+        self.seek(tag_parents_skip)?;
 
         // read leftovers
         let mut buf = [0; 9];
-        if let Err(_) = self.read_to_buf(&mut buf) {
-            return Err(DatabaseErrorKind::IOError);
+        if let Err(e) = self.read_to_buf(&mut buf) {
+            return Err(self.io_error_at(e, offset));
         }
 
         let tag_data_type = buf[0];
         let tag_data_size = DatabaseReader::read_u64_from_slice(&buf[1..9]);
 
-        let mut buf = Vec::with_capacity(tag_data_size.try_into().unwrap());
-        if let Err(_) = self.read_to_buf(&mut buf) {
-            return Err(DatabaseErrorKind::IOError);
+        let value_end = (40 + 9 + tag_parents_size).checked_add(tag_data_size);
+        if value_end.is_none_or(|x| x > tag_total_size) {
+            return Err(self.error_at(DatabaseErrorKind::TagValidity, offset));
+        }
+        let buf = match self.read_to_vec(tag_data_size) {
+            Ok(v) => v,
+            Err(e) => return Err(self.io_error_at(e, offset)),
+        };
+
+        let tag_data =
+            match DatabaseReader::decode_tag_data(tag_data_type, &buf, self.current_encoding) {
+                Ok(v) => v,
+                Err(e) => return Err(self.locate_address(e, offset)),
+            };
+
+        Ok(TagData::<TagType> {
+            tag_id,
+            tag_total_size,
+            tag_name,
+            tag_depth,
+            tag_parents_size,
+            tag_parents: AddressList {
+                address_count: tag_parents_size / 16,
+                array: Vec::new(),
+            },
+            tag_data_type,
+            tag_data_size,
+            tag_data,
+        })
+    }
+
+    // Decode tag_data of the given type, reference docs/specification.md for layouts.
+    // Text and Char[] are decoded with text encoding of the cluster.
+    pub fn decode_tag_data(
+        tag_data_type: u8,
+        buf: &[u8],
+        encoding: TextEncoding,
+    ) -> Result<TagType, DatabaseError> {
+        let required_size = match tag_data_type {
+            DATA_TYPE_INTEGER | DATA_TYPE_DOUBLE | DATA_TYPE_ADDRESS_LIST => 8,
+            DATA_TYPE_FLOAT => 4,
+            DATA_TYPE_ADDRESS_ENTRY => 16,
+            DATA_TYPE_TEXT => 2,
+            DATA_TYPE_CHAR => 1,
+            DATA_TYPE_VALUE_REFERENCE => 8,
+            _ => 0,
+        };
+        if buf.len() < required_size {
+            return Err(DatabaseErrorKind::TagValidity.into());
         }
 
         let tag_data = match tag_data_type {
-            0 => TagType::Integer(DatabaseReader::read_u64_from_slice(&buf[0..8])),
-            1 => TagType::Float(DatabaseReader::read_f32_from_slice(&buf[0..4])),
-            2 => TagType::Double(DatabaseReader::read_f64_from_slice(&buf[0..8])),
-            3 => TagType::AddressEntry(AddressEntry {
+            DATA_TYPE_INTEGER => TagType::Integer(DatabaseReader::read_u64_from_slice(&buf[0..8])),
+            DATA_TYPE_FLOAT => TagType::Float(DatabaseReader::read_f32_from_slice(&buf[0..4])),
+            DATA_TYPE_DOUBLE => TagType::Double(DatabaseReader::read_f64_from_slice(&buf[0..8])),
+            DATA_TYPE_ADDRESS_ENTRY => TagType::AddressEntry(AddressEntry {
                 name: DatabaseReader::read_u64_from_slice(&buf[0..8]),
                 address: DatabaseReader::read_u64_from_slice(&buf[8..16]),
             }),
-            4 => {
+            DATA_TYPE_ADDRESS_LIST => {
                 let count = DatabaseReader::read_u64_from_slice(&buf[0..8]);
-                //let size = count * 16;
-
-                let mut entries = Vec::<AddressEntry>::with_capacity(count.try_into().unwrap());
-
-                for i in 0..count {
-                    let start = (i * 16).try_into().unwrap();
-                    let end = start + 16;
-                    let name = DatabaseReader::read_u64_from_slice(&buf[start..end]);
-
-                    let start = end;
-                    let end = start + 16;
-                    let address = DatabaseReader::read_u64_from_slice(&buf[start..end]);
-
-                    entries.push(AddressEntry { name, address });
+                let entries_data = &buf[8..];
+                if (entries_data.len() as u64) / 16 < count {
+                    return Err(DatabaseErrorKind::TagValidity.into());
                 }
 
+                let entries: Vec<AddressEntry> = entries_data
+                    .chunks_exact(16)
+                    .take(count as usize)
+                    .map(|x| AddressEntry {
+                        name: DatabaseReader::read_u64_from_slice(&x[0..8]),
+                        address: DatabaseReader::read_u64_from_slice(&x[8..16]),
+                    })
+                    .collect();
+
                 TagType::AddressList(AddressList {
                     address_count: count,
                     array: entries,
                 })
             }
-            5 => TagType::Text(
-                String::from_utf8(buf[0..tag_data_size.try_into().unwrap()].to_vec()).unwrap(),
-            ),
-            _ => todo!(),
+            DATA_TYPE_TEXT => {
+                let text_size: usize = DatabaseReader::read_u16_from_slice(&buf[0..2]).into();
+                if buf.len() < 2 + text_size {
+                    return Err(DatabaseErrorKind::TagValidity.into());
+                }
+                TagType::Text(encoding.decode(&buf[2..2 + text_size])?)
+            }
+            DATA_TYPE_CHAR => {
+                let char_size: usize = buf[0].into();
+                if buf.len() < 1 + char_size {
+                    return Err(DatabaseErrorKind::TagValidity.into());
+                }
+                TagType::Char(encoding.decode(&buf[1..1 + char_size])?)
+            }
+            DATA_TYPE_VALUE_REFERENCE => TagType::ValueReference(ValueReference {
+                address: DatabaseReader::read_u64_from_slice(&buf[0..8]),
+            }),
+            _ => return Err(DatabaseErrorKind::UnsupportedDataType.into()),
         };
 
-        Ok(TagData::<TagType> {
-            tag_id,
-            tag_total_size,
-            tag_name,
-            tag_depth,
-            tag_parents_size,
-            tag_parents: AddressList {
-                address_count: tag_depth,
-                array: Vec::with_capacity(tag_depth.try_into().unwrap()),
-            },
-            tag_data_type,
-            tag_data_size,
-            tag_data,
-        })
+        Ok(tag_data)
+    }
+
+    // Addresses of every tag visited while following ValueReference values, starting with the
+    // tag itself. Last address is the tag holding the actual value.
+    // Reference that leads back to an already visited tag is a cycle.
+    pub fn reference_chain(&mut self, offset: u64) -> Result<Vec<u64>, DatabaseError> {
+        let mut chain: Vec<u64> = vec![offset];
+        let mut current = offset;
+        while let TagType::ValueReference(reference) = self.read_tag_data(current)?.tag_data {
+            if chain.contains(&reference.address) {
+                return Err(self.error_at(DatabaseErrorKind::ReferenceCycle, current));
+            }
+            chain.push(reference.address);
+            current = reference.address;
+        }
+
+        Ok(chain)
+    }
+
+    // Read tag data that the tag at the offset resolves to, following ValueReference values.
+    // Tags that are not references resolve to themselves.
+    pub fn dereference(&mut self, offset: u64) -> Result<TagData<TagType>, DatabaseError> {
+        let chain = self.reference_chain(offset)?;
+        match chain.last() {
+            Some(v) => self.read_tag_data(*v),
+            None => Err(DatabaseErrorKind::TagMissing.into()),
+        }
     }
 
     pub fn read_parents(
         &mut self,
-        tag_index: TagIndex,
+        offset: u64,
         tag_data: &mut TagData<TagType>,
-    ) -> Result<(), DatabaseErrorKind> {
-        self.seek(
-            self.current_index_table_offset
-                + <u64 as TryInto<i64>>::try_into(tag_index.offset + 41).unwrap(),
-        )?;
+    ) -> Result<(), DatabaseError> {
+        // Parents follow 40 bytes of tag_id, tag_total_size, tag_name, tag_depth and tag_parents_size.
+        self.seek_address(offset.saturating_add(40))?;
+        tag_data.tag_parents.array.clear();
 
-        let parent_count = tag_data.tag_depth; // Not needed, it's here just for semantics.
+        let parent_count = tag_data.tag_parents_size / 16;
         for _ in 0..parent_count {
             let mut buf = [0; 16];
-            if let Err(_) = self.read_to_buf(&mut buf) {
-                return Err(DatabaseErrorKind::IOError);
+            if let Err(e) = self.read_to_buf(&mut buf) {
+                return Err(self.io_error_at(e, offset));
             }
 
             tag_data.tag_parents.array.push(AddressEntry {
@@ -539,50 +1331,90 @@ impl DatabaseReader {
         query: &Vec<QueryEntry>,
         offset: u64,
         tag_data: &TagData<TagType>,
-    ) -> Result<SearchResult, DatabaseErrorKind> {
+    ) -> Result<SearchResult, DatabaseError> {
         // Return all upstream matches in form of AddressList, representing full sequence of search
-        let result = self.recursive_upstream_search(query, 0, Vec::new(), offset, tag_data);
+        self.recursive_upstream_search(query, 0, Vec::new(), offset, tag_data)
+    }
 
-        return result;
+    // Run every query upstream from the tag at offset and test the results with the predicate.
+    // Every result is accompanied by tag data of the last tag of each matched path,
+    // empty query matches the tag itself.
+    pub fn test_query_conditional(
+        &mut self,
+        queries: &[Vec<QueryEntry>],
+        predicate: &QueryPredicate,
+        offset: u64,
+    ) -> Result<bool, DatabaseError> {
+        let tag_data = self.read_tag_data(offset)?;
+
+        let mut results: Vec<(SearchResult, Option<Vec<TagData<TagType>>>)> =
+            Vec::with_capacity(queries.len());
+        for query in queries {
+            let result = self.recursive_upstream_search(query, 0, Vec::new(), offset, &tag_data)?;
+
+            let paths: Vec<&[AddressEntry]> = match &result {
+                SearchResult::Match(path) => vec![path.as_slice()],
+                SearchResult::Found(list) => list.iter().map(|x| x.array.as_slice()).collect(),
+                SearchResult::None => Vec::new(),
+            };
+            let mut last_tags: Vec<TagData<TagType>> = Vec::with_capacity(paths.len());
+            for path in paths {
+                let address = path.last().map_or(offset, |x| x.address);
+                last_tags.push(self.read_tag_data(address)?);
+            }
+
+            if last_tags.is_empty() {
+                results.push((result, None));
+            } else {
+                results.push((result, Some(last_tags)));
+            }
+        }
+
+        Ok(predicate(results))
     }
 
     fn recursive_upstream_search(
         &mut self,
         query: &Vec<QueryEntry>,
         query_index: i32,
-        mut hierarchy: Vec<AddressEntry>,
+        hierarchy: Vec<AddressEntry>,
         offset: u64,
         tag_data: &TagData<TagType>,
-    ) -> Result<SearchResult, DatabaseErrorKind> {
-        self.seek(
-            self.current_index_table_offset + <u64 as TryInto<i64>>::try_into(offset + 41).unwrap(),
-        )?;
-
-        let mut valid_search_paths: Vec<(AddressEntry, i32)> = Vec::new();
-
-        let parent_count = tag_data.tag_parents_size / 16;
-        if query_index == query.iter().count().try_into().unwrap() {
+    ) -> Result<SearchResult, DatabaseError> {
+        if query_index == query.len().try_into().unwrap() {
             // Query has ended. We found an entire path, therefore it's a Match.
             return Ok(SearchResult::Match(hierarchy));
         }
         let q = &query[<i32 as TryInto<usize>>::try_into(query_index).unwrap()];
 
+        // Parents are read before matching, since matching reads other tags.
+        self.seek_address(offset.saturating_add(40))?;
+        let parent_count = tag_data.tag_parents_size / 16;
+        let mut parents: Vec<AddressEntry> = Vec::new();
+        for _ in 0..parent_count {
+            let mut buf = [0; 16];
+            if let Err(e) = self.read_to_buf(&mut buf) {
+                return Err(self.io_error_at(e, offset));
+            }
+
+            parents.push(AddressEntry {
+                name: DatabaseReader::read_u64_from_slice(&buf[0..8]),
+                address: DatabaseReader::read_u64_from_slice(&buf[8..16]),
+            });
+        }
+
+        let mut valid_search_paths: Vec<(AddressEntry, i32)> = Vec::new();
         let mut matches: Vec<AddressList> = Vec::new();
 
         // Check every parent to find those that match the condition
-        for i in 0..parent_count {
+        for (i, entry) in parents.into_iter().enumerate() {
             let next_index = query_index + 1;
 
-            let mut buf = [0; 16];
-            if let Err(_) = self.read_to_buf(&mut buf) {
-                return Err(DatabaseErrorKind::IOError);
+            // Parents can't be their own ancestors, stop on cycles.
+            if hierarchy.iter().any(|x| x.address == entry.address) {
+                continue;
             }
 
-            let entry = AddressEntry {
-                name: DatabaseReader::read_u64_from_slice(&buf[0..8]),
-                address: DatabaseReader::read_u64_from_slice(&buf[8..16]),
-            };
-
             match q {
                 QueryEntry::Id(id) => {
                     let tag_data = self.read_tag_data(entry.address);
@@ -600,7 +1432,7 @@ impl DatabaseReader {
                 }
 
                 QueryEntry::ArrayIndex(index) => {
-                    if *index == i {
+                    if *index == i as u64 {
                         valid_search_paths.push((entry, next_index));
                     }
                 }
@@ -631,56 +1463,411 @@ impl DatabaseReader {
                     }
                 }
 
-                QueryEntry::QueryConditional(ref predicate) => {
-                    todo!()
+                QueryEntry::QueryConditional(ref queries, ref predicate) => {
+                    if let Ok(true) = self.test_query_conditional(queries, predicate, entry.address)
+                    {
+                        valid_search_paths.push((entry, next_index));
+                    }
+                }
+
+                QueryEntry::UpstreamQueryConditional(ref queries, ref predicate) => {
+                    let mut next_index = next_index;
+                    match self.test_query_conditional(queries, predicate, entry.address) {
+                        Ok(true) => {}
+                        Ok(false) => next_index -= 1,
+                        Err(_) => continue,
+                    }
+                    valid_search_paths.push((entry, next_index));
                 }
             }
         }
 
         // Recursively run on every parent, to either find a Match or None, later returning Found that contains all results of Match
         for parent in valid_search_paths {
-            hierarchy.push(parent.0);
             let tag_data = match self.read_tag_data(parent.0.address) {
                 Ok(v) => v,
                 Err(_) => {
                     continue;
                 }
             };
+            let mut parent_hierarchy = hierarchy.clone();
+            parent_hierarchy.push(parent.0);
+
             let r = self.recursive_upstream_search(
                 query,
                 parent.1,
-                hierarchy.clone(),
+                parent_hierarchy,
                 parent.0.address,
                 &tag_data,
             );
-            if let Ok(r) = r {
-                match r {
-                    SearchResult::Match(m) => matches.push(AddressList {
-                        address_count: m.iter().count().try_into().unwrap(),
-                        array: m,
-                    }),
-                    SearchResult::Found(list) => {
-                        // Potentially significant performance impact. We should consider better implementations.
-                        let mut list = list
-                            .iter()
-                            .take_while(|x| !matches.contains(x))
-                            .map(|x| x.clone())
-                            .collect();
-                        matches.append(&mut list);
-                        return Ok(SearchResult::Found(matches));
+            match r {
+                Ok(SearchResult::Match(m)) => {
+                    let m = AddressList::new(m);
+                    if !matches.contains(&m) {
+                        matches.push(m);
                     }
-                    SearchResult::None => {
-                        continue;
+                }
+                Ok(SearchResult::Found(list)) => {
+                    // Potentially significant performance impact. We should consider better implementations.
+                    for m in list {
+                        if !matches.contains(&m) {
+                            matches.push(m);
+                        }
                     }
                 }
+                _ => continue,
             }
-            hierarchy.pop();
         }
 
         if matches.is_empty() {
             return Ok(SearchResult::None);
         }
         // Recursively return all matches
-        return Ok(SearchResult::Found(matches));
+        Ok(SearchResult::Found(matches))
+    }
+}
+
+// Cluster loaded from a database file, before it is merged into BTag.
+struct LoadedCluster {
+    metadata: ClusterMetadata,
+    index_tables: Vec<IndexTable>,
+    names: NamesIndexTable,
+    tags: DataIndexTable,
+    references: ReferenceCountTable,
+}
+
+impl BTag {
+    // Load every cluster of the database file, following next_cluster chain.
+    // Database is ready to use only after all cluster metadata is loaded,
+    // reference docs/specification.md for further information.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<BTag, DatabaseError> {
+        BTag::open_many(&[path.as_ref()])
+    }
+
+    // Open every database file (with DATABASE_FILE_EXTENSION) of the directory as one database.
+    pub fn open_dir<P: AsRef<Path>>(path: P) -> Result<BTag, DatabaseError> {
+        let path = path.as_ref();
+        let entries = match fs::read_dir(path) {
+            Ok(v) => v,
+            Err(e) => return Err(DatabaseError::from(e).with_path(path)),
+        };
+
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(v) => v,
+                Err(e) => return Err(DatabaseError::from(e).with_path(path)),
+            };
+            let path = entry.path();
+            if path.is_file()
+                && path.extension().and_then(|x| x.to_str()) == Some(DATABASE_FILE_EXTENSION)
+            {
+                paths.push(path);
+            }
+        }
+        // Keep cluster order stable between runs.
+        paths.sort();
+
+        BTag::open_many(&paths)
+    }
+
+    // Open set of database files as one database.
+    // Every file is independent, so each one is loaded in it's own thread.
+    pub fn open_many<P: AsRef<Path> + Sync>(paths: &[P]) -> Result<BTag, DatabaseError> {
+        if paths.is_empty() {
+            return Err(DatabaseErrorKind::ClusterValidity.into());
+        }
+
+        // Interrupted transactions are finished before loading. Journals that depend
+        // on the journal of another file are recovered first, while it still exists.
+        for secondary in [true, false] {
+            for path in paths {
+                let journal = Journal::path_for(path.as_ref());
+                if journal.exists() && Journal::read(&journal)?.primary().is_some() == secondary {
+                    Journal::recover(path.as_ref())?;
+                }
+            }
+        }
+
+        let loaded: Vec<Result<(DatabaseReader, Vec<LoadedCluster>), DatabaseError>> =
+            thread::scope(|scope| {
+                let handles: Vec<_> = paths
+                    .iter()
+                    .map(|path| {
+                        scope.spawn(move || {
+                            let mut reader = DatabaseReader::open(path)?;
+                            let clusters = BTag::load_clusters(&mut reader)?;
+                            Ok((reader, clusters))
+                        })
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|handle| match handle.join() {
+                        Ok(v) => v,
+                        Err(_) => Err(DatabaseErrorKind::IOError.into()),
+                    })
+                    .collect()
+            });
+
+        let mut btag = BTag {
+            readers: Vec::with_capacity(paths.len()),
+            clusters: Vec::new(),
+            index_tables: HashMap::new(),
+            name_registries: HashMap::new(),
+            tag_index_tables: HashMap::new(),
+            reference_count_tables: HashMap::new(),
+            cluster_readers: HashMap::new(),
+            last_cluster_index: 0,
+        };
+
+        for file in loaded {
+            let (reader, clusters) = file?;
+            let reader_index = btag.readers.len();
+            btag.readers.push(reader);
+            btag.insert_clusters(reader_index, clusters)?;
+        }
+
+        Ok(btag)
+    }
+
+    fn insert_clusters(
+        &mut self,
+        reader_index: usize,
+        clusters: Vec<LoadedCluster>,
+    ) -> Result<(), DatabaseError> {
+        for cluster in clusters {
+            let cluster_index = cluster.metadata.cluster_index;
+            if self.cluster_readers.contains_key(&cluster_index) {
+                return Err(self.readers[reader_index]
+                    .locate(
+                        DatabaseErrorKind::ClusterDuplicateIndex.into(),
+                        cluster.metadata.cluster_offset,
+                    )
+                    .with_cluster_index(cluster_index));
+            }
+
+            self.last_cluster_index = self.last_cluster_index.max(cluster_index);
+            self.cluster_readers.insert(cluster_index, reader_index);
+            self.index_tables
+                .insert(cluster_index, cluster.index_tables);
+            let names = match NameRegistry::from_table(
+                cluster.names,
+                cluster.metadata.last_name_index,
+                cluster.metadata.encoding,
+            ) {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(e
+                        .with_path(self.readers[reader_index].path())
+                        .with_cluster_index(cluster_index))
+                }
+            };
+            self.name_registries.insert(cluster_index, names);
+            self.reference_count_tables
+                .insert(cluster_index, cluster.references);
+            self.tag_index_tables
+                .insert(cluster_index, cluster.tags.into());
+            self.clusters.push(cluster.metadata);
+        }
+
+        Ok(())
+    }
+
+    // Load clusters of the database file again, dropping everything that is kept in memory.
+    pub(crate) fn reload_clusters(&mut self, reader_index: usize) -> Result<(), DatabaseError> {
+        let cluster_indexes: Vec<u64> = self
+            .cluster_readers
+            .iter()
+            .filter(|(_, i)| **i == reader_index)
+            .map(|(cluster_index, _)| *cluster_index)
+            .collect();
+        for cluster_index in cluster_indexes {
+            self.cluster_readers.remove(&cluster_index);
+            self.index_tables.remove(&cluster_index);
+            self.name_registries.remove(&cluster_index);
+            self.reference_count_tables.remove(&cluster_index);
+            self.tag_index_tables.remove(&cluster_index);
+            self.clusters.retain(|x| x.cluster_index != cluster_index);
+        }
+
+        let clusters = BTag::load_clusters(&mut self.readers[reader_index])?;
+        self.insert_clusters(reader_index, clusters)?;
+        self.last_cluster_index = self
+            .clusters
+            .iter()
+            .map(|x| x.cluster_index)
+            .max()
+            .unwrap_or(0);
+
+        Ok(())
+    }
+
+    fn load_clusters(reader: &mut DatabaseReader) -> Result<Vec<LoadedCluster>, DatabaseError> {
+        let file_size = reader.file_size()?;
+
+        let mut clusters: Vec<LoadedCluster> = Vec::new();
+
+        let mut cluster_offset: u64 = 0;
+        loop {
+            let cluster = reader.read_cluster(cluster_offset)?;
+
+            if cluster.index_table_offset < cluster_offset + CLUSTER_METADATA_SIZE
+                || cluster
+                    .index_table_offset
+                    .checked_add(cluster.index_table_header_size())
+                    .is_none_or(|x| x > file_size)
+                || cluster_offset
+                    .checked_add(cluster.database_size)
+                    .is_none_or(|x| x > file_size)
+            {
+                return Err(reader
+                    .locate(DatabaseErrorKind::ClusterValidity.into(), cluster_offset)
+                    .with_cluster_index(cluster.cluster_index));
+            }
+            if clusters
+                .iter()
+                .any(|x| x.metadata.cluster_index == cluster.cluster_index)
+            {
+                return Err(reader
+                    .locate(
+                        DatabaseErrorKind::ClusterDuplicateIndex.into(),
+                        cluster_offset,
+                    )
+                    .with_cluster_index(cluster.cluster_index));
+            }
+
+            let mut index_tables: Vec<IndexTable> = Vec::new();
+            let mut names = NamesIndexTable::new(Vec::new());
+            let mut tags = DataIndexTable { tags: Vec::new() };
+            let mut references = ReferenceCountTable::new(Vec::new());
+            for page in reader.index_table_pages(&cluster) {
+                let mut page = page?;
+                names.names.append(&mut page.names.names);
+                tags.tags.append(&mut page.tags.tags);
+                references.append(page.references);
+                index_tables.push(page.index_table);
+            }
+
+            let next_cluster = cluster.next_cluster;
+            let cluster_index = cluster.cluster_index;
+            clusters.push(LoadedCluster {
+                metadata: cluster,
+                index_tables,
+                names,
+                tags,
+                references,
+            });
+
+            // next_cluster equal to 0x00 is treated as non-existent.
+            // Clusters may only follow each other, otherwise chain could loop forever.
+            if next_cluster == 0 {
+                break;
+            }
+            if next_cluster <= cluster_offset {
+                // next_cluster(u64) ends the metadata.
+                return Err(reader
+                    .locate(
+                        DatabaseErrorKind::ClusterValidity.into(),
+                        cluster_offset + 54,
+                    )
+                    .with_cluster_index(cluster_index));
+            }
+            cluster_offset = next_cluster;
+        }
+
+        Ok(clusters)
+    }
+
+    pub fn clusters(&self) -> &[ClusterMetadata] {
+        &self.clusters
+    }
+
+    pub fn last_cluster_index(&self) -> u64 {
+        self.last_cluster_index
+    }
+
+    // Every index table page of the cluster.
+    pub fn index_tables(&self, cluster_index: u64) -> Option<&[IndexTable]> {
+        self.index_tables.get(&cluster_index).map(|x| x.as_slice())
+    }
+
+    pub fn names_index(&self, cluster_index: u64) -> Option<&NamesIndexTable> {
+        self.name_registries.get(&cluster_index).map(|x| x.table())
+    }
+
+    pub fn tags_index(&self, cluster_index: u64) -> Option<&TagIndexTable> {
+        self.tag_index_tables.get(&cluster_index)
+    }
+
+    pub fn cluster(&self, cluster_index: u64) -> Option<&ClusterMetadata> {
+        self.clusters
+            .iter()
+            .find(|x| x.cluster_index == cluster_index)
+    }
+
+    // Reader of the file that contains the cluster, with addresses relative to that cluster.
+    pub fn reader(&mut self, cluster_index: u64) -> Option<&mut DatabaseReader> {
+        let reader_index = *self.cluster_readers.get(&cluster_index)?;
+        let cluster = self
+            .clusters
+            .iter()
+            .find(|x| x.cluster_index == cluster_index)?;
+        let reader = self.readers.get_mut(reader_index)?;
+        reader.select_cluster(cluster);
+        Some(reader)
+    }
+
+    // Find cluster index and index entry of the tag.
+    pub fn locate_tag(&self, tag_id: u64) -> Option<(u64, &TagIndex)> {
+        self.tag_index_tables
+            .iter()
+            .find_map(|(cluster_index, table)| {
+                table
+                    .tags
+                    .iter()
+                    .find(|x| x.tag_id == tag_id)
+                    .map(|x| (*cluster_index, x))
+            })
+    }
+
+    // Index entry of the tag at the address of the cluster.
+    pub fn tag_at(&self, cluster_index: u64, address: u64) -> Option<&TagIndex> {
+        self.tag_index_tables
+            .get(&cluster_index)?
+            .tags
+            .iter()
+            .find(|x| x.offset == address)
+    }
+
+    // Read tag data together with it's parents.
+    pub fn read_tag(&mut self, tag_id: u64) -> Result<TagData<TagType>, DatabaseError> {
+        let (cluster_index, offset) = match self.locate_tag(tag_id) {
+            Some((cluster_index, tag_index)) => (cluster_index, tag_index.offset),
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+        let reader = match self.reader(cluster_index) {
+            Some(v) => v,
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+
+        let mut tag_data = reader.read_tag_data(offset)?;
+        reader.read_parents(offset, &mut tag_data)?;
+        Ok(tag_data)
+    }
+
+    // Read tag data that the tag resolves to, following ValueReference values.
+    pub fn dereference_tag(&mut self, tag_id: u64) -> Result<TagData<TagType>, DatabaseError> {
+        let (cluster_index, offset) = match self.locate_tag(tag_id) {
+            Some((cluster_index, tag_index)) => (cluster_index, tag_index.offset),
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+        let reader = match self.reader(cluster_index) {
+            Some(v) => v,
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+
+        reader.dereference(offset)
     }
 }
@@ -1,3 +1,562 @@
-fn main() {
-    println!("Hello, world!");
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::Path,
+    process::ExitCode,
+    time::Instant,
+};
+
+use btag::{
+    compile_source, parse_json, parse_query, BTag, DatabaseError, DatabaseErrorKind,
+    DatabaseReader, DatabaseWriter, Literal, Property, Query, QueryParseError, Statement,
+    TextEncoding, FORMAT_VERSION,
+};
+
+const USAGE: &str = "usage: btag <command> [arguments]
+
+commands:
+    info <database>                    cluster metadata and index table pages
+    names <database>                   names of every cluster
+    tags <database>                    data index table of every cluster
+    dump [--raw] <database>            every cluster as a tree of tags, or with --raw
+                                       every field of the files with it's offset
+    get <database> <query>             tags matched by the query
+    set <database> <target> = <value>  set value of every tag matched by the target
+    shell <database>                   run queries and Set statements interactively
+    export [--ids] <database>          database as JSON, with --ids every tag keeps it's id
+    import [--ids] <json> <file> [cluster_index] [utf8|utf16le|latin1]
+                                       create database from JSON, with --ids tags keep
+                                       their $id
+    compile <source> <file>            create database from a text source, see Source format
+                                       in docs/specification.md
+    verify <file>...                   check integrity of database files
+    repair <file> <destination>        write fixed copy of the file
+    upgrade <database>                 rewrite clusters of older format versions in place
+    create <file> [cluster_index] [utf8|utf16le|latin1]
+                                       create database with a single empty cluster
+
+<database> is a database file or a directory of database files.";
+
+type CommandResult = Result<ExitCode, Box<dyn Error>>;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match (args.first().map(|x| x.as_str()), args.len()) {
+        (Some("info"), 2) => info(&args[1]),
+        (Some("names"), 2) => names(&args[1]),
+        (Some("tags"), 2) => tags(&args[1]),
+        (Some("dump"), 2) => dump(&args[1], false),
+        (Some("dump"), 3) if args[1] == "--raw" => dump(&args[2], true),
+        (Some("get"), n) if n > 2 => get(&args[1], &args[2..].join(" ")),
+        (Some("set"), n) if n > 2 => set(&args[1], &args[2..].join(" ")),
+        (Some("shell"), 2) => shell(&args[1]),
+        (Some("export"), 2) => export(&args[1], false),
+        (Some("export"), 3) if args[1] == "--ids" => export(&args[2], true),
+        (Some("import"), 3..=5) if args[1] != "--ids" => {
+            import(&args[1], &args[2], args.get(3), args.get(4), false)
+        }
+        (Some("import"), 4..=6) if args[1] == "--ids" => {
+            import(&args[2], &args[3], args.get(4), args.get(5), true)
+        }
+        (Some("compile"), 3) => compile(&args[1], &args[2]),
+        (Some("verify"), n) if n > 1 => verify(&args[1..]),
+        (Some("repair"), 3) => repair(&args[1], &args[2]),
+        (Some("upgrade"), 2) => upgrade(&args[1]),
+        (Some("create"), 2..=4) => create(&args[1], args.get(2), args.get(3)),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("btag: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn open(path: &str) -> Result<BTag, Box<dyn Error>> {
+    let btag = if Path::new(path).is_dir() {
+        BTag::open_dir(path)?
+    } else {
+        BTag::open(path)?
+    };
+    Ok(btag)
+}
+
+fn cluster_indexes(btag: &BTag) -> Vec<u64> {
+    btag.clusters().iter().map(|x| x.cluster_index()).collect()
+}
+
+fn info(path: &str) -> CommandResult {
+    let btag = open(path)?;
+    for cluster in btag.clusters() {
+        println!("cluster {}", cluster.cluster_index());
+        println!("  version              {}", cluster.version());
+        println!("  cluster_offset       {}", cluster.cluster_offset());
+        println!("  index_table_offset   {}", cluster.index_table_offset());
+        println!(
+            "  text_encoding        {} ({:?})",
+            cluster.text_encoding(),
+            cluster.encoding()
+        );
+        println!("  database_size        {}", cluster.database_size());
+        println!("  last_name_index      {}", cluster.last_name_index());
+        println!("  names_index_padding  {}", cluster.names_index_padding());
+        println!("  data_index_padding   {}", cluster.data_index_padding());
+        println!("  tag_data_padding     {}", cluster.tag_data_padding());
+        println!("  next_cluster         {}", cluster.next_cluster());
+
+        for page in btag.index_tables(cluster.cluster_index()).unwrap_or(&[]) {
+            println!("  page {}", page.page_address());
+            println!("    index_table_size   {}", page.index_table_size());
+            println!(
+                "    names              {} bytes at {}",
+                page.index_table_names_size(),
+                page.index_table_names_offset()
+            );
+            println!(
+                "    tags               {} bytes at {}",
+                page.index_table_tags_size(),
+                page.index_table_tags_offset()
+            );
+            println!(
+                "    references         {} bytes at {}",
+                page.index_table_references_size(),
+                page.index_table_references_offset()
+            );
+            println!(
+                "    next_page_offset   {}",
+                page.index_table_next_page_offset()
+            );
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn names(path: &str) -> CommandResult {
+    let btag = open(path)?;
+    for cluster_index in cluster_indexes(&btag) {
+        println!("cluster {}", cluster_index);
+        if let Some(names) = btag.names_index(cluster_index) {
+            for name in names.names() {
+                println!("  {} {}", name.name(), name.name_string());
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn tags(path: &str) -> CommandResult {
+    let btag = open(path)?;
+    for cluster_index in cluster_indexes(&btag) {
+        println!("cluster {}", cluster_index);
+        if let Some(tags) = btag.tags_index(cluster_index) {
+            for tag in tags.tags() {
+                println!(
+                    "  #{} {} depth {} offset {}",
+                    tag.tag_id(),
+                    btag.display_path(cluster_index, tag.full_path()),
+                    tag.depth(),
+                    tag.offset()
+                );
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn dump(path: &str, raw: bool) -> CommandResult {
+    let mut btag = open(path)?;
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    if raw {
+        btag.dump_layout(&mut out)?;
+    } else {
+        btag.dump(&mut out)?;
+    }
+    out.flush()?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn get(path: &str, source: &str) -> CommandResult {
+    let mut btag = open(path)?;
+    for statement in parse_query(source)? {
+        match statement {
+            Statement::Get(query) => {
+                for (cluster_index, found) in btag.find(&query)? {
+                    if let Some(entry) = found.array().last() {
+                        print_match(&mut btag, &query, cluster_index, entry.address())?;
+                    }
+                }
+            }
+            // Names are always loaded together with the database.
+            Statement::PreloadNames(_) => {}
+            Statement::Set(_) => return Err("values are set with `btag set`".into()),
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn set(path: &str, source: &str) -> CommandResult {
+    let mut btag = open(path)?;
+    for statement in parse_query(source)? {
+        let statement = match statement {
+            Statement::Set(v) => v,
+            Statement::PreloadNames(_) => continue,
+            Statement::Get(_) => return Err("queries are run with `btag get`".into()),
+        };
+
+        let changed = btag.execute_set(&statement)?;
+        println!("{} tags changed", changed.len());
+        if statement.return_changed {
+            for tag_id in changed {
+                print_tag(&mut btag, tag_id)?;
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+const SHELL_HELP: &str =
+    "Queries and Set statements of docs/specification.md are run as they are typed,
+`;` separates statements. Every match is printed with it's name path.
+
+    users.joey.wallet.euro             tags matched by the query
+    wallet.euro..0..%name              name or %depth of every match
+    #97 = 1200                         set value of every matched tag
+    PRELOAD NAMES                      load names of every cluster
+    history                            previous statements
+    !!, !<n>                           run last or n-th statement again
+    help                               this help
+    exit                               leave the shell, as does end of input";
+
+// Read-eval-print loop over the database. Errors are printed and the shell goes on.
+fn shell(path: &str) -> CommandResult {
+    let mut btag = open(path)?;
+    println!(
+        "{}: {} clusters, `help` for commands",
+        path,
+        btag.clusters().len()
+    );
+
+    let mut history: Vec<String> = Vec::new();
+    let mut line = String::new();
+    loop {
+        print!("btag> ");
+        io::stdout().flush()?;
+        line.clear();
+        if io::stdin().read_line(&mut line)? == 0 {
+            println!();
+            break;
+        }
+
+        let mut input = line.trim().to_string();
+        if let Some(reference) = input.strip_prefix('!') {
+            // `!!` is the last statement, `!<n>` is n-th statement of the history, from 1.
+            let entry = match reference {
+                "!" => history.last(),
+                v => v
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|x| x.checked_sub(1))
+                    .and_then(|x| history.get(x)),
+            };
+            match entry {
+                Some(v) => {
+                    input = v.clone();
+                    println!("{}", input);
+                }
+                None => {
+                    eprintln!("btag: no {} in history", input);
+                    continue;
+                }
+            }
+        }
+
+        match input.as_str() {
+            "" => continue,
+            "exit" | "quit" => break,
+            "help" => println!("{}", SHELL_HELP),
+            "history" => {
+                for (i, entry) in history.iter().enumerate() {
+                    println!("{:>5}  {}", i + 1, entry);
+                }
+            }
+            _ => {
+                if let Err(e) = run(&mut btag, &input) {
+                    match e.downcast_ref::<QueryParseError>() {
+                        Some(v) => print_parse_error(&input, v),
+                        None => eprintln!("btag: {}", e),
+                    }
+                }
+                if history.last() != Some(&input) {
+                    history.push(input);
+                }
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// Run every statement of the source, printing results and time it took to run it.
+fn run(btag: &mut BTag, source: &str) -> Result<(), Box<dyn Error>> {
+    for statement in parse_query(source)? {
+        let start = Instant::now();
+        match statement {
+            Statement::Get(query) => {
+                let found = btag.find(&query)?;
+                let elapsed = start.elapsed();
+                for (cluster_index, path) in found.iter() {
+                    if let Some(entry) = path.array().last() {
+                        print_match(btag, &query, *cluster_index, entry.address())?;
+                    }
+                }
+                println!("{} matches in {:?}", found.len(), elapsed);
+            }
+            Statement::Set(statement) => {
+                let changed = btag.execute_set(&statement)?;
+                let elapsed = start.elapsed();
+                if statement.return_changed {
+                    for tag_id in changed.iter() {
+                        print_tag(btag, *tag_id)?;
+                    }
+                }
+                println!("{} tags changed in {:?}", changed.len(), elapsed);
+            }
+            // Names are loaded together with the database, there is nothing left to load.
+            Statement::PreloadNames(_) => {
+                let names: usize = cluster_indexes(btag)
+                    .iter()
+                    .filter_map(|x| btag.name_registry(*x))
+                    .map(|x| x.len())
+                    .sum();
+                println!(
+                    "{} names of {} clusters loaded in {:?}",
+                    names,
+                    btag.clusters().len(),
+                    start.elapsed()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Point at the part of the statement that couldn't be parsed.
+fn print_parse_error(source: &str, error: &QueryParseError) {
+    let start = source[..error.span.start.min(source.len())].chars().count();
+    let width = source[error.span.start.min(source.len())..error.span.end.min(source.len())]
+        .chars()
+        .count()
+        .max(1);
+    eprintln!("  {}", source);
+    eprintln!("  {}{}", " ".repeat(start), "^".repeat(width));
+    eprintln!("btag: {}", error.message);
+}
+
+// Check every file and print each problem. Fails when any file has problems.
+fn verify(paths: &[String]) -> CommandResult {
+    let mut status = ExitCode::SUCCESS;
+    for path in paths {
+        let report = match DatabaseReader::open(path).and_then(|mut x| x.verify()) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("btag: {}", e);
+                status = ExitCode::FAILURE;
+                continue;
+            }
+        };
+        for problem in report.problems() {
+            println!("{}", problem);
+        }
+        println!(
+            "{}: {} clusters, {} tags, {} problems",
+            path,
+            report.clusters(),
+            report.tags(),
+            report.problems().len()
+        );
+        if !report.is_ok() {
+            status = ExitCode::FAILURE;
+        }
+    }
+
+    Ok(status)
+}
+
+// Write fixed copy of the file and print what has been changed.
+fn repair(path: &str, destination: &str) -> CommandResult {
+    let report = DatabaseReader::open(path)?.repair(destination)?;
+    for tag_id in report.recovered_tags() {
+        println!("#{}: recovered from tag data", tag_id);
+    }
+    for tag_id in report.dropped_tags() {
+        println!("#{}: dropped, value points nowhere", tag_id);
+    }
+    for cluster_index in report.skipped_clusters() {
+        println!(
+            "cluster {}: skipped, duplicate cluster index",
+            cluster_index
+        );
+    }
+    println!(
+        "{}: {} clusters, {} tags, {} dropped addresses",
+        destination,
+        report.clusters(),
+        report.tags(),
+        report.dropped_addresses()
+    );
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn upgrade(path: &str) -> CommandResult {
+    let mut btag = open(path)?;
+    let upgraded = btag.upgrade()?;
+    for cluster_index in upgraded.iter() {
+        println!(
+            "cluster {}: upgraded to version {}",
+            cluster_index, FORMAT_VERSION
+        );
+    }
+    println!("{}: {} clusters upgraded", path, upgraded.len());
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn export(path: &str, preserve: bool) -> CommandResult {
+    let mut btag = open(path)?;
+    println!("{:#}", btag.export_json(preserve)?);
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn import(
+    json: &str,
+    path: &str,
+    cluster_index: Option<&String>,
+    encoding: Option<&String>,
+    preserve_ids: bool,
+) -> CommandResult {
+    let mut writer = new_writer(path, cluster_index, encoding)?;
+    let document = parse_json(&fs::read_to_string(json)?)?;
+    writer.import_json(&document, preserve_ids)?;
+    writer.write_file(path)?;
+    println!("{}: imported {}", path, json);
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn compile(source: &str, path: &str) -> CommandResult {
+    if fs::exists(path)? {
+        return Err(format!("{} already exists", path).into());
+    }
+    let writer =
+        compile_source(&fs::read_to_string(source)?).map_err(|e| format!("{}: {}", source, e))?;
+    writer.write_file(path)?;
+    println!("{}: compiled {}", path, source);
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn create(path: &str, cluster_index: Option<&String>, encoding: Option<&String>) -> CommandResult {
+    new_writer(path, cluster_index, encoding)?.write_file(path)?;
+    println!("{}: created", path);
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// Writer of a new database file, which may not exist yet.
+fn new_writer(
+    path: &str,
+    cluster_index: Option<&String>,
+    encoding: Option<&String>,
+) -> Result<DatabaseWriter, Box<dyn Error>> {
+    if fs::exists(path)? {
+        return Err(format!("{} already exists", path).into());
+    }
+    let cluster_index: u64 = match cluster_index {
+        Some(v) => v.parse()?,
+        None => 0,
+    };
+    let encoding = match encoding.map(|x| x.to_ascii_lowercase()).as_deref() {
+        None | Some("utf8") => TextEncoding::Utf8,
+        Some("utf16le") => TextEncoding::Utf16Le,
+        Some("latin1") => TextEncoding::Latin1,
+        Some(_) => {
+            return Err(DatabaseError::from(DatabaseErrorKind::UnsupportedTextEncoding).into())
+        }
+    };
+
+    Ok(DatabaseWriter::new(cluster_index, encoding))
+}
+
+fn print_tag(btag: &mut BTag, tag_id: u64) -> Result<(), Box<dyn Error>> {
+    let (cluster_index, full_path) = match btag.locate_tag(tag_id) {
+        Some((cluster_index, tag)) => (cluster_index, tag.full_path().to_vec()),
+        None => return Err(DatabaseError::from(DatabaseErrorKind::TagMissing).into()),
+    };
+    let tag = btag.read_tag(tag_id)?;
+    println!(
+        "#{} {} = {}",
+        tag_id,
+        btag.display_path(cluster_index, &full_path),
+        btag.display_value(cluster_index, tag.tag_data())
+    );
+    Ok(())
+}
+
+fn print_tag_at(btag: &mut BTag, cluster_index: u64, address: u64) -> Result<(), Box<dyn Error>> {
+    match btag.tag_at(cluster_index, address) {
+        Some(v) => {
+            let tag_id = v.tag_id();
+            print_tag(btag, tag_id)
+        }
+        None => Err(DatabaseError::from(DatabaseErrorKind::TagMissing).into()),
+    }
+}
+
+// Tag the query matched, or the property the query ends with.
+fn print_match(
+    btag: &mut BTag,
+    query: &Query,
+    cluster_index: u64,
+    address: u64,
+) -> Result<(), Box<dyn Error>> {
+    let property = match query.property() {
+        Some(v) => v,
+        None => return print_tag_at(btag, cluster_index, address),
+    };
+    let (tag_id, full_path) = match btag.tag_at(cluster_index, address) {
+        Some(v) => (v.tag_id(), v.full_path().to_vec()),
+        None => return Err(DatabaseError::from(DatabaseErrorKind::TagMissing).into()),
+    };
+    let value = match btag.property(cluster_index, address, property)? {
+        Literal::Text(v) => v,
+        Literal::Integer(v) => v.to_string(),
+    };
+    let property = match property {
+        Property::Name => "%name",
+        Property::Depth => "%depth",
+    };
+    println!(
+        "#{} {} {} = {}",
+        tag_id,
+        btag.display_path(cluster_index, &full_path),
+        property,
+        value
+    );
+    Ok(())
 }
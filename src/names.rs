@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use crate::{BTag, DatabaseError, DatabaseErrorKind, NameIndex, NamesIndexTable, TextEncoding};
+
+// Lookup of names of a single cluster in both directions.
+// Both name ids and name strings are unique, reference docs/specification.md.
+#[derive(Debug, Default)]
+pub struct NameRegistry {
+    table: NamesIndexTable,
+    // Positions in table by name string and by name id.
+    ids: HashMap<String, usize>,
+    strings: HashMap<u64, usize>,
+    last_name_index: u64,
+    // Encoding of name strings, name_string_size is the size of encoded string.
+    encoding: TextEncoding,
+}
+
+impl NameRegistry {
+    pub fn new(last_name_index: u64, encoding: TextEncoding) -> Self {
+        NameRegistry {
+            last_name_index,
+            encoding,
+            ..Default::default()
+        }
+    }
+
+    // Build registry from names index table of a cluster.
+    pub fn from_table(
+        table: NamesIndexTable,
+        last_name_index: u64,
+        encoding: TextEncoding,
+    ) -> Result<Self, DatabaseError> {
+        let mut registry = NameRegistry::new(last_name_index, encoding);
+        for name in table.names {
+            registry.push(name)?;
+        }
+        Ok(registry)
+    }
+
+    fn push(&mut self, name: NameIndex) -> Result<(), DatabaseError> {
+        if self.ids.contains_key(&name.name_string) || self.strings.contains_key(&name.name) {
+            return Err(DatabaseErrorKind::NameDuplicate.into());
+        }
+
+        let position = self.table.names.len();
+        self.ids.insert(name.name_string.clone(), position);
+        self.strings.insert(name.name, position);
+        self.last_name_index = self.last_name_index.max(name.name);
+        self.table.names.push(name);
+
+        Ok(())
+    }
+
+    pub fn table(&self) -> &NamesIndexTable {
+        &self.table
+    }
+
+    // Names in order of registration.
+    pub fn names(&self) -> &[NameIndex] {
+        &self.table.names
+    }
+
+    pub fn last_name_index(&self) -> u64 {
+        self.last_name_index
+    }
+
+    pub fn encoding(&self) -> TextEncoding {
+        self.encoding
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.names.is_empty()
+    }
+
+    pub fn id(&self, name_string: &str) -> Option<u64> {
+        let position = *self.ids.get(name_string)?;
+        Some(self.table.names[position].name)
+    }
+
+    pub fn name_string(&self, name: u64) -> Option<&str> {
+        let position = *self.strings.get(&name)?;
+        Some(&self.table.names[position].name_string)
+    }
+
+    // Register name with given id.
+    pub fn insert(&mut self, name: u64, name_string: &str) -> Result<(), DatabaseError> {
+        let name_string_size: u16 = match self.encoding.encode(name_string)?.len().try_into() {
+            Ok(v) => v,
+            Err(_) => return Err(DatabaseErrorKind::StringValidity.into()),
+        };
+        self.push(NameIndex::new(
+            name,
+            name_string_size,
+            name_string.to_string(),
+        ))
+    }
+
+    // Get id of the string, registering it as last_name_index + 1 if it doesn't exist yet.
+    pub fn allocate(&mut self, name_string: &str) -> Result<u64, DatabaseError> {
+        if let Some(name) = self.id(name_string) {
+            return Ok(name);
+        }
+
+        let name = self.last_name_index + 1;
+        self.insert(name, name_string)?;
+        Ok(name)
+    }
+}
+
+impl BTag {
+    pub fn name_registry(&self, cluster_index: u64) -> Option<&NameRegistry> {
+        self.name_registries.get(&cluster_index)
+    }
+
+    // Name id of the string in the cluster.
+    pub fn name_id(&self, cluster_index: u64, name_string: &str) -> Option<u64> {
+        self.name_registries.get(&cluster_index)?.id(name_string)
+    }
+
+    // Name string of the id in the cluster.
+    pub fn name_string(&self, cluster_index: u64, name: u64) -> Option<&str> {
+        self.name_registries.get(&cluster_index)?.name_string(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(names: &[(u64, &str)]) -> NamesIndexTable {
+        NamesIndexTable::new(
+            names
+                .iter()
+                .map(|(name, string)| {
+                    NameIndex::new(*name, string.len() as u16, string.to_string())
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn names_are_found_both_ways() {
+        let registry = NameRegistry::from_table(
+            table(&[(1, "users"), (7, "joey"), (3, "mary ann")]),
+            7,
+            TextEncoding::Utf8,
+        )
+        .unwrap();
+        assert_eq!(registry.len(), 3);
+        for (name, string) in [(1, "users"), (7, "joey"), (3, "mary ann")] {
+            assert_eq!(registry.id(string), Some(name));
+            assert_eq!(registry.name_string(name), Some(string));
+        }
+        assert_eq!(registry.id("Joey"), None);
+        assert_eq!(registry.name_string(2), None);
+        // Names keep the order of the table.
+        assert_eq!(
+            registry
+                .names()
+                .iter()
+                .map(|x| x.name)
+                .collect::<Vec<u64>>(),
+            vec![1, 7, 3]
+        );
+    }
+
+    #[test]
+    fn duplicates_are_refused() {
+        for names in [
+            &[(1, "users"), (2, "users")][..],
+            &[(1, "users"), (1, "joey")][..],
+        ] {
+            assert_eq!(
+                NameRegistry::from_table(table(names), 2, TextEncoding::Utf8)
+                    .unwrap_err()
+                    .kind(),
+                DatabaseErrorKind::NameDuplicate
+            );
+        }
+
+        let mut registry = NameRegistry::new(0, TextEncoding::Utf8);
+        registry.insert(4, "users").unwrap();
+        for (name, string) in [(5, "users"), (4, "joey")] {
+            assert_eq!(
+                registry.insert(name, string).unwrap_err().kind(),
+                DatabaseErrorKind::NameDuplicate
+            );
+        }
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn new_names_follow_last_name_index() {
+        // last_name_index may be past every name, ids of removed names are not reused.
+        let mut registry =
+            NameRegistry::from_table(table(&[(1, "users"), (2, "joey")]), 10, TextEncoding::Utf8)
+                .unwrap();
+        assert_eq!(registry.allocate("joey").unwrap(), 2);
+        assert_eq!(registry.allocate("wallet").unwrap(), 11);
+        assert_eq!(registry.allocate("euro").unwrap(), 12);
+        assert_eq!(registry.allocate("wallet").unwrap(), 11);
+        assert_eq!(registry.last_name_index(), 12);
+        // Inserted id past last_name_index moves it.
+        registry.insert(20, "dollar").unwrap();
+        assert_eq!(registry.allocate("yen").unwrap(), 21);
+        assert_eq!(registry.table().names.len(), 6);
+
+        // name_string_size is the size in the encoding of the cluster.
+        let mut registry = NameRegistry::new(0, TextEncoding::Utf16Le);
+        let name = registry.allocate("plzeň").unwrap();
+        assert_eq!(name, 1);
+        assert_eq!(registry.names()[0].name_string_size, 10);
+    }
+}
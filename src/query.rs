@@ -0,0 +1,1451 @@
+use std::{error::Error, fmt};
+
+use crate::{QueryEntry, QueryPredicate, SearchResult, TagData, TagType};
+
+// Predicate built from query conditionals.
+pub type TagPredicate = dyn Fn(&TagData<TagType>) -> bool;
+
+// Position of a query part in the source text, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    pub(crate) fn to(self, other: Span) -> Span {
+        Span::new(self.start, other.end)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl QueryParseError {
+    pub(crate) fn new(message: impl Into<String>, span: Span) -> Self {
+        QueryParseError {
+            message: message.into(),
+            span,
+        }
+    }
+}
+
+impl fmt::Display for QueryParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {}..{}",
+            self.message, self.span.start, self.span.end
+        )
+    }
+}
+
+impl Error for QueryParseError {}
+
+// Reference "Commands" of docs/specification.md for the syntax.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
+    // `wallet.euro`
+    Get(Query),
+    // `wallet.euro = 1200`, `(wallet.euro = 1200)$%=%`
+    Set(SetStatement),
+    // `PRELOAD NAMES`
+    PreloadNames(Span),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetStatement {
+    pub target: Query,
+    pub value: Value,
+    // `$%=%`, return every tag changed by the statement.
+    pub return_changed: bool,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub steps: Vec<Step>,
+    // `:<num>`, take only nth match of the query.
+    pub nth: Option<u64>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub kind: StepKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StepKind {
+    // `wallet`
+    Name(String),
+    // `0`
+    Index(u64),
+    // `#87`
+    Id(u64),
+    // `*`
+    Wildcard,
+    // `..`
+    Parent,
+    // `..$<condition>`
+    ParentIf(Condition),
+    // `...$<condition>`
+    Ancestors(Condition),
+    // `.-`
+    Backtrace,
+    // `%name`
+    Property(Property),
+    // `%name=wallet`
+    Filter(Comparison),
+    // `( query & query )$peq:0`
+    Group(Group),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Group {
+    pub queries: Vec<Query>,
+    pub combinator: Option<Combinator>,
+    pub nth: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Combinator {
+    // `$peq`, results present in every query.
+    PartialEqual,
+    // `$union`, results present in any query.
+    Union,
+    // `$diff`, results of the first query not present in any other query.
+    Difference,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Property {
+    Name,
+    Depth,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Operator {
+    pub fn compare<T: PartialOrd>(&self, left: T, right: T) -> bool {
+        match self {
+            Operator::Equal => left == right,
+            Operator::NotEqual => left != right,
+            Operator::Less => left < right,
+            Operator::LessEqual => left <= right,
+            Operator::Greater => left > right,
+            Operator::GreaterEqual => left >= right,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    pub property: Property,
+    pub operator: Operator,
+    pub value: Literal,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Comparison(Comparison),
+    // `(..%name=a)`, query started from the tested tag must have matches.
+    Query(Query),
+    // `%name=b&depth=1`
+    All(Vec<Condition>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    Integer(u64),
+    Text(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Integer(u64),
+    Float(f32),
+    Double(f64),
+    Text(String),
+    Char(String),
+    // `#16`, value of another tag.
+    Copy(Query),
+    // `&#87`, address of another tag.
+    Reference(Query),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Token {
+    Ident(String),
+    Number(String),
+    Str(String),
+    Hash,
+    Dot,
+    DotDot,
+    DotDotDot,
+    Dollar,
+    Percent,
+    Colon,
+    Amp,
+    LParen,
+    RParen,
+    Eq,
+    NotEq,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    Minus,
+    Star,
+    Semicolon,
+    Eof,
+}
+
+pub(crate) fn describe(token: &Token) -> String {
+    match token {
+        Token::Ident(v) => format!("`{}`", v),
+        Token::Number(v) => format!("`{}`", v),
+        Token::Str(v) => format!("\"{}\"", v),
+        Token::Eof => "end of query".to_string(),
+        Token::Hash => "`#`".to_string(),
+        Token::Dot => "`.`".to_string(),
+        Token::DotDot => "`..`".to_string(),
+        Token::DotDotDot => "`...`".to_string(),
+        Token::Dollar => "`$`".to_string(),
+        Token::Percent => "`%`".to_string(),
+        Token::Colon => "`:`".to_string(),
+        Token::Amp => "`&`".to_string(),
+        Token::LParen => "`(`".to_string(),
+        Token::RParen => "`)`".to_string(),
+        Token::Eq => "`=`".to_string(),
+        Token::NotEq => "`!=`".to_string(),
+        Token::Less => "`<`".to_string(),
+        Token::LessEq => "`<=`".to_string(),
+        Token::Greater => "`>`".to_string(),
+        Token::GreaterEq => "`>=`".to_string(),
+        Token::Minus => "`-`".to_string(),
+        Token::Star => "`*`".to_string(),
+        Token::Semicolon => "`;`".to_string(),
+    }
+}
+
+pub(crate) fn tokenize(source: &str) -> Result<Vec<(Token, Span)>, QueryParseError> {
+    let mut tokens: Vec<(Token, Span)> = Vec::new();
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let end_of = |i: usize| -> usize {
+        match chars.get(i) {
+            Some((position, _)) => *position,
+            None => source.len(),
+        }
+    };
+
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let next = chars.get(i + 1).map(|x| x.1);
+        let (token, length) = match c {
+            '.' => {
+                let mut count = 1;
+                while count < 3 && chars.get(i + count).map(|x| x.1) == Some('.') {
+                    count += 1;
+                }
+                let token = match count {
+                    1 => Token::Dot,
+                    2 => Token::DotDot,
+                    _ => Token::DotDotDot,
+                };
+                (token, count)
+            }
+            '#' => (Token::Hash, 1),
+            '$' => (Token::Dollar, 1),
+            '%' => (Token::Percent, 1),
+            ':' => (Token::Colon, 1),
+            '&' => (Token::Amp, 1),
+            '(' => (Token::LParen, 1),
+            ')' => (Token::RParen, 1),
+            '-' => (Token::Minus, 1),
+            '*' => (Token::Star, 1),
+            ';' => (Token::Semicolon, 1),
+            '=' => (Token::Eq, 1),
+            '!' if next == Some('=') => (Token::NotEq, 2),
+            '<' if next == Some('=') => (Token::LessEq, 2),
+            '<' => (Token::Less, 1),
+            '>' if next == Some('=') => (Token::GreaterEq, 2),
+            '>' => (Token::Greater, 1),
+            '"' => {
+                let mut text = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j).map(|x| x.1) {
+                        Some('"') => break,
+                        Some('\\') => {
+                            match chars.get(j + 1).map(|x| x.1) {
+                                Some('n') => text.push('\n'),
+                                Some('t') => text.push('\t'),
+                                Some(v) => text.push(v),
+                                None => {
+                                    return Err(QueryParseError::new(
+                                        "unterminated string",
+                                        Span::new(start, source.len()),
+                                    ))
+                                }
+                            }
+                            j += 2;
+                        }
+                        Some(v) => {
+                            text.push(v);
+                            j += 1;
+                        }
+                        None => {
+                            return Err(QueryParseError::new(
+                                "unterminated string",
+                                Span::new(start, source.len()),
+                            ))
+                        }
+                    }
+                }
+                (Token::Str(text), j + 1 - i)
+            }
+            c if c.is_ascii_digit() => {
+                let mut j = i;
+                while chars.get(j).is_some_and(|x| x.1.is_ascii_digit()) {
+                    j += 1;
+                }
+                (Token::Number(source[start..end_of(j)].to_string()), j - i)
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut j = i;
+                while chars
+                    .get(j)
+                    .is_some_and(|x| x.1.is_alphanumeric() || x.1 == '_')
+                {
+                    j += 1;
+                }
+                (Token::Ident(source[start..end_of(j)].to_string()), j - i)
+            }
+            _ => {
+                return Err(QueryParseError::new(
+                    format!("unexpected character `{}`", c),
+                    Span::new(start, end_of(i + 1)),
+                ))
+            }
+        };
+
+        tokens.push((token, Span::new(start, end_of(i + length))));
+        i += length;
+    }
+
+    tokens.push((Token::Eof, Span::new(source.len(), source.len())));
+    Ok(tokens)
+}
+
+pub(crate) struct Parser {
+    pub(crate) tokens: Vec<(Token, Span)>,
+    pub(crate) position: usize,
+}
+
+impl Parser {
+    pub(crate) fn peek(&self) -> &Token {
+        &self.tokens[self.position].0
+    }
+
+    pub(crate) fn peek_at(&self, offset: usize) -> &Token {
+        let i = (self.position + offset).min(self.tokens.len() - 1);
+        &self.tokens[i].0
+    }
+
+    pub(crate) fn span(&self) -> Span {
+        self.tokens[self.position].1
+    }
+
+    pub(crate) fn previous_span(&self) -> Span {
+        self.tokens[self.position.saturating_sub(1)].1
+    }
+
+    pub(crate) fn advance(&mut self) -> (Token, Span) {
+        let token = self.tokens[self.position].clone();
+        if self.position < self.tokens.len() - 1 {
+            self.position += 1;
+        }
+        token
+    }
+
+    pub(crate) fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == token {
+            self.advance();
+            return true;
+        }
+        false
+    }
+
+    pub(crate) fn unexpected(&self, expected: &str) -> QueryParseError {
+        QueryParseError::new(
+            format!("expected {}, found {}", expected, describe(self.peek())),
+            self.span(),
+        )
+    }
+
+    pub(crate) fn expect(&mut self, token: &Token) -> Result<Span, QueryParseError> {
+        if self.peek() == token {
+            return Ok(self.advance().1);
+        }
+        Err(self.unexpected(&describe(token)))
+    }
+
+    pub(crate) fn number(&mut self) -> Result<u64, QueryParseError> {
+        match self.advance() {
+            (Token::Number(v), span) => match v.parse::<u64>() {
+                Ok(v) => Ok(v),
+                Err(_) => Err(QueryParseError::new("number is too big", span)),
+            },
+            (token, span) => Err(QueryParseError::new(
+                format!("expected number, found {}", describe(&token)),
+                span,
+            )),
+        }
+    }
+
+    fn is_keyword(&self, offset: usize, keyword: &str) -> bool {
+        match self.peek_at(offset) {
+            Token::Ident(v) => v.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, QueryParseError> {
+        let start = self.span();
+
+        if self.is_keyword(0, "PRELOAD") && self.is_keyword(1, "NAMES") {
+            self.advance();
+            self.advance();
+            return Ok(Statement::PreloadNames(start.to(self.previous_span())));
+        }
+
+        // `( target = value )$%=%`
+        if self.peek() == &Token::LParen {
+            let position = self.position;
+            self.advance();
+            let target = self.query()?;
+            if self.eat(&Token::Eq) {
+                let value = self.value()?;
+                target.check_steps(false, false)?;
+                value.check_steps()?;
+                self.expect(&Token::RParen)?;
+                let mut return_changed = false;
+                if self.eat(&Token::Dollar) {
+                    self.expect(&Token::Percent)?;
+                    self.expect(&Token::Eq)?;
+                    self.expect(&Token::Percent)?;
+                    return_changed = true;
+                }
+                return Ok(Statement::Set(SetStatement {
+                    target,
+                    value,
+                    return_changed,
+                    span: start.to(self.previous_span()),
+                }));
+            }
+            self.position = position;
+        }
+
+        let target = self.query()?;
+        if self.eat(&Token::Eq) {
+            let value = self.value()?;
+            target.check_steps(false, false)?;
+            value.check_steps()?;
+            return Ok(Statement::Set(SetStatement {
+                target,
+                value,
+                return_changed: false,
+                span: start.to(self.previous_span()),
+            }));
+        }
+
+        target.check_steps(true, false)?;
+        Ok(Statement::Get(target))
+    }
+
+    fn starts_segment(&self) -> bool {
+        matches!(
+            self.peek(),
+            Token::Ident(_)
+                | Token::Str(_)
+                | Token::Number(_)
+                | Token::Hash
+                | Token::Star
+                | Token::Percent
+                | Token::LParen
+        )
+    }
+
+    pub(crate) fn query(&mut self) -> Result<Query, QueryParseError> {
+        let start = self.span();
+        let mut steps: Vec<Step> = Vec::new();
+
+        loop {
+            match self.peek() {
+                Token::Dot if !steps.is_empty() => {
+                    let dot = self.advance().1;
+                    if self.peek() == &Token::Minus {
+                        let span = dot.to(self.advance().1);
+                        steps.push(Step {
+                            kind: StepKind::Backtrace,
+                            span,
+                        });
+                    } else {
+                        steps.push(self.segment()?);
+                    }
+                }
+                Token::DotDot => {
+                    let span = self.advance().1;
+                    if self.eat(&Token::Dollar) {
+                        let condition = self.condition()?;
+                        steps.push(Step {
+                            kind: StepKind::ParentIf(condition),
+                            span: span.to(self.previous_span()),
+                        });
+                    } else {
+                        steps.push(Step {
+                            kind: StepKind::Parent,
+                            span,
+                        });
+                        // `..0`, `..%name`
+                        if self.starts_segment() && self.peek() != &Token::LParen {
+                            steps.push(self.segment()?);
+                        }
+                    }
+                }
+                Token::DotDotDot => {
+                    let span = self.advance().1;
+                    self.expect(&Token::Dollar)?;
+                    let condition = self.condition()?;
+                    steps.push(Step {
+                        kind: StepKind::Ancestors(condition),
+                        span: span.to(self.previous_span()),
+                    });
+                }
+                _ if steps.is_empty() && self.starts_segment() => {
+                    steps.push(self.segment()?);
+                }
+                _ => break,
+            }
+        }
+
+        if steps.is_empty() {
+            return Err(self.unexpected("query"));
+        }
+
+        let mut nth = None;
+        if self.eat(&Token::Colon) {
+            nth = Some(self.number()?);
+        }
+
+        Ok(Query {
+            steps,
+            nth,
+            span: start.to(self.previous_span()),
+        })
+    }
+
+    fn segment(&mut self) -> Result<Step, QueryParseError> {
+        let start = self.span();
+        let kind = match self.peek().clone() {
+            Token::Ident(v) | Token::Str(v) => {
+                self.advance();
+                StepKind::Name(v)
+            }
+            Token::Number(_) => StepKind::Index(self.number()?),
+            Token::Hash => {
+                self.advance();
+                StepKind::Id(self.number()?)
+            }
+            Token::Star => {
+                self.advance();
+                StepKind::Wildcard
+            }
+            Token::Percent => {
+                self.advance();
+                let property = self.property()?;
+                match self.operator() {
+                    Some(operator) => {
+                        let value = self.literal()?;
+                        StepKind::Filter(Comparison {
+                            property,
+                            operator,
+                            value,
+                            span: start.to(self.previous_span()),
+                        })
+                    }
+                    None => StepKind::Property(property),
+                }
+            }
+            Token::LParen => {
+                self.advance();
+                let mut queries = vec![self.query()?];
+                while self.eat(&Token::Amp) {
+                    queries.push(self.query()?);
+                }
+                self.expect(&Token::RParen)?;
+
+                let mut combinator = None;
+                if self.peek() == &Token::Dollar {
+                    if let Token::Ident(_) = self.peek_at(1) {
+                        self.advance();
+                        combinator = Some(self.combinator()?);
+                    }
+                }
+                let mut nth = None;
+                if self.eat(&Token::Colon) {
+                    nth = Some(self.number()?);
+                }
+
+                StepKind::Group(Group {
+                    queries,
+                    combinator,
+                    nth,
+                })
+            }
+            _ => return Err(self.unexpected("name, index, `#id`, `*`, `%property` or `(`")),
+        };
+
+        Ok(Step {
+            kind,
+            span: start.to(self.previous_span()),
+        })
+    }
+
+    fn combinator(&mut self) -> Result<Combinator, QueryParseError> {
+        match self.advance() {
+            (Token::Ident(v), _) if v == "peq" => Ok(Combinator::PartialEqual),
+            (Token::Ident(v), _) if v == "union" => Ok(Combinator::Union),
+            (Token::Ident(v), _) if v == "diff" => Ok(Combinator::Difference),
+            (token, span) => Err(QueryParseError::new(
+                format!("unknown combinator {}", describe(&token)),
+                span,
+            )),
+        }
+    }
+
+    fn property(&mut self) -> Result<Property, QueryParseError> {
+        match self.advance() {
+            (Token::Ident(v), _) if v == "name" => Ok(Property::Name),
+            (Token::Ident(v), _) if v == "depth" => Ok(Property::Depth),
+            (token, span) => Err(QueryParseError::new(
+                format!("unknown property {}", describe(&token)),
+                span,
+            )),
+        }
+    }
+
+    fn operator(&mut self) -> Option<Operator> {
+        let operator = match self.peek() {
+            Token::Eq => Operator::Equal,
+            Token::NotEq => Operator::NotEqual,
+            Token::Less => Operator::Less,
+            Token::LessEq => Operator::LessEqual,
+            Token::Greater => Operator::Greater,
+            Token::GreaterEq => Operator::GreaterEqual,
+            _ => return None,
+        };
+        self.advance();
+        Some(operator)
+    }
+
+    fn literal(&mut self) -> Result<Literal, QueryParseError> {
+        match self.peek().clone() {
+            Token::Number(_) => Ok(Literal::Integer(self.number()?)),
+            Token::Ident(v) | Token::Str(v) => {
+                self.advance();
+                Ok(Literal::Text(v))
+            }
+            _ => Err(self.unexpected("value")),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, QueryParseError> {
+        let mut conditions = vec![self.condition_term()?];
+        while self.eat(&Token::Amp) {
+            conditions.push(self.condition_term()?);
+        }
+
+        if conditions.len() == 1 {
+            return Ok(conditions.pop().unwrap());
+        }
+        Ok(Condition::All(conditions))
+    }
+
+    fn condition_term(&mut self) -> Result<Condition, QueryParseError> {
+        if self.eat(&Token::LParen) {
+            let query = self.query()?;
+            self.expect(&Token::RParen)?;
+            return Ok(Condition::Query(query));
+        }
+
+        let start = self.span();
+        // `%` is optional in conditions, i.e. `depth=1` is the same as `%depth=1`.
+        self.eat(&Token::Percent);
+        let property = self.property()?;
+        let operator = match self.operator() {
+            Some(v) => v,
+            None => return Err(self.unexpected("comparison operator")),
+        };
+        let value = self.literal()?;
+
+        Ok(Condition::Comparison(Comparison {
+            property,
+            operator,
+            value,
+            span: start.to(self.previous_span()),
+        }))
+    }
+
+    pub(crate) fn value(&mut self) -> Result<Value, QueryParseError> {
+        // `To [VARIABLE_TYPE] <value>`
+        // Type is only a keyword when a literal follows, `#1 = text.x` copies the value of `text`.
+        let mut value_type: Option<(String, Span)> = None;
+        if let Token::Ident(v) = self.peek() {
+            let keyword = v.to_ascii_uppercase();
+            let literal = matches!(
+                self.peek_at(1),
+                Token::Str(_) | Token::Number(_) | Token::Minus
+            );
+            if literal && ["INTEGER", "FLOAT", "DOUBLE", "TEXT", "CHAR"].contains(&keyword.as_str())
+            {
+                value_type = Some((keyword, self.advance().1));
+            }
+        }
+
+        let start = self.span();
+        let value = match self.peek().clone() {
+            Token::Amp if value_type.is_none() => {
+                self.advance();
+                return Ok(Value::Reference(self.query()?));
+            }
+            Token::Hash | Token::Ident(_) | Token::LParen if value_type.is_none() => {
+                return Ok(Value::Copy(self.query()?));
+            }
+            Token::Str(v) => {
+                self.advance();
+                Value::Text(v)
+            }
+            Token::Minus | Token::Number(_) => {
+                let negative = self.eat(&Token::Minus);
+                let integer = match self.advance() {
+                    (Token::Number(v), _) => v,
+                    (token, span) => {
+                        return Err(QueryParseError::new(
+                            format!("expected number, found {}", describe(&token)),
+                            span,
+                        ))
+                    }
+                };
+                let mut text = if negative {
+                    format!("-{}", integer)
+                } else {
+                    integer
+                };
+                let mut decimal = false;
+                if self.peek() == &Token::Dot {
+                    if let Token::Number(fraction) = self.peek_at(1).clone() {
+                        self.advance();
+                        self.advance();
+                        text = format!("{}.{}", text, fraction);
+                        decimal = true;
+                    }
+                }
+                let span = start.to(self.previous_span());
+
+                if decimal || negative {
+                    match text.parse::<f64>() {
+                        Ok(v) => Value::Double(v),
+                        Err(_) => return Err(QueryParseError::new("invalid number", span)),
+                    }
+                } else {
+                    match text.parse::<u64>() {
+                        Ok(v) => Value::Integer(v),
+                        Err(_) => return Err(QueryParseError::new("number is too big", span)),
+                    }
+                }
+            }
+            _ => return Err(self.unexpected("value")),
+        };
+        let span = start.to(self.previous_span());
+
+        let (value_type, type_span) = match value_type {
+            Some(v) => v,
+            None => return Ok(value),
+        };
+        let mismatch = || {
+            QueryParseError::new(
+                format!("value can't be used as {}", value_type),
+                type_span.to(span),
+            )
+        };
+        Ok(match (value_type.as_str(), value) {
+            ("INTEGER", Value::Integer(v)) => Value::Integer(v),
+            ("FLOAT", Value::Integer(v)) => Value::Float(v as f32),
+            ("FLOAT", Value::Double(v)) => Value::Float(v as f32),
+            ("DOUBLE", Value::Integer(v)) => Value::Double(v as f64),
+            ("DOUBLE", Value::Double(v)) => Value::Double(v),
+            ("TEXT", Value::Text(v)) => Value::Text(v),
+            ("CHAR", Value::Text(v)) => Value::Char(v),
+            _ => return Err(mismatch()),
+        })
+    }
+}
+
+// Parse one or more `;` separated statements.
+// Reference "Commands" of docs/specification.md for the syntax.
+pub fn parse_query(source: &str) -> Result<Vec<Statement>, QueryParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+
+    let mut statements: Vec<Statement> = Vec::new();
+    loop {
+        if parser.peek() == &Token::Eof {
+            break;
+        }
+        statements.push(parser.statement()?);
+        if !parser.eat(&Token::Semicolon) && parser.peek() != &Token::Eof {
+            return Err(parser.unexpected("`;` or end of query"));
+        }
+    }
+
+    if statements.is_empty() {
+        return Err(parser.unexpected("query"));
+    }
+    Ok(statements)
+}
+
+impl Comparison {
+    // Build predicate over tag data. Names are resolved into name ids with resolve_name.
+    pub fn to_predicate<F: Fn(&str) -> Option<u64>>(
+        &self,
+        resolve_name: &F,
+    ) -> Result<Box<TagPredicate>, QueryParseError> {
+        let operator = self.operator;
+        match (self.property, &self.value) {
+            (Property::Name, Literal::Text(v)) => {
+                let name = match resolve_name(v) {
+                    Some(v) => v,
+                    None => {
+                        return Err(QueryParseError::new(
+                            format!("unknown name `{}`", v),
+                            self.span,
+                        ))
+                    }
+                };
+                Ok(Box::new(move |tag| operator.compare(tag.tag_name, name)))
+            }
+            (Property::Depth, Literal::Integer(v)) => {
+                let depth = *v;
+                Ok(Box::new(move |tag| operator.compare(tag.tag_depth, depth)))
+            }
+            _ => Err(QueryParseError::new(
+                "value doesn't match the property",
+                self.span,
+            )),
+        }
+    }
+}
+
+impl Condition {
+    // Build predicate over tag data. Query conditions need database access and can't be lowered.
+    pub fn to_predicate<F: Fn(&str) -> Option<u64>>(
+        &self,
+        resolve_name: &F,
+    ) -> Result<Box<TagPredicate>, QueryParseError> {
+        match self {
+            Condition::Comparison(comparison) => comparison.to_predicate(resolve_name),
+            Condition::Query(query) => Err(QueryParseError::new(
+                "query conditions can't be lowered into a predicate",
+                query.span,
+            )),
+            Condition::All(conditions) => {
+                let mut predicates = Vec::with_capacity(conditions.len());
+                for condition in conditions {
+                    predicates.push(condition.to_predicate(resolve_name)?);
+                }
+                Ok(Box::new(move |tag| predicates.iter().all(|x| x(tag))))
+            }
+        }
+    }
+
+    fn has_query(&self) -> bool {
+        match self {
+            Condition::Comparison(_) => false,
+            Condition::Query(_) => true,
+            Condition::All(conditions) => conditions.iter().any(|x| x.has_query()),
+        }
+    }
+
+    // Lower condition into entry matched against parents, upstream matches every node upstream.
+    // Conditions with queries become QueryConditional, where the first query is empty and
+    // matches the tested tag itself, so comparisons can be checked on it.
+    pub fn to_query_entry<F: Fn(&str) -> Option<u64>>(
+        &self,
+        resolve_name: &F,
+        upstream: bool,
+    ) -> Result<QueryEntry, QueryParseError> {
+        if !self.has_query() {
+            let predicate = self.to_predicate(resolve_name)?;
+            let predicate = Box::new(move |tag: TagData<TagType>| predicate(&tag));
+            if upstream {
+                return Ok(QueryEntry::UpstreamConditional(predicate));
+            }
+            return Ok(QueryEntry::Conditional(predicate));
+        }
+
+        let conditions = match self {
+            Condition::All(conditions) => conditions.as_slice(),
+            condition => std::slice::from_ref(condition),
+        };
+        let mut queries: Vec<Vec<QueryEntry>> = vec![Vec::new()];
+        let mut predicates: Vec<Box<TagPredicate>> = Vec::new();
+        for condition in conditions {
+            match condition {
+                Condition::Query(query) => {
+                    queries.push(relative_upstream_entries(&query.steps, resolve_name)?)
+                }
+                condition => predicates.push(condition.to_predicate(resolve_name)?),
+            }
+        }
+
+        let predicate: Box<QueryPredicate> = Box::new(move |results| {
+            let mut results = results.into_iter();
+            let tag = match results.next() {
+                Some((_, Some(tags))) => tags,
+                _ => return false,
+            };
+            if !tag.iter().all(|x| predicates.iter().all(|p| p(x))) {
+                return false;
+            }
+            results.all(|(result, _)| !matches!(result, SearchResult::None))
+        });
+        if upstream {
+            return Ok(QueryEntry::UpstreamQueryConditional(queries, predicate));
+        }
+        Ok(QueryEntry::QueryConditional(queries, predicate))
+    }
+}
+
+fn resolve<F: Fn(&str) -> Option<u64>>(
+    resolve_name: &F,
+    name: &str,
+    span: Span,
+) -> Result<u64, QueryParseError> {
+    match resolve_name(name) {
+        Some(v) => Ok(v),
+        None => Err(QueryParseError::new(
+            format!("unknown name `{}`", name),
+            span,
+        )),
+    }
+}
+
+// Entries of steps matched against children, `*` matches every child.
+fn child_entries<F: Fn(&str) -> Option<u64>>(
+    steps: &[Step],
+    resolve_name: &F,
+) -> Result<Vec<QueryEntry>, QueryParseError> {
+    let mut entries: Vec<QueryEntry> = Vec::with_capacity(steps.len());
+    for step in steps {
+        let entry = match &step.kind {
+            StepKind::Name(name) => QueryEntry::Name(resolve(resolve_name, name, step.span)?),
+            StepKind::Index(i) => QueryEntry::ArrayIndex(*i),
+            StepKind::Id(id) => QueryEntry::Id(*id),
+            StepKind::Wildcard => QueryEntry::Conditional(Box::new(|_| true)),
+            StepKind::Filter(comparison) => {
+                let predicate = comparison.to_predicate(resolve_name)?;
+                QueryEntry::Conditional(Box::new(move |tag| predicate(&tag)))
+            }
+            // Upstream steps, `.-`, `%name` and groups are run by BTag::find.
+            StepKind::Parent
+            | StepKind::ParentIf(_)
+            | StepKind::Ancestors(_)
+            | StepKind::Backtrace
+            | StepKind::Property(_)
+            | StepKind::Group(_) => {
+                return Err(QueryParseError::new(
+                    "step can't be lowered into downstream search",
+                    step.span,
+                ))
+            }
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// First step of a query selects tags anywhere in the cluster.
+// Numbers can't be array indexes there, so they are names, i.e. `(1..&2..)`.
+fn start_entry<F: Fn(&str) -> Option<u64>>(
+    step: &Step,
+    resolve_name: &F,
+) -> Result<QueryEntry, QueryParseError> {
+    match &step.kind {
+        StepKind::Index(i) => Ok(QueryEntry::Name(resolve(
+            resolve_name,
+            &i.to_string(),
+            step.span,
+        )?)),
+        _ => match child_entries(std::slice::from_ref(step), resolve_name)?.pop() {
+            Some(v) => Ok(v),
+            None => Err(QueryParseError::new("empty query", step.span)),
+        },
+    }
+}
+
+impl Step {
+    // Whether step walks from tag to it's parents.
+    pub fn is_upstream(&self) -> bool {
+        matches!(
+            self.kind,
+            StepKind::Parent | StepKind::ParentIf(_) | StepKind::Ancestors(_)
+        )
+    }
+}
+
+// Entries of upstream steps, matched from the tag the steps are applied to.
+fn relative_upstream_entries<F: Fn(&str) -> Option<u64>>(
+    steps: &[Step],
+    resolve_name: &F,
+) -> Result<Vec<QueryEntry>, QueryParseError> {
+    let mut entries: Vec<QueryEntry> = Vec::new();
+    let mut steps = steps.iter().peekable();
+    while let Some(step) = steps.next() {
+        match &step.kind {
+            StepKind::Parent => {
+                let entry = match steps.peek().map(|x| &x.kind) {
+                    Some(StepKind::Index(i)) => QueryEntry::ArrayIndex(*i),
+                    Some(StepKind::Name(name)) => {
+                        QueryEntry::Name(resolve(resolve_name, name, step.span)?)
+                    }
+                    Some(StepKind::Id(id)) => QueryEntry::Id(*id),
+                    Some(StepKind::Filter(comparison)) => {
+                        let predicate = comparison.to_predicate(resolve_name)?;
+                        QueryEntry::Conditional(Box::new(move |tag| predicate(&tag)))
+                    }
+                    _ => {
+                        entries.push(QueryEntry::Conditional(Box::new(|_| true)));
+                        continue;
+                    }
+                };
+                entries.push(entry);
+                steps.next();
+            }
+            StepKind::ParentIf(condition) => {
+                entries.push(condition.to_query_entry(resolve_name, false)?)
+            }
+            StepKind::Ancestors(condition) => {
+                entries.push(condition.to_query_entry(resolve_name, true)?)
+            }
+            // Downstream steps, `.-`, `%name` and groups are run by BTag::find.
+            StepKind::Name(_)
+            | StepKind::Index(_)
+            | StepKind::Id(_)
+            | StepKind::Wildcard
+            | StepKind::Filter(_)
+            | StepKind::Backtrace
+            | StepKind::Property(_)
+            | StepKind::Group(_) => {
+                return Err(QueryParseError::new(
+                    "step can't be lowered into upstream search",
+                    step.span,
+                ))
+            }
+        }
+    }
+    Ok(entries)
+}
+
+impl Condition {
+    // Queries of conditions only test for matches.
+    fn check_steps(&self) -> Result<(), QueryParseError> {
+        match self {
+            Condition::Comparison(_) => Ok(()),
+            Condition::Query(query) => query.check_steps(false, true),
+            Condition::All(conditions) => conditions.iter().try_for_each(Condition::check_steps),
+        }
+    }
+}
+
+impl Value {
+    fn check_steps(&self) -> Result<(), QueryParseError> {
+        match self {
+            Value::Copy(query) | Value::Reference(query) => query.check_steps(false, false),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Query {
+    // Whether query walks from tags to their parents.
+    pub fn is_upstream(&self) -> bool {
+        self.steps.iter().any(Step::is_upstream)
+    }
+
+    // `%name` or `%depth` ending the query, it's read from every match.
+    pub fn property(&self) -> Option<Property> {
+        match self.steps.last().map(|x| &x.kind) {
+            Some(StepKind::Property(v)) => Some(*v),
+            _ => None,
+        }
+    }
+
+    // Reject steps that can't be run where they are. Property can only end a query whose
+    // matches are returned, conditions can't step back with `.-`, groups start a query.
+    fn check_steps(&self, property: bool, condition: bool) -> Result<(), QueryParseError> {
+        for (i, step) in self.steps.iter().enumerate() {
+            match &step.kind {
+                StepKind::Property(_) if i == 0 => {
+                    return Err(QueryParseError::new(
+                        "property needs tags to be read from",
+                        step.span,
+                    ))
+                }
+                StepKind::Property(_) if !property || i + 1 != self.steps.len() => {
+                    return Err(QueryParseError::new(
+                        "property can only end a query",
+                        step.span,
+                    ))
+                }
+                StepKind::Backtrace if condition => {
+                    return Err(QueryParseError::new(
+                        "`.-` can't be used in conditions",
+                        step.span,
+                    ))
+                }
+                StepKind::Group(_) if i != 0 => {
+                    return Err(QueryParseError::new(
+                        "group can only start a query",
+                        step.span,
+                    ))
+                }
+                StepKind::Group(group) => {
+                    for query in group.queries.iter() {
+                        query.check_steps(false, condition)?;
+                    }
+                }
+                StepKind::ParentIf(v) | StepKind::Ancestors(v) => v.check_steps()?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Every name string used by the query, including names of nested queries and conditions.
+    pub fn names(&self) -> Vec<String> {
+        fn condition_names(condition: &Condition, names: &mut Vec<String>) {
+            match condition {
+                Condition::Comparison(comparison) => comparison_names(comparison, names),
+                Condition::Query(query) => names.extend(query.names()),
+                Condition::All(conditions) => {
+                    for condition in conditions {
+                        condition_names(condition, names);
+                    }
+                }
+            }
+        }
+        fn comparison_names(comparison: &Comparison, names: &mut Vec<String>) {
+            if let (Property::Name, Literal::Text(v)) = (comparison.property, &comparison.value) {
+                names.push(v.clone());
+            }
+        }
+
+        let mut names: Vec<String> = Vec::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            match &step.kind {
+                StepKind::Name(name) => names.push(name.clone()),
+                StepKind::Index(v) if i == 0 => names.push(v.to_string()),
+                StepKind::Filter(comparison) => comparison_names(comparison, &mut names),
+                StepKind::ParentIf(condition) | StepKind::Ancestors(condition) => {
+                    condition_names(condition, &mut names)
+                }
+                StepKind::Group(group) => {
+                    for query in group.queries.iter() {
+                        names.extend(query.names());
+                    }
+                }
+                _ => {}
+            }
+        }
+        names
+    }
+
+    // Lower query into entries for DatabaseReader::find_downstream.
+    // First step selects tags anywhere in the cluster, every next step is matched
+    // against children of the previous match.
+    pub fn to_downstream_entries<F: Fn(&str) -> Option<u64>>(
+        &self,
+        resolve_name: F,
+    ) -> Result<Vec<QueryEntry>, QueryParseError> {
+        let first = match self.steps.first() {
+            Some(v) => v,
+            None => return Err(QueryParseError::new("empty query", self.span)),
+        };
+        let mut entries = vec![start_entry(first, &resolve_name)?];
+        entries.append(&mut child_entries(&self.steps[1..], &resolve_name)?);
+        Ok(entries)
+    }
+
+    // Lower query into entries matched against children of an already found tag,
+    // for DatabaseReader::find_downstream_from.
+    pub fn to_child_entries<F: Fn(&str) -> Option<u64>>(
+        &self,
+        resolve_name: F,
+    ) -> Result<Vec<QueryEntry>, QueryParseError> {
+        child_entries(&self.steps, &resolve_name)
+    }
+
+    // Lower upstream steps applied to an already found tag, i.e. `..$%name=b..0`,
+    // for DatabaseReader::find_upstream.
+    pub fn to_relative_upstream_entries<F: Fn(&str) -> Option<u64>>(
+        &self,
+        resolve_name: F,
+    ) -> Result<Vec<QueryEntry>, QueryParseError> {
+        relative_upstream_entries(&self.steps, &resolve_name)
+    }
+
+    // Lower query into entries for DatabaseReader::find_upstream.
+    // Returns the entry selecting tags the search starts from, and entries matched against parents.
+    //
+    // Downstream path `a.b.c` starts from `c` and checks parents `b` and `a`.
+    // Upstream steps `c..$%name=b...$%depth=0` start from `c` and are matched in order.
+    pub fn to_upstream_entries<F: Fn(&str) -> Option<u64>>(
+        &self,
+        resolve_name: F,
+    ) -> Result<(QueryEntry, Vec<QueryEntry>), QueryParseError> {
+        let upstream_start = self
+            .steps
+            .iter()
+            .position(Step::is_upstream)
+            .unwrap_or(self.steps.len());
+        let unsupported = |step: &Step| {
+            QueryParseError::new("step can't be lowered into upstream search", step.span)
+        };
+        if upstream_start == 0 {
+            return Err(unsupported(&self.steps[0]));
+        }
+        if upstream_start > 1 && upstream_start < self.steps.len() {
+            return Err(unsupported(&self.steps[upstream_start]));
+        }
+
+        let start = if upstream_start == 1 {
+            start_entry(&self.steps[0], &resolve_name)?
+        } else {
+            child_entries(
+                &self.steps[upstream_start - 1..upstream_start],
+                &resolve_name,
+            )?
+            .remove(0)
+        };
+
+        // Downstream path, parents are checked from the closest one.
+        let mut entries: Vec<QueryEntry> = Vec::new();
+        for (i, step) in self.steps[..upstream_start - 1].iter().enumerate().rev() {
+            let entry = match (i, &step.kind) {
+                (0, _) => start_entry(step, &resolve_name)?,
+                (_, StepKind::Name(_)) | (_, StepKind::Id(_)) => {
+                    child_entries(std::slice::from_ref(step), &resolve_name)?.remove(0)
+                }
+                _ => return Err(unsupported(step)),
+            };
+            entries.push(entry);
+        }
+
+        entries.append(&mut relative_upstream_entries(
+            &self.steps[upstream_start..],
+            &resolve_name,
+        )?);
+
+        Ok((start, entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddressList, TagData};
+
+    fn query(source: &str) -> Query {
+        match parse_query(source).unwrap().remove(0) {
+            Statement::Get(v) => v,
+            v => panic!("expected query, found {:?}", v),
+        }
+    }
+
+    fn resolve_name(name: &str) -> Option<u64> {
+        ["a", "b", "c", "euro"]
+            .iter()
+            .position(|x| *x == name)
+            .map(|x| x as u64 + 1)
+    }
+
+    fn tag(tag_name: u64, tag_depth: u64) -> TagData<TagType> {
+        TagData {
+            tag_id: 0,
+            tag_total_size: 0,
+            tag_name,
+            tag_depth,
+            tag_parents_size: 0,
+            tag_parents: AddressList::new(Vec::new()),
+            tag_data_type: 0,
+            tag_data_size: 8,
+            tag_data: TagType::Integer(0),
+        }
+    }
+
+    fn matches_tag(entry: &QueryEntry, tag_name: u64, tag_depth: u64) -> bool {
+        match entry {
+            QueryEntry::Conditional(v) | QueryEntry::UpstreamConditional(v) => {
+                v(tag(tag_name, tag_depth))
+            }
+            _ => panic!("expected conditional"),
+        }
+    }
+
+    #[test]
+    fn downstream_steps_match_children() {
+        let entries = query("a.0.#97.*.%depth>1")
+            .to_downstream_entries(resolve_name)
+            .unwrap();
+        assert!(matches!(
+            entries[..],
+            [
+                QueryEntry::Name(1),
+                QueryEntry::ArrayIndex(0),
+                QueryEntry::Id(97),
+                QueryEntry::Conditional(_),
+                QueryEntry::Conditional(_)
+            ]
+        ));
+        assert!(matches_tag(&entries[3], 2, 0));
+        assert!(matches_tag(&entries[4], 2, 2));
+        assert!(!matches_tag(&entries[4], 2, 1));
+
+        let error = match query("a.b..").to_downstream_entries(resolve_name) {
+            Err(v) => v,
+            Ok(_) => panic!("upstream step was lowered"),
+        };
+        assert_eq!(
+            error.message,
+            "step can't be lowered into downstream search"
+        );
+        assert_eq!(error.span, Span::new(3, 5));
+    }
+
+    #[test]
+    fn downstream_path_checks_parents_from_the_closest_one() {
+        let (start, entries) = query("a.b.c").to_upstream_entries(resolve_name).unwrap();
+        assert!(matches!(start, QueryEntry::Name(3)));
+        assert!(matches!(
+            entries[..],
+            [QueryEntry::Name(2), QueryEntry::Name(1)]
+        ));
+
+        let (start, entries) = query("#97").to_upstream_entries(resolve_name).unwrap();
+        assert!(matches!(start, QueryEntry::Id(97)));
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn upstream_steps_are_matched_in_order() {
+        let (start, entries) = query("euro..0..b..")
+            .to_upstream_entries(resolve_name)
+            .unwrap();
+        assert!(matches!(start, QueryEntry::Name(4)));
+        assert!(matches!(
+            entries[..],
+            [
+                QueryEntry::ArrayIndex(0),
+                QueryEntry::Name(2),
+                QueryEntry::Conditional(_)
+            ]
+        ));
+        assert!(matches_tag(&entries[2], 3, 5));
+
+        let (_, entries) = query("c..$%name=b&depth<2...$%depth=0")
+            .to_upstream_entries(resolve_name)
+            .unwrap();
+        assert!(matches!(entries[0], QueryEntry::Conditional(_)));
+        assert!(matches!(entries[1], QueryEntry::UpstreamConditional(_)));
+        assert!(matches_tag(&entries[0], 2, 1));
+        assert!(!matches_tag(&entries[0], 2, 2));
+        assert!(!matches_tag(&entries[0], 1, 1));
+        assert!(matches_tag(&entries[1], 4, 0));
+        assert!(!matches_tag(&entries[1], 4, 1));
+
+        // Query conditions are run from the tested tag.
+        let (_, entries) = query("c..$%depth=1&(..a)...$(..b)")
+            .to_upstream_entries(resolve_name)
+            .unwrap();
+        match &entries[..] {
+            [QueryEntry::QueryConditional(queries, _), QueryEntry::UpstreamQueryConditional(upstream, _)] =>
+            {
+                assert!(queries[0].is_empty());
+                assert!(matches!(queries[1][..], [QueryEntry::Name(1)]));
+                assert_eq!(upstream.len(), 2);
+            }
+            _ => panic!("expected query conditionals"),
+        }
+    }
+
+    #[test]
+    fn lowering_errors_point_at_the_step() {
+        let error = |source: &'static str| {
+            let error = match query(source).to_upstream_entries(resolve_name) {
+                Err(v) => v,
+                Ok(_) => panic!("`{}` was lowered", source),
+            };
+            (error.message, &source[error.span.start..error.span.end])
+        };
+        assert_eq!(error("a.d.c"), ("unknown name `d`".to_string(), "d"));
+        assert_eq!(
+            error("c..$%name=d"),
+            ("unknown name `d`".to_string(), "%name=d")
+        );
+        assert_eq!(
+            error("c..$%name=1"),
+            ("value doesn't match the property".to_string(), "%name=1")
+        );
+        assert_eq!(
+            error("a.*.c"),
+            (
+                "step can't be lowered into upstream search".to_string(),
+                "*"
+            )
+        );
+        assert_eq!(
+            error("a.b..c"),
+            (
+                "step can't be lowered into upstream search".to_string(),
+                ".."
+            )
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use crate::{BTag, DatabaseError, DatabaseErrorKind, TagType};
+
+// Single entry of REFERENCE_COUNT_TABLE.
+// Holds names of every tag whose value references the address, one for every reference.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReferenceCount {
+    address: u64,
+    count: u64,
+    referencing_tags: Vec<u64>,
+}
+
+impl ReferenceCount {
+    pub fn new(address: u64, referencing_tags: Vec<u64>) -> Self {
+        ReferenceCount {
+            address,
+            count: referencing_tags.len() as u64,
+            referencing_tags,
+        }
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // Names of tags that reference the address.
+    pub fn referencing_tags(&self) -> &[u64] {
+        &self.referencing_tags
+    }
+}
+
+// Reference docs/specification.md for further information.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReferenceCountTable {
+    references: Vec<ReferenceCount>,
+}
+
+impl ReferenceCountTable {
+    pub fn new(references: Vec<ReferenceCount>) -> Self {
+        ReferenceCountTable { references }
+    }
+
+    pub fn references(&self) -> &[ReferenceCount] {
+        &self.references
+    }
+
+    // Names of tags that reference the address.
+    pub fn referencing_tags(&self, address: u64) -> &[u64] {
+        match self.references.iter().find(|x| x.address == address) {
+            Some(v) => &v.referencing_tags,
+            None => &[],
+        }
+    }
+
+    pub fn add(&mut self, address: u64, name: u64) {
+        match self.references.iter_mut().find(|x| x.address == address) {
+            Some(v) => {
+                v.referencing_tags.push(name);
+                v.count += 1;
+            }
+            None => self
+                .references
+                .push(ReferenceCount::new(address, vec![name])),
+        }
+    }
+
+    // Remove single reference made by a tag of the name. Entries without references are dropped.
+    pub fn remove(&mut self, address: u64, name: u64) {
+        if let Some(v) = self.references.iter_mut().find(|x| x.address == address) {
+            if let Some(i) = v.referencing_tags.iter().position(|x| *x == name) {
+                v.referencing_tags.remove(i);
+                v.count -= 1;
+            }
+        }
+        self.references.retain(|x| x.count != 0);
+    }
+
+    // Add references made by the value of a tag of the name.
+    pub fn add_value(&mut self, name: u64, value: &TagType) {
+        for address in value.referenced_addresses() {
+            self.add(address, name);
+        }
+    }
+
+    // Remove references made by the value of a tag of the name.
+    pub fn remove_value(&mut self, name: u64, value: &TagType) {
+        for address in value.referenced_addresses() {
+            self.remove(address, name);
+        }
+    }
+
+    // Value has been moved, references now point to the new address.
+    pub fn move_address(&mut self, old_address: u64, new_address: u64) {
+        let names: Vec<u64> = match self
+            .references
+            .iter()
+            .position(|x| x.address == old_address)
+        {
+            Some(i) => self.references.remove(i).referencing_tags,
+            None => return,
+        };
+        for name in names {
+            self.add(new_address, name);
+        }
+    }
+
+    // Merge table of another index table page into this one.
+    pub fn append(&mut self, other: ReferenceCountTable) {
+        for reference in other.references {
+            for name in reference.referencing_tags {
+                self.add(reference.address, name);
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        for reference in self.references.iter() {
+            buf.extend_from_slice(&reference.address.to_le_bytes());
+            buf.extend_from_slice(&reference.count.to_le_bytes());
+            for name in reference.referencing_tags.iter() {
+                buf.extend_from_slice(&name.to_le_bytes());
+            }
+        }
+        buf
+    }
+}
+
+impl BTag {
+    pub fn reference_count_table(&self, cluster_index: u64) -> Option<&ReferenceCountTable> {
+        self.reference_count_tables.get(&cluster_index)
+    }
+
+    // Tags that reference value of the tag. Tag can only be safely deleted when there are none.
+    // REFERENCE_COUNT_TABLE only holds names, so tags of those names are read to find the ones
+    // whose value references the tag.
+    pub fn referencing_tags(&mut self, tag_id: u64) -> Result<Vec<u64>, DatabaseError> {
+        let (cluster_index, offset) = match self.locate_tag(tag_id) {
+            Some((cluster_index, tag_index)) => (cluster_index, tag_index.offset),
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+        let names = match self.reference_count_tables.get(&cluster_index) {
+            Some(table) => table.referencing_tags(offset).to_vec(),
+            None => Vec::new(),
+        };
+        if names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let candidates: Vec<(u64, u64)> = match self.tag_index_tables.get(&cluster_index) {
+            Some(v) => v
+                .tags
+                .iter()
+                .filter(|x| names.contains(&x.name))
+                .map(|x| (x.tag_id, x.offset))
+                .collect(),
+            None => Vec::new(),
+        };
+        let reader = match self.reader(cluster_index) {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+
+        let mut tag_ids: Vec<u64> = Vec::new();
+        for (candidate, candidate_offset) in candidates {
+            let tag = reader.read_tag_data(candidate_offset)?;
+            if tag.tag_data.referenced_addresses().contains(&offset) {
+                tag_ids.push(candidate);
+            }
+        }
+        Ok(tag_ids)
+    }
+
+    // Address and size of REFERENCE_COUNT_TABLE of the cluster when it's been moved out of
+    // the first page and nothing follows it. Such table grows in place, and whatever is appended
+    // to the cluster is written over it, followed by the table again.
+    pub(crate) fn trailing_references(&self, cluster_index: u64) -> Option<(u64, u32)> {
+        if self.cluster(cluster_index)?.version < 2 {
+            return None;
+        }
+        let pages = self.index_tables.get(&cluster_index)?;
+        let first_page = pages.first()?;
+        let start = first_page.page_address + first_page.index_table_references_offset;
+        let size = first_page.index_table_references_size;
+        if first_page.index_table_references_offset + u64::from(size) <= first_page.index_table_size
+        {
+            return None;
+        }
+        // Pages and records don't overlap the table, so those that start before it end before it.
+        if pages.iter().any(|x| x.page_address > start)
+            || self
+                .tag_index_tables
+                .get(&cluster_index)?
+                .tags
+                .iter()
+                .any(|x| x.offset > start)
+        {
+            return None;
+        }
+        Some((start, size))
+    }
+
+    // Write REFERENCE_COUNT_TABLE of the cluster into it's first index table page.
+    // When table doesn't fit anymore it is moved to the end of the cluster and stays there.
+    // Version 1 clusters have no place for the table, it's only kept in memory.
+    pub fn write_reference_count_table(&mut self, cluster_index: u64) -> Result<(), DatabaseError> {
+        match self.cluster(cluster_index) {
+            Some(v) if v.version < 2 => return Ok(()),
+            Some(_) => {}
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        }
+        let buf = match self.reference_count_tables.get(&cluster_index) {
+            Some(v) => v.to_bytes(),
+            None => Vec::new(),
+        };
+        let size: u32 = match buf.len().try_into() {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(self.cluster_error(DatabaseErrorKind::IndexTableValidity, cluster_index))
+            }
+        };
+        let trailing = self.trailing_references(cluster_index);
+        let (page_address, mut references_offset, old_size, in_page_size) = match self
+            .index_tables
+            .get(&cluster_index)
+            .and_then(|x| x.first())
+        {
+            Some(v) => (
+                v.page_address,
+                v.index_table_references_offset,
+                v.index_table_references_size,
+                v.index_table_size
+                    .saturating_sub(v.index_table_references_offset),
+            ),
+            None => {
+                return Err(self.cluster_error(DatabaseErrorKind::IndexTableValidity, cluster_index))
+            }
+        };
+
+        // Table at the end of the cluster is rewritten in place, growing the cluster when needed.
+        // Leftover of the bigger table is zeroed, so it can't be taken for a record.
+        let mut write: Option<Vec<u8>> = None;
+        match trailing {
+            Some((start, _)) => {
+                self.extend_cluster(cluster_index, start + u64::from(size.max(old_size)))?;
+                let mut table = buf;
+                table.resize(size.max(old_size) as usize, 0);
+                write = Some(table);
+            }
+            None if u64::from(size) <= in_page_size => write = Some(buf),
+            None => references_offset = self.append_to_cluster(cluster_index, &buf)? - page_address,
+        }
+
+        let cluster = match self
+            .clusters
+            .iter()
+            .find(|x| x.cluster_index == cluster_index)
+        {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let reader = &mut self.readers[self.cluster_readers[&cluster_index]];
+        reader.select_cluster(cluster);
+        let pages = match self.index_tables.get_mut(&cluster_index) {
+            Some(v) => v,
+            None => {
+                return Err(self.cluster_error(DatabaseErrorKind::IndexTableValidity, cluster_index))
+            }
+        };
+        if let Some(buf) = write {
+            reader.write_at_address(page_address + references_offset, &buf)?;
+        }
+
+        // references_size and references_offset follow index_table_next_page_offset.
+        let first_page = &mut pages[0];
+        first_page.index_table_references_size = size;
+        first_page.index_table_references_offset = references_offset;
+        reader.write_at_address(page_address + 40, &size.to_le_bytes())?;
+        reader.write_at_address(page_address + 44, &references_offset.to_le_bytes())?;
+
+        // Whole table is kept in the first page now.
+        for page in pages.iter_mut().skip(1) {
+            if page.index_table_references_size != 0 {
+                page.index_table_references_size = 0;
+                reader.write_at_address(page.page_address + 40, &0u32.to_le_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::Path,
+};
+
+use crate::{
+    AddressList, ClusterMetadata, DatabaseError, DatabaseReader, DatabaseWriter, TagData, TagType,
+    ValueReference, DATA_TYPE_FREE,
+};
+
+// Size of tag data record without parents and tag_data.
+const TAG_DATA_HEADER_SIZE: u64 = 40 + 9;
+
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    clusters: u64,
+    tags: u64,
+    recovered_tags: Vec<u64>,
+    dropped_tags: Vec<u64>,
+    dropped_addresses: u64,
+    skipped_clusters: Vec<u64>,
+}
+
+impl RepairReport {
+    // Number of clusters written into the copy.
+    pub fn clusters(&self) -> u64 {
+        self.clusters
+    }
+
+    // Number of tags written into the copy.
+    pub fn tags(&self) -> u64 {
+        self.tags
+    }
+
+    // Tags found by the scan that had no valid index entry.
+    pub fn recovered_tags(&self) -> &[u64] {
+        &self.recovered_tags
+    }
+
+    // Tags left out of the copy, their AddressEntry or ValueReference value points nowhere.
+    pub fn dropped_tags(&self) -> &[u64] {
+        &self.dropped_tags
+    }
+
+    // Number of parents and AddressList entries removed because they point nowhere.
+    pub fn dropped_addresses(&self) -> u64 {
+        self.dropped_addresses
+    }
+
+    // Indexes of clusters left out of the copy because another cluster has the same index.
+    pub fn skipped_clusters(&self) -> &[u64] {
+        &self.skipped_clusters
+    }
+}
+
+// Tag data records of a cluster found by the scan, by address.
+type ScannedRecords = BTreeMap<u64, TagData<TagType>>;
+
+impl DatabaseReader {
+    // Write a fixed copy of the file into destination. Tag data records are the source of truth:
+    // they are found by a linear scan of every cluster, data index table and
+    // REFERENCE_COUNT_TABLE are rebuilt from them and addresses pointing nowhere are dropped.
+    // Names and cluster metadata are kept as far as they can be read.
+    // Clusters are written in the current format version.
+    pub fn repair<P: AsRef<Path>>(
+        &mut self,
+        destination: P,
+    ) -> Result<RepairReport, DatabaseError> {
+        let mut report = RepairReport::default();
+        let mut writers: Vec<DatabaseWriter> = Vec::new();
+        let mut cluster_indexes: HashSet<u64> = HashSet::new();
+
+        let mut cluster_offset: u64 = 0;
+        // Cluster chain is followed as long as cluster metadata can be read,
+        // file without a single readable cluster can't be repaired.
+        loop {
+            let cluster = match self.read_cluster(cluster_offset) {
+                Ok(v) => v,
+                Err(e) if cluster_offset == 0 => return Err(e),
+                Err(_) => break,
+            };
+            if cluster_indexes.insert(cluster.cluster_index) {
+                writers.push(self.repair_cluster(&cluster, &mut report)?);
+                report.clusters += 1;
+            } else {
+                report.skipped_clusters.push(cluster.cluster_index);
+            }
+
+            if cluster.next_cluster <= cluster_offset {
+                break;
+            }
+            cluster_offset = cluster.next_cluster;
+        }
+
+        report.recovered_tags.sort_unstable();
+        report.dropped_tags.sort_unstable();
+
+        let writers: Vec<&DatabaseWriter> = writers.iter().collect();
+        DatabaseWriter::write_clusters(destination, &writers)?;
+
+        Ok(report)
+    }
+
+    fn repair_cluster(
+        &mut self,
+        cluster: &ClusterMetadata,
+        report: &mut RepairReport,
+    ) -> Result<DatabaseWriter, DatabaseError> {
+        let mut writer = DatabaseWriter::new(cluster.cluster_index, cluster.encoding);
+        // Padding bigger than the cluster itself is corrupted, copy is written without it.
+        let cluster_size = cluster.database_size.min(self.file_size()?);
+        let padding = |x: u32| if u64::from(x) <= cluster_size { x } else { 0 };
+        writer.set_paddings(
+            padding(cluster.names_index_padding),
+            padding(cluster.data_index_padding),
+            padding(cluster.tag_data_padding),
+        );
+
+        // Index table pages are read as long as they can be. Records start after every page,
+        // index entries help to find records after a corrupted one.
+        let mut pages: Vec<(u64, u64)> = Vec::new();
+        let mut indexed: HashSet<(u64, u64)> = HashSet::new();
+        let mut starts: BTreeSet<u64> = BTreeSet::new();
+        for page in self.index_table_pages(cluster) {
+            let page = match page {
+                Ok(v) => v,
+                Err(_) => break,
+            };
+            let table = &page.index_table;
+            let page_end = table.page_address.saturating_add(table.index_table_size);
+            pages.push((table.page_address, page_end));
+            starts.insert(page_end);
+            if table.index_table_references_size != 0 {
+                let references_start = table
+                    .page_address
+                    .saturating_add(table.index_table_references_offset);
+                let references_end =
+                    references_start.saturating_add(table.index_table_references_size.into());
+                if references_start >= page_end {
+                    pages.push((references_start, references_end));
+                    starts.insert(references_end);
+                }
+            }
+
+            // Duplicate names can't be written, the first one is kept.
+            for name in page.names.names.iter() {
+                let _ = writer.insert_name(name.name, &name.name_string);
+            }
+            for tag in page.tags.tags.iter() {
+                indexed.insert((tag.offset, tag.tag_id));
+                starts.insert(tag.offset);
+            }
+        }
+
+        // Without any readable page, records are expected after the size of the first one.
+        if pages.is_empty() {
+            let mut buf = [0; 8];
+            if self.seek_address(0).is_ok() && self.read_to_buf(&mut buf).is_ok() {
+                let index_table_size = DatabaseReader::read_u64_from_slice(&buf);
+                pages.push((0, index_table_size));
+                starts.insert(index_table_size);
+            }
+        }
+
+        let records = self.scan_records(cluster, &pages, starts);
+
+        // Same tag id can be found more than once, record that index points to is preferred.
+        let mut addresses: HashMap<u64, u64> = HashMap::new();
+        for (address, record) in records.iter() {
+            if indexed.contains(&(*address, record.tag_id))
+                || !addresses.contains_key(&record.tag_id)
+            {
+                addresses.insert(record.tag_id, *address);
+            }
+        }
+        let mut tag_ids: HashMap<u64, u64> = addresses.iter().map(|(k, v)| (*v, *k)).collect();
+
+        // Values without a target can't be kept. Dropping a tag can leave others without one.
+        loop {
+            let dropped: Vec<u64> = tag_ids
+                .iter()
+                .filter(|(address, _)| match &records[*address].tag_data {
+                    TagType::AddressEntry(entry) => !tag_ids.contains_key(&entry.address),
+                    TagType::ValueReference(reference) => !tag_ids.contains_key(&reference.address),
+                    _ => false,
+                })
+                .map(|(address, _)| *address)
+                .collect();
+            if dropped.is_empty() {
+                break;
+            }
+            for address in dropped {
+                if let Some(tag_id) = tag_ids.remove(&address) {
+                    report.dropped_tags.push(tag_id);
+                }
+            }
+        }
+
+        let mut tags: Vec<(u64, u64, Vec<u64>, TagType)> = Vec::with_capacity(tag_ids.len());
+        for (address, record) in records.iter() {
+            let tag_id = match tag_ids.get(address) {
+                Some(v) => *v,
+                None => continue,
+            };
+            if !indexed.contains(&(*address, tag_id)) {
+                report.recovered_tags.push(tag_id);
+            }
+
+            let mut parents: Vec<u64> = Vec::with_capacity(record.tag_parents.array.len());
+            for parent in record.tag_parents.array.iter() {
+                match tag_ids.get(&parent.address) {
+                    Some(v) if !parents.contains(v) => parents.push(*v),
+                    _ => report.dropped_addresses += 1,
+                }
+            }
+            let value = match &record.tag_data {
+                TagType::AddressEntry(entry) => {
+                    let mut entry = *entry;
+                    entry.address = tag_ids[&entry.address];
+                    TagType::AddressEntry(entry)
+                }
+                TagType::AddressList(list) => {
+                    let mut array = Vec::with_capacity(list.array.len());
+                    for entry in list.array.iter() {
+                        match tag_ids.get(&entry.address) {
+                            Some(v) => {
+                                let mut entry = *entry;
+                                entry.address = *v;
+                                array.push(entry);
+                            }
+                            None => report.dropped_addresses += 1,
+                        }
+                    }
+                    TagType::AddressList(AddressList::new(array))
+                }
+                TagType::ValueReference(reference) => {
+                    TagType::ValueReference(ValueReference::new(tag_ids[&reference.address]))
+                }
+                other => other.clone(),
+            };
+            tags.push((tag_id, record.tag_name, parents, value));
+        }
+
+        // Depth is counted through the first parent, which can't lead back to the tag.
+        let mut first_parents: HashMap<u64, u64> = tags
+            .iter()
+            .filter_map(|(tag_id, _, parents, _)| parents.first().map(|x| (*tag_id, *x)))
+            .collect();
+        for (tag_id, _, parents, _) in tags.iter_mut() {
+            let mut visited: HashSet<u64> = HashSet::new();
+            let mut current = *tag_id;
+            while let Some(parent) = first_parents.get(&current) {
+                if *parent == *tag_id {
+                    first_parents.remove(tag_id);
+                    report.dropped_addresses += parents.len() as u64;
+                    parents.clear();
+                    break;
+                }
+                if !visited.insert(*parent) {
+                    break;
+                }
+                current = *parent;
+            }
+        }
+
+        for (tag_id, name, parents, value) in tags {
+            writer.add_tag(tag_id, name, &parents, value)?;
+            report.tags += 1;
+        }
+
+        Ok(writer)
+    }
+
+    // Follow tag_total_size from every start, skipping index table pages and free records.
+    // Record is only accepted when it can be read as a whole and ends inside of the cluster.
+    fn scan_records(
+        &mut self,
+        cluster: &ClusterMetadata,
+        pages: &[(u64, u64)],
+        mut starts: BTreeSet<u64>,
+    ) -> ScannedRecords {
+        let file_end = match self.file_size() {
+            Ok(v) => v,
+            Err(_) => return ScannedRecords::new(),
+        };
+        let mut cluster_end = cluster
+            .cluster_offset
+            .saturating_add(cluster.database_size)
+            .min(file_end);
+        if cluster.next_cluster > cluster.cluster_offset {
+            cluster_end = cluster_end.min(cluster.next_cluster);
+        }
+        let end = cluster_end.saturating_sub(cluster.index_table_offset);
+
+        let mut records = ScannedRecords::new();
+        // Records may not overlap, every accepted one takes it's range.
+        let mut taken: Vec<(u64, u64)> = pages.to_vec();
+        let mut visited: HashSet<u64> = HashSet::new();
+        while let Some(address) = starts.pop_first() {
+            if address >= end
+                || !visited.insert(address)
+                || taken
+                    .iter()
+                    .any(|(start, end)| address >= *start && address < *end)
+            {
+                continue;
+            }
+
+            let (tag_total_size, tag_data_type) = match self.read_record_header(address) {
+                Some(v) => v,
+                None => continue,
+            };
+            let record_end = match address.checked_add(tag_total_size) {
+                Some(v) if tag_total_size >= TAG_DATA_HEADER_SIZE && v <= end => v,
+                _ => continue,
+            };
+            if taken
+                .iter()
+                .any(|(start, end)| address < *end && record_end > *start)
+            {
+                continue;
+            }
+            starts.insert(record_end);
+            if tag_data_type == DATA_TYPE_FREE {
+                taken.push((address, record_end));
+                continue;
+            }
+
+            let mut record = match self.read_tag_data(address) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            if self.read_parents(address, &mut record).is_err() {
+                continue;
+            }
+            taken.push((address, record_end));
+            records.insert(address, record);
+        }
+
+        records
+    }
+
+    // tag_total_size and tag_data_type of the record at the address, also of free records.
+    pub(crate) fn read_record_header(&mut self, address: u64) -> Option<(u64, u8)> {
+        self.seek_address(address).ok()?;
+        let mut buf = [0; 40];
+        self.read_to_buf(&mut buf).ok()?;
+        let tag_total_size = DatabaseReader::read_u64_from_slice(&buf[8..16]);
+        let tag_parents_size = DatabaseReader::read_u64_from_slice(&buf[32..40]);
+        self.seek(tag_parents_size.try_into().ok()?).ok()?;
+        let mut buf = [0; 1];
+        self.read_to_buf(&mut buf).ok()?;
+        Some((tag_total_size, buf[0]))
+    }
+}
@@ -0,0 +1,512 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+use crate::{
+    AddressList, BTag, Combinator, DatabaseError, DatabaseErrorKind, Group, Literal, Property,
+    Query, QueryEntry, SearchResult, StepKind,
+};
+
+impl SearchResult {
+    fn from_paths(paths: Vec<AddressList>) -> SearchResult {
+        if paths.is_empty() {
+            return SearchResult::None;
+        }
+        SearchResult::Found(paths)
+    }
+
+    // Every path of the result.
+    pub fn paths(&self) -> Vec<AddressList> {
+        match self {
+            SearchResult::Found(list) => list.clone(),
+            SearchResult::Match(path) => vec![AddressList::new(path.clone())],
+            SearchResult::None => Vec::new(),
+        }
+    }
+
+    // Results are compared by address of the tag the path leads to.
+    fn target(path: &AddressList) -> Option<u64> {
+        path.array.last().map(|x| x.address)
+    }
+
+    fn targets(&self) -> HashSet<Option<u64>> {
+        self.paths().iter().map(SearchResult::target).collect()
+    }
+
+    // `$peq`, paths of the first result leading to tags present in every result.
+    pub fn partial_equal(results: &[SearchResult]) -> SearchResult {
+        let (first, others) = match results.split_first() {
+            Some(v) => v,
+            None => return SearchResult::None,
+        };
+        let others: Vec<HashSet<Option<u64>>> = others.iter().map(|x| x.targets()).collect();
+
+        let mut seen: HashSet<Option<u64>> = HashSet::new();
+        let paths = first
+            .paths()
+            .into_iter()
+            .filter(|x| {
+                let target = SearchResult::target(x);
+                others.iter().all(|o| o.contains(&target)) && seen.insert(target)
+            })
+            .collect();
+        SearchResult::from_paths(paths)
+    }
+
+    // `$union`, first path leading to every tag present in any result.
+    pub fn union(results: &[SearchResult]) -> SearchResult {
+        let mut seen: HashSet<Option<u64>> = HashSet::new();
+        let paths = results
+            .iter()
+            .flat_map(|x| x.paths())
+            .filter(|x| seen.insert(SearchResult::target(x)))
+            .collect();
+        SearchResult::from_paths(paths)
+    }
+
+    // `$diff`, paths of the first result leading to tags not present in any other result.
+    pub fn difference(results: &[SearchResult]) -> SearchResult {
+        let (first, others) = match results.split_first() {
+            Some(v) => v,
+            None => return SearchResult::None,
+        };
+        let others: Vec<HashSet<Option<u64>>> = others.iter().map(|x| x.targets()).collect();
+
+        let mut seen: HashSet<Option<u64>> = HashSet::new();
+        let paths = first
+            .paths()
+            .into_iter()
+            .filter(|x| {
+                let target = SearchResult::target(x);
+                !others.iter().any(|o| o.contains(&target)) && seen.insert(target)
+            })
+            .collect();
+        SearchResult::from_paths(paths)
+    }
+}
+
+impl Combinator {
+    pub fn apply(&self, results: &[SearchResult]) -> SearchResult {
+        match self {
+            Combinator::PartialEqual => SearchResult::partial_equal(results),
+            Combinator::Union => SearchResult::union(results),
+            Combinator::Difference => SearchResult::difference(results),
+        }
+    }
+}
+
+impl BTag {
+    // Run query in every cluster, i.e. `wallet.euro`, `a.b.c...$%name=b&(..%name=a)`
+    // or `(1..&2..&3..&4..)$peq`.
+    // Query is split into downstream and upstream runs of steps, every run is applied
+    // to tags found by the previous one. First run can't be upstream.
+    // Group results are combined with it's combinator per cluster, union when none is given.
+    // `.-` steps back to the tag matched before the last one, `%name` and `%depth` ending
+    // the query are left to be read from the matches with BTag::property.
+    // Returns cluster index and full path of every match, from the first found tag to the last.
+    pub fn find(&mut self, query: &Query) -> Result<Vec<(u64, AddressList)>, DatabaseError> {
+        let steps = match query.property() {
+            Some(_) => &query.steps[..query.steps.len() - 1],
+            None => &query.steps[..],
+        };
+        let mut results: Option<Vec<(u64, AddressList)>> = None;
+        let mut position = 0;
+
+        if let Some(StepKind::Group(group)) = steps.first().map(|x| &x.kind) {
+            results = Some(self.find_group(group)?);
+            position = 1;
+        }
+
+        while position < steps.len() {
+            if let StepKind::Backtrace = steps[position].kind {
+                results = match results {
+                    Some(v) => Some(BTag::backtrace(v)),
+                    None => return Err(DatabaseErrorKind::UnsupportedQuery.into()),
+                };
+                position += 1;
+                continue;
+            }
+
+            let upstream = steps[position].is_upstream();
+            let mut end = position + 1;
+            while end < steps.len() {
+                if let StepKind::Backtrace = steps[end].kind {
+                    break;
+                }
+                // `..0`, `..name`, `..#id` and `..%name=a` select parents.
+                let selects_parent = matches!(steps[end - 1].kind, StepKind::Parent)
+                    && matches!(
+                        steps[end].kind,
+                        StepKind::Index(_)
+                            | StepKind::Name(_)
+                            | StepKind::Id(_)
+                            | StepKind::Filter(_)
+                    );
+                if steps[end].is_upstream() != upstream && !(upstream && selects_parent) {
+                    break;
+                }
+                end += 1;
+            }
+
+            let run = Query {
+                steps: steps[position..end].to_vec(),
+                nth: None,
+                span: query.span,
+            };
+            results = match results {
+                Some(results) => Some(self.find_from(results, &run, upstream)?),
+                None if upstream => return Err(DatabaseErrorKind::UnsupportedQuery.into()),
+                None => Some(self.find_downstream(&run)?),
+            };
+            position = end;
+        }
+
+        let results = results.unwrap_or_default();
+        if let Some(nth) = query.nth {
+            return Ok(results.into_iter().nth(nth as usize).into_iter().collect());
+        }
+        Ok(results)
+    }
+
+    // `.-`, drop the last tag of every path. Paths of a single tag have nothing to step back to,
+    // every tag is returned once.
+    fn backtrace(results: Vec<(u64, AddressList)>) -> Vec<(u64, AddressList)> {
+        let mut seen: HashSet<(u64, u64)> = HashSet::new();
+        results
+            .into_iter()
+            .filter_map(|(cluster_index, mut path)| {
+                path.array.pop();
+                let target = SearchResult::target(&path)?;
+                seen.insert((cluster_index, target))
+                    .then_some((cluster_index, path))
+            })
+            .collect()
+    }
+
+    // Property of the tag at the address, `%name` is it's name string and `%depth` it's tag_depth.
+    pub fn property(
+        &self,
+        cluster_index: u64,
+        address: u64,
+        property: Property,
+    ) -> Result<Literal, DatabaseError> {
+        let tag = match self.tag_at(cluster_index, address) {
+            Some(v) => v,
+            None => {
+                return Err(DatabaseError::from(DatabaseErrorKind::TagMissing)
+                    .with_cluster_index(cluster_index))
+            }
+        };
+        match property {
+            Property::Name => match self.name_string(cluster_index, tag.name) {
+                Some(v) => Ok(Literal::Text(v.to_string())),
+                None => Err(DatabaseError::from(DatabaseErrorKind::TagValidity)
+                    .with_cluster_index(cluster_index)),
+            },
+            Property::Depth => Ok(Literal::Integer(tag.depth)),
+        }
+    }
+
+    fn find_group(&mut self, group: &Group) -> Result<Vec<(u64, AddressList)>, DatabaseError> {
+        let mut group_results: Vec<Vec<(u64, AddressList)>> = Vec::new();
+        for sub_query in group.queries.iter() {
+            group_results.push(self.find(sub_query)?);
+        }
+
+        let combinator = group.combinator.unwrap_or(Combinator::Union);
+        let mut results: Vec<(u64, AddressList)> = Vec::new();
+        for cluster in self.clusters.iter() {
+            let cluster_results: Vec<SearchResult> = group_results
+                .iter()
+                .map(|x| {
+                    SearchResult::from_paths(
+                        x.iter()
+                            .filter(|(i, _)| *i == cluster.cluster_index)
+                            .map(|(_, path)| path.clone())
+                            .collect(),
+                    )
+                })
+                .collect();
+            results.extend(
+                combinator
+                    .apply(&cluster_results)
+                    .paths()
+                    .into_iter()
+                    .map(|x| (cluster.cluster_index, x)),
+            );
+        }
+
+        if let Some(nth) = group.nth {
+            return Ok(results.into_iter().nth(nth as usize).into_iter().collect());
+        }
+        Ok(results)
+    }
+
+    // Apply steps to the last tag of every path, extending the paths with new matches.
+    fn find_from(
+        &mut self,
+        results: Vec<(u64, AddressList)>,
+        query: &Query,
+        upstream: bool,
+    ) -> Result<Vec<(u64, AddressList)>, DatabaseError> {
+        let names = query.names();
+        let mut cluster_entries: HashMap<u64, Option<Vec<QueryEntry>>> = HashMap::new();
+        let mut extended: Vec<(u64, AddressList)> = Vec::new();
+
+        for (cluster_index, path) in results {
+            if let Entry::Vacant(slot) = cluster_entries.entry(cluster_index) {
+                let registry = match self.name_registries.get(&cluster_index) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                // Names missing in the cluster can't match anything.
+                let entries = if names.iter().all(|x| registry.id(x).is_some()) {
+                    let resolve = |x: &str| registry.id(x);
+                    let entries = if upstream {
+                        query.to_relative_upstream_entries(resolve)
+                    } else {
+                        query.to_child_entries(resolve)
+                    };
+                    match entries {
+                        Ok(v) => Some(v),
+                        Err(_) => return Err(DatabaseErrorKind::UnsupportedQuery.into()),
+                    }
+                } else {
+                    None
+                };
+                slot.insert(entries);
+            }
+            let entries = match &cluster_entries[&cluster_index] {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let tag = match path.array.last() {
+                Some(v) => *v,
+                None => continue,
+            };
+            let reader = match self.reader(cluster_index) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let found = if upstream {
+                let tag_data = reader.read_tag_data(tag.address)?;
+                // Parents follow the tag.
+                reader
+                    .find_upstream(entries, tag.address, &tag_data)?
+                    .paths()
+            } else {
+                // Children paths start with the tag itself.
+                reader
+                    .find_downstream_from(entries, tag)?
+                    .paths()
+                    .into_iter()
+                    .map(|x| AddressList::new(x.array[1..].to_vec()))
+                    .collect()
+            };
+            for found_path in found {
+                let mut array = path.array.clone();
+                array.extend(found_path.array);
+                extended.push((cluster_index, AddressList::new(array)));
+            }
+        }
+
+        Ok(extended)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::{
+        parse_query, AddressEntry, AddressList, BTag, DatabaseWriter, Literal, Query, Statement,
+        TagType, TextEncoding,
+    };
+
+    // Database file removed once the test ends.
+    struct TestFile(PathBuf);
+
+    impl Drop for TestFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    type Tag<'a> = (u64, &'a str, &'a [u64], Option<TagType>);
+
+    // users.jason.wallet.euro #3 is linked by admins, both wallets hold euro.
+    fn users(name: &str) -> (TestFile, BTag) {
+        open(
+            name,
+            &[
+                (0, "users", &[], None),
+                (1, "jason", &[0], None),
+                (2, "wallet", &[1], None),
+                (3, "euro", &[2, 8], Some(TagType::Float(12.0))),
+                (4, "dollar", &[2], Some(TagType::Double(40.5))),
+                (5, "joey", &[0], None),
+                (6, "wallet", &[5], None),
+                (7, "euro", &[6], Some(TagType::Integer(1200))),
+                (8, "admins", &[], None),
+            ],
+        )
+    }
+
+    // Tags without a value list their children.
+    fn open(name: &str, tags: &[Tag]) -> (TestFile, BTag) {
+        let mut writer = DatabaseWriter::new(0, TextEncoding::Utf8);
+        for (tag_id, name_string, parents, value) in tags.iter() {
+            let value = match value {
+                Some(v) => v.clone(),
+                None => TagType::AddressList(AddressList::new(
+                    tags.iter()
+                        .filter(|x| x.2.contains(tag_id))
+                        .map(|x| AddressEntry::new(writer.add_name(x.1).unwrap(), x.0))
+                        .collect(),
+                )),
+            };
+            let name = writer.add_name(name_string).unwrap();
+            writer.add_tag(*tag_id, name, parents, value).unwrap();
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("btag-search-{}-{}.btag", name, std::process::id()));
+        DatabaseWriter::write_clusters(&path, &[&writer]).unwrap();
+        let btag = BTag::open(&path).unwrap();
+        (TestFile(path), btag)
+    }
+
+    fn query(source: &str) -> Query {
+        match parse_query(source).unwrap().remove(0) {
+            Statement::Get(v) => v,
+            v => panic!("expected query, found {:?}", v),
+        }
+    }
+
+    // Tag ids of the last tag of every match.
+    fn find_ids(btag: &mut BTag, source: &str) -> Vec<u64> {
+        btag.find(&query(source))
+            .unwrap()
+            .into_iter()
+            .map(|(cluster_index, path)| {
+                let address = path.array().last().unwrap().address();
+                btag.tag_at(cluster_index, address).unwrap().tag_id()
+            })
+            .collect()
+    }
+
+    fn properties(btag: &mut BTag, source: &str) -> Vec<Literal> {
+        let query = query(source);
+        let property = query.property().unwrap();
+        btag.find(&query)
+            .unwrap()
+            .into_iter()
+            .map(|(cluster_index, path)| {
+                let address = path.array().last().unwrap().address();
+                btag.property(cluster_index, address, property).unwrap()
+            })
+            .collect()
+    }
+
+    fn text(v: &str) -> Literal {
+        Literal::Text(v.to_string())
+    }
+
+    #[test]
+    fn properties_are_read_from_matches() {
+        let (_file, mut btag) = users("properties");
+
+        // Name of every parent of wallet, only first parent of euro is processed.
+        assert_eq!(
+            properties(&mut btag, "wallet.euro..0..%name"),
+            vec![text("jason"), text("joey")]
+        );
+        assert_eq!(
+            properties(&mut btag, "users.jason.wallet.euro..%name"),
+            vec![text("wallet"), text("admins")]
+        );
+        assert_eq!(
+            properties(&mut btag, "wallet.euro..0..0.%name"),
+            vec![text("jason"), text("joey")]
+        );
+        assert_eq!(
+            properties(&mut btag, "users.%depth"),
+            vec![Literal::Integer(0)]
+        );
+        assert_eq!(
+            properties(&mut btag, "users.*.wallet.*.%depth"),
+            vec![Literal::Integer(3); 3]
+        );
+
+        // Filters select children as well as parents.
+        assert_eq!(find_ids(&mut btag, "users.%name=joey"), vec![5]);
+        assert_eq!(find_ids(&mut btag, "users.*.%depth=2"), vec![2, 6]);
+    }
+
+    #[test]
+    fn backtrace_steps_back_to_the_previous_match() {
+        let (_file, mut btag) = users("backtrace");
+
+        // Every tag is returned once.
+        assert_eq!(find_ids(&mut btag, "wallet.euro.-"), vec![2, 6]);
+        assert_eq!(find_ids(&mut btag, "wallet.*.-"), vec![2, 6]);
+        assert_eq!(find_ids(&mut btag, "users.*.wallet.euro.-.-"), vec![1, 5]);
+        assert_eq!(find_ids(&mut btag, "wallet.euro.-.dollar"), vec![4]);
+        assert_eq!(find_ids(&mut btag, "euro..0.-"), vec![3, 7]);
+        assert!(find_ids(&mut btag, "users.-").is_empty());
+    }
+
+    #[test]
+    fn group_results_are_combined() {
+        // Digits of "Request node that has all children present" of docs/specification.md.
+        let (_file, mut btag) = open(
+            "groups",
+            &[
+                (0, "numbers", &[], None),
+                (1, "1", &[0, 5, 7], Some(TagType::Integer(1))),
+                (2, "2", &[0, 6, 7], Some(TagType::Integer(2))),
+                (3, "3", &[0, 5, 7], Some(TagType::Integer(3))),
+                (4, "4", &[0, 6, 7], Some(TagType::Integer(4))),
+                (5, "odd_digits", &[], None),
+                (6, "even_digits", &[], None),
+                (7, "ints", &[], None),
+            ],
+        );
+
+        assert_eq!(find_ids(&mut btag, "(1..&2..&3..&4..)$peq"), vec![0, 7]);
+        assert_eq!(find_ids(&mut btag, "(1..&3..)$peq"), vec![0, 5, 7]);
+        assert_eq!(find_ids(&mut btag, "(1..&2..)$union"), vec![0, 5, 7, 6]);
+        assert_eq!(find_ids(&mut btag, "(1..&2..)"), vec![0, 5, 7, 6]);
+        assert_eq!(find_ids(&mut btag, "(1..&2..)$diff"), vec![5]);
+        assert_eq!(find_ids(&mut btag, "(2..&1..&3..)$diff"), vec![6]);
+        assert!(find_ids(&mut btag, "(1..&3..)$diff").is_empty());
+    }
+
+    #[test]
+    fn ancestors_are_matched_by_condition() {
+        // a.b.c, x.b.->c and y.a.b.->c of "Request conditional name matches with many name
+        // duplicates, yet different hierarchy" of docs/specification.md.
+        let (_file, mut btag) = open(
+            "ancestors",
+            &[
+                (0, "a", &[], None),
+                (1, "b", &[0], None),
+                (2, "c", &[1, 4, 7], Some(TagType::Integer(1))),
+                (3, "x", &[], None),
+                (4, "b", &[3], None),
+                (5, "y", &[], None),
+                (6, "a", &[5], None),
+                (7, "b", &[6], None),
+            ],
+        );
+
+        assert_eq!(find_ids(&mut btag, "a.b.c"), vec![2, 2]);
+        // Both paths of a.b.c are searched upstream, every one of them reaches a.b and y.a.b.
+        assert_eq!(
+            find_ids(&mut btag, "a.b.c...$%name=b&(..%name=a)"),
+            vec![1, 7, 1, 7]
+        );
+        assert_eq!(find_ids(&mut btag, "c...$%name=b"), vec![1, 4, 7]);
+        assert_eq!(find_ids(&mut btag, "c...$%name=a"), vec![0, 6]);
+    }
+}
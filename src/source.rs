@@ -0,0 +1,611 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+};
+
+use crate::{
+    query::{tokenize, Parser, Token},
+    AddressEntry, AddressList, DatabaseError, DatabaseWriter, Query, QueryParseError, Span,
+    StepKind, TagType, TextEncoding, Value, ValueReference,
+};
+
+// Error of a source line, span is in bytes of the line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceError {
+    pub message: String,
+    // Line number, from 1.
+    pub line: usize,
+    pub span: Span,
+}
+
+impl SourceError {
+    fn new(message: impl Into<String>, line: usize, span: Span) -> Self {
+        SourceError {
+            message: message.into(),
+            line,
+            span,
+        }
+    }
+
+    fn parse(error: QueryParseError, line: usize) -> Self {
+        // Every statement takes a single line.
+        let message = error.message.replace("end of query", "end of line");
+        SourceError::new(message, line, error.span)
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.message,
+            self.line,
+            self.span.start + 1
+        )
+    }
+}
+
+impl Error for SourceError {}
+
+// Tag declared by the source. Parents and entries are positions in SourceCompiler::tags.
+struct SourceTag {
+    name: String,
+    tag_id: Option<u64>,
+    parents: Vec<usize>,
+    entries: Vec<(String, usize)>,
+    value: Option<SourceValue>,
+    // Where the tag has been declared.
+    line: usize,
+    span: Span,
+}
+
+enum SourceValue {
+    Scalar(TagType),
+    // `#16`, value of another tag.
+    Copy(Query),
+    // `&#87`
+    Reference(Query),
+    // `-> #87`
+    Address(Query),
+}
+
+#[derive(Default)]
+struct SourceCompiler {
+    tags: Vec<SourceTag>,
+    roots: Vec<usize>,
+    // Tags with id given by the source.
+    ids: HashMap<u64, usize>,
+    cluster_index: Option<u64>,
+    encoding: Option<TextEncoding>,
+    paddings: Option<(u32, u32, u32)>,
+}
+
+// Compile source into a cluster, reference "Source format" of docs/specification.md.
+pub fn compile_source(source: &str) -> Result<DatabaseWriter, SourceError> {
+    let mut compiler = SourceCompiler::default();
+    for (i, line) in source.lines().enumerate() {
+        compiler.line(strip_comment(line), i + 1)?;
+    }
+    compiler.finish()
+}
+
+// Line without `//` comment, which may not start inside of a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '/' if !in_string && previous == '/' => return &line[..i - 1],
+            _ => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+fn convert(value: TagType, data_type: &str) -> Option<TagType> {
+    Some(match (data_type.to_ascii_lowercase().as_str(), value) {
+        ("integer", TagType::Integer(v)) => TagType::Integer(v),
+        ("float", TagType::Integer(v)) => TagType::Float(v as f32),
+        ("float", TagType::Double(v)) => TagType::Float(v as f32),
+        ("float", TagType::Float(v)) => TagType::Float(v),
+        ("double", TagType::Integer(v)) => TagType::Double(v as f64),
+        ("double", TagType::Double(v)) => TagType::Double(v),
+        ("text", TagType::Text(v)) => TagType::Text(v),
+        ("char", TagType::Text(v) | TagType::Char(v)) => TagType::Char(v),
+        _ => return None,
+    })
+}
+
+impl SourceCompiler {
+    fn line(&mut self, text: &str, line: usize) -> Result<(), SourceError> {
+        if text.trim().is_empty() {
+            return Ok(());
+        }
+        let mut parser = Parser {
+            tokens: tokenize(text).map_err(|e| SourceError::parse(e, line))?,
+            position: 0,
+        };
+        if self.directive(&mut parser, line)? {
+            return Ok(());
+        }
+
+        let start = parser.span();
+        let tag = self.target(&mut parser, line)?;
+
+        // `users.joey #5`
+        if parser.eat(&Token::Hash) {
+            let tag_id = parser.number().map_err(|e| SourceError::parse(e, line))?;
+            let span = start.to(parser.previous_span());
+            match self.ids.get(&tag_id) {
+                Some(v) if *v != tag => {
+                    let message = format!("#{} is already declared", tag_id);
+                    return Err(SourceError::new(message, line, span));
+                }
+                _ => {}
+            }
+            if let Some(v) = self.tags[tag].tag_id.filter(|x| *x != tag_id) {
+                let message = format!("`{}` is already #{}", self.path(tag), v);
+                return Err(SourceError::new(message, line, span));
+            }
+            self.tags[tag].tag_id = Some(tag_id);
+            self.ids.insert(tag_id, tag);
+        }
+
+        if parser.eat(&Token::Eq) {
+            let value = self.value(&mut parser, line)?;
+            let span = start.to(parser.previous_span());
+            if !self.tags[tag].entries.is_empty() {
+                let message = format!("`{}` has entries, it can't have a value", self.path(tag));
+                return Err(SourceError::new(message, line, span));
+            }
+            if self.tags[tag].value.is_some() {
+                let message = format!("value of `{}` is already set", self.path(tag));
+                return Err(SourceError::new(message, line, span));
+            }
+            self.tags[tag].value = Some(value);
+        }
+
+        if parser.peek() != &Token::Eof {
+            let error = parser.unexpected("`#`, `=` or end of line");
+            return Err(SourceError::parse(error, line));
+        }
+
+        Ok(())
+    }
+
+    // `CLUSTER 3`, `ENCODING utf16le`, `PADDING 2 4 8`
+    fn directive(&mut self, parser: &mut Parser, line: usize) -> Result<bool, SourceError> {
+        let keyword = match (parser.peek(), parser.peek_at(1)) {
+            (Token::Ident(v), Token::Number(_) | Token::Ident(_))
+                if ["CLUSTER", "ENCODING", "PADDING"].contains(&v.as_str()) =>
+            {
+                v.clone()
+            }
+            _ => return Ok(false),
+        };
+        let start = parser.advance().1;
+        let number = |parser: &mut Parser| -> Result<u64, SourceError> {
+            parser.number().map_err(|e| SourceError::parse(e, line))
+        };
+        let padding = |parser: &mut Parser| -> Result<u32, SourceError> {
+            let span = parser.span();
+            match u32::try_from(number(parser)?) {
+                Ok(v) => Ok(v),
+                Err(_) => Err(SourceError::new("padding is too big", line, span)),
+            }
+        };
+
+        let is_set = match keyword.as_str() {
+            "CLUSTER" => self.cluster_index.replace(number(parser)?).is_some(),
+            "ENCODING" => {
+                let (token, span) = parser.advance();
+                let encoding = match token {
+                    Token::Ident(v) => match v.to_ascii_lowercase().as_str() {
+                        "utf8" => TextEncoding::Utf8,
+                        "utf16le" => TextEncoding::Utf16Le,
+                        "latin1" => TextEncoding::Latin1,
+                        _ => {
+                            let message = format!("unsupported text encoding `{}`", v);
+                            return Err(SourceError::new(message, line, span));
+                        }
+                    },
+                    _ => {
+                        let error = QueryParseError::new("expected text encoding", span);
+                        return Err(SourceError::parse(error, line));
+                    }
+                };
+                self.encoding.replace(encoding).is_some()
+            }
+            _ => {
+                let paddings = (padding(parser)?, padding(parser)?, padding(parser)?);
+                self.paddings.replace(paddings).is_some()
+            }
+        };
+        let span = start.to(parser.previous_span());
+        if is_set {
+            return Err(SourceError::new(
+                format!("{} is already set", keyword),
+                line,
+                span,
+            ));
+        }
+        if parser.peek() != &Token::Eof {
+            return Err(SourceError::parse(parser.unexpected("end of line"), line));
+        }
+
+        Ok(true)
+    }
+
+    // `users.joey.wallet`, `#87.wallet`, `admins.#11`. Missing tags are declared,
+    // `#<id>` after the first name puts an already declared tag into the list.
+    fn target(&mut self, parser: &mut Parser, line: usize) -> Result<usize, SourceError> {
+        let mut current: Option<usize> = None;
+        loop {
+            let (token, span) = parser.advance();
+            let tag = match token {
+                Token::Ident(v) | Token::Str(v) | Token::Number(v) => {
+                    self.child(current, v, line, span)?
+                }
+                Token::Hash => {
+                    let tag_id = parser.number().map_err(|e| SourceError::parse(e, line))?;
+                    let span = span.to(parser.previous_span());
+                    let tag = match self.ids.get(&tag_id) {
+                        Some(v) => *v,
+                        None => {
+                            let message = format!("#{} isn't declared yet", tag_id);
+                            return Err(SourceError::new(message, line, span));
+                        }
+                    };
+                    match current {
+                        Some(list) => self.link(list, tag, line, span)?,
+                        None => tag,
+                    }
+                }
+                _ => {
+                    parser.position -= 1;
+                    return Err(SourceError::parse(parser.unexpected("name or `#`"), line));
+                }
+            };
+            current = Some(tag);
+            if !parser.eat(&Token::Dot) {
+                return Ok(tag);
+            }
+        }
+    }
+
+    // Entry of the list with the name, or root tag without list. Declared when missing.
+    fn child(
+        &mut self,
+        list: Option<usize>,
+        name: String,
+        line: usize,
+        span: Span,
+    ) -> Result<usize, SourceError> {
+        let existing = match list {
+            Some(v) => self.tags[v]
+                .entries
+                .iter()
+                .find(|(x, _)| *x == name)
+                .map(|(_, x)| *x),
+            None => self
+                .roots
+                .iter()
+                .copied()
+                .find(|x| self.tags[*x].name == name),
+        };
+        if let Some(v) = existing {
+            return Ok(v);
+        }
+        if let Some(v) = list.filter(|x| self.tags[*x].value.is_some()) {
+            let message = format!("`{}` has a value, it can't have entries", self.path(v));
+            return Err(SourceError::new(message, line, span));
+        }
+
+        let tag = self.tags.len();
+        self.tags.push(SourceTag {
+            name: name.clone(),
+            tag_id: None,
+            parents: list.into_iter().collect(),
+            entries: Vec::new(),
+            value: None,
+            line,
+            span,
+        });
+        match list {
+            Some(v) => self.tags[v].entries.push((name, tag)),
+            None => self.roots.push(tag),
+        }
+        Ok(tag)
+    }
+
+    // Put the tag into the list, which becomes one more parent of the tag.
+    fn link(
+        &mut self,
+        list: usize,
+        tag: usize,
+        line: usize,
+        span: Span,
+    ) -> Result<usize, SourceError> {
+        if self.tags[list].value.is_some() {
+            let message = format!("`{}` has a value, it can't have entries", self.path(list));
+            return Err(SourceError::new(message, line, span));
+        }
+        if !self.tags[list].entries.iter().any(|(_, x)| *x == tag) {
+            let name = self.tags[tag].name.clone();
+            self.tags[list].entries.push((name, tag));
+        }
+        if !self.tags[tag].parents.contains(&list) {
+            self.tags[tag].parents.push(list);
+        }
+        Ok(tag)
+    }
+
+    // Value of Set statement, `-> <path>` or `<value> : <type>`.
+    fn value(&mut self, parser: &mut Parser, line: usize) -> Result<SourceValue, SourceError> {
+        if parser.peek() == &Token::Minus && parser.peek_at(1) == &Token::Greater {
+            parser.advance();
+            parser.advance();
+            let query = parser.query().map_err(|e| SourceError::parse(e, line))?;
+            return Ok(SourceValue::Address(query));
+        }
+
+        let start = parser.span();
+        let value = match parser.value().map_err(|e| SourceError::parse(e, line))? {
+            Value::Integer(v) => TagType::Integer(v),
+            Value::Float(v) => TagType::Float(v),
+            Value::Double(v) => TagType::Double(v),
+            Value::Text(v) => TagType::Text(v),
+            Value::Char(v) => TagType::Char(v),
+            Value::Copy(query) => return Ok(SourceValue::Copy(query)),
+            Value::Reference(query) => return Ok(SourceValue::Reference(query)),
+        };
+        if !parser.eat(&Token::Colon) {
+            return Ok(SourceValue::Scalar(value));
+        }
+
+        let (token, span) = parser.advance();
+        let data_type = match token {
+            Token::Ident(v) => v,
+            _ => {
+                parser.position -= 1;
+                return Err(SourceError::parse(parser.unexpected("type"), line));
+            }
+        };
+        let known = ["integer", "float", "double", "text", "char"];
+        if !known.contains(&data_type.to_ascii_lowercase().as_str()) {
+            return Err(SourceError::new(
+                format!("unknown type {}", data_type),
+                line,
+                span,
+            ));
+        }
+        match convert(value, &data_type) {
+            Some(v) => Ok(SourceValue::Scalar(v)),
+            None => Err(SourceError::new(
+                format!("value can't be used as {}", data_type),
+                line,
+                start.to(span),
+            )),
+        }
+    }
+
+    // `users.joey.wallet`, through the first parents.
+    fn path(&self, tag: usize) -> String {
+        let mut names: Vec<&str> = Vec::new();
+        let mut visited: HashSet<usize> = HashSet::new();
+        let mut current = Some(tag);
+        while let Some(v) = current.filter(|x| visited.insert(*x)) {
+            names.push(&self.tags[v].name);
+            current = self.tags[v].parents.first().copied();
+        }
+        names.reverse();
+        names.join(".")
+    }
+
+    // Tag at the path of `&<path>`, `-> <path>` or copied value.
+    fn find(&self, query: &Query, line: usize) -> Result<usize, SourceError> {
+        let unsupported = || {
+            SourceError::new(
+                "only names and `#<id>` can be used in the path",
+                line,
+                query.span,
+            )
+        };
+        if query.nth.is_some() {
+            return Err(unsupported());
+        }
+
+        let mut current: Option<usize> = None;
+        for step in query.steps.iter() {
+            let name = match &step.kind {
+                StepKind::Name(v) => v.clone(),
+                StepKind::Index(v) => v.to_string(),
+                StepKind::Id(v) => {
+                    let tag = self.ids.get(v).copied();
+                    current = match current {
+                        None => tag,
+                        Some(list) => {
+                            tag.filter(|x| self.tags[list].entries.iter().any(|(_, v)| v == x))
+                        }
+                    };
+                    if current.is_none() {
+                        break;
+                    }
+                    continue;
+                }
+                _ => return Err(unsupported()),
+            };
+            current = match current {
+                Some(list) => self.tags[list]
+                    .entries
+                    .iter()
+                    .find(|(x, _)| *x == name)
+                    .map(|(_, x)| *x),
+                None => self
+                    .roots
+                    .iter()
+                    .copied()
+                    .find(|x| self.tags[*x].name == name),
+            };
+            if current.is_none() {
+                break;
+            }
+        }
+
+        match current {
+            Some(v) => Ok(v),
+            None => Err(SourceError::new("no tag at the path", line, query.span)),
+        }
+    }
+
+    // Value copied from the tag, following copies of copies.
+    fn copy(&self, tag: usize, line: usize, span: Span) -> Result<&SourceValue, SourceError> {
+        let mut visited: HashSet<usize> = HashSet::new();
+        let mut current = tag;
+        loop {
+            if !visited.insert(current) {
+                return Err(SourceError::new("values are copied in a cycle", line, span));
+            }
+            match &self.tags[current].value {
+                Some(SourceValue::Copy(query)) => current = self.find(query, line)?,
+                Some(v) => return Ok(v),
+                None => {
+                    let message = format!("entries of `{}` can't be copied", self.path(current));
+                    return Err(SourceError::new(message, line, span));
+                }
+            }
+        }
+    }
+
+    // Value references may chain, but not lead back to a tag of the chain.
+    fn check_references(&self) -> Result<(), SourceError> {
+        for (i, tag) in self.tags.iter().enumerate() {
+            let mut visited: HashSet<usize> = HashSet::from([i]);
+            let mut current = i;
+            while let Some(SourceValue::Reference(query)) = &self.tags[current].value {
+                current = self.find(query, tag.line)?;
+                if !visited.insert(current) {
+                    let message = format!("references of `{}` form a cycle", self.path(i));
+                    return Err(SourceError::new(message, tag.line, tag.span));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<DatabaseWriter, SourceError> {
+        self.check_references()?;
+
+        // Tags without id get the smallest one that isn't taken, in order of declaration.
+        let mut tag_ids: Vec<u64> = Vec::with_capacity(self.tags.len());
+        let mut next_tag_id: u64 = 0;
+        for tag in self.tags.iter() {
+            match tag.tag_id {
+                Some(v) => tag_ids.push(v),
+                None => {
+                    while self.ids.contains_key(&next_tag_id) {
+                        next_tag_id += 1;
+                    }
+                    tag_ids.push(next_tag_id);
+                    next_tag_id += 1;
+                }
+            }
+        }
+
+        let mut writer = DatabaseWriter::new(
+            self.cluster_index.unwrap_or(0),
+            self.encoding.unwrap_or(TextEncoding::Utf8),
+        );
+        if let Some((names, tags, data)) = self.paddings {
+            writer.set_paddings(names, tags, data);
+        }
+
+        for (i, tag) in self.tags.iter().enumerate() {
+            let error = |e: DatabaseError| SourceError::new(e.to_string(), tag.line, tag.span);
+            let value = match &tag.value {
+                None => {
+                    let mut array: Vec<AddressEntry> = Vec::with_capacity(tag.entries.len());
+                    for (name, entry) in tag.entries.iter() {
+                        let name = writer.add_name(name).map_err(error)?;
+                        array.push(AddressEntry::new(name, tag_ids[*entry]));
+                    }
+                    TagType::AddressList(AddressList::new(array))
+                }
+                Some(value) => {
+                    let value = match value {
+                        SourceValue::Copy(query) => {
+                            let source = self.find(query, tag.line)?;
+                            self.copy(source, tag.line, query.span)?
+                        }
+                        other => other,
+                    };
+                    match value {
+                        SourceValue::Scalar(v) => v.clone(),
+                        SourceValue::Reference(query) => {
+                            let target = self.find(query, tag.line)?;
+                            TagType::ValueReference(ValueReference::new(tag_ids[target]))
+                        }
+                        SourceValue::Address(query) => {
+                            let target = self.find(query, tag.line)?;
+                            let name = writer.add_name(&self.tags[target].name).map_err(error)?;
+                            TagType::AddressEntry(AddressEntry::new(name, tag_ids[target]))
+                        }
+                        // Copies are followed by copy.
+                        SourceValue::Copy(query) => {
+                            let message = "values are copied in a cycle";
+                            return Err(SourceError::new(message, tag.line, query.span));
+                        }
+                    }
+                }
+            };
+            let parents: Vec<u64> = tag.parents.iter().map(|x| tag_ids[*x]).collect();
+            let name = writer.add_name(&tag.name).map_err(error)?;
+            writer
+                .add_tag(tag_ids[i], name, &parents, value)
+                .map_err(error)?;
+        }
+
+        Ok(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_are_stripped_outside_of_strings() {
+        assert_eq!(strip_comment("a.b = 1 // one"), "a.b = 1 ");
+        assert_eq!(strip_comment("// whole line"), "");
+        assert_eq!(strip_comment("a = \"http://x\""), "a = \"http://x\"");
+        assert_eq!(strip_comment("a = \"\\\"//\" // quote"), "a = \"\\\"//\" ");
+        assert_eq!(strip_comment("a = 4 / 2"), "a = 4 / 2");
+    }
+
+    #[test]
+    fn values_convert_to_declared_type() {
+        assert_eq!(
+            convert(TagType::Integer(12), "FLOAT"),
+            Some(TagType::Float(12.0))
+        );
+        assert_eq!(
+            convert(TagType::Integer(12), "double"),
+            Some(TagType::Double(12.0))
+        );
+        assert_eq!(
+            convert(TagType::Text("jo".to_string()), "Char"),
+            Some(TagType::Char("jo".to_string()))
+        );
+        // Fractions and strings don't turn into integers.
+        assert_eq!(convert(TagType::Double(1.5), "integer"), None);
+        assert_eq!(convert(TagType::Text("1".to_string()), "integer"), None);
+        assert_eq!(convert(TagType::Integer(1), "text"), None);
+    }
+}
@@ -0,0 +1,436 @@
+use crate::{
+    AddressEntry, AddressList, BTag, DatabaseError, DatabaseErrorKind, DatabaseWriter, IndexTable,
+    TagIndex, TagType, DATA_TYPE_FREE,
+};
+
+// Size of tag_id, tag_total_size, tag_name, tag_depth and tag_parents_size.
+const TAG_HEADER_SIZE: u64 = 40;
+
+impl BTag {
+    // Insert new tag at the end of the cluster. Parents are tag ids of tags in the same cluster,
+    // addresses in the value are addresses in the cluster.
+    // Tag is added to values of parents that are AddressList, so it can be found downstream.
+    // Index entry and new name are written into a new index table page.
+    // Returns address of the new tag.
+    pub fn insert_tag(
+        &mut self,
+        cluster_index: u64,
+        tag_id: u64,
+        name_string: &str,
+        parents: &[u64],
+        value: TagType,
+    ) -> Result<u64, DatabaseError> {
+        self.journaled(
+            &format!(
+                "INSERT #{} {:?} = {} INTO {} OF CLUSTER {}",
+                tag_id,
+                name_string,
+                value,
+                parents
+                    .iter()
+                    .map(|x| format!("#{}", x))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                cluster_index
+            ),
+            |btag| btag.write_tag(cluster_index, tag_id, name_string, parents, value),
+        )
+    }
+
+    // Delete tag, marking it's record as free and removing it's index entry.
+    // Tag is removed from values of it's parents that are AddressList.
+    // Tags with children or referenced by other tags can't be deleted.
+    pub fn delete_tag(&mut self, tag_id: u64) -> Result<(), DatabaseError> {
+        self.journaled(&format!("DELETE #{}", tag_id), |btag| {
+            btag.remove_tag(tag_id)
+        })
+    }
+
+    fn write_tag(
+        &mut self,
+        cluster_index: u64,
+        tag_id: u64,
+        name_string: &str,
+        parents: &[u64],
+        value: TagType,
+    ) -> Result<u64, DatabaseError> {
+        if let Some((other_cluster, other)) = self.locate_tag(tag_id) {
+            return Err(self.error_at(
+                DatabaseErrorKind::TagDuplicateId,
+                other_cluster,
+                other.offset,
+            ));
+        }
+        if self.reader(cluster_index).is_none() {
+            return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index));
+        }
+
+        // Parents must be in the same cluster, depth and full_path follow the first one.
+        let mut parent_entries: Vec<AddressEntry> = Vec::with_capacity(parents.len());
+        let mut full_path: Vec<u64> = Vec::new();
+        for (i, parent_id) in parents.iter().enumerate() {
+            let parent = match self.locate_tag(*parent_id) {
+                Some((parent_cluster, parent)) if parent_cluster == cluster_index => parent,
+                Some((parent_cluster, parent)) => {
+                    return Err(self.error_at(
+                        DatabaseErrorKind::TagValidity,
+                        parent_cluster,
+                        parent.offset,
+                    ))
+                }
+                None => {
+                    return Err(self.cluster_error(DatabaseErrorKind::TagValidity, cluster_index))
+                }
+            };
+            if i == 0 {
+                full_path = parent.full_path.clone();
+            }
+            parent_entries.push(AddressEntry {
+                name: parent.name,
+                address: parent.offset,
+            });
+        }
+
+        let names = match self.name_registries.get_mut(&cluster_index) {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let new_name = names.id(name_string).is_none();
+        let name = names.allocate(name_string)?;
+        full_path.push(name);
+        let depth = full_path.len() as u64 - 1;
+
+        let cluster = match self
+            .clusters
+            .iter()
+            .find(|x| x.cluster_index == cluster_index)
+        {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let (tag_data_type, data) = DatabaseWriter::encode_tag_data(&value, cluster.encoding)?;
+        let record = DatabaseWriter::encode_tag_record(
+            tag_id,
+            name,
+            depth,
+            &parent_entries,
+            tag_data_type,
+            &data,
+            cluster.tag_data_padding,
+        );
+
+        // New index table page follows the record.
+        let mut names_section: Vec<u8> = Vec::new();
+        if new_name {
+            if let Some(entry) = names.names().last() {
+                names_section = DatabaseWriter::encode_name_entry(
+                    entry,
+                    cluster.encoding,
+                    cluster.names_index_padding,
+                )?;
+            }
+        }
+        let header_size = cluster.index_table_header_size();
+        let version = cluster.version;
+        let data_index_padding = cluster.data_index_padding;
+
+        let address = self.append_to_cluster(cluster_index, &record)?;
+        let tag_index = TagIndex::new(tag_id, name, depth, full_path, address);
+        let tags_section = DatabaseWriter::encode_tag_index_entry(&tag_index, data_index_padding);
+
+        let mut page = IndexTable {
+            index_table_size: header_size + names_section.len() as u64 + tags_section.len() as u64,
+            index_table_names_size: names_section.len() as u32,
+            index_table_names_offset: header_size,
+            index_table_tags_size: tags_section.len() as u32,
+            index_table_tags_offset: header_size + names_section.len() as u64,
+            index_table_next_page_offset: 0,
+            index_table_references_size: 0,
+            index_table_references_offset: 0,
+            page_address: 0,
+        };
+        let mut page_buf: Vec<u8> = Vec::with_capacity(page.index_table_size as usize);
+        page_buf.extend_from_slice(&page.index_table_size.to_le_bytes());
+        page_buf.extend_from_slice(&page.index_table_names_size.to_le_bytes());
+        page_buf.extend_from_slice(&page.index_table_names_offset.to_le_bytes());
+        page_buf.extend_from_slice(&page.index_table_tags_size.to_le_bytes());
+        page_buf.extend_from_slice(&page.index_table_tags_offset.to_le_bytes());
+        page_buf.extend_from_slice(&page.index_table_next_page_offset.to_le_bytes());
+        if version >= 2 {
+            page_buf.extend_from_slice(&page.index_table_references_size.to_le_bytes());
+            page_buf.extend_from_slice(&page.index_table_references_offset.to_le_bytes());
+        }
+        page_buf.extend_from_slice(&names_section);
+        page_buf.extend_from_slice(&tags_section);
+        page.page_address = self.append_to_cluster(cluster_index, &page_buf)?;
+
+        let cluster = match self
+            .clusters
+            .iter_mut()
+            .find(|x| x.cluster_index == cluster_index)
+        {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let reader = &mut self.readers[self.cluster_readers[&cluster_index]];
+        reader.select_cluster(cluster);
+        let pages = match self.index_tables.get_mut(&cluster_index) {
+            Some(v) => v,
+            None => {
+                return Err(self.cluster_error(DatabaseErrorKind::IndexTableValidity, cluster_index))
+            }
+        };
+
+        // Link the page from the last one, next_page_offset is relative to the page start.
+        if let Some(last_page) = pages.last_mut() {
+            last_page.index_table_next_page_offset = page.page_address - last_page.page_address;
+            reader.write_at_address(
+                last_page.page_address + 32,
+                &last_page.index_table_next_page_offset.to_le_bytes(),
+            )?;
+        }
+
+        // last_name_index of cluster metadata, database_size is kept by append_to_cluster.
+        if new_name {
+            cluster.last_name_index = name;
+            reader.write_at(cluster.cluster_offset + 34, &name.to_le_bytes())?;
+        }
+
+        let mut tag_index = tag_index;
+        tag_index.entry_address = page.page_address + page.index_table_tags_offset;
+        pages.push(page);
+        if let Some(table) = self.tag_index_tables.get_mut(&cluster_index) {
+            table.tags.push(tag_index);
+        }
+
+        self.reference_count_tables
+            .entry(cluster_index)
+            .or_default()
+            .add_value(name, &value);
+        self.write_reference_count_table(cluster_index)?;
+
+        // Add the tag to parents AddressList.
+        for parent_id in parents {
+            let parent = self.read_tag(*parent_id)?;
+            if let TagType::AddressList(list) = parent.tag_data {
+                let mut array = list.array;
+                array.push(AddressEntry { name, address });
+                self.write_value(*parent_id, TagType::AddressList(AddressList::new(array)))?;
+            }
+        }
+
+        match self.locate_tag(tag_id) {
+            Some((_, tag_index)) => Ok(tag_index.offset),
+            None => Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        }
+    }
+
+    fn remove_tag(&mut self, tag_id: u64) -> Result<(), DatabaseError> {
+        let (cluster_index, offset) = match self.locate_tag(tag_id) {
+            Some((cluster_index, tag_index)) => (cluster_index, tag_index.offset),
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+        let tag = self.read_tag(tag_id)?;
+
+        // Tags with children can't be deleted, it would leave them with dangling parents.
+        let tags: Vec<(u64, u64)> = match self.tag_index_tables.get(&cluster_index) {
+            Some(v) => v.tags.iter().map(|x| (x.tag_id, x.offset)).collect(),
+            None => Vec::new(),
+        };
+        let reader = match self.reader(cluster_index) {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        for (_, other_offset) in tags.iter() {
+            let mut other = reader.read_tag_data(*other_offset)?;
+            reader.read_parents(*other_offset, &mut other)?;
+            if other.tag_parents.array.iter().any(|x| x.address == offset) {
+                return Err(reader.error_at(DatabaseErrorKind::TagReferenced, offset));
+            }
+        }
+
+        // Remove the tag from parents AddressList.
+        for parent in tag.tag_parents.array.iter() {
+            let parent_id = match tags.iter().find(|(_, x)| *x == parent.address) {
+                Some((parent_id, _)) => *parent_id,
+                None => continue,
+            };
+            let parent = self.read_tag(parent_id)?;
+            if let TagType::AddressList(list) = parent.tag_data {
+                if list.array.iter().any(|x| x.address == offset) {
+                    let array = list
+                        .array
+                        .into_iter()
+                        .filter(|x| x.address != offset)
+                        .collect();
+                    self.write_value(parent_id, TagType::AddressList(AddressList::new(array)))?;
+                }
+            }
+        }
+
+        // Deleting referenced value is forbidden, reference docs/specification.md.
+        if !self.referencing_tags(tag_id)?.is_empty() {
+            return Err(self.error_at(DatabaseErrorKind::TagReferenced, cluster_index, offset));
+        }
+
+        let cluster = match self
+            .clusters
+            .iter()
+            .find(|x| x.cluster_index == cluster_index)
+        {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let reader = &mut self.readers[self.cluster_readers[&cluster_index]];
+        reader.select_cluster(cluster);
+        reader.write_at_address(
+            offset + TAG_HEADER_SIZE + tag.tag_parents_size,
+            &[DATA_TYPE_FREE],
+        )?;
+
+        // Rewrite tags section of the page without the entry.
+        let table = match self.tag_index_tables.get_mut(&cluster_index) {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let position = match table.tags.iter().position(|x| x.tag_id == tag_id) {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let entry_address = table.tags[position].entry_address;
+        let pages = match self.index_tables.get_mut(&cluster_index) {
+            Some(v) => v,
+            None => {
+                return Err(self.cluster_error(DatabaseErrorKind::IndexTableValidity, cluster_index))
+            }
+        };
+        let page = match pages.iter_mut().find(|x| {
+            let tags_start = x.page_address + x.index_table_tags_offset;
+            entry_address >= tags_start
+                && entry_address < tags_start + u64::from(x.index_table_tags_size)
+        }) {
+            Some(v) => v,
+            None => {
+                return Err(self.error_at(
+                    DatabaseErrorKind::IndexTableValidity,
+                    cluster_index,
+                    entry_address,
+                ))
+            }
+        };
+        table.tags.remove(position);
+
+        let tags_start = page.page_address + page.index_table_tags_offset;
+        let tags_end = tags_start + u64::from(page.index_table_tags_size);
+        let mut tags_section: Vec<u8> = Vec::new();
+        for tag_index in table.tags.iter_mut() {
+            if tag_index.entry_address >= tags_start && tag_index.entry_address < tags_end {
+                tag_index.entry_address = tags_start + tags_section.len() as u64;
+                tags_section.append(&mut DatabaseWriter::encode_tag_index_entry(
+                    tag_index,
+                    cluster.data_index_padding,
+                ));
+            }
+        }
+        reader.write_at_address(tags_start, &tags_section)?;
+        page.index_table_tags_size = tags_section.len() as u32;
+        // tags_size follows index_table_size, names_size and names_offset.
+        reader.write_at_address(
+            page.page_address + 20,
+            &page.index_table_tags_size.to_le_bytes(),
+        )?;
+
+        if let Some(table) = self.reference_count_tables.get_mut(&cluster_index) {
+            table.remove_value(tag.tag_name, &tag.tag_data);
+        }
+        self.write_reference_count_table(cluster_index)
+    }
+
+    // Start transaction, every change made through it is committed or rolled back at once.
+    // Reference "Journaling" of docs/specification.md.
+    pub fn transaction(&mut self) -> Result<Transaction<'_>, DatabaseError> {
+        if self.in_journal() {
+            return Err(DatabaseErrorKind::TransactionState.into());
+        }
+        self.begin_journal("BEGIN")?;
+        Ok(Transaction {
+            btag: self,
+            finished: false,
+        })
+    }
+}
+
+// Batch of changes over every cluster of the database, committed all at once or not at all.
+// Failed change rolls back the whole transaction. Transaction that is dropped without
+// commit is rolled back.
+pub struct Transaction<'a> {
+    btag: &'a mut BTag,
+    finished: bool,
+}
+
+impl Transaction<'_> {
+    // Database as seen by the transaction, including it's uncommitted changes.
+    pub fn btag(&mut self) -> &mut BTag {
+        self.btag
+    }
+
+    fn run<T>(
+        &mut self,
+        change: impl FnOnce(&mut BTag) -> Result<T, DatabaseError>,
+    ) -> Result<T, DatabaseError> {
+        if self.finished {
+            return Err(DatabaseErrorKind::TransactionState.into());
+        }
+        match change(self.btag) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                self.finished = true;
+                self.btag.rollback_journal()?;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn set(&mut self, tag_id: u64, value: TagType) -> Result<(), DatabaseError> {
+        self.run(|btag| btag.set_value(tag_id, value))
+    }
+
+    pub fn insert(
+        &mut self,
+        cluster_index: u64,
+        tag_id: u64,
+        name_string: &str,
+        parents: &[u64],
+        value: TagType,
+    ) -> Result<u64, DatabaseError> {
+        self.run(|btag| btag.insert_tag(cluster_index, tag_id, name_string, parents, value))
+    }
+
+    pub fn delete(&mut self, tag_id: u64) -> Result<(), DatabaseError> {
+        self.run(|btag| btag.delete_tag(tag_id))
+    }
+
+    pub fn commit(mut self) -> Result<(), DatabaseError> {
+        if self.finished {
+            return Err(DatabaseErrorKind::TransactionState.into());
+        }
+        self.finished = true;
+        self.btag.commit_journal()
+    }
+
+    pub fn rollback(mut self) -> Result<(), DatabaseError> {
+        if self.finished {
+            return Err(DatabaseErrorKind::TransactionState.into());
+        }
+        self.finished = true;
+        self.btag.rollback_journal()
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.btag.rollback_journal();
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use crate::{
+    BTag, ClusterMetadata, DatabaseError, DatabaseErrorKind, DatabaseWriter, SetStatement, TagType,
+    Value, ValueReference, DATA_TYPE_FREE,
+};
+
+// Size of tag_id, tag_total_size, tag_name, tag_depth and tag_parents_size.
+const TAG_HEADER_SIZE: u64 = 40;
+// Size of tag_data_type and tag_data_size.
+const TAG_VALUE_HEADER_SIZE: u64 = 9;
+// Following clusters are moved by at least this much, so cluster that keeps growing
+// doesn't move them on every change.
+const CLUSTER_GROWTH_MINIMUM: u64 = 4096;
+// Size of a single read and write while moving clusters.
+const CLUSTER_MOVE_CHUNK_SIZE: u64 = 1 << 20;
+
+impl BTag {
+    // Set value of the tag.
+    // Value is rewritten in place when it fits into tag_total_size. Otherwise tag is moved
+    // to the end of it's cluster, which grows even when other clusters follow it in the file,
+    // and every reference to the old address is changed to the new one.
+    // Reference "Value address moving" of docs/specification.md.
+    // Change is journaled, unless it's a part of already journaled transaction.
+    pub fn set_value(&mut self, tag_id: u64, value: TagType) -> Result<(), DatabaseError> {
+        self.journaled(&format!("#{} = {}", tag_id, value), |btag| {
+            btag.write_value(tag_id, value)
+        })
+    }
+
+    // Run Set statement, i.e. `wallet.euro = 1200`, setting the value to every matched tag.
+    // `#16` copies value of the first match of the query, `&#87` references it's first match.
+    // Addresses can't leave their cluster, so copied address values and references
+    // must be in the cluster of the changed tag.
+    // All changes are journaled together. Returns ids of changed tags.
+    pub fn execute_set(&mut self, statement: &SetStatement) -> Result<Vec<u64>, DatabaseError> {
+        let source = match &statement.value {
+            Value::Copy(query) | Value::Reference(query) => {
+                match self.find(query)?.into_iter().next() {
+                    Some((cluster_index, path)) => match path.array.last() {
+                        Some(v) => Some((cluster_index, v.address)),
+                        None => {
+                            return Err(
+                                self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)
+                            )
+                        }
+                    },
+                    None => return Err(DatabaseErrorKind::TagMissing.into()),
+                }
+            }
+            _ => None,
+        };
+        let copied = match (&statement.value, source) {
+            (Value::Copy(_), Some((cluster_index, address))) => match self.reader(cluster_index) {
+                Some(v) => Some(v.dereference(address)?.tag_data),
+                None => {
+                    return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index))
+                }
+            },
+            _ => None,
+        };
+
+        let mut changes: Vec<(u64, TagType)> = Vec::new();
+        for (cluster_index, path) in self.find(&statement.target)? {
+            let (tag_id, address) = match path.array.last() {
+                Some(v) => match self.tag_at(cluster_index, v.address) {
+                    Some(tag_index) => (tag_index.tag_id, v.address),
+                    None => {
+                        return Err(self.error_at(
+                            DatabaseErrorKind::TagMissing,
+                            cluster_index,
+                            v.address,
+                        ))
+                    }
+                },
+                None => continue,
+            };
+            let same_cluster = source.is_some_and(|x| x.0 == cluster_index);
+            let value = match &statement.value {
+                Value::Integer(v) => TagType::Integer(*v),
+                Value::Float(v) => TagType::Float(*v),
+                Value::Double(v) => TagType::Double(*v),
+                Value::Text(v) => TagType::Text(v.clone()),
+                Value::Char(v) => TagType::Char(v.clone()),
+                Value::Copy(_) => match &copied {
+                    Some(v) if same_cluster || v.referenced_addresses().is_empty() => v.clone(),
+                    _ => {
+                        return Err(self.error_at(
+                            DatabaseErrorKind::TagValidity,
+                            cluster_index,
+                            address,
+                        ))
+                    }
+                },
+                Value::Reference(_) => match source {
+                    Some((_, source_address)) if same_cluster => {
+                        TagType::ValueReference(ValueReference::new(source_address))
+                    }
+                    _ => {
+                        return Err(self.error_at(
+                            DatabaseErrorKind::TagValidity,
+                            cluster_index,
+                            address,
+                        ))
+                    }
+                },
+            };
+            if !changes.iter().any(|x| x.0 == tag_id) {
+                changes.push((tag_id, value));
+            }
+        }
+
+        if changes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query: Vec<String> = changes
+            .iter()
+            .map(|(tag_id, value)| format!("#{} = {}", tag_id, value))
+            .collect();
+        self.journaled(&query.join("; "), |btag| {
+            for (tag_id, value) in changes.iter() {
+                btag.write_value(*tag_id, value.clone())?;
+            }
+            Ok(changes.iter().map(|x| x.0).collect())
+        })
+    }
+
+    pub(crate) fn write_value(&mut self, tag_id: u64, value: TagType) -> Result<(), DatabaseError> {
+        let (cluster_index, offset) = match self.locate_tag(tag_id) {
+            Some((cluster_index, tag_index)) => (cluster_index, tag_index.offset),
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+        let encoding = match self.cluster(cluster_index) {
+            Some(v) => v.encoding,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let (tag_data_type, data) = DatabaseWriter::encode_tag_data(&value, encoding)?;
+
+        let reader = match self.reader(cluster_index) {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        // Reference must not lead back to the tag itself.
+        if let TagType::ValueReference(reference) = &value {
+            if reader.reference_chain(reference.address)?.contains(&offset) {
+                return Err(reader.error_at(DatabaseErrorKind::ReferenceCycle, offset));
+            }
+        }
+        let mut tag = reader.read_tag_data(offset)?;
+
+        let data_offset = offset + TAG_HEADER_SIZE + tag.tag_parents_size;
+        let mut value_buf: Vec<u8> = Vec::with_capacity(data.len() + 9);
+        value_buf.push(tag_data_type);
+        value_buf.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        value_buf.extend_from_slice(&data);
+
+        let required_size =
+            TAG_HEADER_SIZE + tag.tag_parents_size + TAG_VALUE_HEADER_SIZE + data.len() as u64;
+        if required_size <= tag.tag_total_size {
+            reader.write_at_address(data_offset, &value_buf)?;
+            self.update_value_references(cluster_index, tag.tag_name, &tag.tag_data, &value);
+            return self.write_reference_count_table(cluster_index);
+        }
+
+        // Value doesn't fit, move the whole tag to the end of the cluster.
+        reader.read_parents(offset, &mut tag)?;
+        let tag_data_padding = match self.cluster(cluster_index) {
+            Some(v) => v.tag_data_padding,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let record = DatabaseWriter::encode_tag_record(
+            tag.tag_id,
+            tag.tag_name,
+            tag.tag_depth,
+            &tag.tag_parents.array,
+            tag_data_type,
+            &data,
+            tag_data_padding,
+        );
+        let new_offset = self.append_to_cluster(cluster_index, &record)?;
+
+        // Old record is kept in place, but marked as free.
+        if let Some(reader) = self.reader(cluster_index) {
+            reader.write_at_address(data_offset, &[DATA_TYPE_FREE])?;
+        }
+
+        self.update_value_references(cluster_index, tag.tag_name, &tag.tag_data, &value);
+        self.move_references(cluster_index, offset, new_offset)
+    }
+
+    // Write buffer at the end of the cluster, growing it, and return it's address.
+    // REFERENCE_COUNT_TABLE kept at the end of the cluster is moved after the buffer,
+    // reference BTag::trailing_references.
+    pub(crate) fn append_to_cluster(
+        &mut self,
+        cluster_index: u64,
+        buf: &[u8],
+    ) -> Result<u64, DatabaseError> {
+        let trailing = self.trailing_references(cluster_index);
+        let address = match trailing {
+            Some((start, _)) => start,
+            None => self.cluster_end_address(cluster_index)?,
+        };
+        let table = match (trailing, self.reader(cluster_index)) {
+            (Some((start, size)), Some(reader)) => {
+                let position = reader.current_index_table_offset + start;
+                reader.read_at(position, size.into())?
+            }
+            _ => Vec::new(),
+        };
+        self.extend_cluster(
+            cluster_index,
+            address + buf.len() as u64 + table.len() as u64,
+        )?;
+
+        let reader = match self.reader(cluster_index) {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        reader.write_at_address(address, buf)?;
+        if trailing.is_some() {
+            let table_address = address + buf.len() as u64;
+            reader.write_at_address(table_address, &table)?;
+            let reader = &mut self.readers[self.cluster_readers[&cluster_index]];
+            if let Some(first_page) = self
+                .index_tables
+                .get_mut(&cluster_index)
+                .and_then(|x| x.first_mut())
+            {
+                // references_offset follows references_size.
+                first_page.index_table_references_offset = table_address - first_page.page_address;
+                reader.write_at_address(
+                    first_page.page_address + 44,
+                    &first_page.index_table_references_offset.to_le_bytes(),
+                )?;
+            }
+        }
+
+        Ok(address)
+    }
+
+    // Address right after the last byte of the cluster.
+    pub(crate) fn cluster_end_address(&self, cluster_index: u64) -> Result<u64, DatabaseError> {
+        match self.cluster(cluster_index) {
+            Some(v) => {
+                Ok((v.cluster_offset + v.database_size).saturating_sub(v.index_table_offset))
+            }
+            None => Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        }
+    }
+
+    // Grow the cluster, so it ends at the address or after it.
+    pub(crate) fn extend_cluster(
+        &mut self,
+        cluster_index: u64,
+        end: u64,
+    ) -> Result<(), DatabaseError> {
+        let cluster_end = self.cluster_end_address(cluster_index)?;
+        if end <= cluster_end {
+            return Ok(());
+        }
+        self.grow_cluster(cluster_index, end - cluster_end)?;
+
+        let cluster = match self
+            .clusters
+            .iter_mut()
+            .find(|x| x.cluster_index == cluster_index)
+        {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let reader = &mut self.readers[self.cluster_readers[&cluster_index]];
+        reader.select_cluster(cluster);
+        // database_size follows BTAG, version, cluster_index, index_table_offset and text_encoding.
+        cluster.database_size += end - cluster_end;
+        reader.write_at(
+            cluster.cluster_offset + 26,
+            &cluster.database_size.to_le_bytes(),
+        )
+    }
+
+    // Make room for size bytes at the end of the cluster, returning absolute position
+    // where they start. Cluster grows into the space left before the next cluster of it's file.
+    // When there isn't enough, every following cluster is moved towards the end of the file.
+    // Reference "Cluster growth" of docs/specification.md.
+    fn grow_cluster(&mut self, cluster_index: u64, size: u64) -> Result<u64, DatabaseError> {
+        let reader_index = match self.cluster_readers.get(&cluster_index) {
+            Some(v) => *v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let (cluster_offset, cluster_end) = match self.cluster(cluster_index) {
+            Some(v) => (v.cluster_offset, v.cluster_offset + v.database_size),
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let cluster_readers = &self.cluster_readers;
+        let same_file =
+            |x: &ClusterMetadata| cluster_readers.get(&x.cluster_index) == Some(&reader_index);
+        let next_offset = match self
+            .clusters
+            .iter()
+            .filter(|x| same_file(x) && x.cluster_offset > cluster_offset)
+            .map(|x| x.cluster_offset)
+            .min()
+        {
+            Some(v) => v,
+            None => return Ok(cluster_end),
+        };
+        let required_end = match cluster_end.checked_add(size) {
+            Some(v) => v,
+            None => {
+                return Err(self.readers[reader_index]
+                    .locate(DatabaseErrorKind::ClusterFull.into(), cluster_end)
+                    .with_cluster_index(cluster_index))
+            }
+        };
+        if required_end <= next_offset {
+            return Ok(cluster_end);
+        }
+        let shift = (required_end - next_offset).max(CLUSTER_GROWTH_MINIMUM);
+
+        // Rest of the file is copied from it's end, so nothing is overwritten before it's read.
+        let reader = &mut self.readers[reader_index];
+        let mut end = reader.file_size()?;
+        while end > next_offset {
+            let start = end.saturating_sub(CLUSTER_MOVE_CHUNK_SIZE).max(next_offset);
+            let buf = reader.read_at(start, end - start)?;
+            reader.write_at(start + shift, &buf)?;
+            end = start;
+        }
+        // Space that is left after the requested size stays empty.
+        let gap = (next_offset + shift).saturating_sub(required_end);
+        reader.write_at(required_end, &vec![0; gap as usize])?;
+
+        // Addresses are relative to index tables, so only offsets in cluster metadata change.
+        // index_table_offset is at 16 and next_cluster at 54 of cluster metadata.
+        for cluster in self.clusters.iter_mut().filter(|x| same_file(x)) {
+            if cluster.cluster_offset > cluster_offset {
+                cluster.cluster_offset += shift;
+                cluster.index_table_offset += shift;
+                reader.write_at(
+                    cluster.cluster_offset + 16,
+                    &cluster.index_table_offset.to_le_bytes(),
+                )?;
+            }
+            if cluster.next_cluster > cluster_offset {
+                cluster.next_cluster += shift;
+                reader.write_at(
+                    cluster.cluster_offset + 54,
+                    &cluster.next_cluster.to_le_bytes(),
+                )?;
+            }
+        }
+
+        Ok(cluster_end)
+    }
+
+    fn update_value_references(
+        &mut self,
+        cluster_index: u64,
+        name: u64,
+        old_value: &TagType,
+        new_value: &TagType,
+    ) {
+        let table = self
+            .reference_count_tables
+            .entry(cluster_index)
+            .or_default();
+        table.remove_value(name, old_value);
+        table.add_value(name, new_value);
+    }
+
+    // Change every address of the cluster that points to old_address into new_address.
+    // This includes data index table, tag parents, AddressEntry, AddressList, ValueReference
+    // and REFERENCE_COUNT_TABLE.
+    pub fn move_references(
+        &mut self,
+        cluster_index: u64,
+        old_address: u64,
+        new_address: u64,
+    ) -> Result<(), DatabaseError> {
+        if self.reader(cluster_index).is_none() {
+            return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index));
+        }
+        let reader = &mut self.readers[self.cluster_readers[&cluster_index]];
+        let table = match self.tag_index_tables.get_mut(&cluster_index) {
+            Some(v) => v,
+            None => return Err(self.cluster_error(DatabaseErrorKind::TagMissing, cluster_index)),
+        };
+        let new_address_bytes = new_address.to_le_bytes();
+
+        // Data index table entries, offset is the last field of the entry.
+        for tag_index in table.tags.iter_mut() {
+            if tag_index.offset == old_address {
+                tag_index.offset = new_address;
+                let entry_offset =
+                    tag_index.entry_address + 24 + tag_index.full_path.len() as u64 * 8;
+                reader.write_at_address(entry_offset, &new_address_bytes)?;
+            }
+        }
+
+        for tag_index in table.tags.iter() {
+            let mut tag = reader.read_tag_data(tag_index.offset)?;
+            reader.read_parents(tag_index.offset, &mut tag)?;
+
+            // Every parent entry is (name, address).
+            for (i, parent) in tag.tag_parents.array.iter().enumerate() {
+                if parent.address == old_address {
+                    let parent_offset = tag_index.offset + TAG_HEADER_SIZE + i as u64 * 16 + 8;
+                    reader.write_at_address(parent_offset, &new_address_bytes)?;
+                }
+            }
+
+            let data_offset =
+                tag_index.offset + TAG_HEADER_SIZE + tag.tag_parents_size + TAG_VALUE_HEADER_SIZE;
+            match tag.tag_data {
+                TagType::AddressEntry(entry) if entry.address == old_address => {
+                    reader.write_at_address(data_offset + 8, &new_address_bytes)?;
+                }
+                TagType::AddressList(list) => {
+                    // Entries follow address_count.
+                    for (i, entry) in list.array.iter().enumerate() {
+                        if entry.address == old_address {
+                            let entry_offset = data_offset + 8 + i as u64 * 16 + 8;
+                            reader.write_at_address(entry_offset, &new_address_bytes)?;
+                        }
+                    }
+                }
+                TagType::ValueReference(reference) if reference.address == old_address => {
+                    reader.write_at_address(data_offset, &new_address_bytes)?;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(table) = self.reference_count_tables.get_mut(&cluster_index) {
+            table.move_address(old_address, new_address);
+        }
+        self.write_reference_count_table(cluster_index)
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use crate::{
+    AddressEntry, AddressList, BTag, DatabaseError, DatabaseErrorKind, DatabaseWriter, TagType,
+    ValueReference, CLUSTER_METADATA_SIZE, FORMAT_VERSION,
+};
+
+impl BTag {
+    // Rewrite every cluster of older format version to FORMAT_VERSION.
+    // Returns indexes of upgraded clusters.
+    pub fn upgrade(&mut self) -> Result<Vec<u64>, DatabaseError> {
+        let outdated: Vec<u64> = self
+            .clusters
+            .iter()
+            .filter(|x| x.version < FORMAT_VERSION)
+            .map(|x| x.cluster_index)
+            .collect();
+        for cluster_index in outdated.iter() {
+            self.upgrade_cluster(*cluster_index)?;
+        }
+
+        Ok(outdated)
+    }
+
+    // Rewrite the cluster in place in FORMAT_VERSION. Tag ids, names and values are kept,
+    // addresses change and free records are dropped.
+    // Rewritten cluster must fit before the next cluster of the file.
+    // Returns false when cluster already has current version.
+    pub fn upgrade_cluster(&mut self, cluster_index: u64) -> Result<bool, DatabaseError> {
+        let version = match self.cluster(cluster_index) {
+            Some(v) => v.version,
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+        if version == FORMAT_VERSION {
+            return Ok(false);
+        }
+
+        self.journaled(&format!("UPGRADE CLUSTER {}", cluster_index), |btag| {
+            btag.rewrite_cluster(cluster_index)
+        })?;
+        Ok(true)
+    }
+
+    fn rewrite_cluster(&mut self, cluster_index: u64) -> Result<(), DatabaseError> {
+        let cluster = match self.cluster(cluster_index) {
+            Some(v) => v,
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+        let cluster_offset = cluster.cluster_offset;
+        let next_cluster = cluster.next_cluster;
+
+        let mut writer = DatabaseWriter::new(cluster_index, cluster.encoding);
+        writer.set_paddings(
+            cluster.names_index_padding,
+            cluster.data_index_padding,
+            cluster.tag_data_padding,
+        );
+        if let Some(names) = self.name_registries.get(&cluster_index) {
+            for name in names.names() {
+                writer.insert_name(name.name, &name.name_string)?;
+            }
+        }
+
+        // Writer refers to tags by ids, every address is resolved back to the tag id.
+        let tags: Vec<(u64, u64)> = match self.tag_index_tables.get(&cluster_index) {
+            Some(v) => v.tags.iter().map(|x| (x.tag_id, x.offset)).collect(),
+            None => Vec::new(),
+        };
+        let tag_ids: HashMap<u64, u64> = tags.iter().map(|(id, offset)| (*offset, *id)).collect();
+        let resolve = |address: u64| -> Result<u64, DatabaseError> {
+            match tag_ids.get(&address) {
+                Some(v) => Ok(*v),
+                None => Err(DatabaseErrorKind::TagValidity.into()),
+            }
+        };
+
+        let reader_index = self.cluster_readers[&cluster_index];
+        let reader = match self.reader(cluster_index) {
+            Some(v) => v,
+            None => return Err(DatabaseErrorKind::TagMissing.into()),
+        };
+        for (tag_id, offset) in tags.iter() {
+            let mut tag = reader.read_tag_data(*offset)?;
+            reader.read_parents(*offset, &mut tag)?;
+
+            let mut parents: Vec<u64> = Vec::with_capacity(tag.tag_parents.array.len());
+            for parent in tag.tag_parents.array.iter() {
+                parents.push(resolve(parent.address)?);
+            }
+            let value = match tag.tag_data {
+                TagType::AddressEntry(entry) => {
+                    TagType::AddressEntry(AddressEntry::new(entry.name, resolve(entry.address)?))
+                }
+                TagType::AddressList(list) => {
+                    let mut array = Vec::with_capacity(list.array.len());
+                    for entry in list.array.iter() {
+                        array.push(AddressEntry::new(entry.name, resolve(entry.address)?));
+                    }
+                    TagType::AddressList(AddressList::new(array))
+                }
+                TagType::ValueReference(reference) => {
+                    TagType::ValueReference(ValueReference::new(resolve(reference.address)?))
+                }
+                other => other,
+            };
+            writer.add_tag(*tag_id, tag.tag_name, &parents, value)?;
+        }
+
+        let mut buf = writer.to_bytes(cluster_offset)?;
+        // Keep the chain, next_cluster is the last field of metadata.
+        let next_cluster_position = CLUSTER_METADATA_SIZE as usize - 8;
+        buf[next_cluster_position..CLUSTER_METADATA_SIZE as usize]
+            .copy_from_slice(&next_cluster.to_le_bytes());
+        if next_cluster != 0 && cluster_offset + buf.len() as u64 > next_cluster {
+            return Err(DatabaseErrorKind::ClusterFull.into());
+        }
+
+        let reader = &mut self.readers[reader_index];
+        if next_cluster == 0 {
+            // Space left after the shrunk cluster stays a part of it, so cluster still ends
+            // with the file and can grow. database_size follows BTAG, version, cluster_index,
+            // index_table_offset and text_encoding.
+            let database_size = (reader.file_size()? - cluster_offset).max(buf.len() as u64);
+            buf[26..34].copy_from_slice(&database_size.to_le_bytes());
+        }
+        reader.write_at(cluster_offset, &buf)?;
+        self.reload_clusters(reader_index)
+    }
+}
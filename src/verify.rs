@@ -0,0 +1,448 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    BTag, ClusterMetadata, DatabaseError, DatabaseErrorKind, DatabaseReader, NameIndex,
+    ReferenceCountTable, TagData, TagIndex, TagType, CLUSTER_METADATA_SIZE,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyProblemKind {
+    // Structure couldn't be read, nothing that depends on it is checked.
+    Unreadable(DatabaseErrorKind),
+    ClusterDuplicateIndex,
+    // Cluster extends past the end of the file or into the next cluster.
+    DatabaseSize { database_size: u64, available: u64 },
+    // Last cluster ends before the end of the file.
+    TrailingData { size: u64 },
+    NameDuplicateId { name: u64 },
+    NameDuplicateString { name: u64 },
+    TagDuplicateId,
+    // Index entry points to a record of another tag.
+    TagIdMismatch { record_tag_id: u64 },
+    // Parent, AddressEntry or ValueReference address doesn't point to a tag record.
+    DanglingAddress { address: u64 },
+    // tag_depth isn't the depth of the first parent + 1, or 0 for tags without parents.
+    DepthMismatch { tag_depth: u64, expected: u64 },
+    // REFERENCE_COUNT_TABLE entry doesn't match references made by tag values.
+    ReferenceCountMismatch { address: u64 },
+}
+
+// Single problem together with where it was found, located the same way as DatabaseError.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyProblem {
+    kind: VerifyProblemKind,
+    path: Option<PathBuf>,
+    // Absolute offset in the file.
+    offset: Option<u64>,
+    cluster_index: Option<u64>,
+    tag_id: Option<u64>,
+}
+
+impl VerifyProblem {
+    pub fn kind(&self) -> VerifyProblemKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    pub fn cluster_index(&self) -> Option<u64> {
+        self.cluster_index
+    }
+
+    pub fn tag_id(&self) -> Option<u64> {
+        self.tag_id
+    }
+}
+
+impl From<DatabaseError> for VerifyProblem {
+    fn from(error: DatabaseError) -> Self {
+        VerifyProblem {
+            kind: VerifyProblemKind::Unreadable(error.kind()),
+            path: error.path().map(|x| x.to_path_buf()),
+            offset: error.offset(),
+            cluster_index: error.cluster_index(),
+            tag_id: None,
+        }
+    }
+}
+
+impl fmt::Display for VerifyProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyProblemKind::Unreadable(kind) => write!(f, "{}", kind),
+            VerifyProblemKind::ClusterDuplicateIndex => write!(f, "duplicate cluster index"),
+            VerifyProblemKind::DatabaseSize {
+                database_size,
+                available,
+            } => write!(
+                f,
+                "database_size {} exceeds {} available bytes",
+                database_size, available
+            ),
+            VerifyProblemKind::TrailingData { size } => {
+                write!(f, "{} bytes after the last cluster", size)
+            }
+            VerifyProblemKind::NameDuplicateId { name } => write!(f, "duplicate name id {}", name),
+            VerifyProblemKind::NameDuplicateString { name } => {
+                write!(f, "name {} duplicates string of another name", name)
+            }
+            VerifyProblemKind::TagDuplicateId => write!(f, "duplicate tag id"),
+            VerifyProblemKind::TagIdMismatch { record_tag_id } => {
+                write!(f, "index entry points to record of tag #{}", record_tag_id)
+            }
+            VerifyProblemKind::DanglingAddress { address } => {
+                write!(f, "address {} doesn't point to a tag", address)
+            }
+            VerifyProblemKind::DepthMismatch {
+                tag_depth,
+                expected,
+            } => write!(f, "tag_depth {} instead of {}", tag_depth, expected),
+            VerifyProblemKind::ReferenceCountMismatch { address } => {
+                write!(
+                    f,
+                    "reference count of address {} doesn't match values",
+                    address
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for VerifyProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(tag_id) = self.tag_id {
+            write!(f, "#{}: ", tag_id)?;
+        }
+        write!(f, "{}", self.kind)?;
+        if let Some(path) = &self.path {
+            write!(f, " in {}", path.display())?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        if let Some(cluster_index) = self.cluster_index {
+            write!(f, " of cluster {}", cluster_index)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    clusters: u64,
+    tags: u64,
+    problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    // Number of clusters that have been checked.
+    pub fn clusters(&self) -> u64 {
+        self.clusters
+    }
+
+    // Number of tag records that have been checked.
+    pub fn tags(&self) -> u64 {
+        self.tags
+    }
+
+    pub fn problems(&self) -> &[VerifyProblem] {
+        &self.problems
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn append(&mut self, mut other: VerifyReport) {
+        self.clusters += other.clusters;
+        self.tags += other.tags;
+        self.problems.append(&mut other.problems);
+    }
+}
+
+impl DatabaseReader {
+    // Check every cluster of the file without loading it as a database, so files that can't be
+    // opened are checked as well. Every problem is reported, a structure that can't be read
+    // only stops checks of what depends on it.
+    // Reference docs/specification.md for the checked rules.
+    pub fn verify(&mut self) -> Result<VerifyReport, DatabaseError> {
+        let file_size = self.file_size()?;
+        let mut report = VerifyReport::default();
+        let mut cluster_indexes: HashSet<u64> = HashSet::new();
+
+        let mut cluster_offset: u64 = 0;
+        loop {
+            let cluster = match self.read_cluster(cluster_offset) {
+                Ok(v) => v,
+                Err(e) => {
+                    report.problems.push(e.into());
+                    break;
+                }
+            };
+            report.clusters += 1;
+
+            if !cluster_indexes.insert(cluster.cluster_index) {
+                report.problems.push(self.cluster_problem(
+                    VerifyProblemKind::ClusterDuplicateIndex,
+                    &cluster,
+                    cluster_offset,
+                ));
+            }
+
+            // Cluster must end before the next one, last cluster ends with the file.
+            let cluster_end = cluster_offset.checked_add(cluster.database_size);
+            let available_end = match cluster.next_cluster {
+                0 => file_size,
+                v => v.min(file_size),
+            };
+            match cluster_end {
+                Some(v) if v <= available_end => {
+                    if cluster.next_cluster == 0 && v < file_size {
+                        report.problems.push(self.cluster_problem(
+                            VerifyProblemKind::TrailingData {
+                                size: file_size - v,
+                            },
+                            &cluster,
+                            v,
+                        ));
+                    }
+                }
+                _ => report.problems.push(self.cluster_problem(
+                    VerifyProblemKind::DatabaseSize {
+                        database_size: cluster.database_size,
+                        available: available_end.saturating_sub(cluster_offset),
+                    },
+                    &cluster,
+                    cluster_offset + 26,
+                )),
+            }
+
+            if cluster.index_table_offset < cluster_offset + CLUSTER_METADATA_SIZE {
+                report.problems.push(self.cluster_problem(
+                    VerifyProblemKind::Unreadable(DatabaseErrorKind::ClusterValidity),
+                    &cluster,
+                    cluster_offset + 16,
+                ));
+            } else {
+                self.verify_cluster(&cluster, &mut report);
+            }
+
+            // Clusters may only follow each other, otherwise chain could loop forever.
+            if cluster.next_cluster == 0 {
+                break;
+            }
+            if cluster.next_cluster <= cluster_offset {
+                report.problems.push(self.cluster_problem(
+                    VerifyProblemKind::Unreadable(DatabaseErrorKind::ClusterValidity),
+                    &cluster,
+                    cluster_offset + 54,
+                ));
+                break;
+            }
+            cluster_offset = cluster.next_cluster;
+        }
+
+        Ok(report)
+    }
+
+    fn verify_cluster(&mut self, cluster: &ClusterMetadata, report: &mut VerifyReport) {
+        let mut names: Vec<NameIndex> = Vec::new();
+        let mut tags: Vec<TagIndex> = Vec::new();
+        let mut references = ReferenceCountTable::new(Vec::new());
+        for page in self.index_table_pages(cluster) {
+            match page {
+                Ok(mut v) => {
+                    names.append(&mut v.names.names);
+                    tags.append(&mut v.tags.tags);
+                    references.append(v.references);
+                }
+                Err(e) => {
+                    report.problems.push(e.into());
+                    break;
+                }
+            }
+        }
+
+        let mut name_ids: HashSet<u64> = HashSet::new();
+        let mut name_strings: HashSet<&str> = HashSet::new();
+        for name in names.iter() {
+            if !name_ids.insert(name.name) {
+                report.problems.push(self.cluster_problem(
+                    VerifyProblemKind::NameDuplicateId { name: name.name },
+                    cluster,
+                    cluster.index_table_offset,
+                ));
+            }
+            if !name_strings.insert(&name.name_string) {
+                report.problems.push(self.cluster_problem(
+                    VerifyProblemKind::NameDuplicateString { name: name.name },
+                    cluster,
+                    cluster.index_table_offset,
+                ));
+            }
+        }
+
+        // Records that index entries point to, by address.
+        let mut tag_ids: HashSet<u64> = HashSet::new();
+        let mut records: HashMap<u64, TagData<TagType>> = HashMap::new();
+        for tag in tags.iter() {
+            if !tag_ids.insert(tag.tag_id) {
+                report.problems.push(self.tag_problem(
+                    VerifyProblemKind::TagDuplicateId,
+                    tag.tag_id,
+                    tag.entry_address,
+                ));
+            }
+
+            let mut record = match self.read_tag_data(tag.offset) {
+                Ok(v) => v,
+                Err(e) => {
+                    report.problems.push(VerifyProblem {
+                        tag_id: Some(tag.tag_id),
+                        ..e.into()
+                    });
+                    continue;
+                }
+            };
+            if let Err(e) = self.read_parents(tag.offset, &mut record) {
+                report.problems.push(VerifyProblem {
+                    tag_id: Some(tag.tag_id),
+                    ..e.into()
+                });
+                continue;
+            }
+            if record.tag_id != tag.tag_id {
+                report.problems.push(self.tag_problem(
+                    VerifyProblemKind::TagIdMismatch {
+                        record_tag_id: record.tag_id,
+                    },
+                    tag.tag_id,
+                    tag.offset,
+                ));
+                continue;
+            }
+            report.tags += 1;
+            records.insert(tag.offset, record);
+        }
+
+        let mut offsets: Vec<u64> = records.keys().copied().collect();
+        offsets.sort_unstable();
+        let mut value_references = ReferenceCountTable::new(Vec::new());
+        for offset in offsets.iter() {
+            let record = &records[offset];
+
+            let addresses = record
+                .tag_parents
+                .array
+                .iter()
+                .map(|x| x.address)
+                .chain(record.tag_data.referenced_addresses());
+            for address in addresses {
+                if !records.contains_key(&address) {
+                    report.problems.push(self.tag_problem(
+                        VerifyProblemKind::DanglingAddress { address },
+                        record.tag_id,
+                        *offset,
+                    ));
+                }
+            }
+
+            // Depth is counted through the first parent.
+            let expected = match record.tag_parents.array.first() {
+                Some(parent) => records
+                    .get(&parent.address)
+                    .map(|x| x.tag_depth.saturating_add(1)),
+                None => Some(0),
+            };
+            if let Some(expected) = expected {
+                if record.tag_depth != expected {
+                    report.problems.push(self.tag_problem(
+                        VerifyProblemKind::DepthMismatch {
+                            tag_depth: record.tag_depth,
+                            expected,
+                        },
+                        record.tag_id,
+                        *offset,
+                    ));
+                }
+            }
+
+            value_references.add_value(record.tag_name, &record.tag_data);
+        }
+
+        // Version 1 clusters have no REFERENCE_COUNT_TABLE.
+        if cluster.version < 2 {
+            return;
+        }
+        let mut addresses: Vec<u64> = references
+            .references()
+            .iter()
+            .chain(value_references.references())
+            .map(|x| x.address())
+            .collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+        for address in addresses {
+            let mut stored = references.referencing_tags(address).to_vec();
+            let mut expected = value_references.referencing_tags(address).to_vec();
+            stored.sort_unstable();
+            expected.sort_unstable();
+            if stored != expected {
+                report.problems.push(self.cluster_problem(
+                    VerifyProblemKind::ReferenceCountMismatch { address },
+                    cluster,
+                    cluster.index_table_offset,
+                ));
+            }
+        }
+    }
+
+    // Problem at absolute position in the file.
+    fn cluster_problem(
+        &self,
+        kind: VerifyProblemKind,
+        cluster: &ClusterMetadata,
+        position: u64,
+    ) -> VerifyProblem {
+        VerifyProblem {
+            kind,
+            path: Some(self.path.clone()),
+            offset: Some(position),
+            cluster_index: Some(cluster.cluster_index),
+            tag_id: None,
+        }
+    }
+
+    // Problem of the tag at the address of current cluster.
+    fn tag_problem(&self, kind: VerifyProblemKind, tag_id: u64, address: u64) -> VerifyProblem {
+        VerifyProblem {
+            kind,
+            path: Some(self.path.clone()),
+            offset: Some(self.current_index_table_offset.saturating_add(address)),
+            cluster_index: self.current_cluster_index,
+            tag_id: Some(tag_id),
+        }
+    }
+}
+
+impl BTag {
+    // Verify every file of the database, reference DatabaseReader::verify.
+    pub fn verify(&mut self) -> Result<VerifyReport, DatabaseError> {
+        let mut report = VerifyReport::default();
+        for reader in self.readers.iter_mut() {
+            report.append(reader.verify()?);
+        }
+
+        Ok(report)
+    }
+}
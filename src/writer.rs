@@ -0,0 +1,593 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{
+    AddressEntry, AddressList, DatabaseError, DatabaseErrorKind, NameIndex, NameRegistry,
+    ReferenceCountTable, TagIndex, TagType, TextEncoding, ValueReference, CLUSTER_METADATA_SIZE,
+    DATA_TYPE_ADDRESS_ENTRY, DATA_TYPE_ADDRESS_LIST, DATA_TYPE_CHAR, DATA_TYPE_DOUBLE,
+    DATA_TYPE_FLOAT, DATA_TYPE_INTEGER, DATA_TYPE_TEXT, DATA_TYPE_VALUE_REFERENCE, FORMAT_VERSION,
+    INDEX_TABLE_HEADER_SIZE,
+};
+
+// Size of tag data record without parents and tag_data.
+const TAG_DATA_HEADER_SIZE: u64 = 40 + 9;
+
+// Tag that will be written by DatabaseWriter.
+// Parents and addresses inside of AddressEntry and AddressList are tag ids, not addresses.
+// They are resolved to addresses of written tags on write.
+#[derive(Debug)]
+struct WriterTag {
+    tag_id: u64,
+    name: u64,
+    parents: Vec<u64>,
+    value: TagType,
+}
+
+// Creates a single database cluster in the layout expected by DatabaseReader.
+// Reference docs/specification.md for further information.
+#[derive(Debug)]
+pub struct DatabaseWriter {
+    cluster_index: u64,
+    text_encoding: TextEncoding,
+    names_index_padding: u32,
+    data_index_padding: u32,
+    tag_data_padding: u32,
+    names: NameRegistry,
+    tags: Vec<WriterTag>,
+    tag_lookup: HashMap<u64, usize>,
+}
+
+impl DatabaseWriter {
+    pub fn new(cluster_index: u64, text_encoding: TextEncoding) -> Self {
+        DatabaseWriter {
+            cluster_index,
+            text_encoding,
+            names_index_padding: 0,
+            data_index_padding: 0,
+            tag_data_padding: 0,
+            names: NameRegistry::new(0, text_encoding),
+            tags: Vec::new(),
+            tag_lookup: HashMap::new(),
+        }
+    }
+
+    pub fn set_paddings(
+        &mut self,
+        names_index_padding: u32,
+        data_index_padding: u32,
+        tag_data_padding: u32,
+    ) {
+        self.names_index_padding = names_index_padding;
+        self.data_index_padding = data_index_padding;
+        self.tag_data_padding = tag_data_padding;
+    }
+
+    // Get name for the string, registering a new one if it doesn't exist yet.
+    // Names are unique, new ones get last_name_index + 1.
+    pub fn add_name(&mut self, name_string: &str) -> Result<u64, DatabaseError> {
+        self.names.allocate(name_string)
+    }
+
+    // Register name with given id, i.e. to keep names of an existing cluster.
+    pub fn insert_name(&mut self, name: u64, name_string: &str) -> Result<(), DatabaseError> {
+        self.names.insert(name, name_string)
+    }
+
+    pub fn name(&self, name_string: &str) -> Option<u64> {
+        self.names.id(name_string)
+    }
+
+    pub fn contains_tag(&self, tag_id: u64) -> bool {
+        self.tag_lookup.contains_key(&tag_id)
+    }
+
+    // Smallest tag id that is bigger than every added one.
+    pub fn next_tag_id(&self) -> u64 {
+        self.tag_lookup.keys().max().map_or(0, |x| x + 1)
+    }
+
+    // Add tag with given parents.
+    // Parents, AddressEntry, AddressList and ValueReference addresses must be tag ids of tags
+    // added to this writer. Parents may be added later, they're checked when encoding.
+    // Tag can't be it's own parent and every parent is listed once.
+    pub fn add_tag(
+        &mut self,
+        tag_id: u64,
+        name: u64,
+        parents: &[u64],
+        value: TagType,
+    ) -> Result<(), DatabaseError> {
+        if self.tag_lookup.contains_key(&tag_id) {
+            return Err(DatabaseErrorKind::TagDuplicateId.into());
+        }
+        for (i, parent) in parents.iter().enumerate() {
+            if *parent == tag_id || parents[..i].contains(parent) {
+                return Err(DatabaseErrorKind::TagValidity.into());
+            }
+        }
+
+        self.tag_lookup.insert(tag_id, self.tags.len());
+        self.tags.push(WriterTag {
+            tag_id,
+            name,
+            parents: parents.to_vec(),
+            value,
+        });
+
+        Ok(())
+    }
+
+    // Replace value of previously added tag.
+    pub fn set_value(&mut self, tag_id: u64, value: TagType) -> Result<(), DatabaseError> {
+        match self.tag_lookup.get(&tag_id) {
+            Some(i) => {
+                self.tags[*i].value = value;
+                Ok(())
+            }
+            None => Err(DatabaseErrorKind::TagValidity.into()),
+        }
+    }
+
+    // Encode tag_data, returning tag_data_type and bytes. Addresses must be already resolved.
+    // Text and Char[] are encoded with text encoding of the cluster.
+    pub fn encode_tag_data(
+        tag_data: &TagType,
+        encoding: TextEncoding,
+    ) -> Result<(u8, Vec<u8>), DatabaseError> {
+        let mut buf: Vec<u8> = Vec::new();
+        let tag_data_type = match tag_data {
+            TagType::Integer(v) => {
+                buf.extend_from_slice(&v.to_le_bytes());
+                DATA_TYPE_INTEGER
+            }
+            TagType::Float(v) => {
+                buf.extend_from_slice(&v.to_le_bytes());
+                DATA_TYPE_FLOAT
+            }
+            TagType::Double(v) => {
+                buf.extend_from_slice(&v.to_le_bytes());
+                DATA_TYPE_DOUBLE
+            }
+            TagType::AddressEntry(entry) => {
+                buf.extend_from_slice(&entry.name.to_le_bytes());
+                buf.extend_from_slice(&entry.address.to_le_bytes());
+                DATA_TYPE_ADDRESS_ENTRY
+            }
+            TagType::AddressList(list) => {
+                buf.extend_from_slice(&(list.array.len() as u64).to_le_bytes());
+                for entry in list.array.iter() {
+                    buf.extend_from_slice(&entry.name.to_le_bytes());
+                    buf.extend_from_slice(&entry.address.to_le_bytes());
+                }
+                DATA_TYPE_ADDRESS_LIST
+            }
+            TagType::Text(text) => {
+                let text = encoding.encode(text)?;
+                let text_size: u16 = match text.len().try_into() {
+                    Ok(v) => v,
+                    Err(_) => return Err(DatabaseErrorKind::StringValidity.into()),
+                };
+                buf.extend_from_slice(&text_size.to_le_bytes());
+                buf.extend_from_slice(&text);
+                DATA_TYPE_TEXT
+            }
+            TagType::Char(text) => {
+                let text = encoding.encode(text)?;
+                let char_size: u8 = match text.len().try_into() {
+                    Ok(v) => v,
+                    Err(_) => return Err(DatabaseErrorKind::StringValidity.into()),
+                };
+                buf.push(char_size);
+                buf.extend_from_slice(&text);
+                DATA_TYPE_CHAR
+            }
+            TagType::ValueReference(reference) => {
+                buf.extend_from_slice(&reference.address.to_le_bytes());
+                DATA_TYPE_VALUE_REFERENCE
+            }
+        };
+
+        Ok((tag_data_type, buf))
+    }
+
+    // Encode whole tag data record, reference "Tag data" of docs/specification.md.
+    // tag_total_size covers the record together with padding.
+    pub fn encode_tag_record(
+        tag_id: u64,
+        tag_name: u64,
+        tag_depth: u64,
+        parents: &[AddressEntry],
+        tag_data_type: u8,
+        data: &[u8],
+        padding: u32,
+    ) -> Vec<u8> {
+        let parents_size = parents.len() as u64 * 16;
+        let total_size =
+            TAG_DATA_HEADER_SIZE + parents_size + data.len() as u64 + u64::from(padding);
+
+        let mut buf: Vec<u8> = Vec::with_capacity(total_size as usize);
+        buf.extend_from_slice(&tag_id.to_le_bytes());
+        buf.extend_from_slice(&total_size.to_le_bytes());
+        buf.extend_from_slice(&tag_name.to_le_bytes());
+        buf.extend_from_slice(&tag_depth.to_le_bytes());
+        buf.extend_from_slice(&parents_size.to_le_bytes());
+        for parent in parents {
+            buf.extend_from_slice(&parent.name.to_le_bytes());
+            buf.extend_from_slice(&parent.address.to_le_bytes());
+        }
+        buf.push(tag_data_type);
+        buf.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        buf.extend_from_slice(data);
+        buf.resize(total_size as usize, 0);
+
+        buf
+    }
+
+    // Entry of names section of index table page.
+    pub fn encode_name_entry(
+        name: &NameIndex,
+        encoding: TextEncoding,
+        padding: u32,
+    ) -> Result<Vec<u8>, DatabaseError> {
+        let name_string = encoding.encode(&name.name_string)?;
+        let name_string_size: u16 = match name_string.len().try_into() {
+            Ok(v) => v,
+            Err(_) => return Err(DatabaseErrorKind::StringValidity.into()),
+        };
+
+        let mut buf: Vec<u8> = Vec::with_capacity(10 + name_string.len());
+        buf.extend_from_slice(&name.name.to_le_bytes());
+        buf.extend_from_slice(&name_string_size.to_le_bytes());
+        buf.extend_from_slice(&name_string);
+        buf.resize(buf.len() + padding as usize, 0);
+        Ok(buf)
+    }
+
+    // Entry of tags section of index table page.
+    pub fn encode_tag_index_entry(tag: &TagIndex, padding: u32) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(32 + tag.full_path.len() * 8);
+        buf.extend_from_slice(&tag.tag_id.to_le_bytes());
+        buf.extend_from_slice(&tag.name.to_le_bytes());
+        buf.extend_from_slice(&tag.depth.to_le_bytes());
+        for name in tag.full_path.iter() {
+            buf.extend_from_slice(&name.to_le_bytes());
+        }
+        buf.extend_from_slice(&tag.offset.to_le_bytes());
+        buf.resize(buf.len() + padding as usize, 0);
+        buf
+    }
+
+    // Convert value with tag ids into value with addresses.
+    fn resolve_value(&self, value: &TagType, addresses: &[u64]) -> Result<TagType, DatabaseError> {
+        let resolve = |entry: &AddressEntry| -> Result<AddressEntry, DatabaseError> {
+            match self.tag_lookup.get(&entry.address) {
+                Some(i) => Ok(AddressEntry::new(entry.name, addresses[*i])),
+                None => Err(DatabaseErrorKind::TagValidity.into()),
+            }
+        };
+
+        Ok(match value {
+            TagType::AddressEntry(entry) => TagType::AddressEntry(resolve(entry)?),
+            TagType::AddressList(list) => {
+                let mut array = Vec::with_capacity(list.array.len());
+                for entry in list.array.iter() {
+                    array.push(resolve(entry)?);
+                }
+                TagType::AddressList(AddressList::new(array))
+            }
+            TagType::ValueReference(reference) => match self.tag_lookup.get(&reference.address) {
+                Some(i) => TagType::ValueReference(ValueReference::new(addresses[*i])),
+                None => return Err(DatabaseErrorKind::TagValidity.into()),
+            },
+            other => other.clone(),
+        })
+    }
+
+    // Depth and full path of every tag. Depth is counted through the first parent.
+    // Every parent must be added to the writer.
+    fn tag_paths(&self) -> Result<Vec<Vec<u64>>, DatabaseError> {
+        if self
+            .tags
+            .iter()
+            .any(|x| x.parents.iter().any(|x| !self.tag_lookup.contains_key(x)))
+        {
+            return Err(DatabaseErrorKind::TagValidity.into());
+        }
+
+        let mut paths: Vec<Option<Vec<u64>>> = vec![None; self.tags.len()];
+
+        for start in 0..self.tags.len() {
+            // Walk up the first parents until a tag with known path, then fill the chain back.
+            let mut chain: Vec<usize> = Vec::new();
+            let mut current = Some(start);
+            while let Some(i) = current {
+                if paths[i].is_some() {
+                    break;
+                }
+                if chain.contains(&i) {
+                    return Err(DatabaseErrorKind::TagValidity.into());
+                }
+                chain.push(i);
+                current = match self.tags[i].parents.first() {
+                    Some(parent) => match self.tag_lookup.get(parent) {
+                        Some(v) => Some(*v),
+                        None => return Err(DatabaseErrorKind::TagValidity.into()),
+                    },
+                    None => None,
+                };
+            }
+
+            for i in chain.into_iter().rev() {
+                let mut path = match self.tags[i].parents.first() {
+                    Some(parent) => paths[self.tag_lookup[parent]].clone().unwrap(),
+                    None => Vec::new(),
+                };
+                path.push(self.tags[i].name);
+                paths[i] = Some(path);
+            }
+        }
+
+        Ok(paths.into_iter().map(|x| x.unwrap()).collect())
+    }
+
+    // Encode the cluster, as if it starts at cluster_offset of the file.
+    // next_cluster is left as 0x00.
+    pub fn to_bytes(&self, cluster_offset: u64) -> Result<Vec<u8>, DatabaseError> {
+        let paths = self.tag_paths()?;
+
+        // Names section
+        let mut names_section: Vec<u8> = Vec::new();
+        for name in self.names.names().iter() {
+            names_section.append(&mut DatabaseWriter::encode_name_entry(
+                name,
+                self.text_encoding,
+                self.names_index_padding,
+            )?);
+        }
+
+        // Tags section size doesn't depend on offsets, so tag data can be placed right after it.
+        let tags_section_size: u64 = paths
+            .iter()
+            .map(|x| 24 + x.len() as u64 * 8 + 8 + u64::from(self.data_index_padding))
+            .sum();
+
+        let names_size: u32 = match names_section.len().try_into() {
+            Ok(v) => v,
+            Err(_) => return Err(DatabaseErrorKind::IndexTableValidity.into()),
+        };
+        let tags_size: u32 = match tags_section_size.try_into() {
+            Ok(v) => v,
+            Err(_) => return Err(DatabaseErrorKind::IndexTableValidity.into()),
+        };
+
+        // Unresolved values reference tags, which map to addresses one to one,
+        // so size of the reference count table is known before addresses are.
+        let mut tag_references = ReferenceCountTable::new(Vec::new());
+        for tag in self.tags.iter() {
+            tag_references.add_value(tag.name, &tag.value);
+        }
+        let references_size: u32 = match tag_references.to_bytes().len().try_into() {
+            Ok(v) => v,
+            Err(_) => return Err(DatabaseErrorKind::IndexTableValidity.into()),
+        };
+
+        let index_table_size = INDEX_TABLE_HEADER_SIZE
+            + u64::from(names_size)
+            + tags_section_size
+            + u64::from(references_size);
+
+        // Encode values first, record sizes are needed to determine addresses.
+        let mut encoded: Vec<(u8, Vec<u8>)> = Vec::with_capacity(self.tags.len());
+        for tag in self.tags.iter() {
+            // Addresses are fixed-size, placeholder resolution is enough to know the size.
+            let (tag_data_type, data) =
+                DatabaseWriter::encode_tag_data(&tag.value, self.text_encoding)?;
+            encoded.push((tag_data_type, data));
+        }
+
+        let mut addresses: Vec<u64> = Vec::with_capacity(self.tags.len());
+        let mut address = index_table_size;
+        for (tag, (_, data)) in self.tags.iter().zip(encoded.iter()) {
+            addresses.push(address);
+            address += TAG_DATA_HEADER_SIZE
+                + tag.parents.len() as u64 * 16
+                + data.len() as u64
+                + u64::from(self.tag_data_padding);
+        }
+
+        // Tags section
+        let mut tags_section: Vec<u8> = Vec::new();
+        for (i, tag) in self.tags.iter().enumerate() {
+            tags_section.append(&mut DatabaseWriter::encode_tag_index_entry(
+                &TagIndex::new(
+                    tag.tag_id,
+                    tag.name,
+                    paths[i].len() as u64 - 1,
+                    paths[i].clone(),
+                    addresses[i],
+                ),
+                self.data_index_padding,
+            ));
+        }
+
+        // Tag data
+        let mut tag_data: Vec<u8> = Vec::new();
+        let mut references = ReferenceCountTable::new(Vec::new());
+        for (i, tag) in self.tags.iter().enumerate() {
+            let value = self.resolve_value(&tag.value, &addresses)?;
+            references.add_value(tag.name, &value);
+            let (tag_data_type, data) =
+                DatabaseWriter::encode_tag_data(&value, self.text_encoding)?;
+            let parents: Vec<AddressEntry> = tag
+                .parents
+                .iter()
+                .map(|x| {
+                    let parent_index = self.tag_lookup[x];
+                    AddressEntry::new(self.tags[parent_index].name, addresses[parent_index])
+                })
+                .collect();
+
+            tag_data.append(&mut DatabaseWriter::encode_tag_record(
+                tag.tag_id,
+                tag.name,
+                paths[i].len() as u64 - 1,
+                &parents,
+                tag_data_type,
+                &data,
+                self.tag_data_padding,
+            ));
+        }
+
+        let index_table_offset = cluster_offset + CLUSTER_METADATA_SIZE;
+        let database_size = CLUSTER_METADATA_SIZE + index_table_size + tag_data.len() as u64;
+
+        let mut buf: Vec<u8> = Vec::with_capacity(database_size as usize);
+        // Cluster metadata
+        buf.extend_from_slice(b"BTAG");
+        buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buf.extend_from_slice(&self.cluster_index.to_le_bytes());
+        buf.extend_from_slice(&index_table_offset.to_le_bytes());
+        buf.extend_from_slice(&self.text_encoding.code().to_le_bytes());
+        buf.extend_from_slice(&database_size.to_le_bytes());
+        buf.extend_from_slice(&self.names.last_name_index().to_le_bytes());
+        buf.extend_from_slice(&self.names_index_padding.to_le_bytes());
+        buf.extend_from_slice(&self.data_index_padding.to_le_bytes());
+        buf.extend_from_slice(&self.tag_data_padding.to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes());
+
+        // Index table page
+        buf.extend_from_slice(&index_table_size.to_le_bytes());
+        buf.extend_from_slice(&names_size.to_le_bytes());
+        buf.extend_from_slice(&INDEX_TABLE_HEADER_SIZE.to_le_bytes());
+        buf.extend_from_slice(&tags_size.to_le_bytes());
+        buf.extend_from_slice(&(INDEX_TABLE_HEADER_SIZE + u64::from(names_size)).to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.extend_from_slice(&references_size.to_le_bytes());
+        buf.extend_from_slice(
+            &(INDEX_TABLE_HEADER_SIZE + u64::from(names_size) + tags_section_size).to_le_bytes(),
+        );
+        buf.extend_from_slice(&names_section);
+        buf.extend_from_slice(&tags_section);
+        buf.extend_from_slice(&references.to_bytes());
+
+        buf.extend_from_slice(&tag_data);
+
+        Ok(buf)
+    }
+
+    // Write single cluster database file.
+    pub fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<(), DatabaseError> {
+        DatabaseWriter::write_clusters(path, &[self])
+    }
+
+    // Write several clusters into one file, chaining them with next_cluster.
+    pub fn write_clusters<P: AsRef<Path>>(
+        path: P,
+        writers: &[&DatabaseWriter],
+    ) -> Result<(), DatabaseError> {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut previous_cluster: Option<usize> = None;
+
+        for writer in writers {
+            let cluster_offset = buf.len();
+            if let Some(previous) = previous_cluster {
+                // Patch next_cluster of previous cluster, which is the last field of metadata.
+                let next_cluster = previous + CLUSTER_METADATA_SIZE as usize - 8;
+                buf[next_cluster..next_cluster + 8]
+                    .copy_from_slice(&(cluster_offset as u64).to_le_bytes());
+            }
+            buf.append(&mut writer.to_bytes(cluster_offset as u64)?);
+            previous_cluster = Some(cluster_offset);
+        }
+
+        if let Err(e) = fs::write(&path, buf) {
+            return Err(DatabaseError::from(e).with_path(path));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DatabaseReader, DATA_TYPE_FREE};
+
+    #[test]
+    fn char_values_round_trip() {
+        // Char[] is char_size(u8) text(char_size).
+        for text in ["", "jo", "žlutý", &"a".repeat(255)] {
+            let value = TagType::Char(text.to_string());
+            let (tag_data_type, data) =
+                DatabaseWriter::encode_tag_data(&value, TextEncoding::Utf8).unwrap();
+            assert_eq!(tag_data_type, DATA_TYPE_CHAR);
+            assert_eq!(data[0] as usize, text.len());
+            assert_eq!(data.len(), 1 + text.len());
+            assert_eq!(
+                DatabaseReader::decode_tag_data(tag_data_type, &data, TextEncoding::Utf8).unwrap(),
+                value
+            );
+        }
+        assert_eq!(
+            DatabaseWriter::encode_tag_data(&TagType::Char("a".repeat(256)), TextEncoding::Utf8)
+                .unwrap_err()
+                .kind(),
+            DatabaseErrorKind::StringValidity
+        );
+        // char_size past the end of the value.
+        assert_eq!(
+            DatabaseReader::decode_tag_data(DATA_TYPE_CHAR, &[3, b'j', b'o'], TextEncoding::Utf8)
+                .unwrap_err()
+                .kind(),
+            DatabaseErrorKind::TagValidity
+        );
+    }
+
+    #[test]
+    fn unknown_type_code_is_an_error() {
+        for tag_data_type in [8, 100, DATA_TYPE_FREE] {
+            assert_eq!(
+                DatabaseReader::decode_tag_data(tag_data_type, &[0; 16], TextEncoding::Utf8)
+                    .unwrap_err()
+                    .kind(),
+                DatabaseErrorKind::UnsupportedDataType
+            );
+        }
+    }
+
+    #[test]
+    fn every_parent_must_be_a_tag_of_the_writer() {
+        let mut writer = DatabaseWriter::new(0, TextEncoding::Utf8);
+        let users = writer.add_name("users").unwrap();
+        let joey = writer.add_name("joey").unwrap();
+        writer
+            .add_tag(
+                0,
+                users,
+                &[],
+                TagType::AddressList(AddressList::new(Vec::new())),
+            )
+            .unwrap();
+
+        // Tag as it's own parent, or the same parent twice.
+        for parents in [&[0, 1][..], &[1][..], &[0, 0][..]] {
+            assert_eq!(
+                writer
+                    .add_tag(1, joey, parents, TagType::Integer(1))
+                    .unwrap_err()
+                    .kind(),
+                DatabaseErrorKind::TagValidity
+            );
+        }
+        assert!(!writer.contains_tag(1));
+
+        // Parents that were never added are found when encoding, not only the first one.
+        writer
+            .add_tag(1, joey, &[0, 99], TagType::Integer(1))
+            .unwrap();
+        assert_eq!(
+            writer.to_bytes(0).unwrap_err().kind(),
+            DatabaseErrorKind::TagValidity
+        );
+        writer.add_tag(99, users, &[], TagType::Integer(2)).unwrap();
+        assert!(writer.to_bytes(0).is_ok());
+    }
+}
@@ -0,0 +1,210 @@
+// Every command of `btag` reports through it's exit status, stdout and stderr.
+
+mod common;
+
+use std::{ffi::OsStr, fs, path::Path, process::Command};
+
+use btag::*;
+use common::*;
+
+// users.joey.wallet.euro #3, total #5 holds an AddressEntry of it.
+fn users() -> DatabaseWriter {
+    let mut writer = cluster(
+        0,
+        &[
+            (0, "users", &[], None),
+            (1, "joey", &[0], None),
+            (2, "wallet", &[1], None),
+            (3, "euro", &[2], Some(TagType::Double(12.5))),
+            (4, "name", &[1], Some(TagType::Text("joey".to_string()))),
+            (5, "total", &[], None),
+        ],
+    );
+    let euro = writer.name("euro").unwrap();
+    writer
+        .set_value(5, TagType::AddressEntry(AddressEntry::new(euro, 3)))
+        .unwrap();
+    writer
+}
+
+// Exit code, stdout and stderr of the command.
+fn btag(args: &[&dyn AsRef<OsStr>]) -> (Option<i32>, String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_btag"))
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        output.status.code(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+fn display(path: &Path) -> String {
+    path.display().to_string()
+}
+
+#[test]
+fn database_is_opened() {
+    let dir = test_dir("cli-open");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users()]);
+
+    let (code, stdout, stderr) = btag(&[&"info", &path]);
+    assert_eq!((code, stderr.as_str()), (Some(0), ""));
+    assert!(stdout.starts_with("cluster 0\n  version              2\n"));
+    let (code, stdout, _) = btag(&[&"get", &path, &"users.joey.wallet.euro"]);
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, "#3 users.joey.wallet.euro = 12.5\n");
+    // Directory is opened as one database.
+    let (code, stdout, _) = btag(&[&"get", &dir.to_path_buf(), &"total"]);
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, "#5 total = euro #3\n");
+}
+
+#[test]
+fn missing_file_and_usage_are_errors() {
+    let dir = test_dir("cli-missing");
+    let path = dir.join("missing.btag");
+
+    for command in ["info", "verify", "export", "upgrade"] {
+        let (code, stdout, stderr) = btag(&[&command, &path]);
+        assert_eq!((code, stdout.as_str()), (Some(1), ""));
+        assert!(stderr.starts_with(&format!("btag: I/O error in {}", display(&path))));
+    }
+    let (code, _, stderr) = btag(&[&"repair", &path, &dir.join("repaired.btag")]);
+    assert_eq!(code, Some(1));
+    assert!(stderr.contains(&display(&path)));
+    assert!(!dir.join("repaired.btag").exists());
+
+    // Unknown commands and wrong number of arguments print usage.
+    let usages: [&[&dyn AsRef<OsStr>]; 3] = [&[], &[&"open"], &[&"repair", &path]];
+    for args in usages {
+        let (code, stdout, stderr) = btag(args);
+        assert_eq!((code, stdout.as_str()), (Some(2), ""));
+        assert!(stderr.starts_with("usage: btag <command>"));
+    }
+}
+
+#[test]
+fn verify_reports_problems() {
+    let dir = test_dir("cli-verify");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users()]);
+
+    let (code, stdout, stderr) = btag(&[&"verify", &path]);
+    assert_eq!((code, stderr.as_str()), (Some(0), ""));
+    assert_eq!(
+        stdout,
+        format!("{}: 1 clusters, 6 tags, 0 problems\n", display(&path))
+    );
+
+    // database_size(u64) past the end of the file.
+    let mut bytes = fs::read(&path).unwrap();
+    let database_size = bytes.len() as u64 + 100;
+    bytes[26..34].copy_from_slice(&database_size.to_le_bytes());
+    let broken = dir.join("broken.btag");
+    fs::write(&broken, &bytes).unwrap();
+    let (code, stdout, _) = btag(&[&"verify", &path, &broken]);
+    assert_eq!(code, Some(1));
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].contains("of cluster 0"));
+    assert_eq!(
+        lines[2],
+        format!("{}: 1 clusters, 6 tags, 1 problems", display(&broken))
+    );
+}
+
+#[test]
+fn repair_writes_fixed_copy() {
+    let dir = test_dir("cli-repair");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users()]);
+    let repaired = dir.join("repaired.btag");
+
+    let (code, stdout, stderr) = btag(&[&"repair", &path, &repaired]);
+    assert_eq!((code, stderr.as_str()), (Some(0), ""));
+    assert_eq!(
+        stdout,
+        format!(
+            "{}: 1 clusters, 6 tags, 0 dropped addresses\n",
+            display(&repaired)
+        )
+    );
+    assert_valid(&repaired);
+    assert_readable(&repaired);
+}
+
+#[test]
+fn upgrade_rewrites_old_clusters() {
+    let dir = test_dir("cli-upgrade");
+    let path = dir.join("wallets.btag");
+    let wallets = RawCluster::new(
+        0,
+        &[(1, "wallets"), (2, "euro")],
+        vec![
+            RawTag(0, 1, vec![], RawValue::Children(vec![1])),
+            RawTag(1, 2, vec![0], RawValue::Double(12.5)),
+        ],
+    );
+    write_raw(&path, &[&wallets]);
+
+    let (code, stdout, stderr) = btag(&[&"upgrade", &path]);
+    assert_eq!((code, stderr.as_str()), (Some(0), ""));
+    assert_eq!(
+        stdout,
+        format!(
+            "cluster 0: upgraded to version {}\n{}: 1 clusters upgraded\n",
+            FORMAT_VERSION,
+            display(&path)
+        )
+    );
+    assert_valid(&path);
+
+    let (code, stdout, _) = btag(&[&"upgrade", &path]);
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, format!("{}: 0 clusters upgraded\n", display(&path)));
+}
+
+#[test]
+fn export_and_import_keep_the_database() {
+    let dir = test_dir("cli-json");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users()]);
+
+    let (code, json, stderr) = btag(&[&"export", &"--ids", &path]);
+    assert_eq!((code, stderr.as_str()), (Some(0), ""));
+    assert!(json.contains("\"$id\": 3"));
+    let json_path = dir.join("users.json");
+    fs::write(&json_path, &json).unwrap();
+
+    let imported = dir.join("imported.btag");
+    let (code, stdout, stderr) = btag(&[&"import", &"--ids", &json_path, &imported, &"7"]);
+    assert_eq!((code, stderr.as_str()), (Some(0), ""));
+    assert_eq!(
+        stdout,
+        format!("{}: imported {}\n", display(&imported), display(&json_path))
+    );
+    assert_valid(&imported);
+    let (code, exported, _) = btag(&[&"export", &"--ids", &imported]);
+    assert_eq!(code, Some(0));
+    assert_eq!(exported, json);
+    assert_eq!(
+        BTag::open(&imported).unwrap().clusters()[0].cluster_index(),
+        7
+    );
+
+    // Existing files are never overwritten, broken JSON is refused.
+    let (code, _, stderr) = btag(&[&"import", &json_path, &imported]);
+    assert_eq!(code, Some(1));
+    assert_eq!(
+        stderr,
+        format!("btag: {} already exists\n", display(&imported))
+    );
+    fs::write(&json_path, "{\"users\": ").unwrap();
+    let (code, _, stderr) = btag(&[&"import", &json_path, &dir.join("broken.btag")]);
+    assert_eq!(code, Some(1));
+    assert!(stderr.starts_with("btag: "));
+    assert!(!dir.join("broken.btag").exists());
+}
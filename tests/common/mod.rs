@@ -0,0 +1,385 @@
+// Helpers shared by integration tests.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use btag::*;
+
+// Empty directory of the test, removed once the test ends, whether it passes or not.
+pub struct TestDir(PathBuf);
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+pub fn test_dir(name: &str) -> TestDir {
+    let dir = std::env::temp_dir().join(format!("btag-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    TestDir(dir)
+}
+
+// Value of a tag written by hand. Children are tag ids of the AddressList entries,
+// Reference is tag id of the referenced tag.
+pub enum RawValue {
+    Integer(u64),
+    Double(f64),
+    Text(&'static str),
+    Children(Vec<u64>),
+    Reference(u64),
+}
+
+// tag_id, name, tag ids of parents and value of a tag written by hand.
+pub struct RawTag(pub u64, pub u64, pub Vec<u64>, pub RawValue);
+
+// Cluster written byte by byte, following "Database cluster metadata" through "Tag data"
+// of docs/specification.md. Names and tags are split into given number of index table
+// pages, tag data records follow the last page. Version 2 pages also hold references
+// of values of their own tags.
+pub struct RawCluster {
+    pub cluster_index: u64,
+    pub names: Vec<(u64, &'static str)>,
+    pub tags: Vec<RawTag>,
+    pub pages: usize,
+    pub version: u32,
+}
+
+impl RawCluster {
+    pub fn new(cluster_index: u64, names: &[(u64, &'static str)], tags: Vec<RawTag>) -> Self {
+        RawCluster {
+            cluster_index,
+            names: names.to_vec(),
+            tags,
+            pages: 1,
+            version: 1,
+        }
+    }
+
+    // Names from the root to the tag, following first parents.
+    pub fn full_path(&self, tag_id: u64) -> Vec<u64> {
+        let tag = self.tags.iter().find(|x| x.0 == tag_id).unwrap();
+        match tag.2.first() {
+            Some(v) => [self.full_path(*v), vec![tag.1]].concat(),
+            None => vec![tag.1],
+        }
+    }
+
+    fn name_entry(name: u64, name_string: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&name.to_le_bytes());
+        buf.extend_from_slice(&(name_string.len() as u16).to_le_bytes());
+        buf.extend_from_slice(name_string.as_bytes());
+        buf
+    }
+
+    fn tag_entry(&self, tag: &RawTag, offset: u64) -> Vec<u8> {
+        let full_path = self.full_path(tag.0);
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&tag.0.to_le_bytes());
+        buf.extend_from_slice(&tag.1.to_le_bytes());
+        buf.extend_from_slice(&(full_path.len() as u64 - 1).to_le_bytes());
+        for name in full_path {
+            buf.extend_from_slice(&name.to_le_bytes());
+        }
+        buf.extend_from_slice(&offset.to_le_bytes());
+        buf
+    }
+
+    // tag_data_type and tag_data of the value, given address of every tag.
+    fn tag_data(&self, value: &RawValue, address: &dyn Fn(u64) -> u64) -> (u8, Vec<u8>) {
+        match value {
+            RawValue::Integer(v) => (0, v.to_le_bytes().to_vec()),
+            RawValue::Double(v) => (2, v.to_le_bytes().to_vec()),
+            RawValue::Text(v) => {
+                let mut buf = (v.len() as u16).to_le_bytes().to_vec();
+                buf.extend_from_slice(v.as_bytes());
+                (5, buf)
+            }
+            RawValue::Children(v) => {
+                let mut buf = (v.len() as u64).to_le_bytes().to_vec();
+                for tag_id in v {
+                    let tag = self.tags.iter().find(|x| x.0 == *tag_id).unwrap();
+                    buf.extend_from_slice(&tag.1.to_le_bytes());
+                    buf.extend_from_slice(&address(*tag_id).to_le_bytes());
+                }
+                (4, buf)
+            }
+            RawValue::Reference(v) => (7, address(*v).to_le_bytes().to_vec()),
+        }
+    }
+
+    // REFERENCE_COUNT_TABLE entries of values of the tags, in order of first reference.
+    fn references(&self, tags: &[RawTag], address: &dyn Fn(u64) -> u64) -> Vec<u8> {
+        let mut references: Vec<(u64, Vec<u64>)> = Vec::new();
+        for tag in tags {
+            let referenced = match &tag.3 {
+                RawValue::Children(v) => v.clone(),
+                RawValue::Reference(v) => vec![*v],
+                _ => Vec::new(),
+            };
+            for tag_id in referenced {
+                let address = address(tag_id);
+                match references.iter_mut().find(|x| x.0 == address) {
+                    Some(v) => v.1.push(tag.1),
+                    None => references.push((address, vec![tag.1])),
+                }
+            }
+        }
+
+        let mut buf = Vec::new();
+        for (address, names) in references {
+            buf.extend_from_slice(&address.to_le_bytes());
+            buf.extend_from_slice(&(names.len() as u64).to_le_bytes());
+            for name in names {
+                buf.extend_from_slice(&name.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    fn record_size(&self, tag: &RawTag) -> u64 {
+        let (_, data) = self.tag_data(&tag.3, &|_| 0);
+        40 + 16 * tag.2.len() as u64 + 9 + data.len() as u64
+    }
+
+    // Bytes of the cluster placed at cluster_offset of it's file.
+    pub fn to_bytes(&self, cluster_offset: u64, next_cluster: u64) -> Vec<u8> {
+        let pages = self.pages.max(1);
+        let chunk = |count: usize, page: usize| {
+            let size = count.div_ceil(pages);
+            (size * page).min(count)..(size * (page + 1)).min(count)
+        };
+
+        // Page sizes don't depend on addresses, records start after the last page.
+        let header_size: u64 = if self.version >= 2 { 52 } else { 40 };
+        let names: Vec<Vec<u8>> = self
+            .names
+            .iter()
+            .map(|(name, name_string)| RawCluster::name_entry(*name, name_string))
+            .collect();
+        let entries: Vec<Vec<u8>> = self.tags.iter().map(|x| self.tag_entry(x, 0)).collect();
+        let page_sizes: Vec<u64> = (0..pages)
+            .map(|page| {
+                let names_size: usize = names[chunk(names.len(), page)].iter().map(Vec::len).sum();
+                let tags_size: usize = entries[chunk(entries.len(), page)]
+                    .iter()
+                    .map(Vec::len)
+                    .sum();
+                let references_size = match self.version {
+                    1 => 0,
+                    // Tag ids stand in for addresses, both are unique.
+                    _ => self
+                        .references(&self.tags[chunk(self.tags.len(), page)], &|x| x)
+                        .len(),
+                };
+                header_size + (names_size + tags_size + references_size) as u64
+            })
+            .collect();
+        let mut addresses = Vec::new();
+        let mut address: u64 = page_sizes.iter().sum();
+        for tag in self.tags.iter() {
+            addresses.push((tag.0, address));
+            address += self.record_size(tag);
+        }
+        let address = |tag_id: u64| addresses.iter().find(|x| x.0 == tag_id).unwrap().1;
+
+        let mut index_table = Vec::new();
+        for page in 0..pages {
+            let names_section: Vec<u8> = names[chunk(names.len(), page)].concat();
+            let tags_section: Vec<u8> = self.tags[chunk(self.tags.len(), page)]
+                .iter()
+                .flat_map(|x| self.tag_entry(x, address(x.0)))
+                .collect();
+            let next_page = if page + 1 < pages {
+                page_sizes[page]
+            } else {
+                0
+            };
+            let tags_offset = header_size + names_section.len() as u64;
+            index_table.extend_from_slice(&page_sizes[page].to_le_bytes());
+            index_table.extend_from_slice(&(names_section.len() as u32).to_le_bytes());
+            index_table.extend_from_slice(&header_size.to_le_bytes());
+            index_table.extend_from_slice(&(tags_section.len() as u32).to_le_bytes());
+            index_table.extend_from_slice(&tags_offset.to_le_bytes());
+            index_table.extend_from_slice(&next_page.to_le_bytes());
+            let references_section = match self.version {
+                1 => Vec::new(),
+                _ => {
+                    let references =
+                        self.references(&self.tags[chunk(self.tags.len(), page)], &address);
+                    index_table.extend_from_slice(&(references.len() as u32).to_le_bytes());
+                    index_table.extend_from_slice(
+                        &(tags_offset + tags_section.len() as u64).to_le_bytes(),
+                    );
+                    references
+                }
+            };
+            index_table.extend(names_section);
+            index_table.extend(tags_section);
+            index_table.extend(references_section);
+        }
+
+        let mut records = Vec::new();
+        for tag in self.tags.iter() {
+            let (tag_data_type, data) = self.tag_data(&tag.3, &address);
+            let full_path = self.full_path(tag.0);
+            records.extend_from_slice(&tag.0.to_le_bytes());
+            records.extend_from_slice(&self.record_size(tag).to_le_bytes());
+            records.extend_from_slice(&tag.1.to_le_bytes());
+            records.extend_from_slice(&(full_path.len() as u64 - 1).to_le_bytes());
+            records.extend_from_slice(&(16 * tag.2.len() as u64).to_le_bytes());
+            for parent in tag.2.iter() {
+                let name = self.tags.iter().find(|x| x.0 == *parent).unwrap().1;
+                records.extend_from_slice(&name.to_le_bytes());
+                records.extend_from_slice(&address(*parent).to_le_bytes());
+            }
+            records.push(tag_data_type);
+            records.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            records.extend(data);
+        }
+
+        let last_name_index = self.names.iter().map(|x| x.0).max().unwrap_or(0);
+        let database_size = 62 + (index_table.len() + records.len()) as u64;
+        let mut buf = Vec::new();
+        buf.extend_from_slice(b"BTAG");
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        buf.extend_from_slice(&self.cluster_index.to_le_bytes());
+        buf.extend_from_slice(&(cluster_offset + 62).to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&database_size.to_le_bytes());
+        buf.extend_from_slice(&last_name_index.to_le_bytes());
+        buf.extend_from_slice(&[0; 12]);
+        buf.extend_from_slice(&next_cluster.to_le_bytes());
+        buf.extend(index_table);
+        buf.extend(records);
+        buf
+    }
+}
+
+// Write clusters one after another into a single file, chained by next_cluster.
+pub fn write_raw(path: &Path, clusters: &[&RawCluster]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (i, cluster) in clusters.iter().enumerate() {
+        let size = cluster.to_bytes(0, 0).len() as u64;
+        let cluster_offset = bytes.len() as u64;
+        let next_cluster = if i + 1 < clusters.len() {
+            cluster_offset + size
+        } else {
+            0
+        };
+        bytes.extend(cluster.to_bytes(cluster_offset, next_cluster));
+    }
+    fs::write(path, &bytes).unwrap();
+    bytes
+}
+
+// Tag added by DatabaseWriter: tag_id, name string, tag ids of parents and value.
+// Tag without a value gets AddressList of every tag that has it as a parent.
+pub type Tag<'a> = (u64, &'a str, &'a [u64], Option<TagType>);
+
+pub fn cluster(cluster_index: u64, tags: &[Tag]) -> DatabaseWriter {
+    encoded_cluster(cluster_index, TextEncoding::Utf8, tags)
+}
+
+// Cluster with names and strings in the given text_encoding.
+pub fn encoded_cluster(cluster_index: u64, encoding: TextEncoding, tags: &[Tag]) -> DatabaseWriter {
+    let mut writer = DatabaseWriter::new(cluster_index, encoding);
+    for (tag_id, name_string, parents, value) in tags {
+        let value = match value {
+            Some(v) => v.clone(),
+            None => TagType::AddressList(AddressList::new(
+                tags.iter()
+                    .filter(|x| x.2.contains(tag_id))
+                    .map(|x| AddressEntry::new(writer.add_name(x.1).unwrap(), x.0))
+                    .collect(),
+            )),
+        };
+        let name = writer.add_name(name_string).unwrap();
+        writer.add_tag(*tag_id, name, parents, value).unwrap();
+    }
+    writer
+}
+
+// Write clusters into a single file, in the given order.
+pub fn write_clusters(path: &Path, clusters: &[DatabaseWriter]) {
+    let writers: Vec<&DatabaseWriter> = clusters.iter().collect();
+    DatabaseWriter::write_clusters(path, &writers).unwrap();
+}
+
+// Database must pass verification without a single problem.
+pub fn assert_valid(path: &Path) {
+    let report = DatabaseReader::open(path).unwrap().verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems());
+}
+
+// Ids of tags matched by the query, in order of matches.
+pub fn find_ids(btag: &mut BTag, query: &str) -> Vec<u64> {
+    let query = match parse_query(query).unwrap().into_iter().next() {
+        Some(Statement::Get(v)) => v,
+        _ => panic!("expected query"),
+    };
+    btag.find(&query)
+        .unwrap()
+        .into_iter()
+        .map(|(cluster_index, path)| {
+            let address = path.array().last().unwrap().address();
+            btag.tag_at(cluster_index, address).unwrap().tag_id()
+        })
+        .collect()
+}
+
+pub fn offset_of(btag: &BTag, tag_id: u64) -> u64 {
+    btag.locate_tag(tag_id).unwrap().1.offset()
+}
+
+// Every tag of the database must be readable, with parents and AddressList entries
+// pointing at records of tags of the same cluster.
+pub fn assert_readable(path: &Path) {
+    let mut btag = BTag::open(path).unwrap();
+    let clusters: Vec<u64> = btag.clusters().iter().map(|x| x.cluster_index()).collect();
+    for cluster_index in clusters {
+        let tags: Vec<(u64, u64)> = btag
+            .tags_index(cluster_index)
+            .unwrap()
+            .tags()
+            .iter()
+            .map(|x| (x.tag_id(), x.offset()))
+            .collect();
+        for (tag_id, _) in tags.iter() {
+            let tag = btag.read_tag(*tag_id).unwrap();
+            assert_eq!(tag.tag_id(), *tag_id);
+            let children = match tag.tag_data() {
+                TagType::AddressList(v) => v.array().to_vec(),
+                _ => Vec::new(),
+            };
+            for entry in tag.tag_parents().array().iter().chain(children.iter()) {
+                assert!(
+                    tags.iter().any(|x| x.1 == entry.address()),
+                    "#{} points nowhere: {:?}",
+                    tag_id,
+                    entry
+                );
+            }
+        }
+    }
+}
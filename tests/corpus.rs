@@ -0,0 +1,347 @@
+// Truncated and corrupted databases must be rejected with an error, never with a panic.
+// Every test starts from a valid database and derives the corpus from it deterministically.
+// By default every 7th position is corrupted, whole corpus is run with `cargo test -- --ignored`.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use btag::*;
+use common::*;
+
+const QUERIES: &[&str] = &[
+    "users.joey.wallet.euro",
+    "users.*.name",
+    "wallet.euro..",
+    "euro...$name=joey",
+    "#2.wallet",
+    "(1..&2..)$peq",
+];
+
+// Step between corrupted positions of the default corpus. It's odd, so every byte
+// of u64 fields gets corrupted somewhere.
+const SAMPLE_STEP: usize = 7;
+
+fn write_database(path: &Path) {
+    let mut users = DatabaseWriter::new(0, TextEncoding::Utf8);
+    let users_name = users.add_name("users").unwrap();
+    let joey = users.add_name("joey").unwrap();
+    let wallet = users.add_name("wallet").unwrap();
+    let euro = users.add_name("euro").unwrap();
+    let name = users.add_name("name").unwrap();
+    let nick = users.add_name("nick").unwrap();
+    let total = users.add_name("total").unwrap();
+    users
+        .add_tag(
+            0,
+            users_name,
+            &[],
+            TagType::AddressList(AddressList::new(vec![AddressEntry::new(joey, 1)])),
+        )
+        .unwrap();
+    users
+        .add_tag(
+            1,
+            joey,
+            &[0],
+            TagType::AddressList(AddressList::new(vec![
+                AddressEntry::new(wallet, 2),
+                AddressEntry::new(name, 4),
+                AddressEntry::new(nick, 5),
+            ])),
+        )
+        .unwrap();
+    users
+        .add_tag(
+            2,
+            wallet,
+            &[1],
+            TagType::AddressList(AddressList::new(vec![AddressEntry::new(euro, 3)])),
+        )
+        .unwrap();
+    users.add_tag(3, euro, &[2], TagType::Double(12.5)).unwrap();
+    users
+        .add_tag(4, name, &[1], TagType::Text("joey".to_string()))
+        .unwrap();
+    users
+        .add_tag(5, nick, &[1], TagType::Char("jo".to_string()))
+        .unwrap();
+    users
+        .add_tag(
+            6,
+            total,
+            &[],
+            TagType::ValueReference(ValueReference::new(3)),
+        )
+        .unwrap();
+
+    let mut numbers = DatabaseWriter::new(1, TextEncoding::Utf16Le);
+    numbers.set_paddings(2, 4, 8);
+    let digits = numbers.add_name("numbers").unwrap();
+    let one = numbers.add_name("1").unwrap();
+    let two = numbers.add_name("2").unwrap();
+    numbers
+        .add_tag(
+            10,
+            digits,
+            &[],
+            TagType::AddressList(AddressList::new(vec![
+                AddressEntry::new(one, 11),
+                AddressEntry::new(two, 12),
+            ])),
+        )
+        .unwrap();
+    numbers
+        .add_tag(11, one, &[10], TagType::Integer(1))
+        .unwrap();
+    numbers
+        .add_tag(12, two, &[10], TagType::Float(2.0))
+        .unwrap();
+
+    DatabaseWriter::write_clusters(path, &[&users, &numbers]).unwrap();
+}
+
+// Open the database and read everything that can be read. Errors are expected.
+fn exercise(path: &Path) {
+    if let Ok(mut reader) = DatabaseReader::open(path) {
+        let _ = reader.verify();
+        let _ = reader.dump_layout(&mut std::io::sink());
+
+        // Whatever could be repaired must be consistent.
+        let repaired = path.with_extension("repaired");
+        if reader.repair(&repaired).is_ok() {
+            let report = DatabaseReader::open(&repaired).unwrap().verify().unwrap();
+            assert!(report.is_ok(), "{:?}", report.problems());
+        }
+    }
+
+    let mut btag = match BTag::open(path) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    let cluster_indexes: Vec<u64> = btag.clusters().iter().map(|x| x.cluster_index()).collect();
+    for cluster_index in cluster_indexes {
+        let tag_ids: Vec<u64> = match btag.tags_index(cluster_index) {
+            Some(v) => v.tags().iter().map(|x| x.tag_id()).collect(),
+            None => Vec::new(),
+        };
+        for tag_id in tag_ids {
+            let _ = btag.read_tag(tag_id);
+            let _ = btag.dereference_tag(tag_id);
+            let _ = btag.referencing_tags(tag_id);
+        }
+    }
+
+    let _ = btag.dump(&mut std::io::sink());
+    // Whatever could be exported must be valid JSON.
+    if let Ok(document) = btag.export_json(true) {
+        assert_eq!(parse_json(&document.to_string()).unwrap(), document);
+    }
+
+    for source in QUERIES {
+        for statement in parse_query(source).unwrap() {
+            if let Statement::Get(query) = statement {
+                let _ = btag.find(&query);
+            }
+        }
+    }
+}
+
+// Small xorshift generator, keeps the corpus the same on every run.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[test]
+fn valid_database_is_readable() {
+    let dir = test_dir("corpus-valid");
+    let path = dir.join("valid.btag");
+    write_database(&path);
+
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(btag.clusters().len(), 2);
+    let report = btag.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems());
+    assert_eq!(report.tags(), 10);
+
+    // Nothing is lost repairing a valid database.
+    let report = DatabaseReader::open(&path)
+        .unwrap()
+        .repair(dir.join("repaired.btag"))
+        .unwrap();
+    assert_eq!((report.clusters(), report.tags()), (2, 10));
+    assert!(report.recovered_tags().is_empty() && report.dropped_tags().is_empty());
+    assert_eq!(report.dropped_addresses(), 0);
+    assert_eq!(
+        btag.dereference_tag(6).unwrap().tag_data(),
+        &TagType::Double(12.5)
+    );
+    assert_eq!(
+        btag.read_tag(5).unwrap().tag_data(),
+        &TagType::Char("jo".to_string())
+    );
+
+    // Tags keep their ids, names and values through JSON.
+    let document = btag.export_json(true).unwrap();
+    let mut writer = DatabaseWriter::new(0, TextEncoding::Utf8);
+    writer
+        .import_json(&parse_json(&format!("{:#}", document)).unwrap(), true)
+        .unwrap();
+    writer.write_file(dir.join("imported.btag")).unwrap();
+    let mut imported = BTag::open(dir.join("imported.btag")).unwrap();
+    assert_eq!(imported.export_json(true).unwrap(), document);
+    exercise(&path);
+}
+
+fn truncated_databases(step: usize) {
+    let dir = test_dir(&format!("corpus-truncated-{}", step));
+    let source = dir.join("source.btag");
+    write_database(&source);
+    let bytes = fs::read(&source).unwrap();
+
+    let path = dir.join("truncated.btag");
+    for size in (0..bytes.len()).step_by(step) {
+        fs::write(&path, &bytes[..size]).unwrap();
+        exercise(&path);
+    }
+}
+
+fn corrupted_bytes(step: usize) {
+    let dir = test_dir(&format!("corpus-bytes-{}", step));
+    let source = dir.join("source.btag");
+    write_database(&source);
+    let bytes = fs::read(&source).unwrap();
+
+    let path = dir.join("corrupted.btag");
+    for position in (0..bytes.len()).step_by(step) {
+        for value in [0x00, 0xFF, 0x80, bytes[position] ^ 0x01] {
+            let mut corrupted = bytes.clone();
+            corrupted[position] = value;
+            fs::write(&path, &corrupted).unwrap();
+            exercise(&path);
+        }
+    }
+}
+
+fn corrupted_sizes(step: usize) {
+    let dir = test_dir(&format!("corpus-sizes-{}", step));
+    let source = dir.join("source.btag");
+    write_database(&source);
+    let bytes = fs::read(&source).unwrap();
+
+    // Sizes, offsets and counts are u64, huge values must not overflow or allocate.
+    let path = dir.join("corrupted.btag");
+    for position in (0..bytes.len().saturating_sub(8)).step_by(step) {
+        for value in [u64::MAX, u64::MAX / 2 + 1, u32::MAX as u64] {
+            let mut corrupted = bytes.clone();
+            corrupted[position..position + 8].copy_from_slice(&value.to_le_bytes());
+            fs::write(&path, &corrupted).unwrap();
+            exercise(&path);
+        }
+    }
+}
+
+fn random_corruption(count: usize) {
+    let dir = test_dir(&format!("corpus-random-{}", count));
+    let source = dir.join("source.btag");
+    write_database(&source);
+    let bytes = fs::read(&source).unwrap();
+
+    let path = dir.join("corrupted.btag");
+    let mut random = Random(0x2545_F491_4F6C_DD1D);
+    for _ in 0..count {
+        let mut corrupted = bytes.clone();
+        for _ in 0..1 + random.next() % 8 {
+            let position = (random.next() % corrupted.len() as u64) as usize;
+            corrupted[position] = random.next() as u8;
+        }
+        fs::write(&path, &corrupted).unwrap();
+        exercise(&path);
+    }
+}
+
+#[test]
+fn corrupted_journals() {
+    let dir = test_dir("corpus-journal");
+    let database = dir.join("journaled.btag");
+    write_database(&database);
+
+    // Interrupted transaction leaves the journal behind.
+    let mut btag = BTag::open(&database).unwrap();
+    btag.begin_journal("#3 = 1").unwrap();
+    btag.set_value(3, TagType::Integer(1)).unwrap();
+    btag.set_value(12, TagType::Text("a longer value".to_string()))
+        .unwrap();
+    drop(btag);
+    let bytes = fs::read(Journal::path_for(&database)).unwrap();
+    assert!(!Journal::read(&Journal::path_for(&database))
+        .unwrap()
+        .writes()
+        .is_empty());
+
+    let path = dir.join("corrupted.journal");
+    for size in 0..bytes.len() {
+        fs::write(&path, &bytes[..size]).unwrap();
+        let _ = Journal::read(&path);
+    }
+    for position in 0..bytes.len() {
+        for value in [0x00, 0xFF, 0x80] {
+            let mut corrupted = bytes.clone();
+            corrupted[position] = value;
+            fs::write(&path, &corrupted).unwrap();
+            let _ = Journal::read(&path);
+        }
+    }
+}
+
+#[test]
+fn sampled_truncated_databases() {
+    truncated_databases(SAMPLE_STEP);
+}
+
+#[test]
+fn sampled_corrupted_bytes() {
+    corrupted_bytes(SAMPLE_STEP);
+}
+
+#[test]
+fn sampled_corrupted_sizes() {
+    corrupted_sizes(SAMPLE_STEP);
+}
+
+#[test]
+fn sampled_random_corruption() {
+    random_corruption(200);
+}
+
+#[test]
+#[ignore]
+fn every_truncated_database() {
+    truncated_databases(1);
+}
+
+#[test]
+#[ignore]
+fn every_corrupted_byte() {
+    corrupted_bytes(1);
+}
+
+#[test]
+#[ignore]
+fn every_corrupted_size() {
+    corrupted_sizes(1);
+}
+
+#[test]
+#[ignore]
+fn all_random_corruption() {
+    random_corruption(2000);
+}
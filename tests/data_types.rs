@@ -0,0 +1,81 @@
+// Values of every type are read back as they were written, unknown type codes are errors.
+// Reference "Data types" of docs/specification.md.
+
+mod common;
+
+use std::fs;
+
+use btag::*;
+use common::*;
+
+fn words() -> DatabaseWriter {
+    cluster(
+        3,
+        &[
+            (0, "words", &[], None),
+            (1, "short", &[0], Some(TagType::Char("jo".to_string()))),
+            (2, "empty", &[0], Some(TagType::Char(String::new()))),
+            (3, "long", &[0], Some(TagType::Text("joey".to_string()))),
+        ],
+    )
+}
+
+#[test]
+fn char_values_round_trip() {
+    let dir = test_dir("data-types-char");
+    let path = dir.join("words.btag");
+    write_clusters(&path, &[words()]);
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(
+        btag.read_tag(1).unwrap().tag_data(),
+        &TagType::Char("jo".to_string())
+    );
+    assert_eq!(btag.read_tag(1).unwrap().tag_data_type(), DATA_TYPE_CHAR);
+    assert_eq!(
+        btag.read_tag(2).unwrap().tag_data(),
+        &TagType::Char(String::new())
+    );
+
+    // Longer value is moved, Text becomes Char[] of the same text.
+    btag.set_value(1, TagType::Char("jason".to_string()))
+        .unwrap();
+    btag.set_value(3, TagType::Char("joey".to_string()))
+        .unwrap();
+    drop(btag);
+    assert_readable(&path);
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(
+        btag.read_tag(1).unwrap().tag_data(),
+        &TagType::Char("jason".to_string())
+    );
+    assert_eq!(
+        btag.read_tag(3).unwrap().tag_data(),
+        &TagType::Char("joey".to_string())
+    );
+}
+
+#[test]
+fn unknown_type_code_is_an_error() {
+    let dir = test_dir("data-types-unknown");
+    let path = dir.join("words.btag");
+    write_clusters(&path, &[words()]);
+    let btag = BTag::open(&path).unwrap();
+    let record = btag.cluster(3).unwrap().index_table_offset() + offset_of(&btag, 1);
+    drop(btag);
+
+    // short has words as it's only parent, tag_data_type follows it.
+    let mut bytes = fs::read(&path).unwrap();
+    bytes[record as usize + 40 + 16] = 42;
+    fs::write(&path, &bytes).unwrap();
+    let mut btag = BTag::open(&path).unwrap();
+    let error = btag.read_tag(1).unwrap_err();
+    assert_eq!(error.kind(), DatabaseErrorKind::UnsupportedDataType);
+    assert_eq!(error.offset(), Some(record));
+    assert_eq!(error.cluster_index(), Some(3));
+    assert_eq!(error.path(), Some(path.as_path()));
+    // Other tags are still read.
+    assert_eq!(
+        btag.read_tag(3).unwrap().tag_data(),
+        &TagType::Text("joey".to_string())
+    );
+}
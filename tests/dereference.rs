@@ -0,0 +1,124 @@
+// ValueReference is stored with it's own type code and followed to the tag holding the value,
+// references that lead back to a visited tag are refused.
+// Reference "ValueReference definition" of docs/specification.md.
+
+mod common;
+
+use std::fs;
+
+use btag::*;
+use common::*;
+
+fn wallet() -> DatabaseWriter {
+    cluster(
+        2,
+        &[
+            (0, "wallet", &[], None),
+            (1, "euro", &[0], Some(TagType::Double(12.5))),
+            (
+                2,
+                "total",
+                &[],
+                Some(TagType::ValueReference(ValueReference::new(1))),
+            ),
+            (
+                3,
+                "alias",
+                &[],
+                Some(TagType::ValueReference(ValueReference::new(2))),
+            ),
+            (4, "other", &[], Some(TagType::Integer(7))),
+        ],
+    )
+}
+
+#[test]
+fn references_are_followed_to_the_value() {
+    let dir = test_dir("dereference-chain");
+    let path = dir.join("wallet.btag");
+    write_clusters(&path, &[wallet()]);
+    let mut btag = BTag::open(&path).unwrap();
+    let euro = offset_of(&btag, 1);
+    let total = offset_of(&btag, 2);
+    let alias = offset_of(&btag, 3);
+
+    let tag = btag.read_tag(3).unwrap();
+    assert_eq!(tag.tag_data_type(), DATA_TYPE_VALUE_REFERENCE);
+    assert_eq!(
+        tag.tag_data(),
+        &TagType::ValueReference(ValueReference::new(total))
+    );
+    assert_eq!(
+        btag.reader(2).unwrap().reference_chain(alias).unwrap(),
+        vec![alias, total, euro]
+    );
+
+    // Tag holding the value is read, tags that aren't references resolve to themselves.
+    for tag_id in [1, 2, 3] {
+        let tag = btag.dereference_tag(tag_id).unwrap();
+        assert_eq!(tag.tag_id(), 1);
+        assert_eq!(tag.tag_data(), &TagType::Double(12.5));
+    }
+    btag.set_value(1, TagType::Double(40.5)).unwrap();
+    assert_eq!(
+        btag.dereference_tag(3).unwrap().tag_data(),
+        &TagType::Double(40.5)
+    );
+
+    // Reference may be pointed at another tag.
+    btag.set_value(
+        2,
+        TagType::ValueReference(ValueReference::new(offset_of(&btag, 4))),
+    )
+    .unwrap();
+    assert_eq!(
+        btag.dereference_tag(3).unwrap().tag_data(),
+        &TagType::Integer(7)
+    );
+    drop(btag);
+    assert_readable(&path);
+}
+
+#[test]
+fn cycles_are_refused() {
+    let dir = test_dir("dereference-cycle");
+    let path = dir.join("wallet.btag");
+    write_clusters(&path, &[wallet()]);
+    let original = fs::read(&path).unwrap();
+
+    // Reference back to the tag itself, directly or through the chain.
+    let mut btag = BTag::open(&path).unwrap();
+    for (tag_id, target) in [(1, 1), (1, 3), (2, 3)] {
+        let address = offset_of(&btag, target);
+        match btag.set_value(
+            tag_id,
+            TagType::ValueReference(ValueReference::new(address)),
+        ) {
+            Err(v) => assert_eq!(v.kind(), DatabaseErrorKind::ReferenceCycle),
+            Ok(_) => panic!("expected #{} = &#{} to be refused", tag_id, target),
+        }
+    }
+    drop(btag);
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    // Cycle written into the file is found while following it.
+    let btag = BTag::open(&path).unwrap();
+    let index_table_offset = btag.cluster(2).unwrap().index_table_offset();
+    let total = offset_of(&btag, 2);
+    let alias = offset_of(&btag, 3);
+    drop(btag);
+    // total has no parents, address follows tag_data_type(u8) and tag_data_size(u64).
+    let mut bytes = original.clone();
+    let position = (index_table_offset + total) as usize + 40 + 9;
+    bytes[position..position + 8].copy_from_slice(&alias.to_le_bytes());
+    fs::write(&path, &bytes).unwrap();
+
+    let mut btag = BTag::open(&path).unwrap();
+    let error = btag.dereference_tag(3).unwrap_err();
+    assert_eq!(error.kind(), DatabaseErrorKind::ReferenceCycle);
+    assert_eq!(error.offset(), Some(index_table_offset + total));
+    assert_eq!(
+        btag.dereference_tag(1).unwrap().tag_data(),
+        &TagType::Double(12.5)
+    );
+}
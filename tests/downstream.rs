@@ -0,0 +1,143 @@
+// Downstream search matches children by name, array index, id and wildcard, start candidates
+// skipped by their full_path never hide a match, not even of linked tags.
+
+mod common;
+
+use btag::*;
+use common::*;
+
+// euro #3 is listed by wallet #2 and linked by admins, best holds AddressEntry of euro #7.
+fn users() -> DatabaseWriter {
+    let mut writer = cluster(
+        0,
+        &[
+            (0, "users", &[], None),
+            (1, "jason", &[0], None),
+            (2, "wallet", &[1], None),
+            (3, "euro", &[2, 8], Some(TagType::Float(12.0))),
+            (4, "dollar", &[2], Some(TagType::Double(40.5))),
+            (5, "joey", &[0], None),
+            (6, "wallet", &[5], None),
+            (7, "euro", &[6], Some(TagType::Integer(1200))),
+            (8, "admins", &[], None),
+            (9, "best", &[], None),
+            (10, "banks", &[], None),
+            (11, "wallet", &[10], None),
+            (12, "yen", &[11], Some(TagType::Integer(5))),
+        ],
+    );
+    let euro = writer.name("euro").unwrap();
+    writer
+        .set_value(9, TagType::AddressEntry(AddressEntry::new(euro, 7)))
+        .unwrap();
+    writer
+}
+
+fn numbers() -> DatabaseWriter {
+    cluster(
+        1,
+        &[
+            (20, "numbers", &[], None),
+            (21, "one", &[20], Some(TagType::Integer(1))),
+        ],
+    )
+}
+
+// Tag ids of the last tag of every match, in the order they are found.
+fn find_ids(btag: &mut BTag, source: &str) -> Vec<u64> {
+    let query = match parse_query(source).unwrap().remove(0) {
+        Statement::Get(v) => v,
+        v => panic!("expected query, found {:?}", v),
+    };
+    btag.find_downstream(&query)
+        .unwrap()
+        .into_iter()
+        .map(|(cluster_index, path)| {
+            let address = path.array().last().unwrap().address();
+            btag.tags_index(cluster_index)
+                .unwrap()
+                .tags()
+                .iter()
+                .find(|x| x.offset() == address)
+                .unwrap()
+                .tag_id()
+        })
+        .collect()
+}
+
+fn name_id(btag: &BTag, name_string: &str) -> u64 {
+    btag.names_index(0)
+        .unwrap()
+        .names()
+        .iter()
+        .find(|x| x.name_string() == name_string)
+        .unwrap()
+        .name()
+}
+
+#[test]
+fn names_indexes_and_wildcards() {
+    let dir = test_dir("downstream-entries");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+    let mut btag = BTag::open(&path).unwrap();
+
+    assert_eq!(find_ids(&mut btag, "wallet.euro"), vec![3, 7]);
+    assert_eq!(find_ids(&mut btag, "users.joey.wallet.euro"), vec![7]);
+    assert_eq!(find_ids(&mut btag, "wallet.yen"), vec![12]);
+    // Only children are matched, not descendants.
+    assert!(find_ids(&mut btag, "users.wallet").is_empty());
+    assert!(find_ids(&mut btag, "users.jason.euro").is_empty());
+
+    // Array indexes of AddressList.
+    assert_eq!(find_ids(&mut btag, "users.1.wallet.0"), vec![7]);
+    assert_eq!(find_ids(&mut btag, "users.0.wallet.1"), vec![4]);
+    assert!(find_ids(&mut btag, "users.*.wallet.2").is_empty());
+    assert_eq!(find_ids(&mut btag, "users.*.wallet.0"), vec![3, 7]);
+
+    // Wildcards match every child, or every tag when they start the query.
+    assert_eq!(find_ids(&mut btag, "users.*.*.dollar"), vec![4]);
+    assert_eq!(find_ids(&mut btag, "users.jason.wallet.*"), vec![3, 4]);
+    assert_eq!(find_ids(&mut btag, "*.wallet.yen"), vec![12]);
+    assert_eq!(find_ids(&mut btag, "*.one"), vec![21]);
+
+    // Ids, as the start and as children.
+    assert_eq!(find_ids(&mut btag, "#6.euro"), vec![7]);
+    assert_eq!(find_ids(&mut btag, "users.#5.wallet"), vec![6]);
+    assert!(find_ids(&mut btag, "users.#6").is_empty());
+
+    // Names missing in a cluster skip it, `:<num>` picks a single match.
+    assert_eq!(find_ids(&mut btag, "numbers.one"), vec![21]);
+    assert!(find_ids(&mut btag, "numbers.euro").is_empty());
+    assert_eq!(find_ids(&mut btag, "wallet.euro:1"), vec![7]);
+}
+
+#[test]
+fn linked_tags_are_found_past_their_full_path() {
+    let dir = test_dir("downstream-linked");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users()]);
+    let mut btag = BTag::open(&path).unwrap();
+
+    // euro #3 is under users.jason.wallet, admins only links it.
+    assert_eq!(btag.tags_index(0).unwrap().tags()[3].full_path().len(), 4);
+    assert_eq!(find_ids(&mut btag, "admins.euro"), vec![3]);
+    assert_eq!(find_ids(&mut btag, "admins.0"), vec![3]);
+    // AddressEntry value has a single child.
+    assert_eq!(find_ids(&mut btag, "best.euro"), vec![7]);
+    assert_eq!(find_ids(&mut btag, "*.euro"), vec![3, 7, 3, 7]);
+
+    // Links added later are taken into account.
+    let euro = offset_of(&btag, 7);
+    let name = name_id(&btag, "euro");
+    btag.set_value(
+        11,
+        TagType::AddressList(AddressList::new(vec![
+            AddressEntry::new(name_id(&btag, "yen"), offset_of(&btag, 12)),
+            AddressEntry::new(name, euro),
+        ])),
+    )
+    .unwrap();
+    assert_eq!(find_ids(&mut btag, "banks.wallet.euro"), vec![7]);
+    assert_eq!(find_ids(&mut btag, "wallet.euro"), vec![3, 7, 7]);
+}
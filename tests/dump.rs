@@ -0,0 +1,92 @@
+// Dumps of a database that has every data type and a moved tag are compared with
+// tests/fixtures/users.dump and tests/fixtures/users.layout.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use btag::*;
+use common::*;
+
+// users.joey.wallet.euro #3 is linked by admins, total and link point at it.
+fn users() -> DatabaseWriter {
+    let mut writer = cluster(
+        0,
+        &[
+            (0, "users", &[], None),
+            (1, "joey", &[0], None),
+            (2, "wallet", &[1], None),
+            (3, "euro", &[2, 5], Some(TagType::Double(12.5))),
+            (4, "name", &[1], Some(TagType::Text("joey".to_string()))),
+            (5, "admins", &[], None),
+            (6, "total", &[], None),
+            (7, "link", &[], None),
+        ],
+    );
+    let euro = writer.name("euro").unwrap();
+    writer
+        .set_value(6, TagType::AddressEntry(AddressEntry::new(euro, 3)))
+        .unwrap();
+    writer
+        .set_value(7, TagType::ValueReference(ValueReference::new(3)))
+        .unwrap();
+    writer
+}
+
+fn numbers() -> DatabaseWriter {
+    cluster(
+        1,
+        &[
+            (10, "numbers", &[], None),
+            (11, "one", &[10], Some(TagType::Integer(1))),
+            (12, "half", &[10], Some(TagType::Float(0.5))),
+            (13, "letters", &[10], Some(TagType::Char("ab".to_string()))),
+        ],
+    )
+}
+
+// Name of joey is moved to the end of the first cluster, leaving a free record behind.
+fn database(path: &Path) {
+    write_clusters(path, &[users(), numbers()]);
+    let mut btag = BTag::open(path).unwrap();
+    btag.set_value(4, TagType::Text("a name that has to be moved".to_string()))
+        .unwrap();
+}
+
+fn fixture(name: &str) -> String {
+    fs::read_to_string(format!("tests/fixtures/{}", name)).unwrap()
+}
+
+#[test]
+fn dump_matches_fixture() {
+    let dir = test_dir("dump-tree");
+    let path = dir.join("users.btag");
+    database(&path);
+
+    let mut out: Vec<u8> = Vec::new();
+    BTag::open(&path).unwrap().dump(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), fixture("users.dump"));
+}
+
+#[test]
+fn dump_layout_matches_fixture() {
+    let dir = test_dir("dump-layout");
+    let path = dir.join("users.btag");
+    database(&path);
+
+    let mut out: Vec<u8> = Vec::new();
+    DatabaseReader::open(&path)
+        .unwrap()
+        .dump_layout(&mut out)
+        .unwrap();
+    let layout = String::from_utf8(out).unwrap();
+    assert_eq!(layout, fixture("users.layout"));
+
+    // Every file of the database is dumped after it's path.
+    let mut out: Vec<u8> = Vec::new();
+    BTag::open(&path).unwrap().dump_layout(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        format!("file {}\n{}", path.display(), layout)
+    );
+}
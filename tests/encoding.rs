@@ -0,0 +1,138 @@
+// Names, Text and Char[] values are written and read in text_encoding of their cluster.
+// Reference "Database cluster metadata" of docs/specification.md.
+
+mod common;
+
+use std::fs;
+
+use btag::*;
+use common::*;
+
+fn cafe() -> DatabaseWriter {
+    encoded_cluster(
+        4,
+        TextEncoding::Latin1,
+        &[
+            (0, "café", &[], None),
+            (1, "crème", &[0], Some(TagType::Text("déjà vu".to_string()))),
+            (2, "prix", &[0], Some(TagType::Char("ÿ".to_string()))),
+        ],
+    )
+}
+
+fn cities() -> DatabaseWriter {
+    encoded_cluster(
+        5,
+        TextEncoding::Utf16Le,
+        &[
+            (10, "města", &[], None),
+            (
+                11,
+                "plzeň",
+                &[10],
+                Some(TagType::Text("žluťoučký kůň €".to_string())),
+            ),
+            (12, "kód", &[10], Some(TagType::Char("日本".to_string()))),
+        ],
+    )
+}
+
+fn contains(bytes: &[u8], buf: &[u8]) -> bool {
+    bytes.windows(buf.len()).any(|x| x == buf)
+}
+
+#[test]
+fn strings_round_trip_in_every_encoding() {
+    let dir = test_dir("encoding-round-trip");
+    let path = dir.join("cities.btag");
+    write_clusters(&path, &[cafe(), cities()]);
+    assert_readable(&path);
+
+    // Strings are stored encoded, sizes are sizes of encoded bytes.
+    let bytes = fs::read(&path).unwrap();
+    assert!(contains(&bytes, b"cr\xe8me"));
+    assert!(contains(
+        &bytes,
+        &[7, 0, b'd', 0xe9, b'j', 0xe0, b' ', b'v', b'u']
+    ));
+    let utf16 = |v: &str| -> Vec<u8> { v.encode_utf16().flat_map(|x| x.to_le_bytes()).collect() };
+    assert!(contains(&bytes, &utf16("plzeň")));
+    assert!(contains(&bytes, &[&[4u8][..], &utf16("日本")].concat()));
+
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(btag.cluster(4).unwrap().encoding(), TextEncoding::Latin1);
+    assert_eq!(btag.cluster(5).unwrap().encoding(), TextEncoding::Utf16Le);
+    let creme = btag.name_id(4, "crème").unwrap();
+    assert_eq!(btag.name_string(4, creme), Some("crème"));
+    assert_eq!(find_ids(&mut btag, "café.crème"), vec![1]);
+    assert_eq!(find_ids(&mut btag, "města.plzeň"), vec![11]);
+    assert_eq!(
+        btag.read_tag(1).unwrap().tag_data(),
+        &TagType::Text("déjà vu".to_string())
+    );
+    assert_eq!(
+        btag.read_tag(2).unwrap().tag_data(),
+        &TagType::Char("ÿ".to_string())
+    );
+    assert_eq!(
+        btag.read_tag(11).unwrap().tag_data(),
+        &TagType::Text("žluťoučký kůň €".to_string())
+    );
+    assert_eq!(
+        btag.read_tag(12).unwrap().tag_data(),
+        &TagType::Char("日本".to_string())
+    );
+
+    // Values and names written later use the encoding as well.
+    btag.set_value(11, TagType::Text("Plzeň – město piva".to_string()))
+        .unwrap();
+    btag.insert_tag(5, 13, "brno", &[10], TagType::Char("ß".to_string()))
+        .unwrap();
+    // Latin-1 has no € sign.
+    assert_eq!(
+        btag.set_value(1, TagType::Text("5 €".to_string()))
+            .unwrap_err()
+            .kind(),
+        DatabaseErrorKind::StringValidity
+    );
+    drop(btag);
+    assert_readable(&path);
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(
+        btag.read_tag(11).unwrap().tag_data(),
+        &TagType::Text("Plzeň – město piva".to_string())
+    );
+    assert_eq!(
+        btag.read_tag(13).unwrap().tag_data(),
+        &TagType::Char("ß".to_string())
+    );
+    assert_eq!(find_ids(&mut btag, "města.brno"), vec![13]);
+    assert_eq!(
+        btag.read_tag(1).unwrap().tag_data(),
+        &TagType::Text("déjà vu".to_string())
+    );
+}
+
+#[test]
+fn unknown_encoding_is_refused() {
+    let dir = test_dir("encoding-unknown");
+    let path = dir.join("cities.btag");
+    write_clusters(&path, &[cafe(), cities()]);
+    let btag = BTag::open(&path).unwrap();
+    let cluster_offset = btag.cluster(5).unwrap().cluster_offset();
+    drop(btag);
+
+    // text_encoding(u16) follows BTAG, version, cluster_index and index_table_offset.
+    let mut bytes = fs::read(&path).unwrap();
+    let position = cluster_offset as usize + 24;
+    bytes[position..position + 2].copy_from_slice(&7u16.to_le_bytes());
+    fs::write(&path, &bytes).unwrap();
+    match BTag::open(&path) {
+        Err(v) => {
+            assert_eq!(v.kind(), DatabaseErrorKind::UnsupportedTextEncoding);
+            assert_eq!(v.offset(), Some(cluster_offset));
+            assert_eq!(v.cluster_index(), Some(5));
+        }
+        Ok(_) => panic!("expected text_encoding 7 to be refused"),
+    }
+}
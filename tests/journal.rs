@@ -0,0 +1,332 @@
+// Transactions interrupted at any step are finished on open: committed ones are replayed,
+// others are rolled back. Crashes are simulated by writing the files a crash would leave.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use btag::*;
+use common::*;
+
+fn users() -> DatabaseWriter {
+    let mut writer = cluster(
+        0,
+        &[
+            (0, "users", &[], None),
+            (1, "joey", &[0], None),
+            (2, "name", &[1], Some(TagType::Text("joey".to_string()))),
+            (3, "wallet", &[1], None),
+            (4, "euro", &[3], Some(TagType::Double(12.5))),
+            (5, "total", &[], None),
+        ],
+    );
+    let euro = writer.name("euro").unwrap();
+    writer
+        .set_value(5, TagType::AddressEntry(AddressEntry::new(euro, 4)))
+        .unwrap();
+    writer
+}
+
+fn numbers() -> DatabaseWriter {
+    cluster(
+        1,
+        &[
+            (10, "numbers", &[], None),
+            (11, "one", &[10], Some(TagType::Integer(1))),
+            (12, "two", &[10], Some(TagType::Integer(2))),
+        ],
+    )
+}
+
+fn long_name() -> TagType {
+    TagType::Text("a name that has to be moved".to_string())
+}
+
+// End of every complete record of the journal, together with number of writes before it.
+// Reference "Journaling" of docs/specification.md.
+fn record_ends(journal: &[u8]) -> Vec<(usize, usize)> {
+    let u64_at = |i: usize| u64::from_le_bytes(journal[i..i + 8].try_into().unwrap()) as usize;
+    let primary_size = u16::from_le_bytes(journal[16..18].try_into().unwrap()) as usize;
+    let mut position = 18 + primary_size;
+    let mut writes = 0;
+    let mut ends = vec![(position, writes)];
+    while position < journal.len() {
+        match journal[position] {
+            0 => {
+                let size =
+                    u32::from_le_bytes(journal[position + 1..position + 5].try_into().unwrap());
+                position += 5 + size as usize;
+            }
+            1 => {
+                let before_size = u64_at(position + 9);
+                let after_size = u64_at(position + 17 + before_size);
+                position += 25 + before_size + after_size;
+                writes += 1;
+            }
+            _ => position += 1,
+        }
+        ends.push((position, writes));
+    }
+    ends
+}
+
+// Database file with the first writes of the journal applied to it.
+fn apply(original: &[u8], writes: &[JournalWrite]) -> Vec<u8> {
+    let mut bytes = original.to_vec();
+    for write in writes {
+        let offset = write.offset() as usize;
+        let end = offset + write.after().len();
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        bytes[offset..end].copy_from_slice(write.after());
+    }
+    bytes
+}
+
+// Leave the files as a crash would and open the database.
+fn crash(path: &Path, database: &[u8], journal: Option<&[u8]>) {
+    fs::write(path, database).unwrap();
+    match journal {
+        Some(v) => fs::write(Journal::path_for(path), v).unwrap(),
+        None => {
+            let _ = fs::remove_file(Journal::path_for(path));
+        }
+    }
+}
+
+#[test]
+fn crash_at_every_step_of_a_transaction() {
+    let dir = test_dir("journal-steps");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+    let original = fs::read(&path).unwrap();
+
+    // Journal as it is right before the commit, and the file after it.
+    let mut btag = BTag::open(&path).unwrap();
+    btag.begin_journal("#2 = \"a name that has to be moved\"")
+        .unwrap();
+    btag.set_value(2, long_name()).unwrap();
+    btag.begin_journal("#12 = 200").unwrap();
+    btag.set_value(12, TagType::Integer(200)).unwrap();
+    let journal = fs::read(Journal::path_for(&path)).unwrap();
+    btag.commit_journal().unwrap();
+    drop(btag);
+    let committed = fs::read(&path).unwrap();
+
+    assert!(!Journal::path_for(&path).exists());
+    fs::write(Journal::path_for(&path), &journal).unwrap();
+    let contents = Journal::read(&Journal::path_for(&path)).unwrap();
+    assert!(!contents.committed());
+    assert_eq!(contents.original_size(), Some(original.len() as u64));
+    assert!(contents.original_size().unwrap() < committed.len() as u64);
+    let writes = contents.writes();
+    assert_eq!(apply(&original, writes), committed);
+
+    let ends = record_ends(&journal);
+    assert_eq!(ends.last().unwrap(), &(journal.len(), writes.len()));
+    for (i, &(end, count)) in ends.iter().enumerate() {
+        // Write is logged before it's made, so the last logged one may be missing.
+        // Crash in the middle of the next record leaves it incomplete.
+        let next = ends.get(i + 1).map_or(end, |x| (end + x.0) / 2);
+        for (applied, journal_end) in [(count, end), (count.saturating_sub(1), end), (count, next)]
+        {
+            crash(
+                &path,
+                &apply(&original, &writes[..applied]),
+                Some(&journal[..journal_end]),
+            );
+            let btag = BTag::open(&path).unwrap();
+            drop(btag);
+            assert_eq!(fs::read(&path).unwrap(), original, "record {}", i);
+            assert!(!Journal::path_for(&path).exists());
+        }
+    }
+
+    // Once commit is logged, transaction is replayed whatever made it into the file.
+    let mut journal = journal;
+    journal.push(2);
+    for count in 0..=writes.len() {
+        crash(&path, &apply(&original, &writes[..count]), Some(&journal));
+        let mut btag = BTag::open(&path).unwrap();
+        assert_eq!(
+            btag.read_tag(12).unwrap().tag_data(),
+            &TagType::Integer(200)
+        );
+        drop(btag);
+        assert_eq!(fs::read(&path).unwrap(), committed, "{} writes", count);
+        assert!(!Journal::path_for(&path).exists());
+    }
+    assert_readable(&path);
+}
+
+#[test]
+fn uncommitted_transaction_is_undone_on_open() {
+    let dir = test_dir("journal-undo");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+    let original = fs::read(&path).unwrap();
+
+    // Process ends in the middle of the transaction.
+    let mut btag = BTag::open(&path).unwrap();
+    btag.begin_journal("#12 = 200").unwrap();
+    btag.set_value(12, TagType::Integer(200)).unwrap();
+    btag.set_value(2, long_name()).unwrap();
+    std::mem::forget(btag);
+    assert_ne!(fs::read(&path).unwrap(), original);
+
+    let mut btag = BTag::open(&path).unwrap();
+    assert!(!btag.in_journal());
+    assert_eq!(btag.read_tag(12).unwrap().tag_data(), &TagType::Integer(2));
+    assert_eq!(
+        btag.read_tag(2).unwrap().tag_data(),
+        &TagType::Text("joey".to_string())
+    );
+    drop(btag);
+    assert_eq!(fs::read(&path).unwrap(), original);
+    assert!(!Journal::path_for(&path).exists());
+}
+
+#[test]
+fn journal_with_partial_header_is_removed_on_open() {
+    let dir = test_dir("journal-header");
+    let first = dir.join("a.btag");
+    let second = dir.join("b.btag");
+    write_clusters(&first, &[users()]);
+    write_clusters(&second, &[numbers()]);
+    let originals = [fs::read(&first).unwrap(), fs::read(&second).unwrap()];
+
+    let mut btag = BTag::open_dir(&dir).unwrap();
+    btag.begin_journal("#12 = 200").unwrap();
+    let journal = fs::read(Journal::path_for(&second)).unwrap();
+    btag.rollback_journal().unwrap();
+    drop(btag);
+
+    // Header of the second journal is followed by the path of the first one.
+    assert!(journal.len() > 20);
+    let partial: [&[u8]; 6] = [
+        &[],
+        &journal[..4],
+        &journal[..17],
+        &journal[..20],
+        b"not a journal at all",
+        &[0xff; 32],
+    ];
+    for bytes in partial {
+        fs::write(Journal::path_for(&second), bytes).unwrap();
+        let contents = Journal::read(&Journal::path_for(&second)).unwrap();
+        assert_eq!(contents.original_size(), None);
+        assert!(contents.writes().is_empty());
+
+        let mut btag = BTag::open_dir(&dir).unwrap();
+        assert!(!btag.in_journal());
+        assert_eq!(btag.read_tag(12).unwrap().tag_data(), &TagType::Integer(2));
+        drop(btag);
+        assert!(!Journal::path_for(&second).exists());
+        assert_eq!(fs::read(&first).unwrap(), originals[0]);
+        assert_eq!(fs::read(&second).unwrap(), originals[1]);
+    }
+}
+
+#[test]
+fn primary_journal_commits_every_file() {
+    let dir = test_dir("journal-primary");
+    let first = dir.join("a.btag");
+    let second = dir.join("b.btag");
+    write_clusters(&first, &[users()]);
+    write_clusters(&second, &[numbers()]);
+    let originals = [fs::read(&first).unwrap(), fs::read(&second).unwrap()];
+
+    let mut btag = BTag::open_dir(&dir).unwrap();
+    btag.begin_journal("#2 = \"a name that has to be moved\"; #12 = 200")
+        .unwrap();
+    btag.set_value(2, long_name()).unwrap();
+    btag.set_value(12, TagType::Integer(200)).unwrap();
+    let journals = [
+        fs::read(Journal::path_for(&first)).unwrap(),
+        fs::read(Journal::path_for(&second)).unwrap(),
+    ];
+    btag.commit_journal().unwrap();
+    drop(btag);
+    let committed = [fs::read(&first).unwrap(), fs::read(&second).unwrap()];
+
+    // Journal of the second file points at the first one.
+    fs::write(Journal::path_for(&second), &journals[1]).unwrap();
+    let contents = Journal::read(&Journal::path_for(&second)).unwrap();
+    assert_eq!(
+        contents.primary(),
+        Some(Journal::path_for(&fs::canonicalize(&first).unwrap()).as_path())
+    );
+    let with_commit = |x: &[u8]| {
+        let mut journal = x.to_vec();
+        journal.push(2);
+        journal
+    };
+
+    // Every write is made, nothing is committed.
+    crash(&first, &committed[0], Some(&journals[0]));
+    crash(&second, &committed[1], Some(&journals[1]));
+    drop(BTag::open_dir(&dir).unwrap());
+    assert_eq!(fs::read(&first).unwrap(), originals[0]);
+    assert_eq!(fs::read(&second).unwrap(), originals[1]);
+
+    // Commit of the first file commits the second one, before and after it's commit is logged,
+    // and after it's journal is removed.
+    let states: [Option<Vec<u8>>; 3] = [
+        Some(journals[1].clone()),
+        Some(with_commit(&journals[1])),
+        None,
+    ];
+    for state in states {
+        crash(&first, &originals[0], Some(&with_commit(&journals[0])));
+        match state {
+            Some(journal) => crash(&second, &originals[1], Some(&journal)),
+            None => crash(&second, &committed[1], None),
+        }
+        let mut btag = BTag::open_dir(&dir).unwrap();
+        assert_eq!(
+            btag.read_tag(12).unwrap().tag_data(),
+            &TagType::Integer(200)
+        );
+        drop(btag);
+        assert_eq!(fs::read(&first).unwrap(), committed[0]);
+        assert_eq!(fs::read(&second).unwrap(), committed[1]);
+        assert!(!Journal::path_for(&first).exists());
+        assert!(!Journal::path_for(&second).exists());
+    }
+
+    // Second file on it's own can't be committed without the first journal.
+    crash(&first, &originals[0], None);
+    crash(&second, &committed[1], Some(&journals[1]));
+    drop(BTag::open(&second).unwrap());
+    assert_eq!(fs::read(&second).unwrap(), originals[1]);
+}
+
+#[test]
+fn queries_are_logged_in_set_syntax() {
+    let dir = test_dir("journal-queries");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+
+    let mut btag = BTag::open(&path).unwrap();
+    btag.begin_journal("wallet.euro = 13.0").unwrap();
+    btag.set_value(12, TagType::Integer(200)).unwrap();
+    btag.set_value(4, TagType::Double(13.0)).unwrap();
+    btag.set_value(2, TagType::Text("jo \"ey\"".to_string()))
+        .unwrap();
+    btag.set_value(11, TagType::Float(1.5)).unwrap();
+    btag.set_value(11, TagType::Char("x".to_string())).unwrap();
+    let contents = Journal::read(&Journal::path_for(&path)).unwrap();
+    assert_eq!(
+        contents.queries(),
+        [
+            "wallet.euro = 13.0",
+            "#12 = 200",
+            "#4 = 13.0",
+            "#2 = \"jo \\\"ey\\\"\"",
+            "#11 = FLOAT 1.5",
+            "#11 = CHAR \"x\"",
+        ]
+    );
+    btag.commit_journal().unwrap();
+}
@@ -0,0 +1,141 @@
+// Databases are exported into the JSON of docs/specification.md and imported back,
+// with or without their tag ids.
+
+mod common;
+
+use btag::*;
+use common::*;
+
+// users.joey.wallet.euro #3 is linked by admins, total and link point at it.
+fn users() -> DatabaseWriter {
+    let mut writer = cluster(
+        0,
+        &[
+            (0, "users", &[], None),
+            (1, "joey", &[0], None),
+            (2, "wallet", &[1], None),
+            (3, "euro", &[2, 5], Some(TagType::Double(12.5))),
+            (4, "name", &[1], Some(TagType::Text("joey".to_string()))),
+            (5, "admins", &[], None),
+            (6, "total", &[], None),
+            (7, "link", &[], None),
+        ],
+    );
+    let euro = writer.name("euro").unwrap();
+    writer
+        .set_value(6, TagType::AddressEntry(AddressEntry::new(euro, 3)))
+        .unwrap();
+    writer
+        .set_value(7, TagType::ValueReference(ValueReference::new(3)))
+        .unwrap();
+    writer
+}
+
+const PRICES: &str = r##"{
+  "prices": {"euro": {"$id": 100, "$value": 12.5}, "dollar": 40},
+  "best": {"$ref": "#100"},
+  "cheap": {"$address": "#100", "$name": "euro"},
+  "watched": {"euro": {"$link": "#100"}}
+}"##;
+
+fn import(json: &str, preserve_ids: bool) -> Result<DatabaseWriter, JsonError> {
+    let mut writer = DatabaseWriter::new(0, TextEncoding::Utf8);
+    writer.import_json(&parse_json(json)?, preserve_ids)?;
+    Ok(writer)
+}
+
+#[test]
+fn references_are_exported_as_tag_labels() {
+    let dir = test_dir("json-export");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users()]);
+    let mut btag = BTag::open(&path).unwrap();
+
+    // Only the referenced tag gets it's `$id`.
+    assert_eq!(
+        btag.export_json(false).unwrap().to_string(),
+        concat!(
+            r##"{"users":{"joey":{"wallet":{"euro":{"$id":3,"$value":12.5}},"name":"joey"}},"##,
+            r##""admins":{"euro":{"$link":"#3"}},"##,
+            r##""total":{"$address":"#3","$name":"euro"},"##,
+            r##""link":{"$ref":"#3"}}"##
+        )
+    );
+    let document = btag.export_json(true).unwrap();
+    assert_eq!(
+        document.get("link").unwrap().to_string(),
+        r##"{"$id":7,"$ref":"#3"}"##
+    );
+    assert_eq!(
+        document.get("users").unwrap().get("$id"),
+        Some(&JsonValue::Number("0".to_string()))
+    );
+}
+
+#[test]
+fn import_without_preserved_ids_gives_new_ids() {
+    let dir = test_dir("json-import");
+    let path = dir.join("prices.btag");
+    import(PRICES, false).unwrap().write_file(&path).unwrap();
+    assert_valid(&path);
+
+    // Tags are numbered in order of the document, references follow them.
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(find_ids(&mut btag, "prices.*"), vec![1, 2]);
+    assert_eq!(find_ids(&mut btag, "watched.euro"), vec![1]);
+    assert_eq!(
+        btag.read_tag(100).unwrap_err().kind(),
+        DatabaseErrorKind::TagMissing
+    );
+    assert_eq!(
+        btag.dereference_tag(3).unwrap().tag_data(),
+        &TagType::Double(12.5)
+    );
+    assert_eq!(
+        btag.export_json(false)
+            .unwrap()
+            .get("cheap")
+            .unwrap()
+            .to_string(),
+        r##"{"$address":"#1","$name":"euro"}"##
+    );
+
+    // Preserved `$id` is kept, other tags are numbered after the biggest one.
+    let path = dir.join("preserved.btag");
+    import(PRICES, true).unwrap().write_file(&path).unwrap();
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(find_ids(&mut btag, "prices.*"), vec![100, 102]);
+    assert_eq!(find_ids(&mut btag, "best"), vec![103]);
+}
+
+#[test]
+fn malformed_documents_are_refused() {
+    let error = parse_json(r#"{"users": [1, 2,]}"#).unwrap_err();
+    assert_eq!(error.offset, Some(16));
+    assert_eq!(error.to_string(), "expected value, found `]` at offset 16");
+    let error = parse_json(r#"{"users": {"joey": 1}"#).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "expected `,` or `}`, found end of document at offset 21"
+    );
+
+    // References must point at a tag of the document.
+    let error = |json: &str| import(json, false).err().unwrap().to_string();
+    assert_eq!(
+        error(r##"{"best": {"$ref": "#9"}}"##),
+        "best: no tag with $id 9"
+    );
+    assert_eq!(
+        error(r##"{"watched": {"euro": {"$link": "#9"}}}"##),
+        "watched: no tag with $id 9"
+    );
+    assert_eq!(
+        error(r##"{"best": {"$ref": "9"}}"##),
+        "best: invalid tag reference \"9\""
+    );
+    assert_eq!(
+        error(r##"{"best": {"$ref": "#1", "$value": 1}}"##),
+        "best: mixed kinds of value"
+    );
+    assert_eq!(error("[1, 2]"), "document must be an object");
+}
@@ -0,0 +1,98 @@
+// Names of every cluster are registered on open, duplicates refuse the cluster.
+// Reference "Names index table" of docs/specification.md.
+
+mod common;
+
+use std::fs;
+
+use btag::*;
+use common::*;
+
+fn users() -> DatabaseWriter {
+    cluster(
+        0,
+        &[
+            (0, "users", &[], None),
+            (1, "joey", &[0], None),
+            (2, "euro", &[1], Some(TagType::Double(12.5))),
+        ],
+    )
+}
+
+fn wallets() -> DatabaseWriter {
+    cluster(
+        1,
+        &[
+            (10, "wallets", &[], None),
+            (11, "euro", &[10], Some(TagType::Integer(1))),
+        ],
+    )
+}
+
+#[test]
+fn every_cluster_has_its_own_names() {
+    let dir = test_dir("names-clusters");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), wallets()]);
+    let btag = BTag::open(&path).unwrap();
+
+    let euro = btag.name_id(0, "euro").unwrap();
+    assert_eq!(btag.name_string(0, euro), Some("euro"));
+    assert_eq!(btag.locate_tag(2).unwrap().1.name(), euro);
+    assert!(btag.name_id(1, "euro").is_some());
+    assert_eq!(btag.name_id(0, "wallets"), None);
+    assert_eq!(btag.name_id(2, "euro"), None);
+    for (cluster_index, count) in [(0, 3), (1, 2)] {
+        let registry = btag.name_registry(cluster_index).unwrap();
+        assert_eq!(
+            registry.last_name_index(),
+            btag.cluster(cluster_index).unwrap().last_name_index()
+        );
+        assert_eq!(registry.len(), count);
+    }
+}
+
+#[test]
+fn cluster_with_duplicate_names_is_refused() {
+    let dir = test_dir("names-duplicate");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users()]);
+    let mut bytes = fs::read(&path).unwrap();
+    let position = bytes.windows(4).position(|x| x == b"joey").unwrap();
+    bytes[position..position + 4].copy_from_slice(b"euro");
+    fs::write(&path, &bytes).unwrap();
+    match BTag::open(&path) {
+        Err(v) => {
+            assert_eq!(v.kind(), DatabaseErrorKind::NameDuplicate);
+            assert_eq!(v.cluster_index(), Some(0));
+            assert_eq!(v.path(), Some(path.as_path()));
+        }
+        Ok(_) => panic!("expected duplicate names to be refused"),
+    }
+}
+
+#[test]
+fn inserted_tags_register_new_names() {
+    let dir = test_dir("names-insert");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), wallets()]);
+    let mut btag = BTag::open(&path).unwrap();
+    let euro = btag.name_id(0, "euro").unwrap();
+    let last_name_index = btag.cluster(0).unwrap().last_name_index();
+
+    // Tag with a new name registers it, existing names are reused.
+    btag.insert_tag(0, 3, "dollar", &[1], TagType::Double(40.5))
+        .unwrap();
+    btag.insert_tag(0, 4, "euro", &[0], TagType::Double(1.5))
+        .unwrap();
+    drop(btag);
+
+    assert_readable(&path);
+    let btag = BTag::open(&path).unwrap();
+    let dollar = btag.name_id(0, "dollar").unwrap();
+    assert_eq!(dollar, last_name_index + 1);
+    assert_eq!(btag.cluster(0).unwrap().last_name_index(), dollar);
+    assert_eq!(btag.locate_tag(4).unwrap().1.name(), euro);
+    assert_eq!(btag.name_registry(0).unwrap().len(), 4);
+    assert_eq!(btag.name_id(1, "dollar"), None);
+}
@@ -0,0 +1,274 @@
+// Every cluster of a file is loaded by following next_cluster, reading the layout of
+// "Database cluster metadata" through "Data index table" in docs/specification.md.
+
+mod common;
+
+use std::fs;
+
+use btag::*;
+use common::*;
+
+fn users() -> RawCluster {
+    RawCluster::new(
+        2,
+        &[
+            (1, "users"),
+            (2, "jason"),
+            (3, "wallet"),
+            (4, "euro"),
+            (5, "admins"),
+        ],
+        vec![
+            RawTag(0, 1, vec![], RawValue::Children(vec![1])),
+            RawTag(1, 2, vec![0], RawValue::Children(vec![2])),
+            RawTag(2, 3, vec![1], RawValue::Children(vec![3])),
+            RawTag(3, 4, vec![2, 4], RawValue::Double(12.5)),
+            RawTag(4, 5, vec![], RawValue::Children(vec![3])),
+        ],
+    )
+}
+
+fn numbers() -> RawCluster {
+    RawCluster::new(
+        7,
+        &[(1, "numbers"), (2, "one")],
+        vec![
+            RawTag(10, 1, vec![], RawValue::Children(vec![11])),
+            RawTag(11, 2, vec![10], RawValue::Integer(1)),
+        ],
+    )
+}
+
+fn letters() -> RawCluster {
+    RawCluster::new(
+        4,
+        &[(1, "letters"), (2, "a")],
+        vec![
+            RawTag(20, 1, vec![], RawValue::Children(vec![21])),
+            RawTag(21, 2, vec![20], RawValue::Text("a")),
+        ],
+    )
+}
+
+#[test]
+fn every_cluster_is_loaded() {
+    let dir = test_dir("open-chain");
+    let path = dir.join("users.btag");
+    let bytes = write_raw(&path, &[&users(), &numbers(), &letters()]);
+    let btag = BTag::open(&path).unwrap();
+
+    // Clusters are kept in the order of the chain, each one starts where previous points.
+    let clusters = btag.clusters();
+    assert_eq!(
+        clusters
+            .iter()
+            .map(|x| x.cluster_index())
+            .collect::<Vec<u64>>(),
+        vec![2, 7, 4]
+    );
+    let mut cluster_offset = 0;
+    for cluster in clusters {
+        assert_eq!(&bytes[cluster_offset as usize..][..4], b"BTAG");
+        assert_eq!(cluster.index_table_offset(), cluster_offset + 62);
+        cluster_offset += cluster.database_size();
+        assert!(cluster.next_cluster() == cluster_offset || cluster.next_cluster() == 0);
+    }
+    assert_eq!(clusters[2].next_cluster(), 0);
+    assert_eq!(cluster_offset, bytes.len() as u64);
+
+    // Biggest cluster index is known, not the one of the last cluster.
+    assert_eq!(btag.last_cluster_index(), 7);
+    for (cluster_index, tag_ids) in [
+        (2, vec![0, 1, 2, 3, 4]),
+        (7, vec![10, 11]),
+        (4, vec![20, 21]),
+    ] {
+        let tags = btag.tags_index(cluster_index).unwrap().tags();
+        assert_eq!(
+            tags.iter().map(|x| x.tag_id()).collect::<Vec<u64>>(),
+            tag_ids
+        );
+        assert!(btag.names_index(cluster_index).is_some());
+    }
+    assert!(btag.tags_index(3).is_none());
+    assert!(btag.names_index(3).is_none());
+}
+
+#[test]
+fn index_entries_are_read() {
+    let dir = test_dir("open-layout");
+    let path = dir.join("users.btag");
+    let bytes = write_raw(&path, &[&users(), &numbers()]);
+    let btag = BTag::open(&path).unwrap();
+
+    // Names entries of every length are read one after another.
+    let names = btag.names_index(2).unwrap().names();
+    assert_eq!(
+        names
+            .iter()
+            .map(|x| (x.name(), x.name_string()))
+            .collect::<Vec<(u64, &str)>>(),
+        users().names
+    );
+    assert!(names
+        .iter()
+        .all(|x| x.name_string_size() as usize == x.name_string().len()));
+
+    // full_path holds depth + 1 names, ending with the name of the tag itself.
+    let tags = btag.tags_index(2).unwrap().tags();
+    let euro = &tags[3];
+    assert_eq!((euro.tag_id(), euro.name(), euro.depth()), (3, 4, 3));
+    assert_eq!(euro.full_path(), &[1, 2, 3, 4]);
+    assert_eq!(tags[4].full_path(), &[5]);
+    assert!(tags
+        .iter()
+        .all(|x| x.full_path().len() as u64 == x.depth() + 1));
+
+    // Offsets are relative to the index table and point at records of the tags.
+    for cluster in btag.clusters() {
+        for tag in btag.tags_index(cluster.cluster_index()).unwrap().tags() {
+            let record = (cluster.index_table_offset() + tag.offset()) as usize;
+            assert_eq!(&bytes[record..record + 8], &tag.tag_id().to_le_bytes());
+        }
+    }
+}
+
+#[test]
+fn broken_chains_are_refused() {
+    let dir = test_dir("open-loop");
+    let path = dir.join("users.btag");
+    let bytes = write_raw(&path, &[&users(), &numbers(), &letters()]);
+    let btag = BTag::open(&path).unwrap();
+    let second = btag.clusters()[0].next_cluster();
+    let last = btag.clusters()[1].next_cluster();
+    drop(btag);
+
+    // Last cluster points back to the second one, or past the end of the file.
+    // next_cluster(u64) ends the metadata.
+    for next_cluster in [second, last, bytes.len() as u64] {
+        let mut bytes = bytes.clone();
+        let position = last as usize + 54;
+        bytes[position..position + 8].copy_from_slice(&next_cluster.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        match BTag::open(&path) {
+            Err(v) => {
+                assert_eq!(v.kind(), DatabaseErrorKind::ClusterValidity);
+                assert_eq!(v.path(), Some(path.as_path()));
+                // Chain going back is refused at next_cluster of the last cluster.
+                if next_cluster <= last {
+                    assert_eq!(v.offset(), Some(position as u64));
+                    assert_eq!(v.cluster_index(), Some(4));
+                }
+            }
+            Ok(_) => panic!("expected next_cluster {} to be refused", next_cluster),
+        }
+    }
+
+    // Cluster index may be used only once.
+    let mut letters = letters();
+    letters.cluster_index = 2;
+    write_raw(&path, &[&users(), &numbers(), &letters]);
+    match BTag::open(&path) {
+        Err(v) => assert_eq!(v.kind(), DatabaseErrorKind::ClusterDuplicateIndex),
+        Ok(_) => panic!("expected duplicate cluster index to be refused"),
+    }
+}
+
+#[test]
+fn every_file_of_a_directory_is_opened() {
+    let dir = test_dir("open-dir");
+    write_raw(&dir.join("users.btag"), &[&users()]);
+    write_raw(&dir.join("numbers.btag"), &[&numbers(), &letters()]);
+    // Only files with the database extension are opened.
+    fs::write(dir.join("notes.txt"), b"not a database").unwrap();
+    fs::create_dir(dir.join("old.btag")).unwrap();
+
+    // Files are opened in order of their paths, clusters in order of their chains.
+    let mut btag = BTag::open_dir(&dir).unwrap();
+    assert_eq!(
+        btag.clusters()
+            .iter()
+            .map(|x| x.cluster_index())
+            .collect::<Vec<u64>>(),
+        vec![7, 4, 2]
+    );
+    assert_eq!(btag.read_tag(3).unwrap().tag_data(), &TagType::Double(12.5));
+    assert_eq!(btag.read_tag(11).unwrap().tag_data(), &TagType::Integer(1));
+    assert_eq!(
+        btag.read_tag(21).unwrap().tag_data(),
+        &TagType::Text("a".to_string())
+    );
+}
+
+#[test]
+fn cluster_index_is_unique_across_files() {
+    let dir = test_dir("open-many-duplicate");
+    let users_path = dir.join("users.btag");
+    let letters_path = dir.join("letters.btag");
+    write_raw(&users_path, &[&users()]);
+    let mut letters = letters();
+    letters.cluster_index = 2;
+    write_raw(&letters_path, &[&numbers(), &letters]);
+
+    // Second cluster of the second file repeats the index of the first file.
+    let letters_offset = numbers().to_bytes(0, 0).len() as u64;
+    match BTag::open_many(&[&users_path, &letters_path]) {
+        Err(v) => {
+            assert_eq!(v.kind(), DatabaseErrorKind::ClusterDuplicateIndex);
+            assert_eq!(v.path(), Some(letters_path.as_path()));
+            assert_eq!(v.offset(), Some(letters_offset));
+            assert_eq!(v.cluster_index(), Some(2));
+        }
+        Ok(_) => panic!("expected duplicate cluster index to be refused"),
+    }
+}
+
+#[test]
+fn index_table_pages_are_merged() {
+    let dir = test_dir("open-pages");
+    let path = dir.join("users.btag");
+    for version in [1, 2] {
+        let mut users = users();
+        users.pages = 3;
+        users.version = version;
+        write_raw(&path, &[&users, &numbers()]);
+        let mut btag = BTag::open(&path).unwrap();
+
+        // Pages are chained, every page holds a part of names and tags.
+        let pages = btag.index_tables(2).unwrap();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].page_address(), 0);
+        assert_eq!(
+            pages[1].page_address(),
+            pages[0].index_table_next_page_offset()
+        );
+        assert_eq!(
+            btag.names_index(2)
+                .unwrap()
+                .names()
+                .iter()
+                .map(|x| (x.name(), x.name_string()))
+                .collect::<Vec<(u64, &str)>>(),
+            users.names
+        );
+        assert_eq!(
+            btag.tags_index(2)
+                .unwrap()
+                .tags()
+                .iter()
+                .map(|x| x.tag_id())
+                .collect::<Vec<u64>>(),
+            vec![0, 1, 2, 3, 4]
+        );
+        assert_eq!(btag.read_tag(3).unwrap().tag_data(), &TagType::Double(12.5));
+        assert_eq!(find_ids(&mut btag, "admins.euro"), vec![3]);
+
+        // wallet of the second page and admins of the third one both reference euro.
+        if version == 2 {
+            let euro = offset_of(&btag, 3);
+            let references = btag.reference_count_table(2).unwrap();
+            assert_eq!(references.referencing_tags(euro), &[3, 5]);
+            assert_eq!(references.references().len(), 3);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// Every form of "Commands" in docs/specification.md parses into statements, and misplaced
+// steps are refused with the span of the step.
+
+use btag::*;
+
+fn query(source: &str) -> Query {
+    match parse_query(source).unwrap().remove(0) {
+        Statement::Get(v) => v,
+        v => panic!("expected query, found {:?}", v),
+    }
+}
+
+fn steps(source: &str) -> Vec<StepKind> {
+    query(source).steps.into_iter().map(|x| x.kind).collect()
+}
+
+fn set(source: &str) -> SetStatement {
+    match parse_query(source).unwrap().remove(0) {
+        Statement::Set(v) => v,
+        v => panic!("expected set, found {:?}", v),
+    }
+}
+
+// Message and the part of the source the error points at.
+fn error(source: &str) -> (String, &str) {
+    let error = parse_query(source).unwrap_err();
+    (error.message, &source[error.span.start..error.span.end])
+}
+
+fn name(v: &str) -> StepKind {
+    StepKind::Name(v.to_string())
+}
+
+fn comparison(source: &str) -> Comparison {
+    match steps(&format!("a..${}", source)).remove(1) {
+        StepKind::ParentIf(Condition::Comparison(v)) => v,
+        v => panic!("expected comparison, found {:?}", v),
+    }
+}
+
+fn text(v: &str) -> Literal {
+    Literal::Text(v.to_string())
+}
+
+#[test]
+fn generic_forms() {
+    assert_eq!(
+        steps("users.joey.wallet.euro"),
+        vec![name("users"), name("joey"), name("wallet"), name("euro")]
+    );
+    assert_eq!(
+        steps("mylist.0.*.#97"),
+        vec![
+            name("mylist"),
+            StepKind::Index(0),
+            StepKind::Wildcard,
+            StepKind::Id(97)
+        ]
+    );
+    assert_eq!(query("wallet.euro:2").nth, Some(2));
+    assert_eq!(
+        steps("users.\"mary ann\""),
+        vec![name("users"), name("mary ann")]
+    );
+
+    assert_eq!(steps("euro.."), vec![name("euro"), StepKind::Parent]);
+    assert_eq!(
+        steps("wallet.euro..0..0.%name"),
+        vec![
+            name("wallet"),
+            name("euro"),
+            StepKind::Parent,
+            StepKind::Index(0),
+            StepKind::Parent,
+            StepKind::Index(0),
+            StepKind::Property(Property::Name)
+        ]
+    );
+    assert_eq!(
+        steps("wallet.euro..0..%name"),
+        vec![
+            name("wallet"),
+            name("euro"),
+            StepKind::Parent,
+            StepKind::Index(0),
+            StepKind::Parent,
+            StepKind::Property(Property::Name)
+        ]
+    );
+    assert_eq!(
+        steps("wallet.euro.-"),
+        vec![name("wallet"), name("euro"), StepKind::Backtrace]
+    );
+    assert_eq!(
+        steps("users.%depth"),
+        vec![name("users"), StepKind::Property(Property::Depth)]
+    );
+    assert_eq!(query("users.%depth").property(), Some(Property::Depth));
+    assert_eq!(query("users.joey").property(), None);
+
+    let statements = parse_query("PRELOAD NAMES; a; b").unwrap();
+    assert_eq!(statements.len(), 3);
+    assert!(matches!(statements[0], Statement::PreloadNames(_)));
+}
+
+#[test]
+fn conditionals() {
+    let operators = [
+        ("=", Operator::Equal),
+        ("!=", Operator::NotEqual),
+        ("<", Operator::Less),
+        ("<=", Operator::LessEqual),
+        (">", Operator::Greater),
+        (">=", Operator::GreaterEqual),
+    ];
+    for (source, operator) in operators {
+        let comparison = comparison(&format!("%depth{}2", source));
+        assert_eq!(comparison.property, Property::Depth);
+        assert_eq!(comparison.operator, operator);
+        assert_eq!(comparison.value, Literal::Integer(2));
+    }
+    // `%` may be omitted.
+    assert_eq!(comparison("name=b").value, text("b"));
+    assert_eq!(comparison("name=b").property, Property::Name);
+
+    match steps("a.b.c...$%name=b&(..%name=a)").remove(3) {
+        StepKind::Ancestors(Condition::All(conditions)) => {
+            assert!(matches!(conditions[0], Condition::Comparison(_)));
+            match &conditions[1] {
+                Condition::Query(v) => assert_eq!(
+                    v.steps[1].kind,
+                    StepKind::Filter(Comparison {
+                        property: Property::Name,
+                        operator: Operator::Equal,
+                        value: text("a"),
+                        span: v.steps[1].span,
+                    })
+                ),
+                v => panic!("expected query, found {:?}", v),
+            }
+        }
+        v => panic!("expected ancestors, found {:?}", v),
+    }
+
+    let statements =
+        parse_query("a.b.c..$%name=b&depth=1..$%name=a.b; a.b.c..$%name=b&depth=2..$%name=a.b")
+            .unwrap();
+    assert_eq!(statements.len(), 2);
+
+    for (source, combinator) in [
+        ("(1..&2..&3..&4..)$peq", Some(Combinator::PartialEqual)),
+        ("(1..&2..)$union", Some(Combinator::Union)),
+        ("(1..&2..)$diff:1", Some(Combinator::Difference)),
+        ("(1..&2..)", None),
+    ] {
+        match steps(source).remove(0) {
+            StepKind::Group(group) => assert_eq!(group.combinator, combinator),
+            v => panic!("expected group, found {:?}", v),
+        }
+    }
+}
+
+#[test]
+fn set_forms() {
+    assert_eq!(set("Joey.wallet.euro = 1200").value, Value::Integer(1200));
+    assert_eq!(set("#97 = 1200").target.steps[0].kind, StepKind::Id(97));
+    assert_eq!(set("#87.#97 = 1200").target.steps.len(), 2);
+    assert!(matches!(set("#97 = #16").value, Value::Copy(_)));
+    assert!(matches!(set("#111 = &#87").value, Value::Reference(_)));
+    assert_eq!(set("#97 = FLOAT 12.0").value, Value::Float(12.0));
+    assert_eq!(set("#97 = DOUBLE 3").value, Value::Double(3.0));
+    assert_eq!(
+        set("#97 = CHAR \"jo\"").value,
+        Value::Char("jo".to_string())
+    );
+
+    // Type names without a literal are names of tags.
+    let copy = |source: &str| match set(source).value {
+        Value::Copy(query) => query.steps.into_iter().map(|x| x.kind).collect::<Vec<_>>(),
+        v => panic!("expected copy, found {:?}", v),
+    };
+    assert_eq!(copy("#1 = text.x"), vec![name("text"), name("x")]);
+    assert_eq!(copy("#1 = float"), vec![name("float")]);
+    assert_eq!(set("#1 = text \"x\"").value, Value::Text("x".to_string()));
+
+    let statement = set("( Joey.wallet.euro ):0 = 1200");
+    assert!(!statement.return_changed);
+    assert_eq!(
+        set("( wallet.euro ):0.borrowers.*.amount = 13")
+            .target
+            .steps
+            .len(),
+        4
+    );
+    assert!(set("(Joey.wallet.euro = 1200)$%=%").return_changed);
+}
+
+#[test]
+fn errors_point_at_the_step() {
+    assert_eq!(
+        error("wallet.%name.euro"),
+        ("property can only end a query".to_string(), "%name")
+    );
+    assert_eq!(
+        error("%depth"),
+        ("property needs tags to be read from".to_string(), "%depth")
+    );
+    assert_eq!(
+        error("(wallet.%name):0 = 1"),
+        ("property can only end a query".to_string(), "%name")
+    );
+    assert_eq!(
+        error("#1 = &wallet.%depth"),
+        ("property can only end a query".to_string(), "%depth")
+    );
+    assert_eq!(
+        error("(a.%name & b)"),
+        ("property can only end a query".to_string(), "%name")
+    );
+    assert_eq!(
+        error("a..$(..0.-)"),
+        ("`.-` can't be used in conditions".to_string(), ".-")
+    );
+    assert_eq!(
+        error("a.(b & c)"),
+        ("group can only start a query".to_string(), "(b & c)")
+    );
+    assert_eq!(
+        error("a.%size"),
+        ("unknown property `size`".to_string(), "size")
+    );
+    assert_eq!(
+        error("(a & b)$same"),
+        ("unknown combinator `same`".to_string(), "same")
+    );
+    assert_eq!(error("a b").1, "b");
+    assert_eq!(error("#1 = INTEGER \"a\"").1, "INTEGER \"a\"");
+}
@@ -0,0 +1,222 @@
+// REFERENCE_COUNT_TABLE holds names of referencing tags, as laid out in docs/specification.md,
+// and keeps its space when it has to grow out of the first index table page.
+
+mod common;
+
+use std::fs;
+
+use btag::*;
+use common::*;
+
+// euro is listed by wallet and referenced by two tags named total and by best.
+// total of counts has the same name, but doesn't reference euro.
+fn users() -> DatabaseWriter {
+    let mut writer = cluster(
+        0,
+        &[
+            (0, "users", &[], None),
+            (1, "joey", &[0], None),
+            (2, "wallet", &[1], None),
+            (3, "euro", &[2], Some(TagType::Double(12.5))),
+            (4, "totals", &[], None),
+            (5, "total", &[4], None),
+            (9, "sums", &[], None),
+            (6, "total", &[9], None),
+            (12, "counts", &[], None),
+            (7, "total", &[12], Some(TagType::Integer(1))),
+            (8, "best", &[], None),
+            (13, "spare", &[], Some(spare())),
+        ],
+    );
+    let euro = writer.name("euro").unwrap();
+    for tag_id in [5, 6, 8] {
+        writer
+            .set_value(tag_id, TagType::AddressEntry(AddressEntry::new(euro, 3)))
+            .unwrap();
+    }
+    writer
+}
+
+// Takes as much space as an AddressEntry, which then replaces it in place.
+fn spare() -> TagType {
+    TagType::Text("fourteen chars".to_string())
+}
+
+fn numbers() -> DatabaseWriter {
+    cluster(
+        1,
+        &[
+            (10, "numbers", &[], None),
+            (11, "one", &[10], Some(TagType::Integer(1))),
+        ],
+    )
+}
+
+fn names(name_strings: &[&str]) -> Vec<u64> {
+    let writer = users();
+    let mut names: Vec<u64> = name_strings
+        .iter()
+        .map(|x| writer.name(x).unwrap())
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+fn euro_names() -> Vec<u64> {
+    names(&["wallet", "total", "total", "best"])
+}
+
+fn stored_names(btag: &BTag, tag_id: u64) -> Vec<u64> {
+    let offset = offset_of(btag, tag_id);
+    let mut names = btag
+        .reference_count_table(0)
+        .unwrap()
+        .referencing_tags(offset)
+        .to_vec();
+    names.sort_unstable();
+    names
+}
+
+fn referencing_tags(btag: &mut BTag, tag_id: u64) -> Vec<u64> {
+    let mut tag_ids = btag.referencing_tags(tag_id).unwrap();
+    tag_ids.sort_unstable();
+    tag_ids
+}
+
+fn euro_entry(btag: &BTag) -> TagType {
+    let euro = btag.locate_tag(3).unwrap().1;
+    TagType::AddressEntry(AddressEntry::new(euro.name(), euro.offset()))
+}
+
+#[test]
+fn table_holds_names_of_referencing_tags() {
+    let dir = test_dir("references-layout");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+
+    // Entries are read straight from the file, address(u64) count(u64) [name(u64); count].
+    let mut reader = DatabaseReader::open(&path).unwrap();
+    let cluster = reader.read_cluster(0).unwrap();
+    let page = reader
+        .read_index_table(cluster.index_table_offset())
+        .unwrap();
+    let bytes = fs::read(&path).unwrap();
+    let start = (cluster.index_table_offset() + page.index_table_references_offset()) as usize;
+    let table = &bytes[start..start + page.index_table_references_size() as usize];
+    let u64_at = |i: usize| u64::from_le_bytes(table[i..i + 8].try_into().unwrap());
+
+    let btag = BTag::open(&path).unwrap();
+    let euro = offset_of(&btag, 3);
+    let mut i = 0;
+    let mut found: Option<Vec<u64>> = None;
+    while i < table.len() {
+        let count = u64_at(i + 8) as usize;
+        let mut entry_names: Vec<u64> = (0..count).map(|x| u64_at(i + 16 + x * 8)).collect();
+        entry_names.sort_unstable();
+        if u64_at(i) == euro {
+            found = Some(entry_names);
+        }
+        i += 16 + count * 8;
+    }
+    assert_eq!(i, table.len());
+    assert_eq!(found.unwrap(), euro_names());
+    assert_eq!(stored_names(&btag, 3), euro_names());
+    assert_eq!(btag.reference_count_table(1).unwrap().references().len(), 1);
+}
+
+#[test]
+fn referencing_tags_are_found_by_name() {
+    let dir = test_dir("references-tags");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(referencing_tags(&mut btag, 3), vec![2, 5, 6, 8]);
+    assert_eq!(referencing_tags(&mut btag, 5), vec![4]);
+    assert!(referencing_tags(&mut btag, 8).is_empty());
+    assert_eq!(
+        btag.referencing_tags(99).unwrap_err().kind(),
+        DatabaseErrorKind::TagMissing
+    );
+
+    // Changed values change the table.
+    btag.set_value(7, euro_entry(&btag)).unwrap();
+    btag.set_value(5, TagType::Integer(5)).unwrap();
+    assert_eq!(referencing_tags(&mut btag, 3), vec![2, 6, 7, 8]);
+    drop(btag);
+
+    assert_readable(&path);
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(stored_names(&btag, 3), euro_names());
+    assert_eq!(referencing_tags(&mut btag, 3), vec![2, 6, 7, 8]);
+}
+
+#[test]
+fn moved_table_stays_at_the_end_of_the_cluster() {
+    let dir = test_dir("references-moved");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+
+    // Table fills the first page, the next reference moves it to the end of the cluster,
+    // which is followed by another one.
+    let mut btag = BTag::open(&path).unwrap();
+    btag.set_value(7, euro_entry(&btag)).unwrap();
+    let page = &btag.index_tables(0).unwrap()[0];
+    assert!(page.index_table_references_offset() >= page.index_table_size());
+
+    // Records appended later are written before the table.
+    btag.set_value(3, TagType::Text("twelve and a half".to_string()))
+        .unwrap();
+    let table = btag.index_tables(0).unwrap()[0].index_table_references_offset();
+    assert!(table > offset_of(&btag, 3));
+    let table_end = |btag: &BTag| {
+        let cluster = btag.cluster(0).unwrap();
+        let page = &btag.index_tables(0).unwrap()[0];
+        cluster.index_table_offset()
+            + page.index_table_references_offset()
+            + u64::from(page.index_table_references_size())
+    };
+    let cluster_end = |btag: &BTag| {
+        let cluster = btag.cluster(0).unwrap();
+        cluster.cluster_offset() + cluster.database_size()
+    };
+    assert_eq!(table_end(&btag), cluster_end(&btag));
+
+    // Table shrinks and grows back in place, then grows the cluster by a single name.
+    let size = btag.cluster(0).unwrap().database_size();
+    btag.set_value(8, TagType::Integer(8)).unwrap();
+    assert_eq!(table_end(&btag), cluster_end(&btag) - 8);
+    btag.set_value(8, euro_entry(&btag)).unwrap();
+    assert_eq!(btag.cluster(0).unwrap().database_size(), size);
+    btag.set_value(13, euro_entry(&btag)).unwrap();
+    assert_eq!(btag.cluster(0).unwrap().database_size(), size + 8);
+    assert_eq!(table_end(&btag), cluster_end(&btag));
+    btag.set_value(13, spare()).unwrap();
+    assert_eq!(btag.cluster(0).unwrap().database_size(), size + 8);
+    assert_eq!(
+        btag.index_tables(0).unwrap()[0].index_table_references_offset(),
+        table
+    );
+    drop(btag);
+
+    // Space left by the shrunk table is zeroed.
+    let bytes = fs::read(&path).unwrap();
+    let btag = BTag::open(&path).unwrap();
+    let end = table_end(&btag) as usize;
+    assert!(bytes[end..cluster_end(&btag) as usize]
+        .iter()
+        .all(|x| *x == 0));
+    drop(btag);
+
+    assert_readable(&path);
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(
+        stored_names(&btag, 3),
+        names(&["wallet", "total", "total", "total", "best"])
+    );
+    assert_eq!(referencing_tags(&mut btag, 3), vec![2, 5, 6, 7, 8]);
+    assert_eq!(
+        btag.read_tag(3).unwrap().tag_data(),
+        &TagType::Text("twelve and a half".to_string())
+    );
+}
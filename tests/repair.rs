@@ -0,0 +1,184 @@
+// Repaired copy of a broken file keeps every tag whose record can be read, without addresses
+// pointing nowhere. Problems are made in the second cluster, so offsets are absolute in the file.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use btag::*;
+use common::*;
+
+fn numbers() -> DatabaseWriter {
+    cluster(
+        0,
+        &[
+            (0, "numbers", &[], None),
+            (1, "one", &[0], Some(TagType::Integer(1))),
+        ],
+    )
+}
+
+// users.alpha.euro #12 is linked by links, gamma holds an AddressEntry of it.
+fn users() -> DatabaseWriter {
+    let mut writer = cluster(
+        5,
+        &[
+            (10, "users", &[], None),
+            (11, "alpha", &[10], None),
+            (12, "euro", &[11, 14], Some(TagType::Double(12.5))),
+            (13, "gamma", &[10], Some(TagType::Integer(0))),
+            (14, "links", &[], None),
+        ],
+    );
+    let euro = writer.name("euro").unwrap();
+    writer
+        .set_value(13, TagType::AddressEntry(AddressEntry::new(euro, 12)))
+        .unwrap();
+    writer
+}
+
+// Bytes of users.btag, with absolute position of the record of every tag.
+fn database(dir: &Path) -> (Vec<u8>, impl Fn(u64) -> usize) {
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[numbers(), users()]);
+    let btag = BTag::open(&path).unwrap();
+    let index_table_offset = btag.cluster(5).unwrap().index_table_offset();
+    let offsets: Vec<(u64, u64)> = (10..15).map(|x| (x, offset_of(&btag, x))).collect();
+    let record = move |tag_id: u64| {
+        let offset = offsets.iter().find(|x| x.0 == tag_id).unwrap().1;
+        (index_table_offset + offset) as usize
+    };
+    (fs::read(&path).unwrap(), record)
+}
+
+// Repair the bytes, copy must be valid and readable.
+fn repair(dir: &Path, bytes: &[u8]) -> (RepairReport, BTag) {
+    let path = dir.join("broken.btag");
+    let repaired = dir.join("repaired.btag");
+    fs::write(&path, bytes).unwrap();
+    let report = DatabaseReader::open(&path)
+        .unwrap()
+        .repair(&repaired)
+        .unwrap();
+    assert_valid(&repaired);
+    assert_readable(&repaired);
+    (report, BTag::open(&repaired).unwrap())
+}
+
+#[test]
+fn tag_without_index_entry_is_recovered() {
+    let dir = test_dir("repair-recovered");
+    let (mut bytes, record) = database(&dir);
+    let btag = BTag::open(dir.join("users.btag")).unwrap();
+    let name = |x: &str| btag.name_id(5, x).unwrap();
+
+    // Entry is tag_id(u64) name(u64) depth(u64) path of names(u64) offset(u64).
+    let mut entry: Vec<u8> = Vec::new();
+    let offset = offset_of(&btag, 12);
+    for v in [
+        12,
+        name("euro"),
+        2,
+        name("users"),
+        name("alpha"),
+        name("euro"),
+        offset,
+    ] {
+        entry.extend_from_slice(&v.to_le_bytes());
+    }
+    // tag_id and offset are wiped, rest of the page stays readable.
+    let position = bytes.windows(entry.len()).position(|x| x == entry).unwrap();
+    bytes[position..position + 8].fill(0);
+    bytes[position + entry.len() - 8..position + entry.len()].fill(0);
+    drop(btag);
+    assert!(record(12) > position);
+
+    let (report, mut btag) = repair(&dir, &bytes);
+    assert_eq!(report.recovered_tags(), &[12]);
+    assert!(report.dropped_tags().is_empty());
+    assert_eq!(report.dropped_addresses(), 0);
+    assert_eq!((report.clusters(), report.tags()), (2, 7));
+    assert_eq!(
+        btag.read_tag(12).unwrap().tag_data(),
+        &TagType::Double(12.5)
+    );
+    assert_eq!(find_ids(&mut btag, "users.alpha.euro"), vec![12]);
+    assert_eq!(find_ids(&mut btag, "links.euro"), vec![12]);
+    // gamma points at the new address of euro.
+    match btag.read_tag(13).unwrap().tag_data() {
+        TagType::AddressEntry(v) => assert_eq!(v.address(), offset_of(&btag, 12)),
+        v => panic!("expected AddressEntry, found {:?}", v),
+    }
+}
+
+#[test]
+fn tag_with_dangling_address_entry_is_dropped() {
+    let dir = test_dir("repair-dropped");
+    let (mut bytes, record) = database(&dir);
+    // gamma has users as it's only parent, value is type(u8) size(u64) name(u64) address(u64).
+    let position = record(13) + 40 + 16 + 9 + 8;
+    bytes[position..position + 8].copy_from_slice(&7u64.to_le_bytes());
+
+    let (report, mut btag) = repair(&dir, &bytes);
+    assert_eq!(report.dropped_tags(), &[13]);
+    assert!(report.recovered_tags().is_empty());
+    // Entry of gamma in users is dropped with it.
+    assert_eq!(report.dropped_addresses(), 1);
+    assert_eq!((report.clusters(), report.tags()), (2, 6));
+    assert_eq!(
+        btag.read_tag(13).unwrap_err().kind(),
+        DatabaseErrorKind::TagMissing
+    );
+    assert_eq!(find_ids(&mut btag, "users.*"), vec![11]);
+}
+
+#[test]
+fn dangling_parent_is_dropped() {
+    let dir = test_dir("repair-parent");
+    let (mut bytes, record) = database(&dir);
+    // Parents of euro follow the 40 bytes of the header, entry is name(u64) address(u64).
+    let position = record(12) + 40 + 16 + 8;
+    bytes[position..position + 8].copy_from_slice(&7u64.to_le_bytes());
+
+    let (report, mut btag) = repair(&dir, &bytes);
+    assert_eq!(report.dropped_addresses(), 1);
+    assert!(report.dropped_tags().is_empty() && report.recovered_tags().is_empty());
+    assert_eq!((report.clusters(), report.tags()), (2, 7));
+    let tag = btag.read_tag(12).unwrap();
+    assert_eq!(tag.tag_parents().array().len(), 1);
+    assert_eq!(tag.tag_depth(), 2);
+    // links still holds euro.
+    assert_eq!(find_ids(&mut btag, "links.euro"), vec![12]);
+}
+
+#[test]
+fn duplicate_cluster_is_skipped() {
+    let dir = test_dir("repair-duplicate");
+    let first = RawCluster::new(
+        4,
+        &[(1, "letters"), (2, "a")],
+        vec![
+            RawTag(20, 1, vec![], RawValue::Children(vec![21])),
+            RawTag(21, 2, vec![20], RawValue::Text("a")),
+        ],
+    );
+    let second = RawCluster::new(
+        4,
+        &[(1, "digits"), (2, "one")],
+        vec![
+            RawTag(30, 1, vec![], RawValue::Children(vec![31])),
+            RawTag(31, 2, vec![30], RawValue::Integer(1)),
+        ],
+    );
+    let bytes = write_raw(&dir.join("letters.btag"), &[&first, &second]);
+
+    let (report, mut btag) = repair(&dir, &bytes);
+    assert_eq!(report.skipped_clusters(), &[4]);
+    assert_eq!((report.clusters(), report.tags()), (1, 2));
+    assert_eq!(btag.clusters().len(), 1);
+    assert_eq!(find_ids(&mut btag, "letters.a"), vec![21]);
+    assert_eq!(
+        btag.read_tag(30).unwrap_err().kind(),
+        DatabaseErrorKind::TagMissing
+    );
+}
@@ -0,0 +1,90 @@
+// Examples of docs/specification.md typed into `btag shell` print what the spec describes.
+
+mod common;
+
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use btag::*;
+use common::*;
+
+// users.jason.wallet.euro #3 is linked by admins.
+fn users() -> DatabaseWriter {
+    cluster(
+        0,
+        &[
+            (0, "users", &[], None),
+            (1, "jason", &[0], None),
+            (2, "wallet", &[1], None),
+            (3, "euro", &[2, 7], Some(TagType::Float(12.0))),
+            (4, "joey", &[0], None),
+            (5, "wallet", &[4], None),
+            (6, "euro", &[5], Some(TagType::Integer(1200))),
+            (7, "admins", &[], None),
+        ],
+    )
+}
+
+// Output of the shell for the input, without timings.
+fn shell(path: &Path, input: &str) -> (String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_btag"))
+        .arg("shell")
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|x| x.trim_start_matches("btag> "))
+        .filter(|x| !x.contains(" matches in ") && !x.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n");
+    (stdout, String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn spec_examples() {
+    let dir = test_dir("shell-spec");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users()]);
+
+    // Name of every parent of wallet, only first parent of euro is processed.
+    let (stdout, stderr) = shell(&path, "wallet.euro..0..%name\n");
+    assert_eq!(stderr, "");
+    assert_eq!(
+        stdout,
+        "#1 users.jason %name = jason\n#4 users.joey %name = joey"
+    );
+
+    let (stdout, _) = shell(&path, "users.%depth; wallet.euro.-\n");
+    assert_eq!(
+        stdout,
+        "#0 users %depth = 0\n\
+         #2 users.jason.wallet = [euro #3]\n\
+         #5 users.joey.wallet = [euro #6]"
+    );
+
+    // Misplaced property is pointed at.
+    let (stdout, stderr) = shell(&path, "users.%name.jason\n");
+    assert_eq!(stdout, "");
+    assert_eq!(
+        stderr,
+        "  users.%name.jason\n        ^^^^^\nbtag: property can only end a query\n"
+    );
+}
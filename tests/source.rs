@@ -0,0 +1,77 @@
+// Sources in tests/fixtures compile into valid databases, errors point at their line.
+
+mod common;
+
+use std::fs;
+
+use btag::*;
+use common::*;
+
+#[test]
+fn fixture_compiles() {
+    let dir = test_dir("source-fixture");
+    let path = dir.join("wallets.btag");
+
+    let source = fs::read_to_string("tests/fixtures/wallets.btagsrc").unwrap();
+    compile_source(&source).unwrap().write_file(&path).unwrap();
+
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(btag.clusters()[0].cluster_index(), 3);
+    let report = btag.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems());
+    assert_eq!(report.tags(), 18);
+
+    let statements = parse_query("users.*.wallet.euro").unwrap();
+    let query = match &statements[0] {
+        Statement::Get(v) => v,
+        _ => panic!("expected query"),
+    };
+    assert_eq!(btag.find(query).unwrap().len(), 2);
+
+    assert_eq!(btag.read_tag(87).unwrap().tag_data(), &TagType::Float(12.0));
+    assert_eq!(
+        btag.dereference_tag(111).unwrap().tag_data(),
+        &TagType::Float(12.0)
+    );
+    assert_eq!(btag.read_tag(6).unwrap().tag_data(), &TagType::Double(29.0));
+    assert_eq!(
+        btag.read_tag(9).unwrap().tag_data(),
+        &TagType::Char("jo".to_string())
+    );
+    assert_eq!(btag.read_tag(15).unwrap().tag_data(), &TagType::Integer(31));
+
+    // Linked tag keeps both lists as parents.
+    let mut dump: Vec<u8> = Vec::new();
+    btag.dump(&mut dump).unwrap();
+    let dump = String::from_utf8(dump).unwrap();
+    assert!(dump.contains("euro #87 Float = FLOAT 12.0 (depth 3, parents [wallet #3, admins #12]"));
+    assert!(dump.contains("best #14 Address = euro #8"));
+}
+
+#[test]
+fn errors_point_at_line() {
+    let error = match compile_source("a = 1\n\n// comment\na.b = 2\n") {
+        Ok(_) => panic!("expected error"),
+        Err(e) => e,
+    };
+    assert_eq!(error.line, 4);
+    assert_eq!(
+        error.to_string(),
+        "`a` has a value, it can't have entries at line 4, column 3"
+    );
+
+    let error = match compile_source("a = &b\nb = &a\n") {
+        Ok(_) => panic!("expected error"),
+        Err(e) => e,
+    };
+    assert_eq!(error.line, 1);
+
+    let error = match compile_source("a.#5\n") {
+        Ok(_) => panic!("expected error"),
+        Err(e) => e,
+    };
+    assert_eq!(
+        error.to_string(),
+        "#5 isn't declared yet at line 1, column 3"
+    );
+}
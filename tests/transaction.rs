@@ -0,0 +1,255 @@
+// Set, insert and delete across clusters are committed together or not at all.
+
+mod common;
+
+use std::fs;
+
+use btag::*;
+use common::*;
+
+fn users() -> DatabaseWriter {
+    let mut writer = cluster(
+        0,
+        &[
+            (0, "users", &[], None),
+            (1, "joey", &[0], None),
+            (2, "name", &[1], Some(TagType::Text("joey".to_string()))),
+            (3, "wallet", &[1], None),
+            (4, "euro", &[3], Some(TagType::Double(12.5))),
+            (5, "total", &[], None),
+        ],
+    );
+    let euro = writer.name("euro").unwrap();
+    writer
+        .set_value(5, TagType::AddressEntry(AddressEntry::new(euro, 4)))
+        .unwrap();
+    writer
+}
+
+fn numbers() -> DatabaseWriter {
+    cluster(
+        1,
+        &[
+            (10, "numbers", &[], None),
+            (11, "one", &[10], Some(TagType::Integer(1))),
+            (12, "two", &[10], Some(TagType::Integer(2))),
+        ],
+    )
+}
+
+#[test]
+fn insert_into_cluster_followed_by_another() {
+    let dir = test_dir("transaction-insert");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+
+    let mut btag = BTag::open(&path).unwrap();
+    btag.insert_tag(0, 20, "dollar", &[3], TagType::Double(40.5))
+        .unwrap();
+    btag.insert_tag(0, 21, "nick", &[1], TagType::Text("jo".to_string()))
+        .unwrap();
+    btag.insert_tag(1, 22, "three", &[10], TagType::Integer(3))
+        .unwrap();
+    drop(btag);
+
+    assert_readable(&path);
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(find_ids(&mut btag, "users.joey.wallet.*"), vec![4, 20]);
+    assert_eq!(find_ids(&mut btag, "users.joey.nick"), vec![21]);
+    assert_eq!(find_ids(&mut btag, "numbers.*"), vec![11, 12, 22]);
+    assert_eq!(
+        btag.read_tag(20).unwrap().tag_data(),
+        &TagType::Double(40.5)
+    );
+    assert_eq!(btag.locate_tag(20).unwrap().1.depth(), 3);
+}
+
+#[test]
+fn commit_keeps_every_change() {
+    let dir = test_dir("transaction-commit");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+
+    let mut btag = BTag::open(&path).unwrap();
+    let mut transaction = btag.transaction().unwrap();
+    transaction.set(11, TagType::Integer(100)).unwrap();
+    transaction
+        .insert(0, 20, "dollar", &[3], TagType::Double(40.5))
+        .unwrap();
+    transaction.delete(2).unwrap();
+    // Uncommitted changes are visible through the transaction.
+    assert_eq!(
+        transaction.btag().read_tag(20).unwrap().tag_data(),
+        &TagType::Double(40.5)
+    );
+    transaction.commit().unwrap();
+    assert!(!btag.in_journal());
+    drop(btag);
+
+    assert!(!Journal::path_for(&path).exists());
+    assert_readable(&path);
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(
+        btag.read_tag(11).unwrap().tag_data(),
+        &TagType::Integer(100)
+    );
+    assert_eq!(find_ids(&mut btag, "users.joey.*"), vec![3]);
+    assert_eq!(find_ids(&mut btag, "users.joey.wallet.dollar"), vec![20]);
+    assert_eq!(
+        btag.read_tag(2).unwrap_err().kind(),
+        DatabaseErrorKind::TagMissing
+    );
+}
+
+#[test]
+fn rollback_restores_the_file() {
+    let dir = test_dir("transaction-rollback");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+    let bytes = fs::read(&path).unwrap();
+
+    let mut btag = BTag::open(&path).unwrap();
+    let mut transaction = btag.transaction().unwrap();
+    transaction.set(12, TagType::Integer(200)).unwrap();
+    transaction
+        .set(2, TagType::Text("a name that has to be moved".to_string()))
+        .unwrap();
+    transaction
+        .insert(0, 20, "dollar", &[3], TagType::Double(40.5))
+        .unwrap();
+    transaction.rollback().unwrap();
+
+    // Clusters are loaded again, nothing of the transaction is left in memory.
+    assert!(btag.locate_tag(20).is_none());
+    assert_eq!(btag.read_tag(12).unwrap().tag_data(), &TagType::Integer(2));
+    drop(btag);
+
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+    assert!(!Journal::path_for(&path).exists());
+}
+
+#[test]
+fn dropped_transaction_is_rolled_back() {
+    let dir = test_dir("transaction-drop");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+    let bytes = fs::read(&path).unwrap();
+
+    let mut btag = BTag::open(&path).unwrap();
+    {
+        let mut transaction = btag.transaction().unwrap();
+        transaction
+            .insert(0, 20, "dollar", &[3], TagType::Double(40.5))
+            .unwrap();
+        transaction.set(11, TagType::Integer(100)).unwrap();
+    }
+    assert!(!btag.in_journal());
+    assert!(btag.locate_tag(20).is_none());
+    drop(btag);
+
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+}
+
+#[test]
+fn failed_change_aborts_the_transaction() {
+    let dir = test_dir("transaction-failed");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+    let bytes = fs::read(&path).unwrap();
+
+    let mut btag = BTag::open(&path).unwrap();
+    let mut transaction = btag.transaction().unwrap();
+    transaction.set(11, TagType::Integer(100)).unwrap();
+    transaction
+        .insert(0, 20, "dollar", &[3], TagType::Double(40.5))
+        .unwrap();
+    // Tag id is already taken. Insert into cluster 0 moved cluster 1 further into the file.
+    let btag_in_transaction = transaction.btag();
+    let eleven = btag_in_transaction.cluster(1).unwrap().index_table_offset()
+        + offset_of(btag_in_transaction, 11);
+    let error = transaction
+        .insert(1, 11, "eleven", &[10], TagType::Integer(11))
+        .unwrap_err();
+    assert_eq!(error.kind(), DatabaseErrorKind::TagDuplicateId);
+    // Error points at the tag that has the id.
+    assert_eq!(error.offset(), Some(eleven));
+    assert_eq!(error.cluster_index(), Some(1));
+    assert_eq!(error.path(), Some(path.as_path()));
+
+    // Nothing can be done with the transaction anymore.
+    assert_eq!(
+        transaction.set(12, TagType::Integer(1)).unwrap_err().kind(),
+        DatabaseErrorKind::TransactionState
+    );
+    assert_eq!(
+        transaction.commit().unwrap_err().kind(),
+        DatabaseErrorKind::TransactionState
+    );
+
+    assert_eq!(btag.read_tag(11).unwrap().tag_data(), &TagType::Integer(1));
+    assert!(btag.locate_tag(20).is_none());
+    drop(btag);
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+}
+
+#[test]
+fn delete_refuses_parents_and_referenced_tags() {
+    let dir = test_dir("transaction-delete");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+    let bytes = fs::read(&path).unwrap();
+
+    let mut btag = BTag::open(&path).unwrap();
+    let index_table_offset = btag.cluster(0).unwrap().index_table_offset();
+    // wallet has children, euro is referenced by total.
+    for tag_id in [3, 4] {
+        let offset = index_table_offset + offset_of(&btag, tag_id);
+        let error = btag.delete_tag(tag_id).unwrap_err();
+        assert_eq!(error.kind(), DatabaseErrorKind::TagReferenced);
+        assert_eq!(error.offset(), Some(offset));
+        assert_eq!(error.cluster_index(), Some(0));
+        assert_eq!(error.path(), Some(path.as_path()));
+    }
+    assert_eq!(find_ids(&mut btag, "users.joey.wallet.euro"), vec![4]);
+    drop(btag);
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+
+    // Once the reference is gone, euro and then wallet can be deleted.
+    let mut btag = BTag::open(&path).unwrap();
+    btag.delete_tag(5).unwrap();
+    btag.delete_tag(4).unwrap();
+    btag.delete_tag(3).unwrap();
+    assert_eq!(find_ids(&mut btag, "users.joey.*"), vec![2]);
+    drop(btag);
+    assert_readable(&path);
+}
+
+#[test]
+fn changes_are_logged_in_the_journal() {
+    let dir = test_dir("transaction-queries");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+
+    let mut btag = BTag::open(&path).unwrap();
+    let mut transaction = btag.transaction().unwrap();
+    transaction.set(11, TagType::Integer(100)).unwrap();
+    transaction
+        .insert(0, 20, "dollar", &[3], TagType::Double(40.5))
+        .unwrap();
+    transaction
+        .insert(0, 21, "nick", &[1, 3], TagType::Text("jo".to_string()))
+        .unwrap();
+    transaction.delete(2).unwrap();
+    let contents = Journal::read(&Journal::path_for(&path)).unwrap();
+    assert_eq!(
+        contents.queries(),
+        [
+            "BEGIN",
+            "#11 = 100",
+            "INSERT #20 \"dollar\" = 40.5 INTO #3 OF CLUSTER 0",
+            "INSERT #21 \"nick\" = \"jo\" INTO #1, #3 OF CLUSTER 0",
+            "DELETE #2",
+        ]
+    );
+    transaction.rollback().unwrap();
+}
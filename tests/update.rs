@@ -0,0 +1,234 @@
+// Values are rewritten in place or moved, together with every address pointing to them,
+// in any cluster of a multi-cluster file.
+// Reference "Value address moving" and "Cluster growth" of docs/specification.md.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use btag::*;
+use common::*;
+
+fn users() -> DatabaseWriter {
+    let mut writer = cluster(
+        0,
+        &[
+            (0, "users", &[], None),
+            (1, "joey", &[0], None),
+            (2, "name", &[1], Some(TagType::Text("joey".to_string()))),
+            (3, "wallet", &[1], None),
+            (4, "euro", &[3], Some(TagType::Double(12.5))),
+            (5, "best", &[], None),
+        ],
+    );
+    let name = writer.name("name").unwrap();
+    writer
+        .set_value(5, TagType::AddressEntry(AddressEntry::new(name, 2)))
+        .unwrap();
+    writer
+}
+
+fn numbers() -> DatabaseWriter {
+    cluster(
+        1,
+        &[
+            (10, "numbers", &[], None),
+            (11, "one", &[10], Some(TagType::Integer(1))),
+            (12, "two", &[10], Some(TagType::Text("two".to_string()))),
+        ],
+    )
+}
+
+fn long_text() -> TagType {
+    TagType::Text("joey, who has a name much longer than the old one".to_string())
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).unwrap().len()
+}
+
+#[test]
+fn value_that_fits_is_rewritten_in_place() {
+    let dir = test_dir("update-in-place");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+    let size = file_size(&path);
+
+    let mut btag = BTag::open(&path).unwrap();
+    let offset = offset_of(&btag, 4);
+    btag.set_value(4, TagType::Double(7.0)).unwrap();
+    btag.set_value(2, TagType::Text("jo".to_string())).unwrap();
+    btag.set_value(11, TagType::Integer(2)).unwrap();
+    assert_eq!(offset_of(&btag, 4), offset);
+    drop(btag);
+
+    assert_eq!(file_size(&path), size);
+    assert_readable(&path);
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(btag.read_tag(4).unwrap().tag_data(), &TagType::Double(7.0));
+    assert_eq!(
+        btag.read_tag(2).unwrap().tag_data(),
+        &TagType::Text("jo".to_string())
+    );
+    assert_eq!(btag.read_tag(11).unwrap().tag_data(), &TagType::Integer(2));
+}
+
+#[test]
+fn moved_value_keeps_every_reference() {
+    let dir = test_dir("update-move");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+
+    // Cluster 0 isn't the last one, it has to grow in the middle of the file.
+    let mut btag = BTag::open(&path).unwrap();
+    let numbers_offset = btag.cluster(1).unwrap().cluster_offset();
+    let old_offset = offset_of(&btag, 2);
+    btag.set_value(2, long_text()).unwrap();
+    let new_offset = offset_of(&btag, 2);
+    assert_ne!(new_offset, old_offset);
+    assert!(btag.cluster(1).unwrap().cluster_offset() > numbers_offset);
+    drop(btag);
+
+    assert_readable(&path);
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(offset_of(&btag, 2), new_offset);
+    assert_eq!(btag.read_tag(2).unwrap().tag_data(), &long_text());
+    // AddressEntry and AddressList entry of the parent.
+    match btag.read_tag(5).unwrap().tag_data() {
+        TagType::AddressEntry(entry) => assert_eq!(entry.address(), new_offset),
+        other => panic!("unexpected value {:?}", other),
+    }
+    match btag.read_tag(1).unwrap().tag_data() {
+        TagType::AddressList(list) => assert_eq!(list.array()[0].address(), new_offset),
+        other => panic!("unexpected value {:?}", other),
+    }
+    // Old record is left in place as a free one.
+    let index_table_offset = btag.cluster(0).unwrap().index_table_offset();
+    let bytes = fs::read(&path).unwrap();
+    assert_eq!(
+        bytes[(index_table_offset + old_offset) as usize + 40 + 16],
+        DATA_TYPE_FREE
+    );
+    assert_eq!(
+        btag.read_tag(12).unwrap().tag_data(),
+        &TagType::Text("two".to_string())
+    );
+}
+
+#[test]
+fn moved_list_keeps_parents_of_children() {
+    let dir = test_dir("update-move-list");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+
+    let mut btag = BTag::open(&path).unwrap();
+    let mut array = match btag.read_tag(1).unwrap().tag_data() {
+        TagType::AddressList(list) => list.array().to_vec(),
+        other => panic!("unexpected value {:?}", other),
+    };
+    let best = btag.locate_tag(5).unwrap().1;
+    array.push(AddressEntry::new(best.name(), best.offset()));
+    btag.set_value(1, TagType::AddressList(AddressList::new(array)))
+        .unwrap();
+    let joey = offset_of(&btag, 1);
+    drop(btag);
+
+    assert_readable(&path);
+    let mut btag = BTag::open(&path).unwrap();
+    for child in [2, 3] {
+        let parents = btag.read_tag(child).unwrap().tag_parents().array().to_vec();
+        assert_eq!(parents[0].address(), joey);
+    }
+    match btag.read_tag(0).unwrap().tag_data() {
+        TagType::AddressList(list) => assert_eq!(list.array()[0].address(), joey),
+        other => panic!("unexpected value {:?}", other),
+    }
+    match btag.read_tag(1).unwrap().tag_data() {
+        TagType::AddressList(list) => assert_eq!(list.address_count(), 3),
+        other => panic!("unexpected value {:?}", other),
+    }
+}
+
+#[test]
+fn growing_cluster_leaves_space_before_the_next_one() {
+    let dir = test_dir("update-grow");
+    let path = dir.join("users.btag");
+    let empty = DatabaseWriter::new(2, TextEncoding::Utf8);
+    write_clusters(&path, &[users(), numbers(), empty]);
+
+    // First move makes room for more than it needs, second one fits into it.
+    let mut btag = BTag::open(&path).unwrap();
+    btag.set_value(2, long_text()).unwrap();
+    let offsets: Vec<u64> = btag.clusters().iter().map(|x| x.cluster_offset()).collect();
+    assert!(offsets[1] > btag.cluster(0).unwrap().database_size());
+    btag.set_value(4, long_text()).unwrap();
+    let moved: Vec<u64> = btag.clusters().iter().map(|x| x.cluster_offset()).collect();
+    assert_eq!(moved, offsets);
+
+    // Cluster in the middle grows the same way.
+    btag.set_value(12, long_text()).unwrap();
+    drop(btag);
+
+    assert_readable(&path);
+    let mut btag = BTag::open(&path).unwrap();
+    for tag_id in [2, 4, 12] {
+        assert_eq!(btag.read_tag(tag_id).unwrap().tag_data(), &long_text());
+    }
+    assert_eq!(btag.clusters().len(), 3);
+    assert!(btag.tags_index(2).unwrap().tags().is_empty());
+}
+
+#[test]
+fn last_cluster_grows_with_the_file() {
+    let dir = test_dir("update-last");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[numbers(), users()]);
+
+    let mut btag = BTag::open(&path).unwrap();
+    btag.set_value(2, long_text()).unwrap();
+    let cluster = btag.cluster(0).unwrap();
+    assert_eq!(
+        cluster.cluster_offset() + cluster.database_size(),
+        file_size(&path)
+    );
+    drop(btag);
+
+    assert_readable(&path);
+}
+
+#[test]
+fn refused_changes_point_at_the_tag() {
+    let dir = test_dir("update-refused");
+    let path = dir.join("users.btag");
+    write_clusters(&path, &[users(), numbers()]);
+    let bytes = fs::read(&path).unwrap();
+    let mut btag = BTag::open(&path).unwrap();
+    let position = |btag: &BTag, cluster_index: u64, tag_id: u64| {
+        btag.cluster(cluster_index).unwrap().index_table_offset() + offset_of(btag, tag_id)
+    };
+
+    // Reference can't leave it's cluster.
+    let statement = match parse_query("numbers.two = &users.joey.name")
+        .unwrap()
+        .remove(0)
+    {
+        Statement::Set(v) => v,
+        v => panic!("expected set, found {:?}", v),
+    };
+    let error = btag.execute_set(&statement).unwrap_err();
+    assert_eq!(error.kind(), DatabaseErrorKind::TagValidity);
+    assert_eq!(error.offset(), Some(position(&btag, 1, 12)));
+    assert_eq!(error.cluster_index(), Some(1));
+    assert_eq!(error.path(), Some(path.as_path()));
+
+    let address = offset_of(&btag, 5);
+    let error = btag
+        .set_value(5, TagType::ValueReference(ValueReference::new(address)))
+        .unwrap_err();
+    assert_eq!(error.kind(), DatabaseErrorKind::ReferenceCycle);
+    assert_eq!(error.offset(), Some(position(&btag, 0, 5)));
+    assert_eq!(error.cluster_index(), Some(0));
+    assert_eq!(error.path(), Some(path.as_path()));
+    drop(btag);
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+}
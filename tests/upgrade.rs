@@ -0,0 +1,104 @@
+// Version 1 clusters are read as they are and upgraded in place, other versions are refused.
+// Reference "Database cluster metadata" of docs/specification.md.
+
+mod common;
+
+use std::{fs, path::Path};
+
+use btag::*;
+use common::*;
+
+// Cluster 0 of version 1, written the way it was before REFERENCE_COUNT_TABLE existed.
+fn wallets() -> RawCluster {
+    RawCluster::new(
+        0,
+        &[(1, "wallets"), (2, "euro"), (3, "dollar"), (4, "total")],
+        vec![
+            RawTag(0, 1, vec![], RawValue::Children(vec![1, 2])),
+            RawTag(1, 2, vec![0], RawValue::Double(12.5)),
+            RawTag(2, 3, vec![0], RawValue::Text("forty")),
+            RawTag(3, 4, vec![], RawValue::Reference(1)),
+        ],
+    )
+}
+
+// Every value of the wallets cluster, as it's read by tag id and found by query.
+fn assert_wallets(path: &Path) {
+    assert_readable(path);
+    let mut btag = BTag::open(path).unwrap();
+    assert_eq!(btag.read_tag(1).unwrap().tag_data(), &TagType::Double(12.5));
+    assert_eq!(
+        btag.read_tag(2).unwrap().tag_data(),
+        &TagType::Text("forty".to_string())
+    );
+    assert_eq!(
+        btag.dereference_tag(3).unwrap().tag_data(),
+        &TagType::Double(12.5)
+    );
+    assert_eq!(find_ids(&mut btag, "wallets.*"), vec![1, 2]);
+    assert_eq!(find_ids(&mut btag, "euro.."), vec![0]);
+    assert_eq!(btag.name_string(0, 4), Some("total"));
+}
+
+#[test]
+fn v1_cluster_is_read_and_upgraded() {
+    let dir = test_dir("upgrade-v1");
+    let path = dir.join("wallets.btag");
+    write_raw(&path, &[&wallets()]);
+    assert_wallets(&path);
+
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(btag.cluster(0).unwrap().version(), 1);
+    assert_eq!(btag.upgrade().unwrap(), vec![0]);
+    assert_eq!(btag.cluster(0).unwrap().version(), FORMAT_VERSION);
+    assert!(btag.upgrade().unwrap().is_empty());
+    drop(btag);
+
+    assert_valid(&path);
+    assert_wallets(&path);
+}
+
+#[test]
+fn upgraded_cluster_must_fit_before_the_next_one() {
+    let dir = test_dir("upgrade-full");
+    let path = dir.join("wallets.btag");
+    let size = wallets().to_bytes(0, 0).len() as u64;
+    let mut bytes = wallets().to_bytes(0, size);
+    let numbers = cluster(
+        1,
+        &[
+            (10, "numbers", &[], None),
+            (11, "one", &[10], Some(TagType::Integer(1))),
+        ],
+    );
+    bytes.extend(numbers.to_bytes(size).unwrap());
+    fs::write(&path, &bytes).unwrap();
+    assert_wallets(&path);
+
+    let mut btag = BTag::open(&path).unwrap();
+    assert_eq!(
+        btag.upgrade().unwrap_err().kind(),
+        DatabaseErrorKind::ClusterFull
+    );
+    assert_eq!(btag.read_tag(11).unwrap().tag_data(), &TagType::Integer(1));
+    drop(btag);
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+}
+
+#[test]
+fn unsupported_versions_are_refused() {
+    let dir = test_dir("upgrade-unsupported");
+    let path = dir.join("wallets.btag");
+    for version in [0u32, 3, u32::MAX] {
+        let mut bytes = wallets().to_bytes(0, 0);
+        bytes[4..8].copy_from_slice(&version.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        match BTag::open(&path) {
+            Err(v) => {
+                assert_eq!(v.kind(), DatabaseErrorKind::ClusterIncompatibleVersion);
+                assert_eq!(v.offset(), Some(0));
+            }
+            Ok(_) => panic!("expected version {} to be refused", version),
+        }
+    }
+}
@@ -0,0 +1,217 @@
+// Every problem of a broken file is reported together with where it was found.
+// Problems are made in the second cluster, so offsets are absolute in the file.
+
+mod common;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use btag::*;
+use common::*;
+
+fn numbers() -> DatabaseWriter {
+    cluster(
+        0,
+        &[
+            (0, "numbers", &[], None),
+            (1, "one", &[0], Some(TagType::Integer(1))),
+        ],
+    )
+}
+
+// users.alpha.euro #12 is linked by links, gamma holds an AddressEntry of it.
+fn users() -> DatabaseWriter {
+    let mut writer = cluster(
+        5,
+        &[
+            (10, "users", &[], None),
+            (11, "alpha", &[10], None),
+            (12, "euro", &[11, 14], Some(TagType::Double(12.5))),
+            (13, "gamma", &[10], Some(TagType::Integer(0))),
+            (14, "links", &[], None),
+        ],
+    );
+    let euro = writer.name("euro").unwrap();
+    writer
+        .set_value(13, TagType::AddressEntry(AddressEntry::new(euro, 12)))
+        .unwrap();
+    writer
+}
+
+struct Broken {
+    path: PathBuf,
+    bytes: Vec<u8>,
+    cluster_offset: u64,
+    index_table_offset: u64,
+}
+
+impl Broken {
+    fn new(dir: &Path) -> Broken {
+        let path = dir.join("users.btag");
+        write_clusters(&path, &[numbers(), users()]);
+        assert_valid(&path);
+        let btag = BTag::open(&path).unwrap();
+        let cluster = btag.cluster(5).unwrap();
+        Broken {
+            bytes: fs::read(&path).unwrap(),
+            cluster_offset: cluster.cluster_offset(),
+            index_table_offset: cluster.index_table_offset(),
+            path,
+        }
+    }
+
+    // Absolute position of the record of the tag.
+    fn record(&self, tag_id: u64) -> u64 {
+        let btag = BTag::open(&self.path).unwrap();
+        self.index_table_offset + offset_of(&btag, tag_id)
+    }
+
+    fn patch(&mut self, position: u64, buf: &[u8]) {
+        let position = position as usize;
+        self.bytes[position..position + buf.len()].copy_from_slice(buf);
+    }
+
+    fn position_of(&self, buf: &[u8]) -> u64 {
+        self.bytes
+            .windows(buf.len())
+            .position(|x| x == buf)
+            .unwrap() as u64
+    }
+
+    fn problems(&self) -> Vec<VerifyProblem> {
+        fs::write(&self.path, &self.bytes).unwrap();
+        let report = DatabaseReader::open(&self.path).unwrap().verify().unwrap();
+        assert_eq!(report.clusters(), 2);
+        report.problems().to_vec()
+    }
+}
+
+fn assert_located(problem: &VerifyProblem, path: &Path, offset: u64, tag_id: Option<u64>) {
+    assert_eq!(problem.path(), Some(path));
+    assert_eq!(problem.offset(), Some(offset));
+    assert_eq!(problem.cluster_index(), Some(5));
+    assert_eq!(problem.tag_id(), tag_id);
+}
+
+#[test]
+fn database_size_past_the_file() {
+    let dir = test_dir("verify-size");
+    let mut broken = Broken::new(&dir);
+    let size = broken.bytes.len() as u64 - broken.cluster_offset;
+    broken.patch(broken.cluster_offset + 26, &(size + 100).to_le_bytes());
+
+    let problems = broken.problems();
+    assert_eq!(problems.len(), 1);
+    assert_eq!(
+        problems[0].kind(),
+        VerifyProblemKind::DatabaseSize {
+            database_size: size + 100,
+            available: size
+        }
+    );
+    assert_located(&problems[0], &broken.path, broken.cluster_offset + 26, None);
+    assert!(problems[0].to_string().contains("of cluster 5"));
+}
+
+#[test]
+fn index_entry_pointing_to_another_record() {
+    let dir = test_dir("verify-tag-id");
+    let mut broken = Broken::new(&dir);
+    let record = broken.record(12);
+    broken.patch(record, &99u64.to_le_bytes());
+
+    let problems = broken.problems();
+    assert_eq!(
+        problems[0].kind(),
+        VerifyProblemKind::TagIdMismatch { record_tag_id: 99 }
+    );
+    assert_located(&problems[0], &broken.path, record, Some(12));
+    // Values referencing the record are left with a dangling address.
+    assert!(problems[1..]
+        .iter()
+        .all(|x| matches!(x.kind(), VerifyProblemKind::DanglingAddress { .. })));
+}
+
+#[test]
+fn dangling_address_entry() {
+    let dir = test_dir("verify-dangling");
+    let mut broken = Broken::new(&dir);
+    // gamma has users as it's only parent, value is type(u8) size(u64) name(u64) address(u64).
+    let record = broken.record(13);
+    broken.patch(record + 40 + 16 + 9 + 8, &7u64.to_le_bytes());
+
+    let problems = broken.problems();
+    assert_eq!(
+        problems[0].kind(),
+        VerifyProblemKind::DanglingAddress { address: 7 }
+    );
+    assert_located(&problems[0], &broken.path, record, Some(13));
+    // Reference count table still counts the old address, and not the new one.
+    assert_eq!(problems.len(), 3);
+    assert!(problems[1..]
+        .iter()
+        .all(|x| matches!(x.kind(), VerifyProblemKind::ReferenceCountMismatch { .. })));
+}
+
+#[test]
+fn duplicate_names() {
+    let dir = test_dir("verify-names");
+    let mut broken = Broken::new(&dir);
+    let btag = BTag::open(&broken.path).unwrap();
+    let alpha = btag.name_id(5, "alpha").unwrap();
+    let gamma = btag.name_id(5, "gamma").unwrap();
+    drop(btag);
+
+    // Entry is name(u64) name_string_size(u16) name_string.
+    let string = broken.position_of(b"gamma");
+    broken.patch(string, b"alpha");
+    let problems = broken.problems();
+    assert_eq!(problems.len(), 1);
+    assert_eq!(
+        problems[0].kind(),
+        VerifyProblemKind::NameDuplicateString { name: gamma }
+    );
+    assert_located(&problems[0], &broken.path, broken.index_table_offset, None);
+
+    broken.patch(string, b"gamma");
+    broken.patch(string - 10, &alpha.to_le_bytes());
+    let problems = broken.problems();
+    assert_eq!(
+        problems[0].kind(),
+        VerifyProblemKind::NameDuplicateId { name: alpha }
+    );
+    assert_located(&problems[0], &broken.path, broken.index_table_offset, None);
+}
+
+#[test]
+fn depth_disagreeing_with_parents() {
+    let dir = test_dir("verify-depth");
+    let mut broken = Broken::new(&dir);
+    // euro is under users.alpha, links has it as well.
+    let record = broken.record(12);
+    let root = broken.record(10);
+    broken.patch(record + 24, &5u64.to_le_bytes());
+
+    let problems = broken.problems();
+    assert_eq!(problems.len(), 1);
+    assert_eq!(
+        problems[0].kind(),
+        VerifyProblemKind::DepthMismatch {
+            tag_depth: 5,
+            expected: 2
+        }
+    );
+    assert_located(&problems[0], &broken.path, record, Some(12));
+
+    // Root tags have depth 0.
+    broken.patch(root + 24, &1u64.to_le_bytes());
+    let problems = broken.problems();
+    assert!(problems.iter().any(|x| x.kind()
+        == VerifyProblemKind::DepthMismatch {
+            tag_depth: 1,
+            expected: 0
+        }
+        && x.tag_id() == Some(10)));
+}